@@ -137,6 +137,18 @@ impl kernel::SysTick for SysTick {
         SYSTICK_BASE.syst_csr.is_set(ControlAndStatus::COUNTFLAG)
     }
 
+    fn get_value(&self) -> u32 {
+        let tics = SYSTICK_BASE.syst_cvr.read(CurrentValue::CURRENT) as u64;
+        let hertz = self.hertz() as u64;
+        if hertz == 0 {
+            return 0;
+        }
+
+        // Convert back from native tics to microseconds, again in 64-bit
+        // arithmetic to avoid overflow.
+        (tics * 1_000_000 / hertz) as u32
+    }
+
     fn reset(&self) {
         SYSTICK_BASE.syst_csr.set(0);
         SYSTICK_BASE.syst_rvr.set(0);
@@ -1,9 +1,11 @@
 //! Implementation of the ARM memory protection unit.
+//!
+//! The regions for each process are laid out by `kernel::mpu::cortexm`.
 
 use kernel;
 use kernel::common::cells::VolatileCell;
-use kernel::common::math::PowerOfTwo;
 use kernel::common::StaticRef;
+use kernel::mpu::{self, MpuConfig, ProcessMemoryLayout, Region};
 
 /// Indicates whether the MPU is present and, if so, how many regions it
 /// supports.
@@ -116,8 +118,6 @@ impl MPU {
     }
 }
 
-type Region = kernel::mpu::Region;
-
 impl kernel::mpu::MPU for MPU {
     fn enable_mpu(&self) {
         let regs = &*self.0;
@@ -128,7 +128,7 @@ impl kernel::mpu::MPU for MPU {
 
         let mpu_type = regs.mpu_type.get();
         let regions = mpu_type.data_regions.get();
-        if regions != 8 {
+        if regions as usize != mpu::cortexm::NUM_REGIONS {
             panic!(
                 "Tock currently assumes 8 MPU regions. This chip has {}",
                 regions
@@ -141,135 +141,23 @@ impl kernel::mpu::MPU for MPU {
         regs.control.set(0b0);
     }
 
-    fn create_region(
-        &self,
-        region_num: usize,
-        start: usize,
-        len: usize,
-        execute: kernel::mpu::ExecutePermission,
-        access: kernel::mpu::AccessPermission,
-    ) -> Option<Region> {
-        if region_num >= 8 {
-            // There are only 8 (0-indexed) regions available
-            return None;
-        }
-
-        // There are two possibilities we support:
-        //
-        // 1. The base address is aligned exactly to the size of the region,
-        //    which uses an MPU region with the exact base address and size of
-        //    the memory region.
-        //
-        // 2. Otherwise, we can use a larger MPU region and expose only MPU
-        //    subregions, as long as the memory region's base address is aligned
-        //    to 1/8th of a larger region size.
-
-        if start % len == 0 {
-            // Memory base aligned to memory size - straight forward case
-            let region_len = PowerOfTwo::floor(len as u32);
-            if region_len.exp::<u32>() < 5 {
-                // Region sizes must be 32 Bytes or larger
-                return None;
-            } else if region_len.exp::<u32>() > 32 {
-                // Region sizes must be 4GB or smaller
-                return None;
-            }
-
-            let xn = execute as u32;
-            let ap = access as u32;
-            Some(unsafe {
-                Region::new(
-                    (start | 1 << 4 | (region_num & 0xf)) as u32,
-                    1 | (region_len.exp::<u32>() - 1) << 1 | ap << 24 | xn << 28,
-                )
-            })
-        } else {
-            // Memory base not aligned to memory size
-
-            // Which (power-of-two) subregion size would align with the base
-            // address?
-            //
-            // We find this by taking smallest binary substring of the base
-            // address with exactly one bit:
-            //
-            //      1 << (start.trailing_zeros())
-            let subregion_size = {
-                let tz = start.trailing_zeros();
-                // `start` should never be 0 because of that's taken care of by
-                // the previous branch, but in case it is, do the right thing
-                // anyway.
-                if tz < 32 {
-                    (1 as usize) << tz
-                } else {
-                    0
-                }
-            };
-
-            // Once we have a subregion size, we get a region size by
-            // multiplying it by the number of subregions per region.
-            let region_size = subregion_size * 8;
-            // Finally, we calculate the region base by finding the nearest
-            // address below `start` that aligns with the region size.
-            let region_start = start - (start % region_size);
-
-            if region_size + region_start - start < len {
-                // Sanity check that the amount left over space in the region
-                // after `start` is at least as large as the memory region we
-                // want to reference.
-                return None;
-            }
-            if len % subregion_size != 0 {
-                // Sanity check that there is some integer X such that
-                // subregion_size * X == len so none of `len` is left over when
-                // we take the max_subregion.
-                return None;
-            }
-
-            // The index of the first subregion to activate is the number of
-            // regions between `region_start` (MPU) and `start` (memory).
-            let min_subregion = (start - region_start) / subregion_size;
-            // The index of the last subregion to activate is the number of
-            // regions that fit in `len`, plus the `min_subregion`, minus one
-            // (because subregions are zero-indexed).
-            let max_subregion = min_subregion + len / subregion_size - 1;
-
-            let region_len = PowerOfTwo::floor(region_size as u32);
-            if region_len.exp::<u32>() < 7 {
-                // Subregions only supported for regions sizes 128 bytes and up.
-                return None;
-            } else if region_len.exp::<u32>() > 32 {
-                // Region sizes must be 4GB or smaller
-                return None;
-            }
-
-            // Turn the min/max subregion into a bitfield where all bits are `1`
-            // except for the bits whose index lie within
-            // [min_subregion, max_subregion]
-            //
-            // Note: Rust ranges are minimum inclusive, maximum exclusive, hence
-            // max_subregion + 1.
-            let subregion_mask =
-                (min_subregion..(max_subregion + 1)).fold(!0, |res, i| res & !(1 << i)) & 0xff;
-
-            let xn = execute as u32;
-            let ap = access as u32;
-            Some(unsafe {
-                Region::new(
-                    (region_start | 1 << 4 | (region_num & 0xf)) as u32,
-                    1 | subregion_mask << 8
-                        | (region_len.exp::<u32>() - 1) << 1
-                        | ap << 24
-                        | xn << 28,
-                )
-            })
-        }
+    fn process_config(&self, layout: &ProcessMemoryLayout) -> Option<MpuConfig> {
+        mpu::cortexm::process_config(layout)
     }
 
-    fn set_mpu(&self, region: Region) {
+    fn configure(&self, config: &MpuConfig) {
         let regs = &*self.0;
 
-        regs.region_base_address.set(region.base_address());
+        for region in config.regions() {
+            regs.region_base_address.set(region.base_address());
+            regs.region_attributes_and_size.set(region.attributes());
+        }
 
-        regs.region_attributes_and_size.set(region.attributes());
+        // Disable the regions the configuration does not use.
+        for region_num in config.regions().len()..mpu::cortexm::NUM_REGIONS {
+            let region = Region::empty(region_num);
+            regs.region_base_address.set(region.base_address());
+            regs.region_attributes_and_size.set(region.attributes());
+        }
     }
 }
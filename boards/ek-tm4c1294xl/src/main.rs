@@ -52,6 +52,7 @@ struct EkTm4c1294xl {
     >,
     gpio: &'static capsules::gpio::GPIO<'static, tm4c129x::gpio::GPIOPin>,
     ipc: kernel::ipc::IPC,
+    message_ipc: kernel::ipc::message::MessageIPC,
     led: &'static capsules::led::LED<'static, tm4c129x::gpio::GPIOPin>,
     button: &'static capsules::button::Button<'static, tm4c129x::gpio::GPIOPin>,
 }
@@ -67,6 +68,7 @@ impl Platform for EkTm4c1294xl {
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc::message::DRIVER_NUM => f(Some(&self.message_ipc)),
             capsules::led::DRIVER_NUM => f(Some(self.led)),
             capsules::button::DRIVER_NUM => f(Some(self.button)),
             _ => f(None),
@@ -82,7 +84,7 @@ pub unsafe fn reset_handler() {
     tm4c129x::sysctl::PSYSCTLM
         .setup_system_clock(tm4c129x::sysctl::SystemClockSource::PllPioscAt120MHz);
 
-    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&mut PROCESSES));
 
     // Create capabilities that the board needs to call certain protected kernel
     // functions.
@@ -236,6 +238,10 @@ pub unsafe fn reset_handler() {
         alarm: alarm,
         gpio: gpio,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
+        message_ipc: kernel::ipc::message::MessageIPC::new(
+            board_kernel,
+            &memory_allocation_capability,
+        ),
         led: led,
         button: button,
     };
@@ -257,14 +263,16 @@ pub unsafe fn reset_handler() {
         &cortexm4::syscall::SysCall::new(),
         &_sapps as *const u8,
         &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
+        &FAULT_RESPONSE,
         &process_management_capability,
     );
+
+    let scheduler = kernel::schedulers::RoundRobinSched::new();
     board_kernel.kernel_loop(
         &tm4c1294,
         &mut chip,
         Some(&tm4c1294.ipc),
+        &scheduler,
         &main_loop_capability,
     );
 }
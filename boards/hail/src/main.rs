@@ -15,11 +15,13 @@ extern crate kernel;
 extern crate cortexm4;
 extern crate sam4l;
 
+use capsules::overflow_alarm::OverflowAlarm;
 use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use capsules::virtual_i2c::{I2CDevice, MuxI2C};
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
 use capsules::virtual_uart::{UartDevice, UartMux};
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::hil;
 use kernel::hil::spi::SpiMaster;
 use kernel::hil::Controller;
@@ -68,6 +70,7 @@ struct Hail {
         'static,
         VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>,
     >,
+    kernel_clock: &'static capsules::kernel_clock::KernelClockDriver,
     ambient_light: &'static capsules::ambient_light::AmbientLight<'static>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
@@ -82,6 +85,7 @@ struct Hail {
     button: &'static capsules::button::Button<'static, sam4l::gpio::GPIOPin>,
     rng: &'static capsules::rng::SimpleRng<'static, sam4l::trng::Trng<'static>>,
     ipc: kernel::ipc::IPC,
+    message_ipc: kernel::ipc::message::MessageIPC,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
     dac: &'static capsules::dac::Dac<'static>,
 }
@@ -97,6 +101,7 @@ impl Platform for Hail {
             capsules::gpio::DRIVER_NUM => f(Some(self.gpio)),
 
             capsules::alarm::DRIVER_NUM => f(Some(self.alarm)),
+            capsules::kernel_clock::DRIVER_NUM => f(Some(self.kernel_clock)),
             capsules::spi::DRIVER_NUM => f(Some(self.spi)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::ambient_light::DRIVER_NUM => f(Some(self.ambient_light)),
@@ -114,6 +119,7 @@ impl Platform for Hail {
             capsules::dac::DRIVER_NUM => f(Some(self.dac)),
 
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc::message::DRIVER_NUM => f(Some(&self.message_ipc)),
             _ => f(None),
         }
     }
@@ -201,7 +207,7 @@ pub unsafe fn reset_handler() {
 
     set_pin_primary_functions();
 
-    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&mut PROCESSES));
 
     // Create capabilities that the board needs to call certain protected kernel
     // functions.
@@ -210,6 +216,15 @@ pub unsafe fn reset_handler() {
     let main_loop_capability = create_capability!(capabilities::MainLoopCapability);
     let memory_allocation_capability = create_capability!(capabilities::MemoryAllocationCapability);
 
+    // Deferred calls for capsules, serviced by the kernel loop.
+    let dynamic_deferred_call_clients =
+        static_init!([DynamicDeferredCallClientState; 4], Default::default());
+    let dynamic_deferred_caller = static_init!(
+        DynamicDeferredCall,
+        DynamicDeferredCall::new(dynamic_deferred_call_clients)
+    );
+    board_kernel.set_dynamic_deferred_call(dynamic_deferred_caller, &main_loop_capability);
+
     // Configure kernel debug gpios as early as possible
     kernel::debug::assign_gpios(
         Some(&sam4l::gpio::PA[13]),
@@ -352,6 +367,26 @@ pub unsafe fn reset_handler() {
     );
     virtual_alarm1.set_client(alarm);
 
+    // Kernel clock, which also ends process timeslices instead of SysTick
+    let kernel_clock_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let kernel_clock = static_init!(
+        OverflowAlarm<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+        OverflowAlarm::new(kernel_clock_alarm)
+    );
+    kernel_clock_alarm.set_client(kernel_clock);
+    kernel_clock.start();
+    board_kernel.set_kernel_clock(kernel_clock, &main_loop_capability);
+    let kernel_clock_driver = static_init!(
+        capsules::kernel_clock::KernelClockDriver,
+        capsules::kernel_clock::KernelClockDriver::new(
+            kernel_clock,
+            board_kernel.create_grant(&memory_allocation_capability)
+        )
+    );
+
     // FXOS8700CQ accelerometer, device address 0x1e
     let fxos8700_i2c = static_init!(I2CDevice, I2CDevice::new(sensors_i2c, 0x1e));
     let fxos8700 = static_init!(
@@ -546,7 +581,12 @@ pub unsafe fn reset_handler() {
         led: led,
         button: button,
         rng: rng,
+        kernel_clock: kernel_clock_driver,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
+        message_ipc: kernel::ipc::message::MessageIPC::new(
+            board_kernel,
+            &memory_allocation_capability,
+        ),
         crc: crc,
         dac: dac,
     };
@@ -593,9 +633,16 @@ pub unsafe fn reset_handler() {
         &cortexm4::syscall::SysCall::new(),
         &_sapps as *const u8,
         &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
+        &FAULT_RESPONSE,
         &process_management_capability,
     );
-    board_kernel.kernel_loop(&hail, &mut chip, Some(&hail.ipc), &main_loop_capability);
+
+    let scheduler = kernel::schedulers::RoundRobinSched::new();
+    board_kernel.kernel_loop(
+        &hail,
+        &mut chip,
+        Some(&hail.ipc),
+        &scheduler,
+        &main_loop_capability,
+    );
 }
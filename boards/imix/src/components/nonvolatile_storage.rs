@@ -75,6 +75,9 @@ impl Component for NonvolatileStorageComponent {
                 0x20000,      // Length of userspace accessible region
                 kernel_start, // Start address of kernel region
                 kernel_len,   // Length of kernel region
+                &[],          // Region sizes for apps without a Storage Size TLV
+                0x4000,       // Largest region a Storage Size TLV can ask for
+                &mut capsules::nonvolatile_storage_driver::REGION_TABLE,
                 &mut capsules::nonvolatile_storage_driver::BUFFER
             )
         );
@@ -42,7 +42,7 @@ use capsules::virtual_alarm::{MuxAlarm, VirtualMuxAlarm};
 use core::cell::Cell;
 use kernel::hil::radio;
 use kernel::hil::time;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
 pub const SRC_ADDR: IPAddr = IPAddr([
@@ -181,7 +181,7 @@ impl<A: time::Alarm> LowpanICMPTest<'a, A> {
 
     fn schedule_next(&self) {
         let delta = (A::Frequency::frequency() * TEST_DELAY_MS) / 1000;
-        let next = self.alarm.now().wrapping_add(delta);
+        let next = self.alarm.now().wrapping_add(A::Ticks::from_u64(delta as u64));
         self.alarm.set_alarm(next);
     }
 
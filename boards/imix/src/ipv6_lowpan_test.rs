@@ -55,7 +55,7 @@ use core::cell::Cell;
 use core::ptr;
 use kernel::hil::radio;
 use kernel::hil::time;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
 pub const MLP: [u8; 8] = [0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7];
@@ -236,7 +236,7 @@ impl<'a, A: time::Alarm> LowpanTest<'a, A> {
 
     fn schedule_next(&self) {
         let delta = (A::Frequency::frequency() * TEST_DELAY_MS) / 1000;
-        let next = self.alarm.now().wrapping_add(delta);
+        let next = self.alarm.now().wrapping_add(A::Ticks::from_u64(delta as u64));
         self.alarm.set_alarm(next);
     }
 
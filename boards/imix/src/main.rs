@@ -26,6 +26,7 @@ use capsules::virtual_i2c::MuxI2C;
 use capsules::virtual_spi::{MuxSpiMaster, VirtualSpiMasterDevice};
 use capsules::virtual_uart::{UartDevice, UartMux};
 use kernel::capabilities;
+use kernel::common::dynamic_deferred_call::{DynamicDeferredCall, DynamicDeferredCallClientState};
 use kernel::component::Component;
 use kernel::hil;
 use kernel::hil::radio;
@@ -107,14 +108,25 @@ static LOCAL_IP_IFACES: [IPAddr; 2] = [
     ]),
 ];
 
-// how should the kernel respond when a process faults
-const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Panic;
+// how should the kernel respond when a process keeps faulting after it has
+// been restarted `MAX_RESTARTS` times
+const FAULT_RESPONSE: kernel::procs::FaultResponse = kernel::procs::FaultResponse::Stop;
+const MAX_RESTARTS: usize = 3;
 
 #[link_section = ".app_memory"]
 static mut APP_MEMORY: [u8; 16384] = [0; 16384];
 
 static mut PROCESSES: [Option<&'static kernel::procs::ProcessType>; NUM_PROCS] = [None, None];
 
+static mut RESTART_STATE: [capsules::fault_policy::RestartBackoffState; NUM_PROCS] = [
+    capsules::fault_policy::RestartBackoffState::new(),
+    capsules::fault_policy::RestartBackoffState::new(),
+];
+
+// Provides the `ProcessManagementCapability` to the fault policy.
+struct ProcessMgmtCap;
+unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+
 /// Dummy buffer that causes the linker to reserve enough space for the stack.
 #[no_mangle]
 #[link_section = ".stack_buffer"]
@@ -136,6 +148,7 @@ struct Imix {
     >,
     spi: &'static capsules::spi::Spi<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>,
     ipc: kernel::ipc::IPC,
+    message_ipc: kernel::ipc::message::MessageIPC,
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
     udp_driver: &'static capsules::net::udp::UDPDriver<'static>,
@@ -189,6 +202,7 @@ impl kernel::Platform for Imix {
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
             capsules::nonvolatile_storage_driver::DRIVER_NUM => f(Some(self.nonvolatile_storage)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc::message::DRIVER_NUM => f(Some(&self.message_ipc)),
             _ => f(None),
         }
     }
@@ -298,7 +312,16 @@ pub unsafe fn reset_handler() {
         trng: true,
     });
 
-    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&mut PROCESSES));
+
+    // Deferred calls for capsules, serviced by the kernel loop.
+    let dynamic_deferred_call_clients =
+        static_init!([DynamicDeferredCallClientState; 4], Default::default());
+    let dynamic_deferred_caller = static_init!(
+        DynamicDeferredCall,
+        DynamicDeferredCall::new(dynamic_deferred_call_clients)
+    );
+    board_kernel.set_dynamic_deferred_call(dynamic_deferred_caller, &main_cap);
 
     // # CONSOLE
     // Create a shared UART channel for the console and for kernel debug.
@@ -328,6 +351,30 @@ pub unsafe fn reset_handler() {
     ast.configure(mux_alarm);
     let alarm = AlarmDriverComponent::new(board_kernel, mux_alarm).finalize();
 
+    // Restart faulting apps, waiting longer each time.
+    let fault_policy_alarm = static_init!(
+        VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+        VirtualMuxAlarm::new(mux_alarm)
+    );
+    let fault_policy = static_init!(
+        capsules::fault_policy::RestartBackoffPolicy<
+            'static,
+            VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+            ProcessMgmtCap,
+        >,
+        capsules::fault_policy::RestartBackoffPolicy::new(
+            board_kernel,
+            fault_policy_alarm,
+            &RESTART_STATE,
+            MAX_RESTARTS,
+            100,
+            10000,
+            FAULT_RESPONSE,
+            ProcessMgmtCap
+        )
+    );
+    fault_policy_alarm.set_client(fault_policy);
+
     // # I2C and I2C Sensors
     let mux_i2c = static_init!(MuxI2C<'static>, MuxI2C::new(&sam4l::i2c::I2C2));
     sam4l::i2c::I2C2.set_master_client(mux_i2c);
@@ -402,6 +449,7 @@ pub unsafe fn reset_handler() {
         crc,
         spi: spi_syscalls,
         ipc: kernel::ipc::IPC::new(board_kernel, &grant_cap),
+        message_ipc: kernel::ipc::message::MessageIPC::new(board_kernel, &grant_cap),
         ninedof,
         radio_driver,
         udp_driver,
@@ -434,10 +482,10 @@ pub unsafe fn reset_handler() {
         &cortexm4::syscall::SysCall::new(),
         &_sapps as *const u8,
         &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
+        fault_policy,
         &process_mgmt_cap,
     );
 
-    board_kernel.kernel_loop(&imix, &mut chip, Some(&imix.ipc), &main_cap);
+    let scheduler = kernel::schedulers::RoundRobinSched::new();
+    board_kernel.kernel_loop(&imix, &mut chip, Some(&imix.ipc), &scheduler, &main_cap);
 }
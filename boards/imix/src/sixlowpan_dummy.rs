@@ -26,7 +26,7 @@ use core::cell::Cell;
 use kernel::hil::radio;
 use kernel::hil::radio::Radio;
 use kernel::hil::time;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 
 static TX_BUF: [u8; 128] = [0; 128];
 
@@ -105,7 +105,7 @@ LowpanTest<'a, R, A> {
 
     fn schedule_next(&self) {
         let delta = (A::Frequency::frequency() * TEST_DELAY_MS) / 1000;
-        let next = self.alarm.now().wrapping_add(delta);
+        let next = self.alarm.now().wrapping_add(A::Ticks::from_u64(delta as u64));
         self.alarm.set_alarm(next);
     }
 
@@ -179,7 +179,7 @@ pub unsafe fn reset_handler() {
     // Wait for it to turn on until we continue
     while !prcm::Power::is_enabled(prcm::PowerDomain::Peripherals) {}
 
-    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&mut PROCESSES));
 
     // Enable the GPIO clocks
     prcm::Clock::enable_gpio();
@@ -366,10 +366,16 @@ pub unsafe fn reset_handler() {
         &cortexm4::syscall::SysCall::new(),
         &_sapps as *const u8,
         &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
+        &FAULT_RESPONSE,
         &process_management_capability,
     );
 
-    board_kernel.kernel_loop(&launchxl, &mut chip, Some(&ipc), &main_loop_capability);
+    let scheduler = kernel::schedulers::RoundRobinSched::new();
+    board_kernel.kernel_loop(
+        &launchxl,
+        &mut chip,
+        Some(&ipc),
+        &scheduler,
+        &main_loop_capability,
+    );
 }
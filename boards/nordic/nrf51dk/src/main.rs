@@ -148,7 +148,7 @@ pub unsafe fn reset_handler() {
     // Loads relocations and clears BSS
     nrf51::init();
 
-    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&mut PROCESSES));
 
     // Create capabilities that the board needs to call certain protected kernel
     // functions.
@@ -408,11 +408,11 @@ pub unsafe fn reset_handler() {
         &cortexm0::syscall::SysCall::new(),
         &_sapps as *const u8,
         &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
+        &FAULT_RESPONSE,
         &process_management_capability,
     );
 
+    let scheduler = kernel::schedulers::RoundRobinSched::new();
     board_kernel.kernel_loop(
         &platform,
         &mut chip,
@@ -420,6 +420,7 @@ pub unsafe fn reset_handler() {
             board_kernel,
             &memory_allocation_capability,
         )),
+        &scheduler,
         &main_loop_capability,
     );
 }
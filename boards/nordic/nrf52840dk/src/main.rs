@@ -138,7 +138,7 @@ pub unsafe fn reset_handler() {
         ]
     );
 
-    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&PROCESSES));
+    let board_kernel = static_init!(kernel::Kernel, kernel::Kernel::new(&mut PROCESSES));
 
     nrf52dk_base::setup_board(
         board_kernel,
@@ -157,7 +157,6 @@ pub unsafe fn reset_handler() {
         )),
         button_pins,
         &mut APP_MEMORY,
-        &mut PROCESSES,
-        FAULT_RESPONSE,
+        &FAULT_RESPONSE,
     );
 }
@@ -84,6 +84,7 @@ pub struct Platform {
     rng: &'static capsules::rng::SimpleRng<'static, nrf5x::trng::Trng<'static>>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
     ipc: kernel::ipc::IPC,
+    message_ipc: kernel::ipc::message::MessageIPC,
     alarm: &'static capsules::alarm::AlarmDriver<
         'static,
         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
@@ -111,6 +112,7 @@ impl kernel::Platform for Platform {
                 f(self.nonvolatile_storage.map_or(None, |nv| Some(nv)))
             }
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
+            kernel::ipc::message::DRIVER_NUM => f(Some(&self.message_ipc)),
             _ => f(None),
         }
     }
@@ -131,8 +133,7 @@ pub unsafe fn setup_board(
     mx25r6435f: &Option<SpiMX25R6435FPins>,
     button_pins: &'static mut [(&'static nrf5x::gpio::GPIOPin, capsules::button::GpioMode)],
     app_memory: &mut [u8],
-    process_pointers: &'static mut [Option<&'static kernel::procs::ProcessType>],
-    app_fault_response: kernel::procs::FaultResponse,
+    app_fault_policy: &'static kernel::procs::ProcessFaultPolicy,
 ) {
     // Make non-volatile memory writable and activate the reset button
     let uicr = nrf52::uicr::Uicr::new();
@@ -384,6 +385,9 @@ pub unsafe fn setup_board(
                 0x20000, // Length of userspace accessible region
                 0,       // Start address of kernel accessible region
                 0x60000, // Length of kernel accessible region
+                &[],     // Region sizes for apps without a Storage Size TLV
+                0x4000,  // Largest region a Storage Size TLV can ask for
+                &mut capsules::nonvolatile_storage_driver::REGION_TABLE,
                 &mut capsules::nonvolatile_storage_driver::BUFFER
             )
         );
@@ -416,6 +420,10 @@ pub unsafe fn setup_board(
         alarm: alarm,
         nonvolatile_storage: nonvolatile_storage,
         ipc: kernel::ipc::IPC::new(board_kernel, &memory_allocation_capability),
+        message_ipc: kernel::ipc::message::MessageIPC::new(
+            board_kernel,
+            &memory_allocation_capability,
+        ),
     };
 
     let mut chip = nrf52::chip::NRF52::new();
@@ -432,15 +440,16 @@ pub unsafe fn setup_board(
         &cortexm4::syscall::SysCall::new(),
         &_sapps as *const u8,
         app_memory,
-        process_pointers,
-        app_fault_response,
+        app_fault_policy,
         &process_management_capability,
     );
 
+    let scheduler = kernel::schedulers::RoundRobinSched::new();
     board_kernel.kernel_loop(
         &platform,
         &mut chip,
         Some(&platform.ipc),
+        &scheduler,
         &main_loop_capability,
     );
 }
@@ -1,28 +1,56 @@
 //! Provides userspace applications with a alarm API.
+//!
+//! Each process has `MAX_ALARMS_PER_APP` independent alarms. Alarms are
+//! one-shot or periodic. A periodic alarm is rearmed relative to its previous
+//! expiration rather than to when the callback ran, so it does not drift.
+//! The underlying alarm is always set to the earliest expiration of all
+//! alarms of all processes.
+//!
+//! Times are in ticks of the underlying alarm, and wrap at the width of its
+//! counter rather than always at 32 bits.
 
 use core::cell::Cell;
-use kernel::hil::time::{self, Alarm, Frequency};
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
 use kernel::{AppId, Callback, Driver, Grant, ReturnCode};
 
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x00000000;
 
+/// Number of alarms each process can have armed at the same time.
+pub const MAX_ALARMS_PER_APP: usize = 4;
+
 #[derive(Copy, Clone, Debug)]
-enum Expiration {
+enum Expiration<T: Ticks> {
     Disabled,
-    Abs(u32),
+    Abs(T),
+}
+
+#[derive(Copy, Clone)]
+struct AppAlarm<T: Ticks> {
+    expiration: Expiration<T>,
+    /// Ticks between expirations of a periodic alarm, 0 for a one-shot alarm.
+    period: T,
+}
+
+impl<T: Ticks> Default for AppAlarm<T> {
+    fn default() -> AppAlarm<T> {
+        AppAlarm {
+            expiration: Expiration::Disabled,
+            period: T::from_u64(0),
+        }
+    }
 }
 
 #[derive(Copy, Clone)]
-pub struct AlarmData {
-    expiration: Expiration,
+pub struct AlarmData<T: Ticks> {
+    alarms: [AppAlarm<T>; MAX_ALARMS_PER_APP],
     callback: Option<Callback>,
 }
 
-impl Default for AlarmData {
-    fn default() -> AlarmData {
+impl<T: Ticks> Default for AlarmData<T> {
+    fn default() -> AlarmData<T> {
         AlarmData {
-            expiration: Expiration::Disabled,
+            alarms: [AppAlarm::default(); MAX_ALARMS_PER_APP],
             callback: None,
         }
     }
@@ -31,41 +59,62 @@ impl Default for AlarmData {
 pub struct AlarmDriver<'a, A: Alarm> {
     alarm: &'a A,
     num_armed: Cell<usize>,
-    app_alarm: Grant<AlarmData>,
-    prev: Cell<u32>,
+    app_alarm: Grant<AlarmData<A::Ticks>>,
+    prev: Cell<A::Ticks>,
 }
 
 impl<A: Alarm> AlarmDriver<'a, A> {
-    pub const fn new(alarm: &'a A, grant: Grant<AlarmData>) -> AlarmDriver<'a, A> {
+    pub fn new(alarm: &'a A, grant: Grant<AlarmData<A::Ticks>>) -> AlarmDriver<'a, A> {
         AlarmDriver {
             alarm: alarm,
             num_armed: Cell::new(0),
             app_alarm: grant,
-            prev: Cell::new(0),
+            prev: Cell::new(A::Ticks::from_u64(0)),
         }
     }
 
-    fn reset_active_alarm(&self, now: u32) -> Option<u32> {
+    fn reset_active_alarm(&self, now: A::Ticks) -> Option<A::Ticks> {
         self.prev.set(now);
-        let mut next_alarm = u32::max_value();
-        let mut next_dist = u32::max_value();
-        for alarm in self.app_alarm.iter() {
-            alarm.enter(|alarm, _| match alarm.expiration {
-                Expiration::Abs(exp) => {
-                    let t_dist = exp.wrapping_sub(now);
-                    if next_dist > t_dist {
-                        next_alarm = exp;
-                        next_dist = t_dist;
+        let mut next_alarm = None;
+        let mut next_dist = 0;
+        for app in self.app_alarm.iter() {
+            app.enter(|app, _| {
+                for alarm in app.alarms.iter() {
+                    if let Expiration::Abs(exp) = alarm.expiration {
+                        let t_dist = exp.wrapping_sub(now).into_u64();
+                        if next_alarm.is_none() || next_dist > t_dist {
+                            next_alarm = Some(exp);
+                            next_dist = t_dist;
+                        }
                     }
                 }
-                Expiration::Disabled => {}
             });
         }
-        if next_alarm != u32::max_value() {
+        next_alarm.map(|next_alarm| {
             self.alarm.set_alarm(next_alarm);
-            Some(next_alarm)
-        } else {
-            None
+            next_alarm
+        })
+    }
+
+    /// Arm `alarm` to expire at `expiration`, and then every `period` ticks if
+    /// `period` is not 0.
+    fn arm(&self, alarm: &mut AppAlarm<A::Ticks>, expiration: A::Ticks, period: A::Ticks) {
+        if let Expiration::Disabled = alarm.expiration {
+            self.num_armed.set(self.num_armed.get() + 1);
+        }
+        alarm.expiration = Expiration::Abs(expiration);
+        alarm.period = period;
+    }
+
+    /// Disarm `alarm`. Returns `EALREADY` if it was not armed.
+    fn disarm(&self, alarm: &mut AppAlarm<A::Ticks>) -> ReturnCode {
+        match alarm.expiration {
+            Expiration::Disabled => ReturnCode::EALREADY,
+            Expiration::Abs(_) => {
+                alarm.expiration = Expiration::Disabled;
+                self.num_armed.set(self.num_armed.get() - 1);
+                ReturnCode::SUCCESS
+            }
         }
     }
 }
@@ -93,12 +142,16 @@ impl<A: Alarm> Driver for AlarmDriver<'a, A> {
     ///
     /// ### `command_num`
     ///
-    /// - `0`: Driver check.
+    /// - `0`: Driver check. Returns the number of alarms per process.
     /// - `1`: Return the clock frequency in Hz.
     /// - `2`: Read the the current clock value
-    /// - `3`: Stop the alarm if it is outstanding
-    /// - `4`: Set an alarm to fire at a given clock value `time`.
-    fn command(&self, cmd_type: usize, data: usize, _: usize, caller_id: AppId) -> ReturnCode {
+    /// - `3`: Stop alarm 0 if it is outstanding
+    /// - `4`: Set alarm 0 to fire at a given clock value `time`.
+    /// - `5`: Set alarm `data1` to fire at clock value `data2`.
+    /// - `6`: Set alarm `data1` to fire every `data2` ticks, starting `data2`
+    ///        ticks from now.
+    /// - `7`: Stop alarm `data1` if it is outstanding.
+    fn command(&self, cmd_type: usize, data: usize, data2: usize, caller_id: AppId) -> ReturnCode {
         // Returns the error code to return to the user and whether we need to
         // reset which is the next active alarm. We only _don't_ reset if we're
         // disabling the underlying alarm anyway, if the underlying alarm is
@@ -108,69 +161,113 @@ impl<A: Alarm> Driver for AlarmDriver<'a, A> {
             .enter(caller_id, |td, _alloc| {
                 let now = self.alarm.now();
                 let (return_code, reset) = match cmd_type {
-                    0 /* check if present */ => (ReturnCode::SuccessWithValue { value: 1 }, false),
+                    0 /* check if present */ => {
+                        (ReturnCode::SuccessWithValue { value: MAX_ALARMS_PER_APP }, false)
+                    },
                     1 /* Get clock frequency */ => {
                         let freq = <A::Frequency>::frequency() as usize;
                         (ReturnCode::SuccessWithValue { value: freq }, false)
                     },
                     2 /* capture time */ => {
-                        (ReturnCode::SuccessWithValue { value: now as usize },
+                        (ReturnCode::SuccessWithValue { value: now.into_u64() as usize },
                          false)
                     },
                     3 /* Stop */ => {
-                        let alarm_id = data as u32;
-                        match td.expiration {
-                            Expiration::Disabled => {
-                                // Request to stop when already stopped
-                                (ReturnCode::EALREADY, false)
-                            },
+                        let alarm_id = A::Ticks::from_u64(data as u64);
+                        match td.alarms[0].expiration {
                             Expiration::Abs(exp) if exp != alarm_id => {
                                 // Request to stop invalid alarm id
                                 (ReturnCode::EINVAL, false)
                             },
                             _ => {
-                                td.expiration = Expiration::Disabled;
-                                let new_num_armed = self.num_armed.get() - 1;
-                                self.num_armed.set(new_num_armed);
-                                (ReturnCode::SUCCESS, true)
+                                let return_code = self.disarm(&mut td.alarms[0]);
+                                (return_code, return_code == ReturnCode::SUCCESS)
                             }
                         }
                     },
                     4 /* Set absolute expiration */ => {
                         let time = data;
-                        // if previously unarmed, but now will become armed
-                        if let Expiration::Disabled = td.expiration {
-                            self.num_armed.set(self.num_armed.get() + 1);
-                        }
-                        td.expiration = Expiration::Abs(time as u32);
+                        let expiration = A::Ticks::from_u64(time as u64);
+                        self.arm(&mut td.alarms[0], expiration, A::Ticks::from_u64(0));
                         (ReturnCode::SuccessWithValue { value: time }, true)
                     },
+                    5 /* Set absolute expiration of an alarm */ => {
+                        match td.alarms.get_mut(data) {
+                            Some(alarm) => {
+                                let expiration = A::Ticks::from_u64(data2 as u64);
+                                self.arm(alarm, expiration, A::Ticks::from_u64(0));
+                                (ReturnCode::SUCCESS, true)
+                            },
+                            None => (ReturnCode::EINVAL, false),
+                        }
+                    },
+                    6 /* Set periodic alarm */ => {
+                        let period = A::Ticks::from_u64(data2 as u64);
+                        match td.alarms.get_mut(data) {
+                            Some(ref mut alarm) if period.into_u64() > 0 => {
+                                self.arm(alarm, now.wrapping_add(period), period);
+                                (ReturnCode::SUCCESS, true)
+                            },
+                            _ => (ReturnCode::EINVAL, false),
+                        }
+                    },
+                    7 /* Stop an alarm */ => {
+                        match td.alarms.get_mut(data) {
+                            Some(alarm) => {
+                                let return_code = self.disarm(alarm);
+                                (return_code, return_code == ReturnCode::SUCCESS)
+                            },
+                            None => (ReturnCode::EINVAL, false),
+                        }
+                    },
                     _ => (ReturnCode::ENOSUPPORT, false)
                 };
                 if reset {
-                    self.reset_active_alarm(now);
+                    if self.num_armed.get() == 0 {
+                        self.alarm.disable();
+                    } else {
+                        self.reset_active_alarm(now);
+                    }
                 }
                 return_code
             }).unwrap_or_else(|err| err.into())
     }
 }
 
-fn has_expired(alarm: u32, now: u32, prev: u32) -> bool {
-    now.wrapping_sub(prev) >= alarm.wrapping_sub(prev)
+/// Whether `alarm` has passed by `now`, given that it had not yet at `prev`.
+fn has_expired<T: Ticks>(alarm: T, now: T, prev: T) -> bool {
+    !now.within_range(prev, alarm)
+}
+
+/// The first expiration of a periodic alarm after `now`, counting in `period`
+/// steps from the expiration `exp` that just passed.
+fn next_period<T: Ticks>(exp: T, period: T, now: T) -> T {
+    let missed = now.wrapping_sub(exp).into_u64() / period.into_u64();
+    exp.wrapping_add(T::from_u64(period.into_u64().wrapping_mul(missed + 1)))
 }
 
 impl<A: Alarm> time::Client for AlarmDriver<'a, A> {
     fn fired(&self) {
         let now = self.alarm.now();
-        self.app_alarm.each(|alarm| {
-            if let Expiration::Abs(exp) = alarm.expiration {
-                let expired = has_expired(exp, now, self.prev.get());
-                if expired {
-                    alarm.expiration = Expiration::Disabled;
-                    self.num_armed.set(self.num_armed.get() - 1);
-                    alarm
-                        .callback
-                        .map(|mut cb| cb.schedule(now as usize, exp as usize, 0));
+        self.app_alarm.each(|app| {
+            let callback = app.callback;
+            for (alarm_id, alarm) in app.alarms.iter_mut().enumerate() {
+                if let Expiration::Abs(exp) = alarm.expiration {
+                    let expired = has_expired(exp, now, self.prev.get());
+                    if expired {
+                        if alarm.period.into_u64() > 0 {
+                            // Rearm from the expiration, not from now, so that
+                            // the alarm does not drift.
+                            let next = next_period(exp, alarm.period, now);
+                            alarm.expiration = Expiration::Abs(next);
+                        } else {
+                            alarm.expiration = Expiration::Disabled;
+                            self.num_armed.set(self.num_armed.get() - 1);
+                        }
+                        callback.map(|mut cb| {
+                            cb.schedule(now.into_u64() as usize, exp.into_u64() as usize, alarm_id)
+                        });
+                    }
                 }
             }
         });
@@ -189,3 +286,28 @@ impl<A: Alarm> time::Client for AlarmDriver<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{has_expired, next_period};
+    use kernel::hil::time::{Ticks, Ticks24};
+
+    #[test]
+    fn expires_across_a_wrap() {
+        let prev = Ticks24(0xFFFFF0);
+        let alarm = Ticks24(0x000010);
+        assert!(!has_expired(alarm, Ticks24(0xFFFFFF), prev));
+        assert!(!has_expired(alarm, Ticks24(0x00000F), prev));
+        assert!(has_expired(alarm, Ticks24(0x000010), prev));
+        assert!(has_expired(alarm, Ticks24(0x000100), prev));
+    }
+
+    #[test]
+    fn periodic_alarm_skips_missed_periods_across_a_wrap() {
+        let period = Ticks24(0x100);
+        let exp = Ticks24(0xFFFF80);
+        assert_eq!(next_period(exp, period, Ticks24(0xFFFF90)), Ticks24(0x000080));
+        assert_eq!(next_period(exp, period, Ticks24(0x000250)), Ticks24(0x000280));
+        assert_eq!(next_period(exp, period, exp).into_u64(), 0x000080);
+    }
+}
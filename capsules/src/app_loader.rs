@@ -0,0 +1,342 @@
+//! Loads new apps while the kernel is running.
+//!
+//! A trusted app, for example one that receives updates over the air, sends
+//! the TBF image of a new app to this driver in chunks. The chunks are written
+//! to the flash region the board reserves for dynamically loaded apps. Once
+//! the whole image is written the kernel validates it and starts the new app
+//! in an empty process slot, without rebooting.
+//!
+//! ```text
+//! +----------------------------+     +----------------------------+
+//! |     userspace (loader)     |     |           kernel           |
+//! +----------------------------+     +----------------------------+
+//!         kernel::Driver         kernel::procs::DynamicProcessLoader
+//! +-----------------------------------------------------------------+
+//! |              capsules::app_loader::AppLoader (this)             |
+//! +-----------------------------------------------------------------+
+//!            hil::nonvolatile_storage::NonvolatileStorage
+//! +-----------------------------------------------------------------+
+//! |               e.g. capsules::nonvolatile_to_pages               |
+//! +-----------------------------------------------------------------+
+//! ```
+//!
+//! The storage must use the same addresses as the kernel, i.e. address `x` in
+//! the storage is the flash byte at address `x`.
+//!
+//! Loading apps is off by default. Only the apps whose package names the board
+//! lists when creating the driver can use it; to every other app it returns
+//! `ENODEVICE`, as if it did not exist. Boards should only list apps whose
+//! package names cannot be taken by other apps, for example by requiring
+//! credentials for them.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let process_loader = static_init!(
+//!     kernel::procs::ProcessLoader<cortexm4::syscall::SysCall>,
+//!     kernel::procs::ProcessLoader::new(
+//!         board_kernel,
+//!         &cortexm4::syscall::SysCall::new(),
+//!         slice::from_raw_parts(&_sdynapps as *const u8, DYNAMIC_APP_FLASH_SIZE),
+//!         &mut DYNAMIC_APP_MEMORY,
+//!         &FAULT_RESPONSE,
+//!         &process_management_capability
+//!     )
+//! );
+//! let app_loader = static_init!(
+//!     capsules::app_loader::AppLoader<'static>,
+//!     capsules::app_loader::AppLoader::new(
+//!         nv_to_page,
+//!         process_loader,
+//!         &[b"ota_updater"],
+//!         kernel::Grant::create(),
+//!         &mut capsules::app_loader::BUFFER
+//!     )
+//! );
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, app_loader);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Draft
+//!
+//! Only one of the apps the board allows can load an image at a time. Loading
+//! an image works as follows:
+//!
+//! 1. Start a new image with command `1`.
+//! 2. For each chunk of the image, copy it to the allowed buffer, write it with
+//!    command `2` and wait for the write done callback.
+//! 3. Start the new app with command `3`.
+//!
+//! ### Allow
+//!
+//! - `0`: Buffer holding the next chunk of the image.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Write done callback. Called with the number of bytes written.
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Start a new image.
+//!   - `data1`: The total size of the TBF image in bytes.
+//!   - Return: `SUCCESS`, `EBUSY` if another app is loading an image or
+//!     `ENOMEM` if there is not enough free flash for the image.
+//! - `2`: Write a chunk of the image from the allowed buffer.
+//!   - `data1`: Offset of the chunk in the image.
+//!   - `data2`: Length of the chunk in bytes.
+//!   - Return: `SUCCESS` if the write started, `EINVAL` if the chunk does not
+//!     fit in the image, `ERESERVE` if no buffer was allowed, or `EBUSY` if a
+//!     write is in progress.
+//! - `3`: Validate the image and start it as a new app.
+//!   - Return: The process slot of the new app, or an error if the image is
+//!     invalid (`EINVAL`) or there is no room for the app (`ENOMEM`).
+//! - `4`: Abandon the image being loaded.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::procs::DynamicProcessLoader;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x10002;
+
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    buffer: Option<AppSlice<Shared, u8>>,
+}
+
+pub struct AppLoader<'a> {
+    storage: &'a hil::nonvolatile_storage::NonvolatileStorage,
+    loader: &'a DynamicProcessLoader,
+    // Package names of the apps that may load images.
+    allowed_apps: &'a [&'a [u8]],
+    apps: Grant<App>,
+    // Internal buffer for copying appslices into.
+    buffer: TakeCell<'static, [u8]>,
+    // The app that is loading an image.
+    current_app: OptionalCell<AppId>,
+    // Flash address and length of the image being loaded.
+    image_address: Cell<usize>,
+    image_length: Cell<usize>,
+    write_in_progress: Cell<bool>,
+}
+
+impl AppLoader<'a> {
+    pub fn new(
+        storage: &'a hil::nonvolatile_storage::NonvolatileStorage,
+        loader: &'a DynamicProcessLoader,
+        allowed_apps: &'a [&'a [u8]],
+        grant: Grant<App>,
+        buffer: &'static mut [u8],
+    ) -> AppLoader<'a> {
+        AppLoader {
+            storage: storage,
+            loader: loader,
+            allowed_apps: allowed_apps,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            current_app: OptionalCell::empty(),
+            image_address: Cell::new(0),
+            image_length: Cell::new(0),
+            write_in_progress: Cell::new(false),
+        }
+    }
+
+    /// Whether the board allows `appid` to load images.
+    fn allowed(&self, appid: AppId) -> bool {
+        let package_name = appid.get_package_name();
+        self.allowed_apps.iter().any(|name| *name == package_name)
+    }
+
+    /// Whether an app other than `appid` is loading an image. An app that
+    /// no longer exists does not count.
+    fn busy_for(&self, appid: AppId) -> bool {
+        self.current_app.map_or(false, |current| {
+            *current != appid && self.apps.enter(*current, |_, _| ()).is_ok()
+        })
+    }
+
+    fn start_image(&self, appid: AppId, length: usize) -> ReturnCode {
+        if self.busy_for(appid) || self.write_in_progress.get() {
+            return ReturnCode::EBUSY;
+        }
+
+        let address = self.loader.next_free_flash_address();
+        let (region_start, region_length) = self.loader.flash_region();
+        if length == 0 || address + length > region_start + region_length {
+            return ReturnCode::ENOMEM;
+        }
+
+        self.current_app.set(appid);
+        self.image_address.set(address);
+        self.image_length.set(length);
+        ReturnCode::SUCCESS
+    }
+
+    fn write_chunk(&self, appid: AppId, offset: usize, length: usize) -> ReturnCode {
+        if self.current_app.map_or(true, |current| *current != appid) {
+            return ReturnCode::EINVAL;
+        }
+        if self.write_in_progress.get() {
+            return ReturnCode::EBUSY;
+        }
+        if length == 0 || offset + length > self.image_length.get() {
+            return ReturnCode::EINVAL;
+        }
+
+        self.apps
+            .enter(appid, |app, _| {
+                let app_buffer = match app.buffer {
+                    Some(ref app_buffer) if app_buffer.len() > 0 => app_buffer,
+                    _ => return ReturnCode::ERESERVE,
+                };
+
+                self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                    // Shorten the write to what the buffers can hold. The app
+                    // learns how much was written from the callback.
+                    let write_len = cmp::min(length, cmp::min(app_buffer.len(), buffer.len()));
+                    buffer[..write_len].copy_from_slice(&app_buffer.as_ref()[..write_len]);
+
+                    self.write_in_progress.set(true);
+                    let address = self.image_address.get() + offset;
+                    let ret = self.storage.write(buffer, address, write_len);
+                    if ret != ReturnCode::SUCCESS {
+                        self.write_in_progress.set(false);
+                    }
+                    ret
+                })
+            }).unwrap_or_else(|err| err.into())
+    }
+
+    fn load_image(&self, appid: AppId) -> ReturnCode {
+        if self.current_app.map_or(true, |current| *current != appid) {
+            return ReturnCode::EINVAL;
+        }
+        if self.write_in_progress.get() {
+            return ReturnCode::EBUSY;
+        }
+
+        self.current_app.clear();
+        match self.loader.load_process(self.image_address.get()) {
+            Ok(new_appid) => ReturnCode::SuccessWithValue {
+                value: new_appid.idx(),
+            },
+            Err(err) => err,
+        }
+    }
+}
+
+impl hil::nonvolatile_storage::NonvolatileStorageClient for AppLoader<'a> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        // This capsule never reads.
+        self.buffer.replace(buffer);
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
+        self.buffer.replace(buffer);
+        self.write_in_progress.set(false);
+
+        self.current_app.map(|appid| {
+            let _ = self.apps.enter(*appid, |app, _| {
+                app.callback.map(|mut cb| cb.schedule(length, 0, 0));
+            });
+        });
+    }
+}
+
+impl Driver for AppLoader<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer holding the next chunk of the image.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        if !self.allowed(appid) {
+            return ReturnCode::ENODEVICE;
+        }
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.buffer = slice;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Write done callback.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        if !self.allowed(app_id) {
+            return ReturnCode::ENODEVICE;
+        }
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Load an app.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Start a new image of `data1` bytes.
+    /// - `2`: Write `data2` bytes from the allowed buffer at offset `data1` in
+    ///        the image.
+    /// - `3`: Validate the image and start it as a new app.
+    /// - `4`: Abandon the image being loaded.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        if !self.allowed(appid) {
+            return ReturnCode::ENODEVICE;
+        }
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self.start_image(appid, data1),
+            2 => self.write_chunk(appid, data1, data2),
+            3 => self.load_image(appid),
+            4 => {
+                if self.current_app.map_or(true, |current| *current != appid) {
+                    ReturnCode::EINVAL
+                } else if self.write_in_progress.get() {
+                    ReturnCode::EBUSY
+                } else {
+                    self.current_app.clear();
+                    ReturnCode::SUCCESS
+                }
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
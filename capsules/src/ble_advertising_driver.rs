@@ -101,7 +101,7 @@ use kernel;
 use kernel::common::cells::OptionalCell;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
 /// Syscall Number
@@ -125,21 +125,21 @@ enum BLEState {
 }
 
 #[derive(Copy, Clone)]
-enum Expiration {
+enum Expiration<T: Ticks> {
     Disabled,
-    Abs(u32),
+    Abs(T),
 }
 
 #[derive(Copy, Clone)]
-struct AlarmData {
-    t0: u32,
-    expiration: Expiration,
+struct AlarmData<T: Ticks> {
+    t0: T,
+    expiration: Expiration<T>,
 }
 
-impl AlarmData {
-    fn new() -> AlarmData {
+impl<T: Ticks> AlarmData<T> {
+    fn new() -> AlarmData<T> {
         AlarmData {
-            t0: 0,
+            t0: T::from_u64(0),
             expiration: Expiration::Disabled,
         }
     }
@@ -161,9 +161,9 @@ const CONNECT_IND: AdvPduType = 0b0101;
 const ADV_SCAN_IND: AdvPduType = 0b0110;
 
 /// Process specific memory
-pub struct App {
+pub struct App<T: Ticks> {
     process_status: Option<BLEState>,
-    alarm_data: AlarmData,
+    alarm_data: AlarmData<T>,
 
     // Advertising meta-data
     adv_data: Option<kernel::AppSlice<kernel::Shared, u8>>,
@@ -183,8 +183,8 @@ pub struct App {
     scan_callback: Option<kernel::Callback>,
 }
 
-impl Default for App {
-    fn default() -> App {
+impl<T: Ticks> Default for App<T> {
+    fn default() -> App<T> {
         App {
             alarm_data: AlarmData::new(),
             adv_data: None,
@@ -201,7 +201,7 @@ impl Default for App {
     }
 }
 
-impl App {
+impl<T: Ticks> App<T> {
     // Bluetooth Core Specification:Vol. 6, Part B, section 1.3.2.1 Static Device Address
     //
     // A static address is a 48-bit randomly generated address and shall meet the following
@@ -288,12 +288,13 @@ impl App {
     }
 
     // Set the next alarm for this app using the period and provided start time.
-    fn set_next_alarm<F: Frequency>(&mut self, now: u32) {
+    fn set_next_alarm<F: Frequency>(&mut self, now: T) {
         self.alarm_data.t0 = now;
         let nonce = self.random_nonce() % 10;
 
-        let period_ms = (self.advertisement_interval_ms + nonce) * F::frequency() / 1000;
-        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period_ms));
+        let period_ms = (self.advertisement_interval_ms + nonce) as u64;
+        let period = T::from_u64(F::ms_to_ticks(period_ms));
+        self.alarm_data.expiration = Expiration::Abs(now.wrapping_add(period));
     }
 }
 
@@ -304,7 +305,7 @@ where
 {
     radio: &'a B,
     busy: Cell<bool>,
-    app: kernel::Grant<App>,
+    app: kernel::Grant<App<A::Ticks>>,
     kernel_tx: kernel::common::cells::TakeCell<'static, [u8]>,
     alarm: &'a A,
     sending_app: OptionalCell<kernel::AppId>,
@@ -318,7 +319,7 @@ where
 {
     pub fn new(
         radio: &'a B,
-        container: kernel::Grant<App>,
+        container: kernel::Grant<App<A::Ticks>>,
         tx_buf: &'static mut [u8],
         alarm: &'a A,
     ) -> BLE<'a, B, A> {
@@ -342,21 +343,21 @@ where
     // likely be chosen.
     fn reset_active_alarm(&self) {
         let now = self.alarm.now();
-        let mut next_alarm = u32::max_value();
-        let mut next_dist = u32::max_value();
+        let mut next_alarm = None;
+        let mut next_dist = 0;
         for app in self.app.iter() {
             app.enter(|app, _| match app.alarm_data.expiration {
                 Expiration::Abs(exp) => {
-                    let t_dist = exp.wrapping_sub(now);
-                    if next_dist > t_dist {
-                        next_alarm = exp;
+                    let t_dist = exp.wrapping_sub(now).into_u64();
+                    if next_alarm.is_none() || next_dist > t_dist {
+                        next_alarm = Some(exp);
                         next_dist = t_dist;
                     }
                 }
                 Expiration::Disabled => {}
             });
         }
-        if next_alarm != u32::max_value() {
+        if let Some(next_alarm) = next_alarm {
             self.alarm.set_alarm(next_alarm);
         }
     }
@@ -385,8 +386,7 @@ where
 
         self.app.each(|app| {
             if let Expiration::Abs(exp) = app.alarm_data.expiration {
-                let expired =
-                    now.wrapping_sub(app.alarm_data.t0) >= exp.wrapping_sub(app.alarm_data.t0);
+                let expired = !now.within_range(app.alarm_data.t0, exp);
                 if expired {
                     if self.busy.get() {
                         // The radio is currently busy, so we won't be able to start the
@@ -562,7 +562,7 @@ where
                             ADV_IND | ADV_NONCONN_IND | ADV_SCAN_IND => {
                                 app.pdu_type = pdu_type;
                                 app.process_status = Some(BLEState::AdvertisingIdle);
-                                app.random_nonce = self.alarm.now();
+                                app.random_nonce = self.alarm.now().into_u64() as u32;
                                 app.advertisement_interval_ms = cmp::max(20, interval as u32);
                                 app.set_next_alarm::<A::Frequency>(self.alarm.now());
                                 self.reset_active_alarm();
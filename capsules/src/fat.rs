@@ -0,0 +1,2488 @@
+//! FAT16 and FAT32 file system on an SD card.
+//!
+//! Finds the first FAT partition of the card, or a file system that starts at
+//! the first block of the card without a partition table, and lets the kernel
+//! and apps list directories, and open, read, write and append to files, so
+//! that for example data an app logs can be read from the card on a computer.
+//!
+//! Only short (8.3) names are supported: `LOG.CSV` or `logs/2018/day1.csv`,
+//! where lower case letters mean the same as upper case ones. Long names that
+//! other systems gave files are ignored, the files are still found under
+//! their short names. Files can be created, but not deleted, and directories
+//! have to exist already. There is no clock, so new files are dated 1 January
+//! 1980.
+//!
+//! Sectors are read through a small write-back cache of `CACHE_SECTORS`
+//! sectors, so that an operation can go back to sectors it used before, like
+//! those of the FAT, without reading them again. Everything an operation
+//! changed is written to the card before it finishes, including the size of a
+//! file in its directory entry. Writes that fill a whole sector, or end a
+//! file, do not read the sector first.
+//!
+//! One operation runs at a time. It is written as a step that runs until it
+//! needs a sector that is not in the cache; the step then runs again once the
+//! sector is read. Everything a step has done so far is kept in the progress
+//! fields of `FatFs`, so running it again carries on where it stopped.
+//!
+//! The file system runs on any `BlockDevice`, and is usually given an
+//! `SDCard`. Files opened by apps are kept in their grants, and the kernel
+//! keeps its `File`s itself. `capsules::test::fat` has a disk image in RAM to
+//! test it with, for example a copy of a card made on a computer.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<
+//!         'static,
+//!         capsules::sdcard::SDCard<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     >,
+//!     capsules::fat::FatFs::new(
+//!         sdcard,
+//!         board_kernel.create_grant(&memory_allocation_capability),
+//!         &mut capsules::fat::CACHE,
+//!         &mut capsules::fat::BUFFER
+//!     )
+//! );
+//! sdcard.set_client(fat);
+//! // Mount cards when they are inserted.
+//! sdcard.detect_changes();
+//! fat.mount();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Draft
+//!
+//! ### Allow
+//!
+//! - `0`: The path of the file or directory to open or list.
+//! - `1`: The data read from or written to a file, or the entry that was
+//!   listed.
+//!
+//! ### Command
+//!
+//! - `0`: Check that the driver exists.
+//! - `1`: Open the file at the first `data1` bytes of the path buffer. `data2`
+//!   is `0` to read it, `1` to write it, creating it or else making it empty,
+//!   or `2` to append to it, creating it if it does not exist. The callback
+//!   gets the handle of the file.
+//! - `2`: Read up to `data2` bytes from file `data1` into the data buffer.
+//!   The callback gets the number of bytes read, which is 0 at the end of the
+//!   file.
+//! - `3`: Write the first `data2` bytes of the data buffer to file `data1`.
+//! - `4`: Close file `data1`.
+//! - `5`: List entry number `data2` of the directory at the first `data1`
+//!   bytes of the path buffer. An empty path is the root directory. The data
+//!   buffer gets the size of the entry as 4 little endian bytes, a byte that
+//!   is `1` for directories, and the name; the callback gets the length of the
+//!   name.
+//! - `6`: Move the position of file `data1` to byte `data2`.
+//! - `7`: Return the size of file `data1`.
+//!
+//! Commands `1`, `2`, `3` and `5` return `SUCCESS` if the operation was
+//! started, and finish with a callback.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation done. The callback is passed the command number of the
+//!   operation, its `ReturnCode`, and the value the command describes.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use sdcard::{SDCard, SDCardClient};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x50004;
+
+/// Size of the sectors of the card, and of the blocks of a `BlockDevice`.
+pub const SECTOR_SIZE: usize = 512;
+
+/// Number of sectors the cache holds.
+pub const CACHE_SECTORS: usize = 4;
+
+/// Longest path, in bytes.
+pub const MAX_PATH_LEN: usize = 64;
+
+/// Number of files an app can have open.
+pub const MAX_FILES: usize = 4;
+
+/// Buffers for the file system, assigned in board `main.rs` files.
+pub static mut CACHE: [u8; CACHE_SECTORS * SECTOR_SIZE] = [0; CACHE_SECTORS * SECTOR_SIZE];
+pub static mut BUFFER: [u8; SECTOR_SIZE] = [0; SECTOR_SIZE];
+
+/// Last two bytes of boot sectors and of the master boot record.
+const BOOT_SIGNATURE: u16 = 0xaa55;
+/// Where the partition table is in the master boot record.
+const PARTITION_TABLE: usize = 446;
+
+/// Marks the free cluster count and next free cluster in the FAT32 FSInfo
+/// sector as unknown.
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// Directory entries: the name, attributes, the high half of the first
+/// cluster on FAT32, dates, the low half of the first cluster and the size.
+const DIR_ENTRY_LEN: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / DIR_ENTRY_LEN) as u32;
+const ATTR_READ_ONLY: u8 = 0x01;
+/// Set in the attributes of volume labels and of the entries with parts of
+/// long names.
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// First name byte of the entry after the last entry in use, and of an entry
+/// that was deleted.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xe5;
+/// First name byte of names that start with 0xe5.
+const ENTRY_KANJI: u8 = 0x05;
+/// 1 January 1980, the first date FAT can store.
+const EPOCH_DATE: u16 = (1 << 5) | 1;
+
+/// A device of `SECTOR_SIZE` byte blocks the file system is on. It tells the
+/// file system when reads and writes finish as an `SDCardClient`.
+pub trait BlockDevice {
+    /// Whether blocks can be read and written.
+    fn is_ready(&self) -> bool;
+
+    /// Get the device ready, calling `init_done()` or `error()` when done.
+    fn initialize(&self) -> ReturnCode;
+
+    fn read_block(&self, buffer: &'static mut [u8], block: u32) -> ReturnCode;
+
+    fn write_block(&self, buffer: &'static mut [u8], block: u32) -> ReturnCode;
+
+    /// Take back the buffer of a read or write that could not be started or
+    /// that failed.
+    fn take_buffer(&self) -> Option<&'static mut [u8]>;
+}
+
+impl<A: hil::time::Alarm> BlockDevice for SDCard<'a, A> {
+    fn is_ready(&self) -> bool {
+        self.is_installed() && self.is_initialized()
+    }
+
+    fn initialize(&self) -> ReturnCode {
+        SDCard::initialize(self)
+    }
+
+    fn read_block(&self, buffer: &'static mut [u8], block: u32) -> ReturnCode {
+        self.read_blocks(buffer, block, 1)
+    }
+
+    fn write_block(&self, buffer: &'static mut [u8], block: u32) -> ReturnCode {
+        self.write_blocks(buffer, block, 1)
+    }
+
+    fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        SDCard::take_buffer(self)
+    }
+}
+
+/// How a file is opened.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Only read the file.
+    Read,
+    /// Read and write the file, which is created or made empty.
+    Write,
+    /// Read the file and write to its end. It is created if it does not
+    /// exist.
+    Append,
+}
+
+impl Mode {
+    fn from_usize(mode: usize) -> Option<Mode> {
+        match mode {
+            0 => Some(Mode::Read),
+            1 => Some(Mode::Write),
+            2 => Some(Mode::Append),
+            _ => None,
+        }
+    }
+}
+
+/// An open file. The file system does not keep track of open files, its
+/// users keep them and pass them to reads and writes, which return them
+/// updated.
+#[derive(Copy, Clone)]
+pub struct File {
+    /// The mount the file was opened in. Files of an earlier mount, for
+    /// example of a card that was swapped since, cannot be used.
+    volume: u32,
+    /// Where the directory entry of the file is.
+    entry_sector: u32,
+    entry_offset: usize,
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    /// The last cluster the file was read or written in, 0 if none yet, and
+    /// its number in the chain of clusters of the file.
+    cluster: u32,
+    cluster_index: u32,
+    mode: Mode,
+}
+
+impl Default for File {
+    fn default() -> File {
+        File {
+            volume: 0,
+            entry_sector: 0,
+            entry_offset: 0,
+            first_cluster: 0,
+            size: 0,
+            position: 0,
+            cluster: 0,
+            cluster_index: 0,
+            mode: Mode::Read,
+        }
+    }
+}
+
+impl File {
+    /// Size of the file, as of the last operation on it.
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    /// Where the next read or write starts. Appends always go to the end.
+    pub fn position(&self) -> usize {
+        self.position as usize
+    }
+
+    /// Move the position, which cannot be past the end of the file.
+    pub fn seek(&mut self, position: usize) -> ReturnCode {
+        if position > self.size as usize {
+            return ReturnCode::EINVAL;
+        }
+        self.position = position as u32;
+        ReturnCode::SUCCESS
+    }
+}
+
+/// An entry of a directory that was listed.
+#[derive(Copy, Clone, Default)]
+pub struct DirEntry {
+    /// The name as `NAME.EXT`.
+    name: [u8; 12],
+    name_len: usize,
+    size: u32,
+    directory: bool,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &[u8] {
+        &self.name[0..self.name_len]
+    }
+
+    pub fn size(&self) -> usize {
+        self.size as usize
+    }
+
+    pub fn is_directory(&self) -> bool {
+        self.directory
+    }
+}
+
+/// Implemented by kernel users of the file system.
+pub trait FatClient {
+    /// A mount finished. `result` is `ENOSUPPORT` if the card has no FAT16 or
+    /// FAT32 file system.
+    fn mount_done(&self, result: ReturnCode);
+
+    /// An open finished. `result` is `FAIL` if a file opened with
+    /// `Mode::Read` does not exist.
+    fn open_done(&self, result: ReturnCode, file: File);
+
+    /// A list finished. `result` is `FAIL` if the directory has fewer
+    /// entries.
+    fn list_done(&self, result: ReturnCode, entry: DirEntry);
+
+    /// A read finished. `length` is 0 at the end of the file.
+    fn read_done(&self, result: ReturnCode, file: File, buffer: &'static mut [u8], length: usize);
+
+    /// A write finished. `length` is less than was asked for if the card is
+    /// full.
+    fn write_done(&self, result: ReturnCode, file: File, buffer: &'static mut [u8], length: usize);
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Where the parts of the mounted file system are. Sectors are numbered from
+/// the start of the card.
+#[derive(Copy, Clone)]
+struct Volume {
+    fat_type: FatType,
+    fat_start: u32,
+    fat_sectors: u32,
+    num_fats: u32,
+    /// The root directory of FAT16, which is not in a cluster.
+    root_start: u32,
+    root_entries: u32,
+    /// The first cluster of the root directory of FAT32.
+    root_cluster: u32,
+    /// The FAT32 FSInfo sector, 0 if there is none.
+    info_sector: u32,
+    data_start: u32,
+    sectors_per_cluster: u32,
+    /// Clusters are numbered from 2 to this.
+    max_cluster: u32,
+}
+
+impl Volume {
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster <= self.max_cluster
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    /// The directory the root directory is, see `FatFs::dir`.
+    fn root_dir(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0,
+            FatType::Fat32 => self.root_cluster,
+        }
+    }
+
+    /// The sector and offset of the entry of `cluster` in FAT number `copy`.
+    fn fat_location(&self, copy: u32, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + copy * self.fat_sectors + offset / SECTOR_SIZE as u32,
+            offset as usize % SECTOR_SIZE,
+        )
+    }
+
+    /// The first cluster of a directory entry.
+    fn entry_cluster(&self, entry: &[u8]) -> u32 {
+        let low = read_u16(entry, 26) as u32;
+        match self.fat_type {
+            FatType::Fat16 => low,
+            FatType::Fat32 => (read_u16(entry, 20) as u32) << 16 | low,
+        }
+    }
+}
+
+/// A sector in the cache.
+#[derive(Copy, Clone)]
+struct Slot {
+    sector: u32,
+    valid: bool,
+    /// Changed since it was read.
+    dirty: bool,
+    /// When the sector was last used, for replacing the least recently used.
+    used: u32,
+}
+
+const EMPTY_SLOT: Slot = Slot {
+    sector: 0,
+    valid: false,
+    dirty: false,
+    used: 0,
+};
+
+/// Why a step of an operation stopped.
+enum Error {
+    /// The sector has to be read into the cache.
+    Load(u32),
+    /// A sector has to be written back to make room in the cache.
+    Evict,
+    /// The operation failed.
+    Fail(ReturnCode),
+}
+
+/// Operations users can request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Operation {
+    Mount,
+    Open(Mode),
+    /// List the entry with this number.
+    List(u32),
+    /// Read or write this many bytes.
+    Read(usize),
+    Write(usize),
+}
+
+impl Operation {
+    /// The command number of the operation, which is passed to the callback.
+    fn command_num(&self) -> usize {
+        match *self {
+            Operation::Mount => 0,
+            Operation::Open(_) => 1,
+            Operation::Read(_) => 2,
+            Operation::Write(_) => 3,
+            Operation::List(_) => 5,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum User {
+    Kernel,
+    App(AppId),
+}
+
+/// What the file system is waiting for the device to finish.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Idle,
+    /// Getting the device ready to mount.
+    Initializing,
+    /// Reading `sector` into cache slot `slot`.
+    Loading { slot: usize, sector: u32 },
+    /// Writing a cache slot to make room in the cache.
+    WritingBack(usize),
+    /// Writing a cache slot at the end of an operation.
+    Flushing(usize),
+}
+
+/// How far the current operation got.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Phase {
+    Start,
+    /// Adding the file being opened to its directory.
+    Create,
+    /// Freeing the clusters of a file that was made empty, from this one on.
+    Truncate(u32),
+    /// Going through the entries of the directory being listed.
+    Listing,
+    /// Moving bytes between the file and the buffer.
+    Data,
+    /// Writing the size and first cluster of the file to its directory entry.
+    UpdateEntry,
+}
+
+/// What the path of an operation leads to.
+enum Found {
+    /// The path is empty, so the root directory.
+    Root,
+    /// The sector and offset of the directory entry of the last component.
+    Entry(u32, usize),
+    /// The last component is not in its directory, which is `FatFs::dir`.
+    Missing,
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    data: Option<AppSlice<Shared, u8>>,
+    files: [Option<File>; MAX_FILES],
+    /// Command waiting for the file system, with its arguments.
+    pending: Option<(usize, usize, usize)>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            path: None,
+            data: None,
+            files: [None; MAX_FILES],
+            pending: None,
+        }
+    }
+}
+
+pub struct FatFs<'a, D: BlockDevice + 'a> {
+    device: &'a D,
+    apps: Grant<App>,
+    cache: TakeCell<'static, [u8]>,
+    slots: Cell<[Slot; CACHE_SECTORS]>,
+    /// Counts uses of the cache, to find the least recently used sector.
+    clock: Cell<u32>,
+    /// Buffer sectors are read into and written from.
+    buffer: TakeCell<'static, [u8]>,
+    state: Cell<State>,
+    volume: OptionalCell<Volume>,
+    /// Changes whenever a card is mounted or goes away.
+    generation: Cell<u32>,
+    /// Where to start looking for a free cluster.
+    free_hint: Cell<u32>,
+
+    /// The operation in progress, and its result once it finished but still
+    /// writes the sectors it changed.
+    current: OptionalCell<(User, Operation)>,
+    result: Cell<(ReturnCode, usize)>,
+
+    // Progress of the current operation.
+    phase: Cell<Phase>,
+    path: Cell<[u8; MAX_PATH_LEN]>,
+    path_len: Cell<usize>,
+    /// Where in the path the component being looked up starts, and its
+    /// short name.
+    component: Cell<usize>,
+    name: Cell<[u8; 11]>,
+    /// The directory being searched: its first cluster, or 0 for the root
+    /// directory of FAT16.
+    dir: Cell<u32>,
+    /// The next entry of the directory to look at, and the last cluster of
+    /// the directory reached with its number in the chain.
+    dir_index: Cell<u32>,
+    dir_cursor: Cell<(u32, u32)>,
+    /// The first free entry seen in the directory.
+    free_entry: OptionalCell<u32>,
+    /// A cluster that was allocated but is not linked into a chain yet, and
+    /// how many of its sectors were cleared.
+    allocated: Cell<u32>,
+    zeroed: Cell<u32>,
+    /// How many clusters were checked while looking for a free one.
+    searched: Cell<u32>,
+    file: Cell<File>,
+    /// The app's handle for `file`.
+    handle: Cell<usize>,
+    /// Bytes read or written so far.
+    done: Cell<usize>,
+    /// Entries counted while listing, and the entry that was listed.
+    listed: Cell<u32>,
+    entry: Cell<DirEntry>,
+
+    kernel_client: OptionalCell<&'static FatClient>,
+    kernel_pending: OptionalCell<Operation>,
+    kernel_path: Cell<[u8; MAX_PATH_LEN]>,
+    kernel_path_len: Cell<usize>,
+    kernel_file: Cell<File>,
+    kernel_buffer: TakeCell<'static, [u8]>,
+}
+
+impl<D: BlockDevice> FatFs<'a, D> {
+    pub fn new(
+        device: &'a D,
+        grant: Grant<App>,
+        cache: &'static mut [u8; CACHE_SECTORS * SECTOR_SIZE],
+        buffer: &'static mut [u8; SECTOR_SIZE],
+    ) -> FatFs<'a, D> {
+        FatFs {
+            device: device,
+            apps: grant,
+            cache: TakeCell::new(cache),
+            slots: Cell::new([EMPTY_SLOT; CACHE_SECTORS]),
+            clock: Cell::new(0),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            volume: OptionalCell::empty(),
+            generation: Cell::new(0),
+            free_hint: Cell::new(2),
+            current: OptionalCell::empty(),
+            result: Cell::new((ReturnCode::SUCCESS, 0)),
+            phase: Cell::new(Phase::Start),
+            path: Cell::new([0; MAX_PATH_LEN]),
+            path_len: Cell::new(0),
+            component: Cell::new(0),
+            name: Cell::new([0; 11]),
+            dir: Cell::new(0),
+            dir_index: Cell::new(0),
+            dir_cursor: Cell::new((0, 0)),
+            free_entry: OptionalCell::empty(),
+            allocated: Cell::new(0),
+            zeroed: Cell::new(0),
+            searched: Cell::new(0),
+            file: Cell::new(File::default()),
+            handle: Cell::new(0),
+            done: Cell::new(0),
+            listed: Cell::new(0),
+            entry: Cell::new(DirEntry::default()),
+            kernel_client: OptionalCell::empty(),
+            kernel_pending: OptionalCell::empty(),
+            kernel_path: Cell::new([0; MAX_PATH_LEN]),
+            kernel_path_len: Cell::new(0),
+            kernel_file: Cell::new(File::default()),
+            kernel_buffer: TakeCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static FatClient) {
+        self.kernel_client.set(client);
+    }
+
+    /// Mount the file system of the card, getting the card ready first if
+    /// needed. Files that were open can no longer be used. Operations
+    /// requested while mounting wait for it.
+    pub fn mount(&self) -> ReturnCode {
+        if self.state.get() != State::Idle || self.current.is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.unmount();
+        if self.device.is_ready() {
+            self.start(User::Kernel, Operation::Mount);
+        } else {
+            let result = self.device.initialize();
+            if result != ReturnCode::SUCCESS {
+                return result;
+            }
+            self.current.set((User::Kernel, Operation::Mount));
+            self.state.set(State::Initializing);
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Open the file at `path`.
+    pub fn open(&self, path: &[u8], mode: Mode) -> ReturnCode {
+        self.request_path(path, Operation::Open(mode))
+    }
+
+    /// List entry number `index` of the directory at `path`. The entries are
+    /// the files and directories in it, without `.` and `..`.
+    pub fn list(&self, path: &[u8], index: usize) -> ReturnCode {
+        self.request_path(path, Operation::List(index as u32))
+    }
+
+    /// Read up to `length` bytes of `file` into `buffer`.
+    pub fn read(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let length = cmp::min(length, buffer.len());
+        self.request_buffer(file, buffer, Operation::Read(length))
+    }
+
+    /// Write the first `length` bytes of `buffer` to `file`.
+    pub fn write(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        length: usize,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let length = cmp::min(length, buffer.len());
+        self.request_buffer(file, buffer, Operation::Write(length))
+    }
+
+    /// Whether an operation of the kernel can be requested.
+    fn kernel_ready(&self) -> ReturnCode {
+        let kernel_busy = self.current.map_or(false, |&mut (user, operation)| match user {
+            User::Kernel => operation != Operation::Mount,
+            User::App(_) => false,
+        });
+        if self.kernel_pending.is_some() || kernel_busy {
+            ReturnCode::EBUSY
+        } else if !self.is_available() {
+            ReturnCode::EOFF
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    fn request_path(&self, path: &[u8], operation: Operation) -> ReturnCode {
+        let ready = self.kernel_ready();
+        if ready != ReturnCode::SUCCESS {
+            return ready;
+        }
+        if path.len() > MAX_PATH_LEN {
+            return ReturnCode::EINVAL;
+        }
+        let mut stored = [0; MAX_PATH_LEN];
+        stored[0..path.len()].copy_from_slice(path);
+        self.kernel_path.set(stored);
+        self.kernel_path_len.set(path.len());
+        self.kernel_pending.set(operation);
+        self.start_next();
+        ReturnCode::SUCCESS
+    }
+
+    fn request_buffer(
+        &self,
+        file: File,
+        buffer: &'static mut [u8],
+        operation: Operation,
+    ) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        let ready = self.kernel_ready();
+        if ready != ReturnCode::SUCCESS {
+            return Err((ready, buffer));
+        }
+        self.kernel_file.set(file);
+        self.kernel_buffer.replace(buffer);
+        self.kernel_pending.set(operation);
+        self.start_next();
+        Ok(())
+    }
+
+    /// Whether a file system is mounted, or will be once mounting finishes.
+    fn is_available(&self) -> bool {
+        self.volume.is_some() || self
+            .current
+            .map_or(false, |&mut (_, operation)| operation == Operation::Mount)
+    }
+
+    fn is_current_app(&self, appid: AppId) -> bool {
+        self.current.map_or(false, |&mut (user, _)| match user {
+            User::App(current) => current == appid,
+            User::Kernel => false,
+        })
+    }
+
+    fn volume(&self) -> Result<Volume, Error> {
+        self.volume
+            .map_or(Err(Error::Fail(ReturnCode::EOFF)), |volume| Ok(*volume))
+    }
+
+    /// Forget the file system, for example because the card was removed.
+    fn unmount(&self) {
+        self.volume.clear();
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.slots.set([EMPTY_SLOT; CACHE_SECTORS]);
+    }
+
+    /// Start the next waiting operation, if there is nothing in progress.
+    /// The kernel goes first, then apps.
+    fn start_next(&self) {
+        if self.state.get() != State::Idle || self.current.is_some() {
+            return;
+        }
+        if let Some(operation) = self.kernel_pending.take() {
+            self.path.set(self.kernel_path.get());
+            self.path_len.set(self.kernel_path_len.get());
+            self.file.set(self.kernel_file.get());
+            self.start(User::Kernel, operation);
+            return;
+        }
+        for cntr in self.apps.iter() {
+            // Apps are entered again while the operation runs, so it is only
+            // set up here.
+            let next = cntr.enter(|app, _| {
+                app.pending.take().map(|(command_num, data1, data2)| {
+                    (app.appid(), self.prepare(app, command_num, data1, data2))
+                })
+            });
+            match next {
+                Some((appid, Ok(operation))) => {
+                    self.start(User::App(appid), operation);
+                    break;
+                }
+                Some((appid, Err((operation, result)))) => {
+                    self.current.set((User::App(appid), operation));
+                    self.end(result, 0);
+                    break;
+                }
+                None => {}
+            }
+        }
+    }
+
+    /// Copy what the command `command_num` of `app` works on to the progress
+    /// fields, and return its operation, or why it cannot run.
+    fn prepare(
+        &self,
+        app: &mut App,
+        command_num: usize,
+        data1: usize,
+        data2: usize,
+    ) -> Result<Operation, (Operation, ReturnCode)> {
+        let invalid = match command_num {
+            1 => Operation::Open(Mode::Read),
+            2 => Operation::Read(0),
+            3 => Operation::Write(0),
+            _ => Operation::List(0),
+        };
+        match command_num {
+            1 | 5 => {
+                let len = data1;
+                let mut path = [0; MAX_PATH_LEN];
+                let copied = app.path.as_ref().map_or(false, |slice| {
+                    if len > slice.len() || len > MAX_PATH_LEN {
+                        return false;
+                    }
+                    path[0..len].copy_from_slice(&slice.as_ref()[0..len]);
+                    true
+                });
+                if !copied {
+                    return Err((invalid, ReturnCode::EINVAL));
+                }
+                self.path.set(path);
+                self.path_len.set(len);
+                if command_num == 1 {
+                    Mode::from_usize(data2)
+                        .map(|mode| Operation::Open(mode))
+                        .ok_or((invalid, ReturnCode::EINVAL))
+                } else {
+                    Ok(Operation::List(data2 as u32))
+                }
+            }
+            _ => {
+                self.handle.set(data1);
+                let file = match app.files.get(data1) {
+                    Some(&Some(file)) => file,
+                    _ => return Err((invalid, ReturnCode::EINVAL)),
+                };
+                self.file.set(file);
+                // The app may have allowed a shorter buffer since.
+                let length = app.data.as_ref().map_or(0, |data| cmp::min(data2, data.len()));
+                if command_num == 2 {
+                    Ok(Operation::Read(length))
+                } else {
+                    Ok(Operation::Write(length))
+                }
+            }
+        }
+    }
+
+    fn start(&self, user: User, operation: Operation) {
+        self.current.set((user, operation));
+        self.phase.set(Phase::Start);
+        self.component.set(0);
+        self.allocated.set(0);
+        self.zeroed.set(0);
+        self.searched.set(0);
+        self.done.set(0);
+        self.listed.set(0);
+        self.entry.set(DirEntry::default());
+
+        if operation == Operation::Mount {
+            self.run();
+            return;
+        }
+        let root_dir = match self.volume.map(|volume| volume.root_dir()) {
+            Some(root_dir) => root_dir,
+            None => return self.end(ReturnCode::EOFF, 0),
+        };
+        self.set_dir(root_dir);
+        match operation {
+            Operation::Read(_) | Operation::Write(_) => {
+                let file = self.file.get();
+                if file.volume != self.generation.get() {
+                    return self.end(ReturnCode::EINVAL, 0);
+                }
+                if let Operation::Write(_) = operation {
+                    if file.mode == Mode::Read {
+                        return self.end(ReturnCode::EINVAL, 0);
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.run();
+    }
+
+    /// Run a step of the current operation, and read or write the sectors
+    /// it needs, or finish it.
+    fn run(&self) {
+        let operation = match self.current.map(|&mut (_, operation)| operation) {
+            Some(operation) => operation,
+            None => return,
+        };
+        let result = match operation {
+            Operation::Mount => self.mount_step(),
+            Operation::Open(mode) => self.open_step(mode),
+            Operation::List(index) => self.list_step(index),
+            Operation::Read(length) => self.read_step(length),
+            Operation::Write(length) => self.write_step(length),
+        };
+        match result {
+            Ok(length) => self.end(ReturnCode::SUCCESS, length),
+            Err(Error::Fail(result)) => self.end(result, self.done.get()),
+            Err(Error::Load(sector)) => self.load(sector),
+            Err(Error::Evict) => {
+                let slot = self.victim(false).unwrap_or(0);
+                self.write_slot(State::WritingBack(slot), slot);
+            }
+        }
+    }
+
+    fn mount_step(&self) -> Result<usize, Error> {
+        let start = self
+            .with_sector(0, |data| partition_start(data))?
+            .ok_or(Error::Fail(ReturnCode::ENOSUPPORT))?;
+        let volume = self
+            .with_sector(start, |data| parse_boot_sector(data, start))?
+            .map_err(Error::Fail)?;
+        if volume.info_sector != 0 {
+            // Free clusters are not counted, so have the count checked the
+            // next time the card is in a computer.
+            let stale = self.with_sector(volume.info_sector, |data| {
+                read_u32(data, 0) == 0x4161_5252
+                    && (read_u32(data, 488) != FS_INFO_UNKNOWN
+                        || read_u32(data, 492) != FS_INFO_UNKNOWN)
+            })?;
+            if stale {
+                self.with_sector_mut(volume.info_sector, |data| {
+                    write_u32(data, 488, FS_INFO_UNKNOWN);
+                    write_u32(data, 492, FS_INFO_UNKNOWN);
+                })?;
+            }
+        }
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.free_hint.set(2);
+        self.volume.set(volume);
+        Ok(0)
+    }
+
+    fn open_step(&self, mode: Mode) -> Result<usize, Error> {
+        let volume = self.volume()?;
+        loop {
+            match self.phase.get() {
+                Phase::Start => match self.resolve()? {
+                    Found::Root => return Err(Error::Fail(ReturnCode::EINVAL)),
+                    Found::Missing if mode == Mode::Read => {
+                        return Err(Error::Fail(ReturnCode::FAIL));
+                    }
+                    Found::Missing => self.phase.set(Phase::Create),
+                    Found::Entry(sector, offset) => {
+                        let (attributes, cluster, size) = self.with_sector(sector, |data| {
+                            let entry = &data[offset..offset + DIR_ENTRY_LEN];
+                            (entry[11], volume.entry_cluster(entry), read_u32(entry, 28))
+                        })?;
+                        if attributes & ATTR_DIRECTORY != 0
+                            || (attributes & ATTR_READ_ONLY != 0 && mode != Mode::Read)
+                        {
+                            return Err(Error::Fail(ReturnCode::EINVAL));
+                        }
+                        if mode != Mode::Write {
+                            let position = if mode == Mode::Append { size } else { 0 };
+                            self.open_file(sector, offset, cluster, size, position, mode);
+                            return Ok(0);
+                        }
+                        // Make the file empty before freeing its clusters, so
+                        // that they are never in use twice.
+                        self.with_sector_mut(sector, |data| {
+                            let entry = &mut data[offset..offset + DIR_ENTRY_LEN];
+                            write_u16(entry, 20, 0);
+                            write_u16(entry, 26, 0);
+                            write_u32(entry, 28, 0);
+                        })?;
+                        self.open_file(sector, offset, 0, 0, 0, mode);
+                        self.phase.set(Phase::Truncate(cluster));
+                    }
+                },
+                Phase::Create => {
+                    let index = match self.free_entry.map(|index| *index) {
+                        Some(index) => index,
+                        None => {
+                            self.grow_dir()?;
+                            continue;
+                        }
+                    };
+                    let (sector, offset) = self
+                        .dir_entry_location(self.dir.get(), index)?
+                        .ok_or(Error::Fail(ReturnCode::FAIL))?;
+                    let name = self.name.get();
+                    self.with_sector_mut(sector, |data| {
+                        new_entry(&mut data[offset..offset + DIR_ENTRY_LEN], &name)
+                    })?;
+                    self.open_file(sector, offset, 0, 0, 0, mode);
+                    return Ok(0);
+                }
+                Phase::Truncate(cluster) => {
+                    self.free_chain(cluster)?;
+                    return Ok(0);
+                }
+                _ => return Err(Error::Fail(ReturnCode::FAIL)),
+            }
+        }
+    }
+
+    fn open_file(
+        &self,
+        sector: u32,
+        offset: usize,
+        first_cluster: u32,
+        size: u32,
+        position: u32,
+        mode: Mode,
+    ) {
+        self.file.set(File {
+            volume: self.generation.get(),
+            entry_sector: sector,
+            entry_offset: offset,
+            first_cluster: first_cluster,
+            size: size,
+            position: position,
+            cluster: 0,
+            cluster_index: 0,
+            mode: mode,
+        });
+    }
+
+    fn list_step(&self, index: u32) -> Result<usize, Error> {
+        let volume = self.volume()?;
+        if self.phase.get() == Phase::Start {
+            let dir = match self.resolve()? {
+                Found::Root => volume.root_dir(),
+                Found::Missing => return Err(Error::Fail(ReturnCode::FAIL)),
+                Found::Entry(sector, offset) => {
+                    let (attributes, cluster) = self.with_sector(sector, |data| {
+                        let entry = &data[offset..offset + DIR_ENTRY_LEN];
+                        (entry[11], volume.entry_cluster(entry))
+                    })?;
+                    if attributes & ATTR_DIRECTORY == 0 {
+                        return Err(Error::Fail(ReturnCode::EINVAL));
+                    }
+                    if cluster == 0 {
+                        volume.root_dir()
+                    } else {
+                        cluster
+                    }
+                }
+            };
+            self.set_dir(dir);
+            self.phase.set(Phase::Listing);
+        }
+        loop {
+            let dir_index = self.dir_index.get();
+            let (sector, offset) = self
+                .dir_entry_location(self.dir.get(), dir_index)?
+                .ok_or(Error::Fail(ReturnCode::FAIL))?;
+            let (end, entry) = self.with_sector(sector, |data| {
+                let entry = &data[offset..offset + DIR_ENTRY_LEN];
+                (entry[0] == ENTRY_END, list_entry(entry))
+            })?;
+            if end {
+                return Err(Error::Fail(ReturnCode::FAIL));
+            }
+            if let Some(entry) = entry {
+                if self.listed.get() == index {
+                    self.entry.set(entry);
+                    return Ok(0);
+                }
+                self.listed.set(self.listed.get() + 1);
+            }
+            self.dir_index.set(dir_index + 1);
+        }
+    }
+
+    fn read_step(&self, length: usize) -> Result<usize, Error> {
+        let volume = self.volume()?;
+        if self.phase.get() == Phase::Start {
+            self.refresh_file()?;
+            self.phase.set(Phase::Data);
+        }
+        loop {
+            let mut file = self.file.get();
+            let done = self.done.get();
+            if done == length || file.position >= file.size {
+                return Ok(done);
+            }
+            if !self.find_cluster(&mut file)? {
+                // The chain of clusters is shorter than the file.
+                return Err(Error::Fail(ReturnCode::FAIL));
+            }
+            let sector = volume.cluster_sector(file.cluster)
+                + file.position % volume.cluster_bytes() / SECTOR_SIZE as u32;
+            let offset = file.position as usize % SECTOR_SIZE;
+            let len = cmp::min(
+                cmp::min(SECTOR_SIZE - offset, length - done),
+                (file.size - file.position) as usize,
+            );
+            self.with_sector(sector, |data| self.copy_out(done, &data[offset..offset + len]))?;
+            file.position += len as u32;
+            self.file.set(file);
+            self.done.set(done + len);
+        }
+    }
+
+    fn write_step(&self, length: usize) -> Result<usize, Error> {
+        let volume = self.volume()?;
+        loop {
+            match self.phase.get() {
+                Phase::Start => {
+                    self.refresh_file()?;
+                    let mut file = self.file.get();
+                    if file.mode == Mode::Append {
+                        file.position = file.size;
+                        self.file.set(file);
+                    }
+                    self.phase.set(Phase::Data);
+                }
+                Phase::Data => {
+                    let mut file = self.file.get();
+                    let done = self.done.get();
+                    // Files end at 4 GiB.
+                    let len = cmp::min(length - done, (u32::max_value() - file.position) as usize);
+                    if len == 0 {
+                        self.phase.set(Phase::UpdateEntry);
+                        continue;
+                    }
+                    if file.first_cluster == 0 {
+                        let cluster = self.allocate()?;
+                        file.first_cluster = cluster;
+                        file.cluster = cluster;
+                        file.cluster_index = 0;
+                        self.file.set(file);
+                        self.allocated.set(0);
+                        continue;
+                    }
+                    if !self.find_cluster(&mut file)? {
+                        // `file.cluster` is the last cluster of the file.
+                        let cluster = self.allocate()?;
+                        self.set_fat_entry(file.cluster, cluster)?;
+                        file.cluster = cluster;
+                        file.cluster_index += 1;
+                        self.file.set(file);
+                        self.allocated.set(0);
+                        continue;
+                    }
+                    let sector = volume.cluster_sector(file.cluster)
+                        + file.position % volume.cluster_bytes() / SECTOR_SIZE as u32;
+                    let offset = file.position as usize % SECTOR_SIZE;
+                    let len = cmp::min(SECTOR_SIZE - offset, len);
+                    let whole = len == SECTOR_SIZE || file.position + len as u32 >= file.size;
+                    if offset == 0 && whole {
+                        // Nothing in the sector has to be kept.
+                        self.overwrite_sector(sector, |data| {
+                            self.copy_in(done, &mut data[0..len]);
+                            for byte in data[len..].iter_mut() {
+                                *byte = 0;
+                            }
+                        })?;
+                    } else {
+                        self.with_sector_mut(sector, |data| {
+                            self.copy_in(done, &mut data[offset..offset + len])
+                        })?;
+                    }
+                    file.position += len as u32;
+                    file.size = cmp::max(file.size, file.position);
+                    self.file.set(file);
+                    self.done.set(done + len);
+                }
+                Phase::UpdateEntry => {
+                    let file = self.file.get();
+                    let offset = file.entry_offset;
+                    self.with_sector_mut(file.entry_sector, |data| {
+                        let entry = &mut data[offset..offset + DIR_ENTRY_LEN];
+                        entry[11] |= ATTR_ARCHIVE;
+                        write_u16(entry, 20, (file.first_cluster >> 16) as u16);
+                        write_u16(entry, 26, file.first_cluster as u16);
+                        write_u32(entry, 28, file.size);
+                    })?;
+                    return Ok(self.done.get());
+                }
+                _ => return Err(Error::Fail(ReturnCode::FAIL)),
+            }
+        }
+    }
+
+    /// Update the first cluster and size of the current file from its
+    /// directory entry, in case the file was written through another `File`.
+    fn refresh_file(&self) -> Result<(), Error> {
+        let volume = self.volume()?;
+        let mut file = self.file.get();
+        let offset = file.entry_offset;
+        let (first_cluster, size) = self.with_sector(file.entry_sector, |data| {
+            let entry = &data[offset..offset + DIR_ENTRY_LEN];
+            (volume.entry_cluster(entry), read_u32(entry, 28))
+        })?;
+        if first_cluster != 0 && !volume.is_cluster(first_cluster) {
+            return Err(Error::Fail(ReturnCode::FAIL));
+        }
+        if first_cluster != file.first_cluster {
+            file.cluster = 0;
+            file.cluster_index = 0;
+        }
+        file.first_cluster = first_cluster;
+        file.size = size;
+        file.position = cmp::min(file.position, size);
+        self.file.set(file);
+        Ok(())
+    }
+
+    /// Find the cluster the position of `file` is in. Returns `false` if the
+    /// chain of clusters of the file ends before, with `file.cluster` the
+    /// last cluster of the chain.
+    fn find_cluster(&self, file: &mut File) -> Result<bool, Error> {
+        let volume = self.volume()?;
+        let wanted = file.position / volume.cluster_bytes();
+        if file.cluster == 0 || file.cluster_index > wanted {
+            if file.first_cluster == 0 {
+                return Ok(false);
+            }
+            file.cluster = file.first_cluster;
+            file.cluster_index = 0;
+        }
+        while file.cluster_index < wanted {
+            let next = self.fat_entry(file.cluster)?;
+            if !volume.is_cluster(next) {
+                return Ok(false);
+            }
+            file.cluster = next;
+            file.cluster_index += 1;
+            self.file.set(*file);
+        }
+        Ok(true)
+    }
+
+    /// Look up the path of the current operation, one component at a time.
+    fn resolve(&self) -> Result<Found, Error> {
+        let volume = self.volume()?;
+        let path = self.path.get();
+        let path = &path[0..self.path_len.get()];
+        loop {
+            let (start, end) = match next_component(path, self.component.get()) {
+                Some(component) => component,
+                None => return Ok(Found::Root),
+            };
+            let name = short_name(&path[start..end]).ok_or(Error::Fail(ReturnCode::EINVAL))?;
+            let last = next_component(path, end).is_none();
+            self.name.set(name);
+            let (sector, offset) = match self.find_in_dir(&name)? {
+                Some(location) => location,
+                None if last => return Ok(Found::Missing),
+                None => return Err(Error::Fail(ReturnCode::FAIL)),
+            };
+            if last {
+                return Ok(Found::Entry(sector, offset));
+            }
+            let (attributes, cluster) = self.with_sector(sector, |data| {
+                let entry = &data[offset..offset + DIR_ENTRY_LEN];
+                (entry[11], volume.entry_cluster(entry))
+            })?;
+            if attributes & ATTR_DIRECTORY == 0 {
+                return Err(Error::Fail(ReturnCode::FAIL));
+            }
+            self.component.set(end);
+            self.set_dir(if cluster == 0 {
+                volume.root_dir()
+            } else {
+                cluster
+            });
+        }
+    }
+
+    /// Start searching the directory `dir`.
+    fn set_dir(&self, dir: u32) {
+        self.dir.set(dir);
+        self.dir_index.set(0);
+        self.dir_cursor.set((0, dir));
+        self.free_entry.clear();
+    }
+
+    /// Find the entry called `name` in the current directory, carrying on
+    /// from `dir_index`. If there is none, `free_entry` is where it can be
+    /// added, unless the directory is full.
+    fn find_in_dir(&self, name: &[u8; 11]) -> Result<Option<(u32, usize)>, Error> {
+        loop {
+            let index = self.dir_index.get();
+            let (sector, offset) = match self.dir_entry_location(self.dir.get(), index)? {
+                Some(location) => location,
+                None => return Ok(None),
+            };
+            let (first, matches) = self.with_sector(sector, |data| {
+                let entry = &data[offset..offset + DIR_ENTRY_LEN];
+                (
+                    entry[0],
+                    entry[11] & ATTR_VOLUME_ID == 0 && &entry[0..11] == name,
+                )
+            })?;
+            if first == ENTRY_END || first == ENTRY_FREE {
+                if self.free_entry.is_none() {
+                    self.free_entry.set(index);
+                }
+                if first == ENTRY_END {
+                    return Ok(None);
+                }
+            } else if matches {
+                return Ok(Some((sector, offset)));
+            }
+            self.dir_index.set(index + 1);
+        }
+    }
+
+    /// The sector and offset of entry `index` of directory `dir`, or `None`
+    /// if the directory is shorter.
+    fn dir_entry_location(&self, dir: u32, index: u32) -> Result<Option<(u32, usize)>, Error> {
+        let volume = self.volume()?;
+        let offset = (index % ENTRIES_PER_SECTOR) as usize * DIR_ENTRY_LEN;
+        if dir == 0 {
+            if index >= volume.root_entries {
+                return Ok(None);
+            }
+            return Ok(Some((volume.root_start + index / ENTRIES_PER_SECTOR, offset)));
+        }
+        let entries_per_cluster = ENTRIES_PER_SECTOR * volume.sectors_per_cluster;
+        let wanted = index / entries_per_cluster;
+        let (mut number, mut cluster) = self.dir_cursor.get();
+        if number > wanted {
+            number = 0;
+            cluster = dir;
+        }
+        while number < wanted {
+            let next = self.fat_entry(cluster)?;
+            if !volume.is_cluster(next) {
+                return Ok(None);
+            }
+            number += 1;
+            cluster = next;
+            self.dir_cursor.set((number, cluster));
+        }
+        let sector =
+            volume.cluster_sector(cluster) + index % entries_per_cluster / ENTRIES_PER_SECTOR;
+        Ok(Some((sector, offset)))
+    }
+
+    /// Add a cluster to the end of the current directory, which is full, and
+    /// make its first entry the free one.
+    fn grow_dir(&self) -> Result<(), Error> {
+        let volume = self.volume()?;
+        if self.dir.get() == 0 {
+            // The root directory of FAT16 has a fixed size.
+            return Err(Error::Fail(ReturnCode::ENOMEM));
+        }
+        let cluster = self.allocate()?;
+        // Clear the cluster before it is part of the directory, so that the
+        // directory never has garbage entries.
+        while self.zeroed.get() < volume.sectors_per_cluster {
+            let sector = volume.cluster_sector(cluster) + self.zeroed.get();
+            self.overwrite_sector(sector, |data| {
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+            })?;
+            self.zeroed.set(self.zeroed.get() + 1);
+        }
+        // Looking for the entry stopped at the last cluster.
+        let (_, last) = self.dir_cursor.get();
+        self.set_fat_entry(last, cluster)?;
+        self.allocated.set(0);
+        self.zeroed.set(0);
+        self.free_entry.set(self.dir_index.get());
+        Ok(())
+    }
+
+    /// Allocate a free cluster as the end of a chain. The cluster is kept in
+    /// `allocated` until the caller linked it, so running the step again
+    /// does not allocate another one.
+    fn allocate(&self) -> Result<u32, Error> {
+        if self.allocated.get() != 0 {
+            return Ok(self.allocated.get());
+        }
+        let volume = self.volume()?;
+        while self.searched.get() < volume.max_cluster - 1 {
+            let cluster = self.free_hint.get();
+            if self.fat_entry(cluster)? == 0 {
+                self.set_fat_entry(cluster, volume.end_of_chain())?;
+                self.allocated.set(cluster);
+                self.searched.set(0);
+                return Ok(cluster);
+            }
+            self.free_hint
+                .set(if cluster >= volume.max_cluster { 2 } else { cluster + 1 });
+            self.searched.set(self.searched.get() + 1);
+        }
+        Err(Error::Fail(ReturnCode::ENOMEM))
+    }
+
+    /// Free the chain of clusters starting at `cluster`.
+    fn free_chain(&self, cluster: u32) -> Result<(), Error> {
+        let volume = self.volume()?;
+        let mut cluster = cluster;
+        while volume.is_cluster(cluster) {
+            let next = self.fat_entry(cluster)?;
+            self.set_fat_entry(cluster, 0)?;
+            if cluster < self.free_hint.get() {
+                self.free_hint.set(cluster);
+            }
+            cluster = next;
+            self.phase.set(Phase::Truncate(cluster));
+        }
+        Ok(())
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, Error> {
+        let volume = self.volume()?;
+        let (sector, offset) = volume.fat_location(0, cluster);
+        self.with_sector(sector, |data| match volume.fat_type {
+            FatType::Fat16 => read_u16(data, offset) as u32,
+            FatType::Fat32 => read_u32(data, offset) & 0x0fff_ffff,
+        })
+    }
+
+    /// Set the entry of `cluster` in every copy of the FAT.
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), Error> {
+        let volume = self.volume()?;
+        // Have every copy in the cache first, so that the copies are never
+        // left different.
+        for copy in 0..volume.num_fats {
+            let (sector, _) = volume.fat_location(copy, cluster);
+            self.with_sector(sector, |_| ())?;
+        }
+        for copy in 0..volume.num_fats {
+            let (sector, offset) = volume.fat_location(copy, cluster);
+            self.with_sector_mut(sector, |data| match volume.fat_type {
+                FatType::Fat16 => write_u16(data, offset, value as u16),
+                FatType::Fat32 => {
+                    // The top four bits are reserved.
+                    let reserved = read_u32(data, offset) & 0xf000_0000;
+                    write_u32(data, offset, reserved | value);
+                }
+            })?;
+        }
+        Ok(())
+    }
+
+    fn find_slot(&self, sector: u32) -> Option<usize> {
+        self.slots
+            .get()
+            .iter()
+            .position(|slot| slot.valid && slot.sector == sector)
+    }
+
+    /// The slot to reuse: an empty one, or else the least recently used one,
+    /// which can have to be clean.
+    fn victim(&self, clean: bool) -> Option<usize> {
+        let slots = self.slots.get();
+        slots.iter().position(|slot| !slot.valid).or_else(|| {
+            slots
+                .iter()
+                .enumerate()
+                .filter(|&(_, slot)| !clean || !slot.dirty)
+                .min_by_key(|&(_, slot)| slot.used)
+                .map(|(slot_num, _)| slot_num)
+        })
+    }
+
+    fn set_slot(&self, slot_num: usize, slot: Slot) {
+        let mut slots = self.slots.get();
+        slots[slot_num] = slot;
+        self.slots.set(slots);
+    }
+
+    /// Record that `slot_num` was used, and changed if `dirty`.
+    fn touch(&self, slot_num: usize, dirty: bool) {
+        let clock = self.clock.get().wrapping_add(1);
+        self.clock.set(clock);
+        let mut slots = self.slots.get();
+        slots[slot_num].used = clock;
+        slots[slot_num].dirty |= dirty;
+        self.slots.set(slots);
+    }
+
+    /// Call `f` with `sector`, if it is in the cache.
+    fn with_sector<F, R>(&self, sector: u32, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        let slot = self.find_slot(sector).ok_or(Error::Load(sector))?;
+        self.touch(slot, false);
+        self.cache
+            .map(|cache| f(&cache[slot * SECTOR_SIZE..(slot + 1) * SECTOR_SIZE]))
+            .ok_or(Error::Fail(ReturnCode::ENOMEM))
+    }
+
+    /// Change `sector` with `f`, if it is in the cache.
+    fn with_sector_mut<F, R>(&self, sector: u32, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let slot = self.find_slot(sector).ok_or(Error::Load(sector))?;
+        self.touch(slot, true);
+        self.cache
+            .map(|cache| f(&mut cache[slot * SECTOR_SIZE..(slot + 1) * SECTOR_SIZE]))
+            .ok_or(Error::Fail(ReturnCode::ENOMEM))
+    }
+
+    /// Like `with_sector_mut()`, for an `f` that sets every byte of the
+    /// sector, so that it does not have to be read first.
+    fn overwrite_sector<F>(&self, sector: u32, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut [u8]),
+    {
+        if self.find_slot(sector).is_none() {
+            let slot = self.victim(true).ok_or(Error::Evict)?;
+            self.set_slot(
+                slot,
+                Slot {
+                    sector: sector,
+                    valid: true,
+                    dirty: false,
+                    used: 0,
+                },
+            );
+        }
+        self.with_sector_mut(sector, f)
+    }
+
+    /// Read `sector` into the cache, first writing back the sector it
+    /// replaces if that changed.
+    fn load(&self, sector: u32) {
+        let slot = self.victim(false).unwrap_or(0);
+        if self.slots.get()[slot].dirty {
+            return self.write_slot(State::WritingBack(slot), slot);
+        }
+        self.set_slot(slot, EMPTY_SLOT);
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.state.set(State::Loading {
+                    slot: slot,
+                    sector: sector,
+                });
+                let result = self.device.read_block(buffer, sector);
+                if result != ReturnCode::SUCCESS {
+                    self.device_failed(result);
+                }
+            }
+            None => self.device_failed(ReturnCode::ENOMEM),
+        }
+    }
+
+    fn write_slot(&self, state: State, slot: usize) {
+        let sector = self.slots.get()[slot].sector;
+        match self.buffer.take() {
+            Some(buffer) => {
+                self.cache.map(|cache| {
+                    buffer[0..SECTOR_SIZE]
+                        .copy_from_slice(&cache[slot * SECTOR_SIZE..(slot + 1) * SECTOR_SIZE]);
+                });
+                self.state.set(state);
+                let result = self.device.write_block(buffer, sector);
+                if result != ReturnCode::SUCCESS {
+                    self.device_failed(result);
+                }
+            }
+            None => self.device_failed(ReturnCode::ENOMEM),
+        }
+    }
+
+    fn mark_clean(&self, slot: usize) {
+        let mut slots = self.slots.get();
+        slots[slot].dirty = false;
+        self.slots.set(slots);
+    }
+
+    /// A read or write of the device failed. What is cached may not match
+    /// the card any more, so it is dropped, and the current operation fails.
+    fn device_failed(&self, result: ReturnCode) {
+        if self.buffer.is_none() {
+            self.device
+                .take_buffer()
+                .map(|buffer| self.buffer.replace(buffer));
+        }
+        if self.buffer.is_none() {
+            // Nothing can be read without the buffer.
+            self.unmount();
+        }
+        self.slots.set([EMPTY_SLOT; CACHE_SECTORS]);
+        self.state.set(State::Idle);
+        self.result.set((result, self.done.get()));
+        self.complete();
+    }
+
+    /// The current operation finished. Write what it changed, then tell its
+    /// user.
+    fn end(&self, result: ReturnCode, length: usize) {
+        self.result.set((result, length));
+        self.flush();
+    }
+
+    fn flush(&self) {
+        let dirty = self.slots.get().iter().position(|slot| slot.valid && slot.dirty);
+        match dirty {
+            Some(slot) => self.write_slot(State::Flushing(slot), slot),
+            None => self.complete(),
+        }
+    }
+
+    /// Tell the user of the current operation that it finished, and start
+    /// the next one.
+    fn complete(&self) {
+        // The client may start another operation.
+        self.state.set(State::Idle);
+        let (result, length) = self.result.get();
+        self.finish(result, length);
+        self.start_next();
+    }
+
+    fn finish(&self, result: ReturnCode, length: usize) {
+        self.current.take().map(|(user, operation)| match user {
+            User::Kernel => {
+                let file = match operation {
+                    Operation::Open(_) if result != ReturnCode::SUCCESS => File::default(),
+                    _ => self.file.get(),
+                };
+                self.kernel_client.map(|client| match operation {
+                    Operation::Mount => client.mount_done(result),
+                    Operation::Open(_) => client.open_done(result, file),
+                    Operation::List(_) => client.list_done(result, self.entry.get()),
+                    Operation::Read(_) => {
+                        self.kernel_buffer
+                            .take()
+                            .map(|buffer| client.read_done(result, file, buffer, length));
+                    }
+                    Operation::Write(_) => {
+                        self.kernel_buffer
+                            .take()
+                            .map(|buffer| client.write_done(result, file, buffer, length));
+                    }
+                });
+            }
+            User::App(appid) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    let (result, value) = self.app_result(app, operation, result, length);
+                    app.callback.map(|mut cb| {
+                        cb.schedule(operation.command_num(), usize::from(result), value)
+                    });
+                });
+            }
+        });
+    }
+
+    /// Give `app` what its operation produced, and return the result and
+    /// value for its callback.
+    fn app_result(
+        &self,
+        app: &mut App,
+        operation: Operation,
+        result: ReturnCode,
+        length: usize,
+    ) -> (ReturnCode, usize) {
+        match operation {
+            Operation::Open(_) if result == ReturnCode::SUCCESS => {
+                match app.files.iter().position(|file| file.is_none()) {
+                    Some(handle) => {
+                        app.files[handle] = Some(self.file.get());
+                        (result, handle)
+                    }
+                    None => (ReturnCode::ENOMEM, 0),
+                }
+            }
+            Operation::Read(_) | Operation::Write(_) => {
+                if let Some(file) = app.files.get_mut(self.handle.get()) {
+                    if file.is_some() {
+                        *file = Some(self.file.get());
+                    }
+                }
+                (result, length)
+            }
+            Operation::List(_) if result == ReturnCode::SUCCESS => {
+                let entry = self.entry.get();
+                app.data.as_mut().map(|data| {
+                    let mut bytes = [0; 5 + 12];
+                    write_u32(&mut bytes, 0, entry.size);
+                    bytes[4] = entry.directory as u8;
+                    bytes[5..5 + entry.name_len].copy_from_slice(entry.name());
+                    let len = cmp::min(5 + entry.name_len, data.len());
+                    data.as_mut()[0..len].copy_from_slice(&bytes[0..len]);
+                });
+                (result, entry.name_len)
+            }
+            _ => (result, 0),
+        }
+    }
+
+    /// Copy `data` read from the current file to the buffer of its user, at
+    /// `offset`.
+    fn copy_out(&self, offset: usize, data: &[u8]) {
+        self.current.map(|&mut (user, _)| match user {
+            User::Kernel => {
+                self.kernel_buffer.map(|buffer| {
+                    buffer[offset..offset + data.len()].copy_from_slice(data);
+                });
+            }
+            User::App(appid) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.data.as_mut().map(|buffer| {
+                        let end = cmp::min(offset + data.len(), buffer.len());
+                        if offset < end {
+                            buffer.as_mut()[offset..end].copy_from_slice(&data[0..end - offset]);
+                        }
+                    });
+                });
+            }
+        });
+    }
+
+    /// Copy the bytes at `offset` in the buffer of the user of the current
+    /// write into `data`.
+    fn copy_in(&self, offset: usize, data: &mut [u8]) {
+        self.current.map(|&mut (user, _)| match user {
+            User::Kernel => {
+                self.kernel_buffer.map(|buffer| {
+                    let len = data.len();
+                    data.copy_from_slice(&buffer[offset..offset + len]);
+                });
+            }
+            User::App(appid) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.data.as_ref().map(|buffer| {
+                        let end = cmp::min(offset + data.len(), buffer.len());
+                        if offset < end {
+                            data[0..end - offset].copy_from_slice(&buffer.as_ref()[offset..end]);
+                        }
+                    });
+                });
+            }
+        });
+    }
+}
+
+impl<D: BlockDevice> SDCardClient for FatFs<'a, D> {
+    fn card_detection_changed(&self, installed: bool) {
+        if !installed {
+            self.unmount();
+        } else if self.volume.is_none() {
+            let _ = self.mount();
+        }
+    }
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if self.state.get() == State::Initializing {
+            self.state.set(State::Idle);
+            self.run();
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        if let State::Loading { slot, sector } = self.state.get() {
+            self.cache.map(|cache| {
+                cache[slot * SECTOR_SIZE..(slot + 1) * SECTOR_SIZE]
+                    .copy_from_slice(&data[0..SECTOR_SIZE]);
+            });
+            self.set_slot(
+                slot,
+                Slot {
+                    sector: sector,
+                    valid: true,
+                    dirty: false,
+                    used: 0,
+                },
+            );
+            self.touch(slot, false);
+            self.buffer.replace(data);
+            self.state.set(State::Idle);
+            self.run();
+        } else {
+            self.buffer.replace(data);
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        match self.state.get() {
+            State::WritingBack(slot) => {
+                self.mark_clean(slot);
+                self.state.set(State::Idle);
+                self.run();
+            }
+            State::Flushing(slot) => {
+                self.mark_clean(slot);
+                self.state.set(State::Idle);
+                self.flush();
+            }
+            _ => {}
+        }
+    }
+
+    fn error(&self, _error: u32) {
+        match self.state.get() {
+            State::Initializing => {
+                self.state.set(State::Idle);
+                self.end(ReturnCode::FAIL, 0);
+            }
+            State::Loading { .. } | State::WritingBack(_) | State::Flushing(_) => {
+                self.device_failed(ReturnCode::FAIL);
+            }
+            State::Idle => {}
+        }
+    }
+}
+
+impl<D: BlockDevice> Driver for FatFs<'a, D> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The path.
+    /// - `1`: The data.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.path = slice,
+                    1 => app.data = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            }).unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: An operation finished.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Open the file at the path of length `data1` in mode `data2`.
+    /// - `2`: Read up to `data2` bytes from file `data1`.
+    /// - `3`: Write `data2` bytes to file `data1`.
+    /// - `4`: Close file `data1`.
+    /// - `5`: List entry `data2` of the directory at the path of length
+    ///   `data1`.
+    /// - `6`: Move the position of file `data1` to `data2`.
+    /// - `7`: Return the size of file `data1`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 | 2 | 3 | 5 => {}
+            4 | 6 | 7 => {
+                return self
+                    .apps
+                    .enter(appid, |app, _| {
+                        // Files are updated when their operation finishes.
+                        let busy = app.pending.is_some() || self.is_current_app(appid);
+                        match (command_num, app.files.get_mut(data1)) {
+                            (_, Some(&mut None)) | (_, None) => ReturnCode::EINVAL,
+                            (7, Some(&mut Some(file))) => ReturnCode::SuccessWithValue {
+                                value: file.size(),
+                            },
+                            _ if busy => ReturnCode::EBUSY,
+                            (4, Some(file)) => {
+                                *file = None;
+                                ReturnCode::SUCCESS
+                            }
+                            (_, Some(&mut Some(ref mut file))) => file.seek(data2),
+                        }
+                    }).unwrap_or_else(|err| err.into())
+            }
+            _ => return ReturnCode::ENOSUPPORT,
+        }
+        if !self.is_available() {
+            return ReturnCode::EOFF;
+        }
+
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() || self.is_current_app(appid) {
+                    return ReturnCode::EBUSY;
+                }
+                let valid = match command_num {
+                    1 => Mode::from_usize(data2).is_some(),
+                    2 | 3 => app.files.get(data1).map_or(false, |file| file.is_some()),
+                    _ => true,
+                };
+                if !valid {
+                    return ReturnCode::EINVAL;
+                }
+                if command_num == 1 && app.files.iter().all(|file| file.is_some()) {
+                    return ReturnCode::ENOMEM;
+                }
+                app.pending = Some((command_num, data1, data2));
+                ReturnCode::SUCCESS
+            }).unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS {
+            self.start_next();
+        }
+        result
+    }
+}
+
+/// Where the file system starts: at the first sector if that is a boot
+/// sector, or else at the first FAT16 or FAT32 partition of the master boot
+/// record.
+fn partition_start(mbr: &[u8]) -> Option<u32> {
+    if parse_boot_sector(mbr, 0).is_ok() {
+        return Some(0);
+    }
+    if read_u16(mbr, 510) != BOOT_SIGNATURE {
+        return None;
+    }
+    (0..4)
+        .map(|partition| PARTITION_TABLE + partition * 16)
+        .find(|&entry| match mbr[entry + 4] {
+            0x04 | 0x06 | 0x0e | 0x0b | 0x0c => true,
+            _ => false,
+        }).map(|entry| read_u32(mbr, entry + 8))
+}
+
+/// Read the BIOS parameter block of the boot sector of a file system that
+/// starts at sector `start`.
+fn parse_boot_sector(boot: &[u8], start: u32) -> Result<Volume, ReturnCode> {
+    let sectors_per_cluster = boot[13] as u32;
+    let reserved = read_u16(boot, 14) as u32;
+    let num_fats = boot[16] as u32;
+    let root_entries = read_u16(boot, 17) as u32;
+    let total_sectors = match read_u16(boot, 19) {
+        0 => read_u32(boot, 32),
+        sectors => sectors as u32,
+    };
+    let fat_sectors = match read_u16(boot, 22) {
+        0 => read_u32(boot, 36),
+        sectors => sectors as u32,
+    };
+    // Two copies of the FAT at most, so that both fit in the cache.
+    if read_u16(boot, 510) != BOOT_SIGNATURE
+        || (boot[0] != 0xeb && boot[0] != 0xe9)
+        || read_u16(boot, 11) as usize != SECTOR_SIZE
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved == 0
+        || num_fats == 0
+        || num_fats > 2
+        || fat_sectors == 0
+    {
+        return Err(ReturnCode::ENOSUPPORT);
+    }
+
+    let root_sectors = (root_entries * DIR_ENTRY_LEN as u32 + SECTOR_SIZE as u32 - 1)
+        / SECTOR_SIZE as u32;
+    let data_start = reserved + num_fats * fat_sectors + root_sectors;
+    if total_sectors <= data_start {
+        return Err(ReturnCode::FAIL);
+    }
+    let clusters = (total_sectors - data_start) / sectors_per_cluster;
+    // The number of clusters decides the type.
+    let (fat_type, entry_len) = if clusters < 4085 {
+        return Err(ReturnCode::ENOSUPPORT);
+    } else if clusters < 65525 {
+        (FatType::Fat16, 2)
+    } else {
+        (FatType::Fat32, 4)
+    };
+    if fat_sectors * (SECTOR_SIZE as u32 / entry_len) < clusters + 2 {
+        return Err(ReturnCode::FAIL);
+    }
+
+    let (root_cluster, info_sector) = match fat_type {
+        FatType::Fat16 => (0, 0),
+        FatType::Fat32 => {
+            let root_cluster = read_u32(boot, 44);
+            if root_cluster < 2 || root_cluster > clusters + 1 {
+                return Err(ReturnCode::FAIL);
+            }
+            let info_sector = match read_u16(boot, 48) {
+                0 | 0xffff => 0,
+                sector => start + sector as u32,
+            };
+            (root_cluster, info_sector)
+        }
+    };
+    Ok(Volume {
+        fat_type: fat_type,
+        fat_start: start + reserved,
+        fat_sectors: fat_sectors,
+        num_fats: num_fats,
+        root_start: start + reserved + num_fats * fat_sectors,
+        root_entries: root_entries,
+        root_cluster: root_cluster,
+        info_sector: info_sector,
+        data_start: start + data_start,
+        sectors_per_cluster: sectors_per_cluster,
+        max_cluster: clusters + 1,
+    })
+}
+
+/// The component of `path` after byte `from`, as a range of bytes.
+fn next_component(path: &[u8], from: usize) -> Option<(usize, usize)> {
+    let start = from + path[from..].iter().position(|&byte| byte != b'/')?;
+    let end = path[start..]
+        .iter()
+        .position(|&byte| byte == b'/')
+        .map_or(path.len(), |len| start + len);
+    Some((start, end))
+}
+
+/// The name of a directory entry for `name`, or `None` if it is not a valid
+/// short name.
+fn short_name(name: &[u8]) -> Option<[u8; 11]> {
+    let (base, extension) = match name.iter().position(|&byte| byte == b'.') {
+        Some(dot) => (&name[0..dot], &name[dot + 1..]),
+        None => (name, &name[0..0]),
+    };
+    if base.is_empty() || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    let mut short = [b' '; 11];
+    for (i, &byte) in base.iter().enumerate() {
+        short[i] = short_name_byte(byte)?;
+    }
+    for (i, &byte) in extension.iter().enumerate() {
+        short[8 + i] = short_name_byte(byte)?;
+    }
+    Some(short)
+}
+
+fn short_name_byte(byte: u8) -> Option<u8> {
+    match byte {
+        b'a'..=b'z' => Some(byte - b'a' + b'A'),
+        b'A'..=b'Z' | b'0'..=b'9' => Some(byte),
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_'
+        | b'`' | b'{' | b'}' | b'~' => Some(byte),
+        _ => None,
+    }
+}
+
+/// What listing shows for a directory entry: files and directories, but not
+/// free entries, `.`, `..`, volume labels or parts of long names.
+fn list_entry(entry: &[u8]) -> Option<DirEntry> {
+    if entry[0] == ENTRY_END
+        || entry[0] == ENTRY_FREE
+        || entry[0] == b'.'
+        || entry[11] & ATTR_VOLUME_ID != 0
+    {
+        return None;
+    }
+    let mut listed = DirEntry::default();
+    for &byte in entry[0..8].iter().take_while(|&&byte| byte != b' ') {
+        listed.name[listed.name_len] = byte;
+        listed.name_len += 1;
+    }
+    if listed.name[0] == ENTRY_KANJI {
+        listed.name[0] = ENTRY_FREE;
+    }
+    if entry[8] != b' ' {
+        listed.name[listed.name_len] = b'.';
+        listed.name_len += 1;
+        for &byte in entry[8..11].iter().take_while(|&&byte| byte != b' ') {
+            listed.name[listed.name_len] = byte;
+            listed.name_len += 1;
+        }
+    }
+    listed.size = read_u32(entry, 28);
+    listed.directory = entry[11] & ATTR_DIRECTORY != 0;
+    Some(listed)
+}
+
+/// Fill in the directory entry of a new, empty file.
+fn new_entry(entry: &mut [u8], name: &[u8; 11]) {
+    for byte in entry.iter_mut() {
+        *byte = 0;
+    }
+    entry[0..11].copy_from_slice(name);
+    entry[11] = ATTR_ARCHIVE;
+    // Created, accessed and modified.
+    write_u16(entry, 16, EPOCH_DATE);
+    write_u16(entry, 18, EPOCH_DATE);
+    write_u16(entry, 24, EPOCH_DATE);
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset] = value as u8;
+    bytes[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    write_u16(bytes, offset, value as u16);
+    write_u16(bytes, offset + 2, (value >> 16) as u16);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use self::std::vec::Vec;
+    use super::{
+        parse_boot_sector, partition_start, DirEntry, FatClient, FatFs, FatType, File, Mode,
+        Volume, CACHE_SECTORS, SECTOR_SIZE,
+    };
+    use core::cell::Cell;
+    use kernel::capabilities::MemoryAllocationCapability;
+    use kernel::common::cells::TakeCell;
+    use kernel::procs::ProcessType;
+    use kernel::{Kernel, ReturnCode};
+    use test::fat::{DiskImage, TestFat};
+
+    /// The images, which leave out the zeros at their end, and the number of
+    /// sectors they were made with.
+    const FAT16_IMAGE: (&[u8], usize) = (include_bytes!("test/fat16.img"), 20000);
+    const FAT32_IMAGE: (&[u8], usize) = (include_bytes!("test/fat32.img"), 66600);
+    const IMAGES: [(&[u8], usize); 2] = [FAT16_IMAGE, FAT32_IMAGE];
+
+    const README: &[u8] = b"Disk image for the tests of the FAT capsule.\n";
+    const HELLO_LINE: &[u8] = b"hello from the host\n";
+
+    struct Capability;
+    // Under `cfg_attr`, so that builds without tests, which forbid unsafe
+    // code, never see the `allow`.
+    #[cfg_attr(test, allow(unsafe_code))]
+    unsafe impl MemoryAllocationCapability for Capability {}
+
+    fn leak<T>(value: T) -> &'static mut T {
+        Box::leak(Box::new(value))
+    }
+
+    fn leak_slice(data: &[u8]) -> &'static mut [u8] {
+        Box::leak(data.to_vec().into_boxed_slice())
+    }
+
+    /// What a request that hands back its buffer when it fails returned.
+    fn started_result<T>(started: Result<(), (ReturnCode, T)>) -> ReturnCode {
+        started.err().map_or(ReturnCode::SUCCESS, |(result, _)| result)
+    }
+
+    /// `image` padded to `sectors` sectors.
+    fn full_image((image, sectors): (&[u8], usize)) -> &'static mut [u8] {
+        let mut data = image.to_vec();
+        data.resize(sectors * SECTOR_SIZE, 0);
+        Box::leak(data.into_boxed_slice())
+    }
+
+    /// The volume of the file system in `image`.
+    fn volume(image: &[u8]) -> Volume {
+        let start = partition_start(&image[0..SECTOR_SIZE]).unwrap();
+        let boot = &image[start as usize * SECTOR_SIZE..][..SECTOR_SIZE];
+        parse_boot_sector(boot, start).ok().unwrap()
+    }
+
+    /// The clusters in use in FAT number `copy` of `image`.
+    fn used_clusters(image: &[u8], copy: u32) -> Vec<u32> {
+        let volume = volume(image);
+        (2..volume.max_cluster + 1)
+            .filter(|&cluster| {
+                let (sector, offset) = volume.fat_location(copy, cluster);
+                let entry = &image[sector as usize * SECTOR_SIZE + offset..];
+                match volume.fat_type {
+                    FatType::Fat16 => super::read_u16(entry, 0) != 0,
+                    FatType::Fat32 => super::read_u32(entry, 0) & 0x0fff_ffff != 0,
+                }
+            }).collect()
+    }
+
+    /// Records the results of the kernel's operations.
+    struct Client {
+        result: Cell<Option<(ReturnCode, usize)>>,
+        file: Cell<File>,
+        entry: Cell<DirEntry>,
+        buffer: TakeCell<'static, [u8]>,
+    }
+
+    impl FatClient for Client {
+        fn mount_done(&self, result: ReturnCode) {
+            self.result.set(Some((result, 0)));
+        }
+
+        fn open_done(&self, result: ReturnCode, file: File) {
+            self.file.set(file);
+            self.result.set(Some((result, 0)));
+        }
+
+        fn list_done(&self, result: ReturnCode, entry: DirEntry) {
+            self.entry.set(entry);
+            self.result.set(Some((result, 0)));
+        }
+
+        fn read_done(&self, result: ReturnCode, file: File, buffer: &'static mut [u8], len: usize) {
+            self.file.set(file);
+            self.buffer.replace(buffer);
+            self.result.set(Some((result, len)));
+        }
+
+        fn write_done(
+            &self,
+            result: ReturnCode,
+            file: File,
+            buffer: &'static mut [u8],
+            len: usize,
+        ) {
+            self.file.set(file);
+            self.buffer.replace(buffer);
+            self.result.set(Some((result, len)));
+        }
+    }
+
+    /// A file system mounted on a disk image.
+    struct Fs {
+        disk: &'static DiskImage,
+        fs: &'static FatFs<'static, DiskImage>,
+        client: &'static Client,
+    }
+
+    impl Fs {
+        fn mount(image: &'static mut [u8]) -> Fs {
+            Fs::mount_disk(leak(DiskImage::new(image)))
+        }
+
+        /// Mount the disk again with a new file system, as after a reset.
+        fn mount_disk(disk: &'static DiskImage) -> Fs {
+            let processes: &'static mut [Option<&'static ProcessType>] = leak([None; 1]);
+            let kernel = leak(Kernel::new(processes));
+            let fs = leak(FatFs::new(
+                disk,
+                kernel.create_grant(&Capability),
+                leak([0; CACHE_SECTORS * SECTOR_SIZE]),
+                leak([0; SECTOR_SIZE]),
+            ));
+            disk.set_client(fs);
+            let client = leak(Client {
+                result: Cell::new(None),
+                file: Cell::new(File::default()),
+                entry: Cell::new(DirEntry::default()),
+                buffer: TakeCell::empty(),
+            });
+            fs.set_client(client);
+            let fs = Fs {
+                disk: disk,
+                fs: fs,
+                client: client,
+            };
+            assert_eq!(fs.finish(fs.fs.mount()).0, ReturnCode::SUCCESS);
+            fs
+        }
+
+        /// Run the disk until the operation that started with `result` is
+        /// done.
+        fn finish(&self, result: ReturnCode) -> (ReturnCode, usize) {
+            assert_eq!(result, ReturnCode::SUCCESS);
+            while self.disk.run_pending() {}
+            self.client.result.take().expect("operation did not finish")
+        }
+
+        fn open(&self, path: &[u8], mode: Mode) -> Result<File, ReturnCode> {
+            match self.finish(self.fs.open(path, mode)).0 {
+                ReturnCode::SUCCESS => Ok(self.client.file.get()),
+                result => Err(result),
+            }
+        }
+
+        /// The name, size and kind of every entry of the directory at `path`.
+        fn list(&self, path: &[u8]) -> Result<Vec<(Vec<u8>, usize, bool)>, ReturnCode> {
+            let mut entries = Vec::new();
+            loop {
+                match self.finish(self.fs.list(path, entries.len())).0 {
+                    ReturnCode::SUCCESS => {
+                        let entry = self.client.entry.get();
+                        entries.push((entry.name().to_vec(), entry.size(), entry.is_directory()));
+                    }
+                    // The end of the directory.
+                    ReturnCode::FAIL if !entries.is_empty() => return Ok(entries),
+                    result => return Err(result),
+                }
+            }
+        }
+
+        /// Read `file` to its end, 100 bytes at a time.
+        fn read_to_end(&self, mut file: File) -> Vec<u8> {
+            let mut data = Vec::new();
+            self.client.buffer.replace(leak_slice(&[0; 100]));
+            loop {
+                let buffer = self.client.buffer.take().unwrap();
+                let started = self.fs.read(file, buffer, 100);
+                let (result, length) = self.finish(started_result(started));
+                assert_eq!(result, ReturnCode::SUCCESS);
+                file = self.client.file.get();
+                if length == 0 {
+                    return data;
+                }
+                self.client
+                    .buffer
+                    .map(|buffer| data.extend_from_slice(&buffer[0..length]));
+            }
+        }
+
+        /// Write `data` to `file`, and return the file after the write.
+        fn write(&self, file: File, data: &[u8]) -> File {
+            let started = self.fs.write(file, leak_slice(data), data.len());
+            let (result, length) = self.finish(started_result(started));
+            assert_eq!((result, length), (ReturnCode::SUCCESS, data.len()));
+            self.client.file.get()
+        }
+    }
+
+    /// The contents of `LOGS/HELLO.TXT`.
+    fn hello_txt() -> Vec<u8> {
+        HELLO_LINE
+            .iter()
+            .cycle()
+            .take(HELLO_LINE.len() * 120)
+            .cloned()
+            .collect()
+    }
+
+    /// Bytes of a file that are not the same at any offset.
+    fn csv(lines: usize) -> Vec<u8> {
+        (0..lines)
+            .flat_map(|line| std::format!("{:05},{:05}\n", line, line * 7).into_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn finds_the_file_system() {
+        let fat16 = full_image(FAT16_IMAGE);
+        let fat32 = full_image(FAT32_IMAGE);
+        // The FAT16 image has a partition table, the FAT32 one does not.
+        assert_eq!(partition_start(&fat16[0..SECTOR_SIZE]), Some(63));
+        assert_eq!(partition_start(&fat32[0..SECTOR_SIZE]), Some(0));
+
+        let volume16 = volume(fat16);
+        assert_eq!(volume16.fat_type, FatType::Fat16);
+        assert_eq!(volume16.num_fats, 2);
+        assert_eq!(volume16.sectors_per_cluster, 4);
+        assert_eq!(volume16.fat_start, 63 + 4);
+        assert_eq!(volume16.root_entries, 512);
+        let volume32 = volume(fat32);
+        assert_eq!(volume32.fat_type, FatType::Fat32);
+        assert_eq!(volume32.root_cluster, 2);
+        assert_eq!(volume32.info_sector, 1);
+        assert_eq!(volume32.fat_start, 32);
+
+        // A partition that is not FAT, and a sector that is neither a boot
+        // sector nor a master boot record.
+        let mut mbr = fat16[0..SECTOR_SIZE].to_vec();
+        mbr[446 + 4] = 0x83;
+        assert_eq!(partition_start(&mbr), None);
+        assert_eq!(partition_start(&[0; SECTOR_SIZE]), None);
+        // The boot sector is only accepted where it is.
+        let boot = &fat16[63 * SECTOR_SIZE..64 * SECTOR_SIZE];
+        assert_eq!(partition_start(boot), Some(0));
+    }
+
+    #[test]
+    fn lists_directories() {
+        for &image in IMAGES.iter() {
+            let fs = Fs::mount(full_image(image));
+            // Without the volume label, long names, `.`, `..` and deleted
+            // entries.
+            assert_eq!(
+                fs.list(b"").unwrap(),
+                [
+                    (b"README.TXT".to_vec(), README.len(), false),
+                    (b"LOGS".to_vec(), 0, true),
+                ]
+            );
+            assert_eq!(fs.list(b"/"), fs.list(b""));
+            assert_eq!(
+                fs.list(b"/logs").unwrap(),
+                [(b"HELLO.TXT".to_vec(), hello_txt().len(), false)]
+            );
+            assert_eq!(fs.list(b"readme.txt"), Err(ReturnCode::EINVAL));
+            assert_eq!(fs.list(b"missing"), Err(ReturnCode::FAIL));
+        }
+    }
+
+    #[test]
+    fn reads_files() {
+        for &image in IMAGES.iter() {
+            let fs = Fs::mount(full_image(image));
+            let readme = fs.open(b"README.TXT", Mode::Read).unwrap();
+            assert_eq!(readme.size(), README.len());
+            assert_eq!(fs.read_to_end(readme), README);
+            // Over several clusters.
+            let hello = fs.open(b"logs/hello.txt", Mode::Read).unwrap();
+            assert_eq!(fs.read_to_end(hello), hello_txt());
+            assert_eq!(fs.open(b"logs/old.txt", Mode::Read).err(), Some(ReturnCode::FAIL));
+            assert_eq!(fs.open(b"logs", Mode::Read).err(), Some(ReturnCode::EINVAL));
+        }
+    }
+
+    #[test]
+    fn creates_writes_appends_and_reads() {
+        for &image in IMAGES.iter() {
+            let fs = Fs::mount(full_image(image));
+            let data = csv(500);
+            let mut file = fs.open(b"logs/data.csv", Mode::Write).unwrap();
+            assert_eq!(file.size(), 0);
+            for chunk in data[0..4000].chunks(300) {
+                file = fs.write(file, chunk);
+            }
+            assert_eq!(file.size(), 4000);
+
+            let mut file = fs.open(b"LOGS/DATA.CSV", Mode::Append).unwrap();
+            assert_eq!(file.size(), 4000);
+            file = fs.write(file, &data[4000..]);
+            assert_eq!(file.size(), data.len());
+            let file = fs.open(b"logs/data.csv", Mode::Read).unwrap();
+            assert_eq!(fs.read_to_end(file), data);
+
+            // Everything is on the disk.
+            let fs = Fs::mount_disk(fs.disk);
+            assert_eq!(
+                fs.list(b"logs").unwrap(),
+                [
+                    (b"HELLO.TXT".to_vec(), hello_txt().len(), false),
+                    (b"DATA.CSV".to_vec(), data.len(), false),
+                ]
+            );
+            let file = fs.open(b"logs/data.csv", Mode::Read).unwrap();
+            assert_eq!(fs.read_to_end(file), data);
+            let image = fs.disk.take_image().unwrap();
+            assert_eq!(used_clusters(image, 0), used_clusters(image, 1));
+        }
+    }
+
+    #[test]
+    fn truncates_files() {
+        for &image in IMAGES.iter() {
+            let used_before = used_clusters(full_image(image), 0).len();
+            let cluster_bytes = volume(full_image(image)).cluster_bytes() as usize;
+            let hello_clusters = (hello_txt().len() + cluster_bytes - 1) / cluster_bytes;
+
+            let fs = Fs::mount(full_image(image));
+            let file = fs.open(b"logs/hello.txt", Mode::Write).unwrap();
+            assert_eq!(file.size(), 0);
+            assert_eq!(fs.read_to_end(file), b"");
+            let file = fs.write(file, b"short");
+            assert_eq!(file.size(), 5);
+
+            let fs = Fs::mount_disk(fs.disk);
+            assert_eq!(fs.list(b"logs").unwrap(), [(b"HELLO.TXT".to_vec(), 5, false)]);
+            let file = fs.open(b"logs/hello.txt", Mode::Read).unwrap();
+            assert_eq!(fs.read_to_end(file), b"short");
+            // The README was not touched.
+            let readme = fs.open(b"readme.txt", Mode::Read).unwrap();
+            assert_eq!(fs.read_to_end(readme), README);
+
+            // The clusters of the old contents are free again, in both FATs.
+            let image = fs.disk.take_image().unwrap();
+            let used = used_clusters(image, 0);
+            assert_eq!(used.len(), used_before - hello_clusters + 1);
+            assert_eq!(used, used_clusters(image, 1));
+        }
+    }
+
+    #[test]
+    fn board_test_passes() {
+        for &image in IMAGES.iter() {
+            let fs = Fs::mount(full_image(image));
+            let test = leak(TestFat::new(fs.fs, fs.disk, leak_slice(&[0; 300])));
+            fs.fs.set_client(test);
+            test.run();
+            while fs.disk.run_pending() {}
+            assert_eq!(test.result(), Some(Ok(())));
+        }
+    }
+}
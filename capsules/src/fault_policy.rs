@@ -0,0 +1,232 @@
+//! Fault policy that restarts faulting apps with an exponential backoff.
+//!
+//! Restarting an app as soon as it faults lets a single crash-looping app
+//! monopolize the kernel. With this policy the kernel instead waits before
+//! restarting an app, and the wait doubles after every restart up to a
+//! maximum. Once an app has been restarted `max_restarts` times the policy
+//! gives up on it and responds with its fallback, normally
+//! `FaultResponse::Stop`, so that the rest of the board keeps running.
+//!
+//! While an app waits to be restarted it stays in the `Fault` state. The
+//! policy needs an alarm and a `RestartBackoffState` for every process slot.
+//!
+//! An app's TBF header can only make this stricter: a header that asks for
+//! the app to be stopped, or to be restarted fewer than `max_restarts` times,
+//! is followed, but a header cannot raise the limit, and a header that asks
+//! for a panic only stops the app unless the fallback is a panic.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! static mut RESTART_STATE: [capsules::fault_policy::RestartBackoffState; NUM_PROCS] = [
+//!     capsules::fault_policy::RestartBackoffState::new(),
+//!     capsules::fault_policy::RestartBackoffState::new(),
+//! ];
+//!
+//! let fault_policy_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let fault_policy = static_init!(
+//!     capsules::fault_policy::RestartBackoffPolicy<
+//!         'static,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!         ProcessMgmtCap,
+//!     >,
+//!     capsules::fault_policy::RestartBackoffPolicy::new(
+//!         board_kernel,
+//!         fault_policy_alarm,
+//!         &RESTART_STATE,
+//!         5,     // Restart an app at most five times,
+//!         100,   // waiting 100 ms before the first restart,
+//!         10000, // and at most 10 s before later ones.
+//!         kernel::procs::FaultResponse::Stop,
+//!         ProcessMgmtCap
+//!     )
+//! );
+//! fault_policy_alarm.set_client(fault_policy);
+//!
+//! kernel::procs::load_processes(
+//!     board_kernel,
+//!     &cortexm4::syscall::SysCall::new(),
+//!     &_sapps as *const u8,
+//!     &mut APP_MEMORY,
+//!     fault_policy,
+//!     &process_management_capability,
+//! );
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::hil::time::{self, Alarm, Frequency, Ticks};
+use kernel::procs::{FaultResponse, ProcessFaultPolicy, ProcessType};
+use kernel::Kernel;
+
+/// Per-process state for the restart backoff policy.
+pub struct RestartBackoffState {
+    /// Alarm time at which the process should be restarted, if it is waiting
+    /// to be restarted. This is the alarm's `Ticks` as a `u64`, so that the
+    /// state does not depend on the alarm type.
+    restart_at: Cell<Option<u64>>,
+}
+
+impl RestartBackoffState {
+    pub const fn new() -> RestartBackoffState {
+        RestartBackoffState {
+            restart_at: Cell::new(None),
+        }
+    }
+}
+
+pub struct RestartBackoffPolicy<'a, A: Alarm, C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    alarm: &'a A,
+    /// One entry per process slot, indexed the same as the processes array.
+    processes: &'a [RestartBackoffState],
+    max_restarts: usize,
+    initial_delay_ms: u32,
+    max_delay_ms: u32,
+    /// Response once an app has been restarted `max_restarts` times.
+    fallback: FaultResponse,
+    /// Alarm time when the alarm was last set.
+    prev: Cell<A::Ticks>,
+    capability: C,
+}
+
+impl<A: Alarm, C: ProcessManagementCapability> RestartBackoffPolicy<'a, A, C> {
+    pub fn new(
+        kernel: &'static Kernel,
+        alarm: &'a A,
+        processes: &'a [RestartBackoffState],
+        max_restarts: usize,
+        initial_delay_ms: u32,
+        max_delay_ms: u32,
+        fallback: FaultResponse,
+        capability: C,
+    ) -> RestartBackoffPolicy<'a, A, C> {
+        RestartBackoffPolicy {
+            kernel: kernel,
+            alarm: alarm,
+            processes: processes,
+            max_restarts: max_restarts,
+            initial_delay_ms: initial_delay_ms,
+            max_delay_ms: max_delay_ms,
+            fallback: fallback,
+            prev: Cell::new(A::Ticks::from_u64(0)),
+            capability: capability,
+        }
+    }
+
+    /// How long to wait before restarting an app that has already been
+    /// restarted `restart_count` times.
+    fn delay_ms(&self, restart_count: usize) -> u32 {
+        let mut delay_ms = self.initial_delay_ms;
+        for _ in 0..restart_count {
+            if delay_ms >= self.max_delay_ms {
+                break;
+            }
+            delay_ms = delay_ms.saturating_mul(2);
+        }
+        cmp::min(delay_ms, self.max_delay_ms)
+    }
+
+    /// Set the alarm for the earliest pending restart, or disable it if no app
+    /// is waiting to be restarted.
+    fn reset_alarm(&self, now: A::Ticks) {
+        self.prev.set(now);
+        let mut next_restart: Option<A::Ticks> = None;
+        for state in self.processes.iter() {
+            if let Some(restart_at) = state.restart_at.get() {
+                let restart_at = A::Ticks::from_u64(restart_at);
+                let sooner = next_restart.map_or(true, |next| restart_at.within_range(now, next));
+                if sooner {
+                    next_restart = Some(restart_at);
+                }
+            }
+        }
+
+        match next_restart {
+            Some(restart_at) => self.alarm.set_alarm(restart_at),
+            None => self.alarm.disable(),
+        }
+    }
+}
+
+/// Whether `alarm` has passed by `now`, given that it had not yet at `prev`.
+fn has_expired<T: Ticks>(alarm: T, now: T, prev: T) -> bool {
+    !now.within_range(prev, alarm)
+}
+
+impl<A: Alarm, C: ProcessManagementCapability> ProcessFaultPolicy
+    for RestartBackoffPolicy<'a, A, C>
+{
+    fn action(&self, process: &ProcessType) -> FaultResponse {
+        if process.debug_restart_count() >= self.max_restarts {
+            return self.fallback;
+        }
+        let delay_ms = self.delay_ms(process.debug_restart_count());
+        match self.processes.get(process.appid().idx()) {
+            Some(state) if delay_ms > 0 => {
+                let now = self.alarm.now();
+                let delay = A::Ticks::from_u64(<A::Frequency>::ms_to_ticks(delay_ms as u64));
+                state
+                    .restart_at
+                    .set(Some(now.wrapping_add(delay).into_u64()));
+                self.reset_alarm(now);
+
+                // Leave the app in the `Fault` state until the alarm fires.
+                FaultResponse::Stop
+            }
+            _ => FaultResponse::Restart,
+        }
+    }
+
+    fn stop_action(&self, process: &ProcessType) -> FaultResponse {
+        // Like `action()`, but without scheduling a restart.
+        if process.debug_restart_count() >= self.max_restarts
+            && self.fallback == FaultResponse::Panic
+        {
+            FaultResponse::Panic
+        } else {
+            FaultResponse::Stop
+        }
+    }
+}
+
+impl<A: Alarm, C: ProcessManagementCapability> time::Client for RestartBackoffPolicy<'a, A, C> {
+    fn fired(&self) {
+        let now = self.alarm.now();
+        let prev = self.prev.get();
+
+        self.kernel
+            .process_each_capability(&self.capability, |appid, _process| {
+                self.processes.get(appid.idx()).map(|state| {
+                    if let Some(restart_at) = state.restart_at.get() {
+                        if has_expired(A::Ticks::from_u64(restart_at), now, prev) {
+                            state.restart_at.set(None);
+                            // Something else may have restarted the app while
+                            // it was waiting, in which case this does nothing.
+                            self.kernel
+                                .restart_faulted_app(appid, &self.capability);
+                        }
+                    }
+                });
+            });
+
+        // Forget about apps that were terminated while they were waiting.
+        for state in self.processes.iter() {
+            if let Some(restart_at) = state.restart_at.get() {
+                if has_expired(A::Ticks::from_u64(restart_at), now, prev) {
+                    state.restart_at.set(None);
+                }
+            }
+        }
+
+        self.reset_alarm(now);
+    }
+}
@@ -0,0 +1,110 @@
+//! Provides userspace with the kernel's monotonic 64-bit clock.
+//!
+//! Unlike the alarm driver, whose 32-bit counter wraps, timestamps from this
+//! driver never wrap and are the same ones the kernel and capsules get from
+//! `kernel::clock::KernelTimer::now()`, so apps can compare them with each
+//! other and with timestamps capsules report.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let kernel_clock_driver = static_init!(
+//!     capsules::kernel_clock::KernelClockDriver,
+//!     capsules::kernel_clock::KernelClockDriver::new(
+//!         kernel_clock,
+//!         board_kernel.create_grant(&memory_allocation_capability)
+//!     )
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Draft
+//!
+//! ### Command
+//!
+//! A 64-bit time does not fit in a command's return value, so it is read in
+//! two halves. Reading the low half saves the high half of the same time for
+//! the calling process, so the two halves always match even if the low half
+//! wraps in between.
+//!
+//! #### `command_num`
+//!
+//! - `0`: Check that the driver exists.
+//!   - Return: `SUCCESS`.
+//! - `1`: Get the clock frequency.
+//!   - Return: Ticks per second.
+//! - `2`: Read the clock.
+//!   - Return: Low 32 bits of the current time, in ticks.
+//! - `3`: Get the high 32 bits of the time read by the latest command `2`.
+//!   - Return: High 32 bits of the time.
+
+use kernel::clock::KernelTimer;
+use kernel::{AppId, Driver, Grant, ReturnCode};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x10004;
+
+/// Per-process state.
+#[derive(Default)]
+pub struct App {
+    /// High 32 bits of the time the process last read.
+    time_high: u32,
+}
+
+pub struct KernelClockDriver {
+    clock: &'static KernelTimer,
+    apps: Grant<App>,
+}
+
+impl KernelClockDriver {
+    pub fn new(clock: &'static KernelTimer, grant: Grant<App>) -> KernelClockDriver {
+        KernelClockDriver {
+            clock: clock,
+            apps: grant,
+        }
+    }
+}
+
+impl Driver for KernelClockDriver {
+    /// Read the clock.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Returns the clock frequency in ticks per second.
+    /// - `2`: Returns the low 32 bits of the current time, and saves the high
+    ///        32 bits for command `3`.
+    /// - `3`: Returns the high 32 bits saved by command `2`.
+    fn command(&self, command_num: usize, _: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+
+            1 => ReturnCode::SuccessWithValue {
+                value: self.clock.frequency() as usize,
+            },
+
+            2 => self
+                .apps
+                .enter(appid, |app, _| {
+                    let now = self.clock.now();
+                    app.time_high = (now >> 32) as u32;
+                    ReturnCode::SuccessWithValue {
+                        value: now as u32 as usize,
+                    }
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            3 => self
+                .apps
+                .enter(appid, |app, _| ReturnCode::SuccessWithValue {
+                    value: app.time_high as usize,
+                })
+                .unwrap_or_else(|err| err.into()),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,1589 @@
+//! Wear-leveled key-value store on flash.
+//!
+//! Values are stored in a log of records spread over a range of flash pages.
+//! Setting or deleting a key appends a record to the page currently being
+//! filled, so every write goes to a different place in flash and pages are
+//! erased in turn. Records are programmed into the erased end of the page
+//! with `hil::flash::EraseFlash::program_page()`, so appending never erases
+//! what is already in the page. When no erased page is left, the oldest page
+//! is garbage collected: the records in it that are still current are copied
+//! to an erased page, and the old page is erased. One page is always kept
+//! erased for this.
+//!
+//! Each record has a CRC. A record that was only partly written when power was
+//! lost fails its CRC and is ignored, together with the rest of its page, so
+//! the store comes back with the values from before the interrupted write.
+//! Pages carry a sequence number, and the index is built by going through the
+//! pages in that order, so the newest record of a key wins, for example when
+//! power was lost in the middle of garbage collection and a key has records
+//! in two pages. A record deleting a key just removes it from the index.
+//!
+//! Keys are at most `MAX_KEY_LEN` bytes. Each app has its own namespace of keys,
+//! derived from its package name so that it stays the same across reboots, and
+//! the kernel has another. An index of all keys is kept in RAM, so the number
+//! of keys the store can hold is the length of the index the board provides.
+//! The index is built by reading every page when the store is initialized. If
+//! flash holds more keys than the index has room for, for example because the
+//! board made the index smaller, the store does not drop any of them: every
+//! operation fails with `ENOMEM` instead.
+//!
+//! The store works on any `hil::flash::EraseFlash`. It needs the flash to
+//! itself, since `virtual_flash` only offers `hil::flash::Flash`.
+//! `capsules::test::kv_store` has an in-memory flash to test it with.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! pub static mut KV_STORE_PAGE: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStore<'static, sam4l::flashcalw::FLASHCALW>,
+//!     capsules::kv_store::KVStore::new(
+//!         &sam4l::flashcalw::FLASH_CONTROLLER,
+//!         board_kernel.create_grant(&memory_allocation_capability),
+//!         0x3c0, // First flash page of the store.
+//!         static_init!([u32; 8], [0; 8]), // One entry per page of the store.
+//!         static_init!(
+//!             [capsules::kv_store::KVIndexEntry; 32],
+//!             [capsules::kv_store::KVIndexEntry::default(); 32]
+//!         ),
+//!         &mut KV_STORE_PAGE
+//!     )
+//! );
+//! hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, kv_store);
+//! kv_store.initialize();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Draft
+//!
+//! ### Allow
+//!
+//! - `0`: The key.
+//! - `1`: The value to set, or where to put the value that is read.
+//!
+//! ### Command
+//!
+//! - `0`: Check that the driver exists.
+//! - `1`: Get the value of the first `data1` bytes of the key buffer.
+//! - `2`: Set the value of the first `data1` bytes of the key buffer to the
+//!   first `data2` bytes of the value buffer.
+//! - `3`: Delete the first `data1` bytes of the key buffer.
+//! - `4`: Copy key number `data1` of the app into the key buffer. Returns the
+//!   length of the key, or `EINVAL` if the app has fewer keys.
+//!
+//! Commands `1` to `3` return `SUCCESS` if the operation was started, and
+//! finish with a callback.
+//!
+//! ### Subscribe
+//!
+//! - `0`: Operation done. The callback is passed the command number of the
+//!   operation, its `ReturnCode` (`FAIL` if the key does not exist), and for
+//!   get the length of the value, which may be longer than the value buffer.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x50003;
+
+/// Longest key, in bytes.
+pub const MAX_KEY_LEN: usize = 16;
+
+/// Namespace of the keys the kernel sets.
+const KERNEL_NAMESPACE: u32 = 0;
+
+/// Start of every page in use: the magic, then the sequence number.
+const PAGE_MAGIC: [u8; 4] = *b"TKV1";
+const PAGE_HEADER_LEN: usize = 8;
+
+/// Record header: magic, flags, key length, a reserved byte, namespace, value
+/// length and CRC. The key and value follow, padded to a multiple of 4 bytes.
+const RECORD_MAGIC: u8 = 0xa5;
+const RECORD_HEADER_LEN: usize = 12;
+/// Record flag marking a deleted key.
+const RECORD_DELETED: u8 = 1 << 0;
+
+/// The bytes of erased flash.
+const ERASED: u8 = 0xff;
+
+/// Operations users can request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Operation {
+    Get,
+    Set,
+    Delete,
+}
+
+impl Operation {
+    /// The command number of the operation, which is passed to the callback.
+    fn command_num(&self) -> usize {
+        match *self {
+            Operation::Get => 1,
+            Operation::Set => 2,
+            Operation::Delete => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+enum User {
+    Kernel,
+    App(AppId),
+}
+
+/// What the store is waiting for the flash to finish.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum State {
+    Uninitialized,
+    /// Reading page `n` of the store to find the sequence numbers of the
+    /// pages.
+    Scanning(usize),
+    /// Reading page `n` of the store to add its records to the index. Pages
+    /// are indexed from the oldest to the newest.
+    Indexing(usize),
+    /// Flash holds more keys than the index has room for, so every operation
+    /// fails.
+    IndexFull,
+    Idle,
+    /// Reading the page with the value of the current get.
+    Reading,
+    /// Erasing a page to append to.
+    ErasingForAppend(usize),
+    /// Programming the appended record into the active page.
+    Writing,
+    /// Erasing a page with no current records to free it.
+    Collecting(usize),
+    /// Erasing the page the current records of `victim` are copied to.
+    ErasingForCopy { victim: usize, dest: usize },
+    /// Reading `victim` to copy its current records.
+    ReadingForCopy { victim: usize, dest: usize },
+    /// Programming the copied records into `dest`.
+    WritingCopy { victim: usize, dest: usize },
+}
+
+/// What a record in a page turned out to be.
+enum Record {
+    /// The rest of the page is erased.
+    End,
+    /// The bytes are not a valid record, for example because power was lost
+    /// while it was written. Nothing after it in the page can be trusted.
+    Invalid,
+    Valid {
+        deleted: bool,
+        namespace: u32,
+        key_len: usize,
+        value_len: usize,
+        len: usize,
+    },
+}
+
+/// Where the current record of one key is. Boards allocate an array of these
+/// for the index.
+#[derive(Copy, Clone)]
+pub struct KVIndexEntry {
+    used: bool,
+    namespace: u32,
+    key: [u8; MAX_KEY_LEN],
+    key_len: usize,
+    /// Page number in the store.
+    page: usize,
+    /// Offset of the record in the page.
+    offset: usize,
+    value_len: usize,
+}
+
+impl Default for KVIndexEntry {
+    fn default() -> KVIndexEntry {
+        KVIndexEntry {
+            used: false,
+            namespace: 0,
+            key: [0; MAX_KEY_LEN],
+            key_len: 0,
+            page: 0,
+            offset: 0,
+            value_len: 0,
+        }
+    }
+}
+
+impl KVIndexEntry {
+    fn is_key(&self, namespace: u32, key: &[u8]) -> bool {
+        self.used && self.namespace == namespace && &self.key[0..self.key_len] == key
+    }
+}
+
+/// Implemented by kernel users of the store.
+pub trait KVStoreClient {
+    /// A get finished. `length` is the length of the value, which may be
+    /// longer than `value`. `result` is `FAIL` if the key does not exist.
+    fn get_complete(
+        &self,
+        result: ReturnCode,
+        key: &'static mut [u8],
+        value: &'static mut [u8],
+        length: usize,
+    );
+
+    /// A set finished.
+    fn set_complete(&self, result: ReturnCode, key: &'static mut [u8], value: &'static mut [u8]);
+
+    /// A delete finished. `result` is `FAIL` if the key did not exist.
+    fn delete_complete(&self, result: ReturnCode, key: &'static mut [u8]);
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    /// Operation waiting for the store, with the key and value lengths.
+    pending: Option<(Operation, usize, usize)>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            key: None,
+            value: None,
+            pending: None,
+        }
+    }
+}
+
+pub struct KVStore<'a, F: hil::flash::EraseFlash + 'static> {
+    flash: &'a F,
+    apps: Grant<App>,
+    /// Flash page number of the first page of the store.
+    start_page: usize,
+    /// Sequence number of each page of the store, 0 if the page is not in
+    /// use.
+    page_seqs: TakeCell<'static, [u32]>,
+    index: TakeCell<'static, [KVIndexEntry]>,
+    buffer: TakeCell<'static, F::Page>,
+    state: Cell<State>,
+
+    /// Page records are appended to, and where the next record goes in it.
+    active_page: OptionalCell<usize>,
+    active_offset: Cell<usize>,
+    /// Sequence number of the next page that is started.
+    next_seq: Cell<u32>,
+    /// Number of garbage collections for the current operation, so that it
+    /// gives up when no page can be freed.
+    collections: Cell<usize>,
+
+    /// The operation in progress, with its namespace, key and value length.
+    current: OptionalCell<(User, Operation)>,
+    namespace: Cell<u32>,
+    key: Cell<[u8; MAX_KEY_LEN]>,
+    key_len: Cell<usize>,
+    value_len: Cell<usize>,
+
+    kernel_client: OptionalCell<&'static KVStoreClient>,
+    kernel_pending: OptionalCell<Operation>,
+    kernel_key: TakeCell<'static, [u8]>,
+    kernel_value: TakeCell<'static, [u8]>,
+}
+
+impl<F: hil::flash::EraseFlash> KVStore<'a, F> {
+    /// Create a store on the `page_seqs.len()` flash pages starting at
+    /// `start_page`, which must be at least 2. It can hold as many keys as
+    /// `index` has entries.
+    pub fn new(
+        flash: &'a F,
+        grant: Grant<App>,
+        start_page: usize,
+        page_seqs: &'static mut [u32],
+        index: &'static mut [KVIndexEntry],
+        buffer: &'static mut F::Page,
+    ) -> KVStore<'a, F> {
+        KVStore {
+            flash: flash,
+            apps: grant,
+            start_page: start_page,
+            page_seqs: TakeCell::new(page_seqs),
+            index: TakeCell::new(index),
+            buffer: TakeCell::new(buffer),
+            state: Cell::new(State::Uninitialized),
+            active_page: OptionalCell::empty(),
+            active_offset: Cell::new(0),
+            next_seq: Cell::new(1),
+            collections: Cell::new(0),
+            current: OptionalCell::empty(),
+            namespace: Cell::new(KERNEL_NAMESPACE),
+            key: Cell::new([0; MAX_KEY_LEN]),
+            key_len: Cell::new(0),
+            value_len: Cell::new(0),
+            kernel_client: OptionalCell::empty(),
+            kernel_pending: OptionalCell::empty(),
+            kernel_key: TakeCell::empty(),
+            kernel_value: TakeCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static KVStoreClient) {
+        self.kernel_client.set(client);
+    }
+
+    /// Read the store from flash. Operations requested before this finishes
+    /// wait for it.
+    pub fn initialize(&self) {
+        if self.state.get() == State::Uninitialized {
+            self.rescan();
+        }
+    }
+
+    /// Get the value of `key` into `value`.
+    pub fn get(
+        &self,
+        key: &'static mut [u8],
+        value: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.kernel_pending.is_some() || self.current_is_kernel() {
+            return Err((ReturnCode::EBUSY, key, value));
+        }
+        self.kernel_key.replace(key);
+        self.kernel_value.replace(value);
+        self.kernel_pending.set(Operation::Get);
+        self.start_next();
+        Ok(())
+    }
+
+    /// Set the value of `key` to `value`.
+    pub fn set(
+        &self,
+        key: &'static mut [u8],
+        value: &'static mut [u8],
+    ) -> Result<(), (ReturnCode, &'static mut [u8], &'static mut [u8])> {
+        if self.kernel_pending.is_some() || self.current_is_kernel() {
+            return Err((ReturnCode::EBUSY, key, value));
+        }
+        self.kernel_key.replace(key);
+        self.kernel_value.replace(value);
+        self.kernel_pending.set(Operation::Set);
+        self.start_next();
+        Ok(())
+    }
+
+    /// Delete `key`.
+    pub fn delete(&self, key: &'static mut [u8]) -> Result<(), (ReturnCode, &'static mut [u8])> {
+        if self.kernel_pending.is_some() || self.current_is_kernel() {
+            return Err((ReturnCode::EBUSY, key));
+        }
+        self.kernel_key.replace(key);
+        self.kernel_pending.set(Operation::Delete);
+        self.start_next();
+        Ok(())
+    }
+
+    /// Copy key number `index` of the kernel into `key`. Returns the length of
+    /// the key, or `None` if the kernel has fewer keys, the store is not
+    /// initialized yet, or its index is full.
+    pub fn key_at(&self, index: usize, key: &mut [u8]) -> Option<usize> {
+        self.copy_key_at(KERNEL_NAMESPACE, index, key)
+    }
+
+    fn current_is_kernel(&self) -> bool {
+        self.current.map_or(false, |&mut (user, _)| match user {
+            User::Kernel => true,
+            User::App(_) => false,
+        })
+    }
+
+    /// The namespace of the keys of `appid`.
+    fn app_namespace(appid: AppId) -> u32 {
+        // FNV-1a hash of the package name, which is never the kernel's.
+        let hash = appid
+            .get_package_name()
+            .iter()
+            .fold(0x811c9dc5, |hash: u32, &byte| {
+                (hash ^ byte as u32).wrapping_mul(0x01000193)
+            });
+        if hash == KERNEL_NAMESPACE {
+            1
+        } else {
+            hash
+        }
+    }
+
+    fn copy_key_at(&self, namespace: u32, index: usize, key: &mut [u8]) -> Option<usize> {
+        match self.state.get() {
+            State::Uninitialized | State::Scanning(_) | State::Indexing(_) | State::IndexFull => {
+                return None
+            }
+            _ => {}
+        }
+        self.index.map_or(None, |entries| {
+            entries
+                .iter()
+                .filter(|entry| entry.used && entry.namespace == namespace)
+                .nth(index)
+                .map(|entry| {
+                    let len = cmp::min(entry.key_len, key.len());
+                    key[0..len].copy_from_slice(&entry.key[0..len]);
+                    entry.key_len
+                })
+        })
+    }
+
+    /// Start the next waiting operation, if the store is idle. The kernel goes
+    /// first, then apps. If the index is full, the operations fail right
+    /// away.
+    fn start_next(&self) {
+        let state = self.state.get();
+        if (state != State::Idle && state != State::IndexFull) || self.current.is_some() {
+            return;
+        }
+        if let Some(operation) = self.kernel_pending.take() {
+            let key_len = self.kernel_key.map_or(0, |key| key.len());
+            let value_len = self.kernel_value.map_or(0, |value| value.len());
+            let key_valid = self.kernel_key.map_or(false, |key| self.set_key(key));
+            self.start(
+                User::Kernel,
+                operation,
+                KERNEL_NAMESPACE,
+                key_valid,
+                key_len,
+                value_len,
+            );
+            return;
+        }
+        for cntr in self.apps.iter() {
+            let started = cntr.enter(|app, _| {
+                app.pending.take().map_or(false, |(operation, key_len, value_len)| {
+                    let key_valid = app.key.as_ref().map_or(false, |key| {
+                        key_len <= key.len() && self.set_key(&key.as_ref()[0..key_len])
+                    });
+                    let value_len = app
+                        .value
+                        .as_ref()
+                        .map_or(0, |value| cmp::min(value_len, value.len()));
+                    let appid = app.appid();
+                    self.start(
+                        User::App(appid),
+                        operation,
+                        Self::app_namespace(appid),
+                        key_valid,
+                        key_len,
+                        value_len,
+                    );
+                    true
+                })
+            });
+            if started {
+                break;
+            }
+        }
+    }
+
+    /// Make `key` the key of the current operation. Returns `false` if it is
+    /// empty or too long.
+    fn set_key(&self, key: &[u8]) -> bool {
+        if key.is_empty() || key.len() > MAX_KEY_LEN {
+            return false;
+        }
+        let mut stored = [0; MAX_KEY_LEN];
+        stored[0..key.len()].copy_from_slice(key);
+        self.key.set(stored);
+        self.key_len.set(key.len());
+        true
+    }
+
+    fn start(
+        &self,
+        user: User,
+        operation: Operation,
+        namespace: u32,
+        key_valid: bool,
+        key_len: usize,
+        value_len: usize,
+    ) {
+        self.current.set((user, operation));
+        if self.state.get() == State::IndexFull {
+            self.complete(ReturnCode::ENOMEM, 0);
+            return;
+        }
+        if !key_valid {
+            self.complete(ReturnCode::EINVAL, 0);
+            return;
+        }
+        self.namespace.set(namespace);
+        self.key_len.set(key_len);
+        self.value_len.set(if operation == Operation::Set {
+            value_len
+        } else {
+            0
+        });
+        self.collections.set(0);
+
+        match self.find_entry() {
+            None if operation != Operation::Set => self.complete(ReturnCode::FAIL, 0),
+            Some(entry) if operation == Operation::Get => {
+                self.value_len.set(entry.value_len);
+                self.read_page(State::Reading, entry.page);
+            }
+            _ => self.append(),
+        }
+    }
+
+    /// The index entry of the current key.
+    fn find_entry(&self) -> Option<KVIndexEntry> {
+        let key = self.key.get();
+        let key = &key[0..self.key_len.get()];
+        let namespace = self.namespace.get();
+        self.index.map_or(None, |entries| {
+            entries
+                .iter()
+                .find(|entry| entry.is_key(namespace, key))
+                .map(|entry| *entry)
+        })
+    }
+
+    /// Length of the record for the current operation.
+    fn record_len(&self) -> usize {
+        align4(RECORD_HEADER_LEN + self.key_len.get() + self.value_len.get())
+    }
+
+    fn page_size(&self) -> usize {
+        self.buffer.map_or(0, |page| page.as_mut().len())
+    }
+
+    /// Append the record for the current set or delete to the log, making
+    /// room for it first if needed.
+    fn append(&self) {
+        let record_len = self.record_len();
+        if PAGE_HEADER_LEN + record_len > self.page_size() {
+            self.complete(ReturnCode::ESIZE, 0);
+            return;
+        }
+        if self.current.map_or(false, |&mut (_, operation)| operation == Operation::Set)
+            && self.find_entry().is_none()
+            && !self
+                .index
+                .map_or(false, |entries| entries.iter().any(|entry| !entry.used))
+        {
+            // No room in the index for another key.
+            self.complete(ReturnCode::ENOMEM, 0);
+            return;
+        }
+
+        let fits = self.active_page.is_some()
+            && self.active_offset.get() + record_len <= self.page_size();
+        if fits {
+            let active_page = self.active_page.unwrap_or(0);
+            self.program_record(active_page, None);
+        } else if self.num_free_pages() >= 2 {
+            // Keep one erased page for garbage collection.
+            let page = self.free_page().unwrap_or(0);
+            self.erase_page(State::ErasingForAppend(page), page);
+        } else {
+            self.collect();
+        }
+    }
+
+    /// Free a page by garbage collecting the oldest one.
+    fn collect(&self) {
+        let num_pages = self.page_seqs.map_or(0, |seqs| seqs.len());
+        self.collections.set(self.collections.get() + 1);
+        if self.collections.get() > num_pages {
+            self.complete(ReturnCode::ENOMEM, 0);
+            return;
+        }
+
+        let victim = self.page_seqs.map_or(None, |seqs| {
+            seqs.iter()
+                .enumerate()
+                .filter(|&(_, &seq)| seq != 0)
+                .min_by_key(|&(_, &seq)| seq)
+                .map(|(page, _)| page)
+        });
+        let victim = match victim {
+            Some(victim) => victim,
+            None => {
+                self.complete(ReturnCode::ENOMEM, 0);
+                return;
+            }
+        };
+        let live_records = self.index.map_or(0, |entries| {
+            entries
+                .iter()
+                .filter(|entry| entry.used && entry.page == victim)
+                .count()
+        });
+        if live_records == 0 {
+            self.erase_page(State::Collecting(victim), victim);
+        } else {
+            match self.free_page() {
+                Some(dest) => self.erase_page(State::ErasingForCopy { victim, dest }, dest),
+                None => self.complete(ReturnCode::ENOMEM, 0),
+            }
+        }
+    }
+
+    /// The first page not in use.
+    fn free_page(&self) -> Option<usize> {
+        self.page_seqs
+            .map_or(None, |seqs| seqs.iter().position(|&seq| seq == 0))
+    }
+
+    fn num_free_pages(&self) -> usize {
+        self.page_seqs
+            .map_or(0, |seqs| seqs.iter().filter(|&&seq| seq == 0).count())
+    }
+
+    fn read_page(&self, state: State, page: usize) {
+        self.state.set(state);
+        match self.buffer.take() {
+            Some(buffer) => {
+                let result = self.flash.read_page(self.start_page + page, buffer);
+                if result != ReturnCode::SUCCESS {
+                    self.flash_failed();
+                }
+            }
+            None => self.flash_failed(),
+        }
+    }
+
+    fn program_page(&self, state: State, page: usize) {
+        self.state.set(state);
+        match self.buffer.take() {
+            Some(buffer) => {
+                let result = self.flash.program_page(self.start_page + page, buffer);
+                if result != ReturnCode::SUCCESS {
+                    self.flash_failed();
+                }
+            }
+            None => self.flash_failed(),
+        }
+    }
+
+    fn erase_page(&self, state: State, page: usize) {
+        self.state.set(state);
+        if self.flash.erase_page(self.start_page + page) != ReturnCode::SUCCESS {
+            self.flash_failed();
+        }
+    }
+
+    /// A flash operation failed. Fail the current operation and read the
+    /// store again, since the index may not match flash anymore.
+    fn flash_failed(&self) {
+        if self.current.is_some() {
+            self.finish(ReturnCode::FAIL, 0);
+        }
+        if self.buffer.is_some() {
+            self.rescan();
+        } else {
+            // The flash kept the buffer, so the store cannot be used anymore.
+            self.state.set(State::Uninitialized);
+        }
+    }
+
+    /// Throw away the index and build it again from flash.
+    fn rescan(&self) {
+        self.index.map(|entries| {
+            for entry in entries.iter_mut() {
+                *entry = KVIndexEntry::default();
+            }
+        });
+        self.page_seqs.map(|seqs| {
+            for seq in seqs.iter_mut() {
+                *seq = 0;
+            }
+        });
+        self.active_page.clear();
+        self.next_seq.set(1);
+        self.read_page(State::Scanning(0), 0);
+    }
+
+    /// Note the sequence number of the page in `buffer`, if it is in use.
+    fn scan_page(&self, page: usize, buffer: &[u8]) {
+        if buffer[0..4] != PAGE_MAGIC {
+            return;
+        }
+        let seq = read_u32(buffer, 4);
+        if seq == 0 {
+            return;
+        }
+        self.page_seqs.map(|seqs| seqs[page] = seq);
+        if seq >= self.next_seq.get() {
+            self.next_seq.set(seq + 1);
+        }
+    }
+
+    /// Read the page in use that comes after `page` in sequence order, or the
+    /// oldest one if `page` is `None`, to add its records to the index. When
+    /// every page is indexed, the store is ready.
+    fn index_next(&self, page: Option<usize>) {
+        let next = self.page_seqs.map_or(None, |seqs| {
+            let after = page.map_or((0, 0), |page| (seqs[page], page));
+            seqs.iter()
+                .enumerate()
+                .map(|(n, &seq)| (seq, n))
+                .filter(|&(seq, n)| seq != 0 && (seq, n) > after)
+                .min()
+                .map(|(_, n)| n)
+        });
+        match next {
+            Some(next) => self.read_page(State::Indexing(next), next),
+            None => {
+                self.state.set(State::Idle);
+                self.start_next();
+            }
+        }
+    }
+
+    /// Apply the records of the page in `buffer` to the index. Pages are
+    /// indexed from the oldest, so each record replaces any earlier one of
+    /// its key, and deleting a key just removes it. Returns `false` if a key
+    /// did not fit in the index.
+    fn index_page(&self, page: usize, buffer: &[u8]) -> bool {
+        let mut offset = PAGE_HEADER_LEN;
+        let end = loop {
+            match parse_record(buffer, offset) {
+                Record::End => break offset,
+                // Nothing can be appended after an invalid record.
+                Record::Invalid => break buffer.len(),
+                Record::Valid {
+                    deleted,
+                    namespace,
+                    key_len,
+                    value_len,
+                    len,
+                } => {
+                    let key_start = offset + RECORD_HEADER_LEN;
+                    let key = &buffer[key_start..key_start + key_len];
+                    let indexed = self.index.map_or(false, |entries| {
+                        if deleted {
+                            remove_key(entries, namespace, key);
+                            true
+                        } else {
+                            index_record(entries, namespace, key, page, offset, value_len)
+                        }
+                    });
+                    if !indexed {
+                        return false;
+                    }
+                    offset += len;
+                }
+            }
+        };
+
+        // The newest page, which is indexed last, is the one to append to.
+        self.active_page.set(page);
+        self.active_offset.set(end);
+        true
+    }
+
+    /// Program the record of the current operation at the active offset of
+    /// `page`, after the page header if the page was just erased and started
+    /// with sequence number `seq`. The rest of the buffer is left erased, so
+    /// nothing else in the page changes.
+    fn program_record(&self, page: usize, seq: Option<u32>) {
+        self.buffer.map(|buffer| {
+            let buffer = buffer.as_mut();
+            for byte in buffer.iter_mut() {
+                *byte = ERASED;
+            }
+            seq.map(|seq| {
+                buffer[0..4].copy_from_slice(&PAGE_MAGIC);
+                write_u32(buffer, 4, seq);
+            });
+            self.write_record(buffer);
+        });
+        self.program_page(State::Writing, page);
+    }
+
+    /// Write the record of the current operation at the active offset of the
+    /// page in `buffer`.
+    fn write_record(&self, buffer: &mut [u8]) {
+        let offset = self.active_offset.get();
+        let key_len = self.key_len.get();
+        let value_len = self.value_len.get();
+        let deleted = self.current.map_or(false, |&mut (_, operation)| {
+            operation == Operation::Delete
+        });
+        let record = &mut buffer[offset..offset + self.record_len()];
+        for byte in record.iter_mut() {
+            *byte = 0;
+        }
+
+        record[0] = RECORD_MAGIC;
+        record[1] = if deleted { RECORD_DELETED } else { 0 };
+        record[2] = key_len as u8;
+        write_u32(record, 4, self.namespace.get());
+        record[8] = value_len as u8;
+        record[9] = (value_len >> 8) as u8;
+        record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key_len]
+            .copy_from_slice(&self.key.get()[0..key_len]);
+        {
+            let value_start = RECORD_HEADER_LEN + key_len;
+            let value = &mut record[value_start..value_start + value_len];
+            self.copy_value_in(value);
+        }
+        let crc = record_crc(record, key_len, value_len);
+        record[10] = crc as u8;
+        record[11] = (crc >> 8) as u8;
+    }
+
+    /// Copy the value of the current set into `value`.
+    fn copy_value_in(&self, value: &mut [u8]) {
+        self.current.map(|&mut (user, _)| match user {
+            User::Kernel => {
+                self.kernel_value.map(|kernel_value| {
+                    let len = value.len();
+                    value.copy_from_slice(&kernel_value[0..len]);
+                });
+            }
+            User::App(appid) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.value.as_ref().map(|app_value| {
+                        // The app may have allowed a shorter buffer since.
+                        let len = cmp::min(value.len(), app_value.len());
+                        value[0..len].copy_from_slice(&app_value.as_ref()[0..len]);
+                    });
+                });
+            }
+        });
+    }
+
+    /// Copy the value of the current get out of `value`.
+    fn copy_value_out(&self, value: &[u8]) {
+        self.current.map(|&mut (user, _)| match user {
+            User::Kernel => {
+                self.kernel_value.map(|kernel_value| {
+                    let len = cmp::min(value.len(), kernel_value.len());
+                    kernel_value[0..len].copy_from_slice(&value[0..len]);
+                });
+            }
+            User::App(appid) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.value.as_mut().map(|app_value| {
+                        let len = cmp::min(value.len(), app_value.len());
+                        app_value.as_mut()[0..len].copy_from_slice(&value[0..len]);
+                    });
+                });
+            }
+        });
+    }
+
+    /// The appended record is in flash, so make the index point to it.
+    fn append_done(&self) {
+        let page = self.active_page.unwrap_or(0);
+        let offset = self.active_offset.get();
+        let key = self.key.get();
+        let key = &key[0..self.key_len.get()];
+        let namespace = self.namespace.get();
+        let value_len = self.value_len.get();
+        let deleted = self.current.map_or(false, |&mut (_, operation)| {
+            operation == Operation::Delete
+        });
+        self.index.map(|entries| {
+            if deleted {
+                remove_key(entries, namespace, key);
+            } else {
+                // `append()` checked that there is room for the key.
+                index_record(entries, namespace, key, page, offset, value_len);
+            }
+        });
+        self.active_offset.set(offset + self.record_len());
+        self.complete(ReturnCode::SUCCESS, 0);
+    }
+
+    /// Copy the current records of `victim` in `buffer` to the start of it,
+    /// making it the page `dest`, and update the index to match. Returns the
+    /// end of the copied records.
+    fn compact(&self, victim: usize, dest: usize, buffer: &mut [u8]) -> usize {
+        let seq = self.next_seq.get();
+        self.next_seq.set(seq + 1);
+        let mut end = PAGE_HEADER_LEN;
+        self.index.map(|entries| {
+            // Copy in order of offset, so records only move towards the start
+            // of the page and never overwrite one not copied yet.
+            let mut last_offset = 0;
+            loop {
+                let next = entries
+                    .iter_mut()
+                    .filter(|entry| {
+                        entry.used && entry.page == victim && entry.offset > last_offset
+                    })
+                    .min_by_key(|entry| entry.offset);
+                match next {
+                    Some(entry) => {
+                        last_offset = entry.offset;
+                        let len = align4(RECORD_HEADER_LEN + entry.key_len + entry.value_len);
+                        for i in 0..len {
+                            buffer[end + i] = buffer[entry.offset + i];
+                        }
+                        entry.page = dest;
+                        entry.offset = end;
+                        end += len;
+                    }
+                    None => break,
+                }
+            }
+        });
+        for byte in buffer[end..].iter_mut() {
+            *byte = ERASED;
+        }
+        buffer[0..4].copy_from_slice(&PAGE_MAGIC);
+        write_u32(buffer, 4, seq);
+        self.page_seqs.map(|seqs| seqs[dest] = seq);
+        end
+    }
+
+    /// Finish the current operation and start the next one.
+    fn complete(&self, result: ReturnCode, length: usize) {
+        // The client may start another operation.
+        if self.state.get() != State::IndexFull {
+            self.state.set(State::Idle);
+        }
+        self.finish(result, length);
+        self.start_next();
+    }
+
+    /// Tell the user of the current operation that it finished.
+    fn finish(&self, result: ReturnCode, length: usize) {
+        self.current.take().map(|(user, operation)| match user {
+            User::Kernel => {
+                let key = self.kernel_key.take();
+                let value = self.kernel_value.take();
+                self.kernel_client.map(move |client| match (key, value) {
+                    (Some(key), Some(value)) => match operation {
+                        Operation::Get => client.get_complete(result, key, value, length),
+                        Operation::Set => client.set_complete(result, key, value),
+                        Operation::Delete => client.delete_complete(result, key),
+                    },
+                    (Some(key), None) => client.delete_complete(result, key),
+                    _ => {}
+                });
+            }
+            User::App(appid) => {
+                let _ = self.apps.enter(appid, |app, _| {
+                    app.callback.map(|mut cb| {
+                        cb.schedule(operation.command_num(), usize::from(result), length)
+                    });
+                });
+            }
+        });
+    }
+}
+
+impl<F: hil::flash::EraseFlash> hil::flash::Client<F> for KVStore<'a, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        let state = self.state.get();
+        if error != hil::flash::Error::CommandComplete {
+            self.buffer.replace(pagebuffer);
+            self.flash_failed();
+            return;
+        }
+        match state {
+            State::Scanning(page) => {
+                self.scan_page(page, pagebuffer.as_mut());
+                self.buffer.replace(pagebuffer);
+                let num_pages = self.page_seqs.map_or(0, |seqs| seqs.len());
+                if page + 1 < num_pages {
+                    self.read_page(State::Scanning(page + 1), page + 1);
+                } else {
+                    self.index_next(None);
+                }
+            }
+            State::Indexing(page) => {
+                let indexed = self.index_page(page, pagebuffer.as_mut());
+                self.buffer.replace(pagebuffer);
+                if indexed {
+                    self.index_next(Some(page));
+                } else {
+                    // Going on without the key would lose it for good once
+                    // its page is garbage collected, so refuse to work
+                    // instead.
+                    self.state.set(State::IndexFull);
+                    self.start_next();
+                }
+            }
+            State::Reading => {
+                let length = self.value_len.get();
+                self.find_entry().map(|entry| {
+                    let start = entry.offset + RECORD_HEADER_LEN + entry.key_len;
+                    self.copy_value_out(&pagebuffer.as_mut()[start..start + length]);
+                });
+                self.buffer.replace(pagebuffer);
+                self.complete(ReturnCode::SUCCESS, length);
+            }
+            State::ReadingForCopy { victim, dest } => {
+                let end = self.compact(victim, dest, pagebuffer.as_mut());
+                self.buffer.replace(pagebuffer);
+                self.active_page.set(dest);
+                self.active_offset.set(end);
+                self.program_page(State::WritingCopy { victim, dest }, dest);
+            }
+            _ => {
+                self.buffer.replace(pagebuffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.buffer.replace(pagebuffer);
+        if error != hil::flash::Error::CommandComplete {
+            self.flash_failed();
+            return;
+        }
+        match self.state.get() {
+            State::Writing => self.append_done(),
+            State::WritingCopy { victim, .. } => {
+                // The copies are safe in flash, so the originals can go.
+                self.erase_page(State::Collecting(victim), victim);
+            }
+            _ => {}
+        }
+    }
+
+    fn erase_complete(&self, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.flash_failed();
+            return;
+        }
+        match self.state.get() {
+            State::ErasingForAppend(page) => {
+                let seq = self.next_seq.get();
+                self.next_seq.set(seq + 1);
+                self.page_seqs.map(|seqs| seqs[page] = seq);
+                self.active_page.set(page);
+                self.active_offset.set(PAGE_HEADER_LEN);
+                self.program_record(page, Some(seq));
+            }
+            State::Collecting(page) => {
+                self.page_seqs.map(|seqs| seqs[page] = 0);
+                if self.active_page.map_or(false, |&mut active| active == page) {
+                    self.active_page.clear();
+                }
+                self.append();
+            }
+            State::ErasingForCopy { victim, dest } => {
+                self.read_page(State::ReadingForCopy { victim, dest }, victim);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<F: hil::flash::EraseFlash> Driver for KVStore<'a, F> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The key.
+    /// - `1`: The value.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = slice,
+                    1 => app.value = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            }).unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: An operation finished.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Get the value of the key of length `data1`.
+    /// - `2`: Set the key of length `data1` to the value of length `data2`.
+    /// - `3`: Delete the key of length `data1`.
+    /// - `4`: Copy key number `data1` of the app into the key buffer.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, appid: AppId) -> ReturnCode {
+        let operation = match command_num {
+            0 => return ReturnCode::SUCCESS,
+            1 => Operation::Get,
+            2 => Operation::Set,
+            3 => Operation::Delete,
+            4 => {
+                return self
+                    .apps
+                    .enter(appid, |app, _| {
+                        let namespace = Self::app_namespace(appid);
+                        app.key
+                            .as_mut()
+                            .and_then(|key| self.copy_key_at(namespace, data1, key.as_mut()))
+                            .map_or(ReturnCode::EINVAL, |len| ReturnCode::SuccessWithValue {
+                                value: len,
+                            })
+                    }).unwrap_or_else(|err| err.into())
+            }
+            _ => return ReturnCode::ENOSUPPORT,
+        };
+
+        let result = self
+            .apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let busy = self.current.map_or(false, |&mut (user, _)| match user {
+                    User::App(current) => current == appid,
+                    User::Kernel => false,
+                });
+                if busy {
+                    return ReturnCode::EBUSY;
+                }
+                app.pending = Some((operation, data1, data2));
+                ReturnCode::SUCCESS
+            }).unwrap_or_else(|err| err.into());
+        if result == ReturnCode::SUCCESS {
+            self.start_next();
+        }
+        result
+    }
+}
+
+/// Round `len` up to a multiple of 4.
+fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32
+        | (bytes[offset + 1] as u32) << 8
+        | (bytes[offset + 2] as u32) << 16
+        | (bytes[offset + 3] as u32) << 24
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset] = value as u8;
+    bytes[offset + 1] = (value >> 8) as u8;
+    bytes[offset + 2] = (value >> 16) as u8;
+    bytes[offset + 3] = (value >> 24) as u8;
+}
+
+/// CRC-16-CCITT of the record header before the CRC, the key and the value.
+fn record_crc(record: &[u8], key_len: usize, value_len: usize) -> u16 {
+    let data = record[0..10]
+        .iter()
+        .chain(record[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key_len + value_len].iter());
+    data.fold(0xffff, |crc: u16, &byte| {
+        let mut crc = crc ^ (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                crc << 1 ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+/// Parse the record at `offset` in `page`.
+fn parse_record(page: &[u8], offset: usize) -> Record {
+    if offset + RECORD_HEADER_LEN > page.len() {
+        return if page[offset..].iter().all(|&byte| byte == ERASED) {
+            Record::End
+        } else {
+            Record::Invalid
+        };
+    }
+    let header = &page[offset..offset + RECORD_HEADER_LEN];
+    if header.iter().all(|&byte| byte == ERASED) {
+        return Record::End;
+    }
+    let key_len = header[2] as usize;
+    let value_len = header[8] as usize | (header[9] as usize) << 8;
+    let len = align4(RECORD_HEADER_LEN + key_len + value_len);
+    if header[0] != RECORD_MAGIC
+        || key_len == 0
+        || key_len > MAX_KEY_LEN
+        || offset + len > page.len()
+    {
+        return Record::Invalid;
+    }
+    let record = &page[offset..offset + len];
+    let crc = header[10] as u16 | (header[11] as u16) << 8;
+    if record_crc(record, key_len, value_len) != crc {
+        return Record::Invalid;
+    }
+    Record::Valid {
+        deleted: header[1] & RECORD_DELETED != 0,
+        namespace: read_u32(header, 4),
+        key_len: key_len,
+        value_len: value_len,
+        len: len,
+    }
+}
+
+/// Make the index entry of a key point to its record at `offset` in `page`.
+/// Returns `false` if the key has no entry and the index is full.
+fn index_record(
+    entries: &mut [KVIndexEntry],
+    namespace: u32,
+    key: &[u8],
+    page: usize,
+    offset: usize,
+    value_len: usize,
+) -> bool {
+    let index = entries
+        .iter()
+        .position(|entry| entry.is_key(namespace, key))
+        .or_else(|| entries.iter().position(|entry| !entry.used));
+    index.map_or(false, |index| {
+        let mut stored = [0; MAX_KEY_LEN];
+        stored[0..key.len()].copy_from_slice(key);
+        entries[index] = KVIndexEntry {
+            used: true,
+            namespace: namespace,
+            key: stored,
+            key_len: key.len(),
+            page: page,
+            offset: offset,
+            value_len: value_len,
+        };
+        true
+    })
+}
+
+/// Remove the index entry of a key, if it has one.
+fn remove_key(entries: &mut [KVIndexEntry], namespace: u32, key: &[u8]) {
+    entries
+        .iter_mut()
+        .find(|entry| entry.is_key(namespace, key))
+        .map(|entry| *entry = KVIndexEntry::default());
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use self::std::vec::Vec;
+    use super::{KVIndexEntry, KVStore, KVStoreClient};
+    use core::cell::Cell;
+    use core::iter;
+    use kernel::capabilities::MemoryAllocationCapability;
+    use kernel::common::cells::TakeCell;
+    use kernel::hil::flash::HasClient;
+    use kernel::procs::ProcessType;
+    use kernel::{Kernel, ReturnCode};
+    use test::kv_store::{MockFlash, MockPage, MOCK_PAGE_SIZE};
+
+    const NUM_PAGES: usize = 4;
+
+    struct Capability;
+    // Under `cfg_attr`, so that builds without tests, which forbid unsafe
+    // code, never see the `allow`.
+    #[cfg_attr(test, allow(unsafe_code))]
+    unsafe impl MemoryAllocationCapability for Capability {}
+
+    fn leak<T>(value: T) -> &'static mut T {
+        Box::leak(Box::new(value))
+    }
+
+    fn filled(byte: u8, len: usize) -> Vec<u8> {
+        iter::repeat(byte).take(len).collect()
+    }
+
+    /// Records the result of the last operation.
+    struct Client {
+        result: Cell<Option<(ReturnCode, usize)>>,
+        value: TakeCell<'static, [u8]>,
+    }
+
+    impl KVStoreClient for Client {
+        fn get_complete(
+            &self,
+            result: ReturnCode,
+            _key: &'static mut [u8],
+            value: &'static mut [u8],
+            length: usize,
+        ) {
+            self.value.replace(value);
+            self.result.set(Some((result, length)));
+        }
+
+        fn set_complete(
+            &self,
+            result: ReturnCode,
+            _key: &'static mut [u8],
+            _value: &'static mut [u8],
+        ) {
+            self.result.set(Some((result, 0)));
+        }
+
+        fn delete_complete(&self, result: ReturnCode, _key: &'static mut [u8]) {
+            self.result.set(Some((result, 0)));
+        }
+    }
+
+    /// A store booted on `flash`, as after a reset.
+    struct Store {
+        flash: &'static MockFlash<'static>,
+        store: &'static KVStore<'static, MockFlash<'static>>,
+        client: &'static Client,
+    }
+
+    impl Store {
+        fn new_flash() -> &'static MockFlash<'static> {
+            leak(MockFlash::new(leak([0; NUM_PAGES * MOCK_PAGE_SIZE])))
+        }
+
+        fn boot(flash: &'static MockFlash<'static>, index_len: usize) -> Store {
+            let processes: &'static mut [Option<&'static ProcessType>] = leak([None; 1]);
+            let kernel = leak(Kernel::new(processes));
+            let store = leak(KVStore::new(
+                flash,
+                kernel.create_grant(&Capability),
+                0,
+                leak([0; NUM_PAGES]),
+                Box::leak(
+                    (0..index_len)
+                        .map(|_| KVIndexEntry::default())
+                        .collect::<Vec<_>>()
+                        .into_boxed_slice(),
+                ),
+                leak(MockPage::new()),
+            ));
+            flash.set_client(store);
+            let client = leak(Client {
+                result: Cell::new(None),
+                value: TakeCell::empty(),
+            });
+            store.set_client(client);
+            store.initialize();
+            while flash.run_pending() {}
+            Store {
+                flash: flash,
+                store: store,
+                client: client,
+            }
+        }
+
+        /// Run the flash until the operation started with `started` is done.
+        fn finish(&self, started: bool) -> (ReturnCode, usize) {
+            assert!(started);
+            while self.flash.run_pending() {}
+            self.client.result.take().expect("operation did not finish")
+        }
+
+        fn set(&self, key: &[u8], value: &[u8]) -> ReturnCode {
+            let key = Box::leak(key.to_vec().into_boxed_slice());
+            let value = Box::leak(value.to_vec().into_boxed_slice());
+            self.finish(self.store.set(key, value).is_ok()).0
+        }
+
+        fn get(&self, key: &[u8]) -> Result<Vec<u8>, ReturnCode> {
+            let key = Box::leak(key.to_vec().into_boxed_slice());
+            let value = Box::leak([0; 128].to_vec().into_boxed_slice());
+            match self.finish(self.store.get(key, value).is_ok()) {
+                (ReturnCode::SUCCESS, length) => {
+                    let value = self.client.value.take().unwrap();
+                    Ok(value[0..length].to_vec())
+                }
+                (result, _) => Err(result),
+            }
+        }
+
+        fn delete(&self, key: &[u8]) -> ReturnCode {
+            let key = Box::leak(key.to_vec().into_boxed_slice());
+            self.finish(self.store.delete(key).is_ok()).0
+        }
+
+        fn keys(&self) -> Vec<Vec<u8>> {
+            let mut keys = Vec::new();
+            let mut key = [0; super::MAX_KEY_LEN];
+            while let Some(len) = self.store.key_at(keys.len(), &mut key) {
+                keys.push(key[0..len].to_vec());
+            }
+            keys.sort();
+            keys
+        }
+    }
+
+    #[test]
+    fn set_get_delete_and_list() {
+        let store = Store::boot(Store::new_flash(), 8);
+        assert_eq!(store.get(b"a"), Err(ReturnCode::FAIL));
+        assert_eq!(store.set(b"a", b"one"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"bb", b"two"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"a", b"three"), ReturnCode::SUCCESS);
+        assert_eq!(store.get(b"a"), Ok(b"three".to_vec()));
+        assert_eq!(store.get(b"bb"), Ok(b"two".to_vec()));
+        assert_eq!(store.keys(), [b"a".to_vec(), b"bb".to_vec()]);
+
+        assert_eq!(store.delete(b"a"), ReturnCode::SUCCESS);
+        assert_eq!(store.delete(b"a"), ReturnCode::FAIL);
+        assert_eq!(store.get(b"a"), Err(ReturnCode::FAIL));
+        assert_eq!(store.keys(), [b"bb".to_vec()]);
+        assert_eq!(store.set(b"", b"x"), ReturnCode::EINVAL);
+        assert_eq!(store.set(&[b'k'; 17], b"x"), ReturnCode::EINVAL);
+
+        let store = Store::boot(store.flash, 8);
+        assert_eq!(store.get(b"a"), Err(ReturnCode::FAIL));
+        assert_eq!(store.get(b"bb"), Ok(b"two".to_vec()));
+        assert_eq!(store.keys(), [b"bb".to_vec()]);
+    }
+
+    #[test]
+    fn garbage_collection_keeps_current_values() {
+        let store = Store::boot(Store::new_flash(), 8);
+        assert_eq!(store.set(b"kept", b"old"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"deleted", b"gone"), ReturnCode::SUCCESS);
+        assert_eq!(store.delete(b"deleted"), ReturnCode::SUCCESS);
+        // Many times the size of the store, so every page is collected.
+        for n in 0..=250 {
+            assert_eq!(store.set(b"counter", &[n; 40]), ReturnCode::SUCCESS);
+        }
+        assert_eq!(store.get(b"kept"), Ok(b"old".to_vec()));
+        assert_eq!(store.get(b"deleted"), Err(ReturnCode::FAIL));
+        assert_eq!(store.get(b"counter"), Ok(filled(250, 40)));
+
+        let store = Store::boot(store.flash, 8);
+        assert_eq!(store.get(b"kept"), Ok(b"old".to_vec()));
+        assert_eq!(store.get(b"deleted"), Err(ReturnCode::FAIL));
+        assert_eq!(store.get(b"counter"), Ok(filled(250, 40)));
+        assert_eq!(store.keys(), [b"counter".to_vec(), b"kept".to_vec()]);
+    }
+
+    #[test]
+    fn store_full() {
+        let store = Store::boot(Store::new_flash(), 2);
+        assert_eq!(store.set(b"a", b"1"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"b", b"2"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"c", b"3"), ReturnCode::ENOMEM);
+        assert_eq!(store.set(b"a", &[0; MOCK_PAGE_SIZE]), ReturnCode::ESIZE);
+        assert_eq!(store.get(b"a"), Ok(b"1".to_vec()));
+    }
+
+    #[test]
+    fn deleted_keys_do_not_take_index_entries() {
+        let store = Store::boot(Store::new_flash(), 2);
+        assert_eq!(store.set(b"a", b"1"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"b", b"2"), ReturnCode::SUCCESS);
+        assert_eq!(store.delete(b"a"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"c", b"3"), ReturnCode::SUCCESS);
+
+        // The record deleting `a` is in flash before `c`, but must not use up
+        // the entry `c` needs.
+        let store = Store::boot(store.flash, 2);
+        assert_eq!(store.keys(), [b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(store.get(b"c"), Ok(b"3".to_vec()));
+    }
+
+    #[test]
+    fn index_too_small_fails_every_operation() {
+        let store = Store::boot(Store::new_flash(), 3);
+        assert_eq!(store.set(b"a", b"1"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"b", b"2"), ReturnCode::SUCCESS);
+        assert_eq!(store.set(b"c", b"3"), ReturnCode::SUCCESS);
+
+        let store = Store::boot(store.flash, 2);
+        assert_eq!(store.keys(), Vec::<Vec<u8>>::new());
+        assert_eq!(store.get(b"a"), Err(ReturnCode::ENOMEM));
+        assert_eq!(store.set(b"d", b"4"), ReturnCode::ENOMEM);
+        assert_eq!(store.delete(b"c"), ReturnCode::ENOMEM);
+
+        // Nothing was lost.
+        let store = Store::boot(store.flash, 3);
+        assert_eq!(store.get(b"c"), Ok(b"3".to_vec()));
+    }
+
+    #[test]
+    fn torn_write_keeps_old_value() {
+        let store = Store::boot(Store::new_flash(), 8);
+        assert_eq!(store.set(b"key", b"before"), ReturnCode::SUCCESS);
+        store.flash.tear_next_write(10);
+        assert_eq!(store.set(b"key", b"after!"), ReturnCode::FAIL);
+        assert_eq!(store.get(b"key"), Ok(b"before".to_vec()));
+
+        // The rest of the torn page is not used, and the next write works.
+        assert_eq!(store.set(b"key", b"again"), ReturnCode::SUCCESS);
+        let store = Store::boot(store.flash, 8);
+        assert_eq!(store.get(b"key"), Ok(b"again".to_vec()));
+        assert_eq!(store.keys(), [b"key".to_vec()]);
+    }
+
+    /// Fill the store until the next set of `counter` has to garbage collect
+    /// a page that still has `kept` in it. Returns the number of sets.
+    fn fill_until_collection(store: &Store) -> u8 {
+        assert_eq!(store.set(b"kept", b"value"), ReturnCode::SUCCESS);
+        let mut n = 0;
+        while store.store.num_free_pages() > 1 || store.store.active_page.is_none() {
+            n += 1;
+            assert_eq!(store.set(b"counter", &[n; 100]), ReturnCode::SUCCESS);
+        }
+        // Each record is 12 bytes of header, 7 of key and 100 of value,
+        // padded to 120.
+        while store.store.active_offset.get() + 120 <= MOCK_PAGE_SIZE {
+            n += 1;
+            assert_eq!(store.set(b"counter", &[n; 100]), ReturnCode::SUCCESS);
+        }
+        n
+    }
+
+    #[test]
+    fn power_loss_during_garbage_collection() {
+        let mut steps = 0;
+        loop {
+            let store = Store::boot(Store::new_flash(), 8);
+            let n = fill_until_collection(&store);
+            let key = Box::leak(b"counter".to_vec().into_boxed_slice());
+            let value = Box::leak(filled(n + 1, 100).into_boxed_slice());
+            assert!(store.store.set(key, value).is_ok());
+            let mut finished = false;
+            for _ in 0..steps {
+                if !store.flash.run_pending() {
+                    finished = true;
+                    break;
+                }
+            }
+            store.flash.lose_power();
+
+            let store = Store::boot(store.flash, 8);
+            assert_eq!(store.get(b"kept"), Ok(b"value".to_vec()));
+            let counter = store.get(b"counter").unwrap();
+            assert!(counter == filled(n, 100) || counter == filled(n + 1, 100));
+            assert_eq!(store.set(b"counter", &[0; 100]), ReturnCode::SUCCESS);
+            assert_eq!(store.get(b"kept"), Ok(b"value".to_vec()));
+            if finished {
+                assert_eq!(counter, filled(n + 1, 100));
+                break;
+            }
+            steps += 1;
+        }
+        // Reading the victim, erasing, copying and appending all happened.
+        assert!(steps > 3);
+    }
+}
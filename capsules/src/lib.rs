@@ -1,7 +1,9 @@
 #![feature(const_fn)]
 #![feature(infer_outlives_requirements, in_band_lifetimes)]
 #![feature(tool_attributes)]
-#![forbid(unsafe_code)]
+#![cfg_attr(not(test), forbid(unsafe_code))]
+// Host tests need to make up the capabilities boards hold.
+#![cfg_attr(test, deny(unsafe_code))]
 #![no_std]
 
 #[allow(unused_imports)]
@@ -19,12 +21,15 @@ pub mod alarm;
 pub mod ambient_light;
 pub mod analog_comparator;
 pub mod app_flash_driver;
+pub mod app_loader;
 pub mod ble_advertising_driver;
 pub mod button;
 pub mod console;
 pub mod crc;
 pub mod dac;
 pub mod debug_process_restart;
+pub mod fat;
+pub mod fault_policy;
 pub mod fm25cl;
 pub mod fxos8700cq;
 pub mod gpio;
@@ -33,6 +38,8 @@ pub mod humidity;
 pub mod i2c_master_slave_driver;
 pub mod ieee802154;
 pub mod isl29035;
+pub mod kernel_clock;
+pub mod kv_store;
 pub mod led;
 pub mod lps25hb;
 pub mod ltc294x;
@@ -43,7 +50,10 @@ pub mod ninedof;
 pub mod nonvolatile_storage_driver;
 pub mod nonvolatile_to_pages;
 pub mod nrf51822_serialization;
+pub mod overflow_alarm;
 pub mod pca9544a;
+pub mod process_console;
+pub mod process_info;
 pub mod rf233;
 pub mod rf233_const;
 pub mod rng;
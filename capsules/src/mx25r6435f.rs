@@ -50,15 +50,18 @@ use core::ops::{Index, IndexMut};
 use kernel::common::cells::OptionalCell;
 use kernel::common::cells::TakeCell;
 use kernel::hil;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
 pub static mut TXBUFFER: [u8; PAGE_SIZE as usize + 4] = [0; PAGE_SIZE as usize + 4];
 pub static mut RXBUFFER: [u8; PAGE_SIZE as usize + 4] = [0; PAGE_SIZE as usize + 4];
 
 const SPI_SPEED: u32 = 8000000;
+const FLASH_SIZE: u32 = 8 * 1024 * 1024;
+const BLOCK_SIZE: u32 = 65536;
 const SECTOR_SIZE: u32 = 4096;
 const PAGE_SIZE: u32 = 256;
+const SECTORS_PER_BLOCK: u32 = BLOCK_SIZE / SECTOR_SIZE;
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
 /// MX25R6435F. The page size is 4k because that is the smallest size that can
@@ -102,6 +105,7 @@ enum Opcodes {
     WREN = 0x06, // Write Enable
     WRDI = 0x04, // Write Disable
     SE = 0x20,   // Sector Erase
+    BE = 0xd8,   // Block Erase (64 KB)
     READ = 0x03, // Normal Read
     PP = 0x02,   // Page Program (write)
     RDID = 0x9f, // Read Identification
@@ -111,6 +115,8 @@ enum Opcodes {
 #[derive(Clone, Copy, PartialEq)]
 enum Operation {
     Erase,
+    /// Erase sectors up to `end`, starting with `next` after this erase.
+    EraseRange { next: u32, end: u32 },
     Write { sector_index: u32 },
 }
 
@@ -246,6 +252,21 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         self.enable_write()
     }
 
+    fn erase_sectors(&self, sector_index: usize, num_sectors: usize) -> ReturnCode {
+        if num_sectors == 0 || sector_index + num_sectors > (FLASH_SIZE / SECTOR_SIZE) as usize {
+            return ReturnCode::EINVAL;
+        }
+        self.configure_spi();
+        self.state.set(State::EraseSectorWriteEnable {
+            sector_index: sector_index as u32,
+            operation: Operation::EraseRange {
+                next: sector_index as u32,
+                end: (sector_index + num_sectors) as u32,
+            },
+        });
+        self.enable_write()
+    }
+
     fn read_sector(&self, sector_index: u32, sector: &'static mut Mx25r6435fSector) -> ReturnCode {
         self.configure_spi();
         self.txbuffer
@@ -286,6 +307,36 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         });
         self.enable_write()
     }
+
+    fn program_sector(
+        &self,
+        sector_index: u32,
+        sector: &'static mut Mx25r6435fSector,
+    ) -> ReturnCode {
+        self.client_sector.replace(sector);
+        self.configure_spi();
+        self.state.set(State::WriteSectorWriteEnable {
+            sector_index,
+            page_index: 0,
+        });
+        self.enable_write()
+    }
+}
+
+/// The first page from `page_index` on that has bytes to program. Programming
+/// erased bytes does not change them, so pages that are all erased are
+/// skipped.
+fn next_page_to_program(sector: &Mx25r6435fSector, page_index: u32) -> u32 {
+    let mut page_index = page_index;
+    while page_index * PAGE_SIZE < SECTOR_SIZE {
+        let start = (page_index * PAGE_SIZE) as usize;
+        let page = &sector.0[start..start + PAGE_SIZE as usize];
+        if page.iter().any(|&byte| byte != hil::flash::ERASED_BYTE) {
+            break;
+        }
+        page_index += 1;
+    }
+    page_index
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
@@ -355,8 +406,24 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 sector_index,
                 operation,
             } => {
+                // When erasing a range, erase whole blocks where the range
+                // covers them.
+                let (opcode, operation) = match operation {
+                    Operation::EraseRange { end, .. }
+                        if sector_index % SECTORS_PER_BLOCK == 0
+                            && sector_index + SECTORS_PER_BLOCK <= end =>
+                    {
+                        let next = sector_index + SECTORS_PER_BLOCK;
+                        (Opcodes::BE, Operation::EraseRange { next, end })
+                    }
+                    Operation::EraseRange { end, .. } => {
+                        let next = sector_index + 1;
+                        (Opcodes::SE, Operation::EraseRange { next, end })
+                    }
+                    _ => (Opcodes::SE, operation),
+                };
                 self.state.set(State::EraseSectorErase { operation });
-                write_buffer[0] = Opcodes::SE as u8;
+                write_buffer[0] = opcode as u8;
                 write_buffer[1] = ((sector_index * SECTOR_SIZE) >> 16) as u8;
                 write_buffer[2] = ((sector_index * SECTOR_SIZE) >> 8) as u8;
                 write_buffer[3] = ((sector_index * SECTOR_SIZE) >> 0) as u8;
@@ -367,9 +434,10 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 self.state.set(State::EraseSectorCheckDone { operation });
                 self.txbuffer.replace(write_buffer);
                 // Datasheet says erase takes 58 ms on average. So we wait that
-                // long.
+                // long. Block erases take longer, but the status check below
+                // keeps waiting until the erase is done.
                 let interval = (58 as u32) * <A::Frequency>::frequency() / 1000;
-                let tics = self.alarm.now().wrapping_add(interval);
+                let tics = self.alarm.now().wrapping_add(A::Ticks::from_u64(interval as u64));
                 self.alarm.set_alarm(tics);
             }
             State::EraseSectorCheckDone { operation } => {
@@ -381,10 +449,28 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                         // Erase is still in progress.
                         self.spi
                             .read_write_bytes(write_buffer, Some(read_buffer), 2);
+                    } else if let Operation::EraseRange { next, end } = operation {
+                        if next < end {
+                            // Erase the rest of the range. Each erase needs
+                            // its own write enable.
+                            self.state.set(State::EraseSectorWriteEnable {
+                                sector_index: next,
+                                operation,
+                            });
+                            self.rxbuffer.replace(read_buffer);
+                            write_buffer[0] = Opcodes::WREN as u8;
+                            self.spi.read_write_bytes(write_buffer, None, 1);
+                        } else {
+                            self.state.set(State::EraseSectorDone);
+                            self.rxbuffer.replace(read_buffer);
+                            self.read_write_done(write_buffer, None, len);
+                        }
                     } else {
                         // Erase has finished, so jump to the next state.
                         let next_state = match operation {
-                            Operation::Erase => State::EraseSectorDone,
+                            Operation::Erase | Operation::EraseRange { .. } => {
+                                State::EraseSectorDone
+                            }
                             Operation::Write { sector_index } => State::WriteSectorWriteEnable {
                                 sector_index,
                                 page_index: 0,
@@ -408,6 +494,9 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 sector_index,
                 page_index,
             } => {
+                let page_index = self
+                    .client_sector
+                    .map_or(page_index, |sector| next_page_to_program(sector, page_index));
                 // Check if we are done. This happens when we have written a
                 // sector's worth of data, one page at a time.
                 if page_index * PAGE_SIZE == SECTOR_SIZE {
@@ -465,7 +554,7 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
                 // Datasheet says write page takes 3.2 ms on average. So we wait
                 // that long.
                 let interval = (3200 as u32) * <A::Frequency>::frequency() / 1000000;
-                let tics = self.alarm.now().wrapping_add(interval);
+                let tics = self.alarm.now().wrapping_add(A::Ticks::from_u64(interval as u64));
                 self.alarm.set_alarm(tics);
             }
             State::WriteSectorWaitDone {
@@ -542,3 +631,26 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time
         self.erase_sector(page_number as u32)
     }
 }
+
+impl<'a, S: hil::spi::SpiMasterDevice + 'a, P: hil::gpio::Pin + 'a, A: hil::time::Alarm + 'a>
+    hil::flash::EraseFlash for MX25R6435F<'a, S, P, A>
+{
+    fn geometry(&self) -> hil::flash::Geometry {
+        hil::flash::Geometry {
+            page_size: SECTOR_SIZE as usize,
+            num_pages: (FLASH_SIZE / SECTOR_SIZE) as usize,
+            program_size: PAGE_SIZE as usize,
+            erase_size: SECTOR_SIZE as usize,
+            max_erase_size: BLOCK_SIZE as usize,
+            write_erases: true,
+        }
+    }
+
+    fn program_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.program_sector(page_number as u32, buf)
+    }
+
+    fn erase_pages(&self, page_number: usize, num_pages: usize) -> ReturnCode {
+        self.erase_sectors(page_number, num_pages)
+    }
+}
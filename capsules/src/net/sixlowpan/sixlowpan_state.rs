@@ -232,7 +232,7 @@ use kernel::common::cells::{MapCell, TakeCell};
 use kernel::common::list::{List, ListLink, ListNode};
 use kernel::hil::radio;
 use kernel::hil::time;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 use net::frag_utils::Bitmap;
 use net::ieee802154::{Header, KeyId, MacAddress, PanID, SecurityLevel};
@@ -632,8 +632,9 @@ pub struct RxState<'a> {
     // Marks if this instance is being used for a packet reassembly or if it is
     // free to use for a new packet.
     busy: Cell<bool>,
-    // The time when packet reassembly started for the current packet.
-    start_time: Cell<u32>,
+    // The time when packet reassembly started for the current packet, as the
+    // clock's `Ticks` converted to a `u64`.
+    start_time: Cell<u64>,
 
     next: ListLink<'a, RxState<'a>>,
 }
@@ -681,21 +682,22 @@ impl RxState<'a> {
 
     // Checks if a given RxState is free or expired (and thus, can be freed).
     // This function implements the reassembly timeout for 6LoWPAN lazily.
-    fn is_busy(&self, frequency: u32, current_time: u32) -> bool {
-        let expired = current_time >= (self.start_time.get() + FRAG_TIMEOUT * frequency);
+    fn is_busy<T: Ticks>(&self, frequency: u32, current_time: T) -> bool {
+        let elapsed = current_time.wrapping_sub(T::from_u64(self.start_time.get()));
+        let expired = elapsed.into_u64() >= FRAG_TIMEOUT as u64 * frequency as u64;
         if expired {
             self.end_receive(None, ReturnCode::FAIL);
         }
         self.busy.get()
     }
 
-    fn start_receive(
+    fn start_receive<T: Ticks>(
         &self,
         src_mac_addr: MacAddress,
         dst_mac_addr: MacAddress,
         dgram_size: u16,
         dgram_tag: u16,
-        current_tics: u32,
+        current_tics: T,
     ) {
         self.dst_mac_addr.set(dst_mac_addr);
         self.src_mac_addr.set(src_mac_addr);
@@ -703,7 +705,7 @@ impl RxState<'a> {
         self.dgram_size.set(dgram_size);
         self.busy.set(true);
         self.bitmap.map(|bitmap| bitmap.clear());
-        self.start_time.set(current_tics);
+        self.start_time.set(current_tics.into_u64());
     }
 
     // This function assumes that the payload is a slice starting from the
@@ -906,7 +908,7 @@ impl<A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
         let rx_state = self
             .rx_states
             .iter()
-            .find(|state| !state.is_busy(self.clock.now(), A::Frequency::frequency()));
+            .find(|state| !state.is_busy(A::Frequency::frequency(), self.clock.now()));
         rx_state
             .map(|state| {
                 state.start_receive(
@@ -977,7 +979,7 @@ impl<A: time::Alarm, C: ContextStore> Sixlowpan<'a, A, C> {
             rx_state = self
                 .rx_states
                 .iter()
-                .find(|state| !state.is_busy(self.clock.now(), A::Frequency::frequency()));
+                .find(|state| !state.is_busy(A::Frequency::frequency(), self.clock.now()));
             // Initialize new state
             rx_state.map(|state| {
                 state.start_receive(
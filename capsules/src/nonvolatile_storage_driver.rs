@@ -1,11 +1,23 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! Each application gets its own region of the memory provided to userland,
+//! and can only read and write within it. Addresses from an application are
+//! relative to the start of its region. The size of the region comes from the
+//! application's Storage Size TBF TLV, capped at a maximum the board sets, or,
+//! for applications without one, from a table of package names and sizes the
+//! board provides. Applications with neither cannot use the storage.
 //!
-//! However, the kernel accessible memory does not have to be the same range
+//! Regions are allocated the first time an application uses the storage and
+//! recorded in a region table at the start of the userspace memory, keyed by
+//! package name. The table persists across reboots, so an application finds
+//! the same data again after it is restarted or updated, and an application
+//! cannot reach the data of another. Regions are never freed or resized: an
+//! application that asks for a different size later keeps its original
+//! region. The table is read and written through the capsule's buffer, so if
+//! the underlying storage fails, the copy in memory is kept and the commands
+//! waiting for it fail instead.
+//!
+//! The kernel accessible memory does not have to be the same range
 //! as the userspace accessible address space. The kernel memory can overlap
 //! if desired, or can be a completely separate range.
 //!
@@ -48,6 +60,11 @@
 //!         0,                           // The byte start address of the region
 //!                                      // that is accessible by the kernel.
 //!         3000,                        // The length of the kernel region.
+//!         &[("sensor_calibration", 256)], // Region sizes for apps without a
+//!                                      // Storage Size TLV.
+//!         1024,                        // The largest region a Storage Size
+//!                                      // TLV can ask for.
+//!         &mut capsules::nonvolatile_storage_driver::REGION_TABLE,
 //!         &mut capsules::nonvolatile_storage_driver::BUFFER));
 //! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
 //! ```
@@ -61,8 +78,25 @@ use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 /// Syscall driver number.
 pub const DRIVER_NUM: usize = 0x50001;
 
+/// The capsule's buffer. It must be at least `REGION_TABLE_LEN` bytes long.
 pub static mut BUFFER: [u8; 512] = [0; 512];
 
+/// Bytes at the start of the userspace memory that hold the region table.
+pub const REGION_TABLE_LEN: usize = 512;
+
+pub static mut REGION_TABLE: [u8; REGION_TABLE_LEN] = [0; REGION_TABLE_LEN];
+
+/// Identifies a valid region table.
+const REGION_TABLE_MAGIC: [u8; 4] = *b"TNVR";
+/// The table starts with the magic and the number of entries.
+const REGION_TABLE_HEADER_LEN: usize = 8;
+/// Each entry is the hash of the full package name, the region size, and as
+/// much of the package name as fits. Regions are laid out one after the other
+/// in the order of their entries, starting after the table.
+const REGION_ENTRY_LEN: usize = 32;
+const REGION_NAME_LEN: usize = REGION_ENTRY_LEN - 8;
+const MAX_REGIONS: usize = (REGION_TABLE_LEN - REGION_TABLE_HEADER_LEN) / REGION_ENTRY_LEN;
+
 #[derive(Clone, Copy, PartialEq)]
 pub enum NonvolatileCommand {
     UserspaceRead,
@@ -75,6 +109,8 @@ pub enum NonvolatileCommand {
 pub enum NonvolatileUser {
     App { app_id: AppId },
     Kernel,
+    /// Loading or saving the region table.
+    RegionTable,
 }
 
 pub struct App {
@@ -86,6 +122,22 @@ pub struct App {
     length: usize,
     buffer_read: Option<AppSlice<Shared, u8>>,
     buffer_write: Option<AppSlice<Shared, u8>>,
+    // Offset from the start of the userspace memory and length of the app's
+    // region, once it is known.
+    region: Option<(usize, usize)>,
+}
+
+impl App {
+    // End the pending command with nothing read or written.
+    fn fail_command(&mut self) {
+        self.pending_command = false;
+        match self.command {
+            NonvolatileCommand::UserspaceRead => {
+                self.callback_read.map(|mut cb| cb.schedule(0, 0, 0))
+            }
+            _ => self.callback_write.map(|mut cb| cb.schedule(0, 0, 0)),
+        };
+    }
 }
 
 impl Default for App {
@@ -99,6 +151,7 @@ impl Default for App {
             length: 0,
             buffer_read: None,
             buffer_write: None,
+            region: None,
         }
     }
 }
@@ -123,6 +176,17 @@ pub struct NonvolatileStorage<'a> {
     // How many bytes allocated to kernel.
     kernel_length: usize,
 
+    // Region sizes for apps without a Storage Size TLV, by package name.
+    app_region_sizes: &'static [(&'static str, usize)],
+    // The largest region a Storage Size TLV can ask for.
+    max_region_size: usize,
+    // Copy of the region table. It is read and written through `buffer`.
+    region_table: TakeCell<'static, [u8]>,
+    // Whether the region table has been read from storage.
+    region_table_loaded: Cell<bool>,
+    // Whether the region table has changes that are not saved yet.
+    region_table_dirty: Cell<bool>,
+
     // Optional client for the kernel. Only needed if the kernel intends to use
     // this nonvolatile storage.
     kernel_client: OptionalCell<&'static hil::nonvolatile_storage::NonvolatileStorageClient>,
@@ -146,6 +210,9 @@ impl NonvolatileStorage<'a> {
         userspace_length: usize,
         kernel_start_address: usize,
         kernel_length: usize,
+        app_region_sizes: &'static [(&'static str, usize)],
+        max_region_size: usize,
+        region_table: &'static mut [u8],
         buffer: &'static mut [u8],
     ) -> NonvolatileStorage<'a> {
         NonvolatileStorage {
@@ -157,6 +224,11 @@ impl NonvolatileStorage<'a> {
             userspace_length: userspace_length,
             kernel_start_address: kernel_start_address,
             kernel_length: kernel_length,
+            app_region_sizes: app_region_sizes,
+            max_region_size: max_region_size,
+            region_table: TakeCell::new(region_table),
+            region_table_loaded: Cell::new(false),
+            region_table_dirty: Cell::new(false),
             kernel_client: OptionalCell::empty(),
             kernel_pending_command: Cell::new(false),
             kernel_command: Cell::new(NonvolatileCommand::KernelRead),
@@ -176,37 +248,13 @@ impl NonvolatileStorage<'a> {
         length: usize,
         app_id: Option<AppId>,
     ) -> ReturnCode {
-        // Do bounds check.
-        match command {
-            NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length
-                    || length > self.userspace_length
-                    || offset + length > self.userspace_length
-                {
-                    return ReturnCode::EINVAL;
-                }
-            }
-            NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
-                // Because the kernel uses the NonvolatileStorage interface,
-                // its calls are absolute addresses.
-                if offset < self.kernel_start_address
-                    || offset >= self.kernel_start_address + self.kernel_length
-                    || length > self.kernel_length
-                    || offset + length > self.kernel_start_address + self.kernel_length
-                {
-                    return ReturnCode::EINVAL;
-                }
-            }
-        }
-
         // Do very different actions if this is a call from userspace
         // or from the kernel.
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
                 app_id.map_or(ReturnCode::FAIL, |appid| {
-                    self.apps
+                    let (return_code, check_queue) = self
+                        .apps
                         .enter(appid, |app, _| {
                             // Get the length of the correct allowed buffer.
                             let allow_buf_len = match command {
@@ -221,7 +269,27 @@ impl NonvolatileStorage<'a> {
 
                             // Check that it exists.
                             if allow_buf_len == 0 || self.buffer.is_none() {
-                                return ReturnCode::ERESERVE;
+                                return (ReturnCode::ERESERVE, false);
+                            }
+
+                            // Find the app's region, if the region table is
+                            // available to look it up.
+                            if app.region.is_none() {
+                                match self.find_or_allocate_region(appid) {
+                                    Ok(region) => app.region = region,
+                                    Err(err) => return (err, false),
+                                }
+                            }
+
+                            // Do bounds check. Userspace sees memory that
+                            // starts at address 0 of its region.
+                            if let Some((_, region_length)) = app.region {
+                                if offset >= region_length
+                                    || length > region_length
+                                    || offset + length > region_length
+                                {
+                                    return (ReturnCode::EINVAL, false);
+                                }
                             }
 
                             // Shorten the length if the application gave us nowhere to
@@ -229,52 +297,59 @@ impl NonvolatileStorage<'a> {
                             let active_len = cmp::min(length, allow_buf_len);
 
                             // First need to determine if we can execute this or must
-                            // queue it.
-                            if self.current_user.is_none() {
+                            // queue it. It has to wait for the region table too if the
+                            // app's region is not known or not saved yet.
+                            if self.current_user.is_none()
+                                && app.region.is_some()
+                                && !self.region_table_dirty.get()
+                            {
                                 // No app is currently using the underlying storage.
                                 // Mark this app as active, and then execute the command.
                                 self.current_user
                                     .set(NonvolatileUser::App { app_id: appid });
-
-                                // Need to copy bytes if this is a write!
-                                if command == NonvolatileCommand::UserspaceWrite {
-                                    app.buffer_write.as_mut().map(|app_buffer| {
-                                        self.buffer.map(|kernel_buffer| {
-                                            // Check that the internal buffer and the buffer that was
-                                            // allowed are long enough.
-                                            let write_len =
-                                                cmp::min(active_len, kernel_buffer.len());
-
-                                            let d = &mut app_buffer.as_mut()[0..write_len];
-                                            for (i, c) in
-                                                kernel_buffer[0..write_len].iter_mut().enumerate()
-                                            {
-                                                *c = d[i];
-                                            }
-                                        });
-                                    });
+                                let return_code =
+                                    self.userspace_call_driver(app, command, offset, active_len);
+                                if return_code != ReturnCode::SUCCESS {
+                                    self.current_user.clear();
                                 }
-
-                                self.userspace_call_driver(command, offset, active_len)
+                                (return_code, false)
                             } else {
                                 // Some app is using the storage, we must wait.
                                 if app.pending_command == true {
                                     // No more room in the queue, nowhere to store this
                                     // request.
-                                    ReturnCode::ENOMEM
+                                    (ReturnCode::ENOMEM, false)
                                 } else {
                                     // We can store this, so lets do it.
                                     app.pending_command = true;
                                     app.command = command;
                                     app.offset = offset;
                                     app.length = active_len;
-                                    ReturnCode::SUCCESS
+                                    (ReturnCode::SUCCESS, self.current_user.is_none())
                                 }
                             }
-                        }).unwrap_or_else(|err| err.into())
+                        }).unwrap_or_else(|err| (err.into(), false));
+
+                    // Nothing is running to start the queued command when it
+                    // finishes, for example when only the region table needs
+                    // to be loaded first, so start it now.
+                    if check_queue {
+                        self.check_queue();
+                    }
+                    return_code
                 })
             }
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
+                // Because the kernel uses the NonvolatileStorage interface,
+                // its calls are absolute addresses.
+                if offset < self.kernel_start_address
+                    || offset >= self.kernel_start_address + self.kernel_length
+                    || length > self.kernel_length
+                    || offset + length > self.kernel_start_address + self.kernel_length
+                {
+                    return ReturnCode::EINVAL;
+                }
+
                 self.kernel_buffer
                     .take()
                     .map_or(ReturnCode::ENOMEM, |kernel_buffer| {
@@ -285,7 +360,7 @@ impl NonvolatileStorage<'a> {
                             // Nothing is using this, lets go!
                             self.current_user.set(NonvolatileUser::Kernel);
 
-                            match command {
+                            let return_code = match command {
                                 NonvolatileCommand::KernelRead => {
                                     self.driver.read(kernel_buffer, offset, active_len)
                                 }
@@ -293,7 +368,11 @@ impl NonvolatileStorage<'a> {
                                     self.driver.write(kernel_buffer, offset, active_len)
                                 }
                                 _ => ReturnCode::FAIL,
+                            };
+                            if return_code != ReturnCode::SUCCESS {
+                                self.current_user.clear();
                             }
+                            return_code
                         } else {
                             if self.kernel_pending_command.get() == true {
                                 ReturnCode::ENOMEM
@@ -311,27 +390,38 @@ impl NonvolatileStorage<'a> {
         }
     }
 
+    // Start a read or write for `app` at `offset` in its region, which must be
+    // known.
     fn userspace_call_driver(
         &self,
+        app: &mut App,
         command: NonvolatileCommand,
         offset: usize,
         length: usize,
     ) -> ReturnCode {
         // Calculate where we want to actually read from in the physical
         // storage.
-        let physical_address = offset + self.userspace_start_address;
+        let region_offset = app.region.map_or(0, |(region_offset, _)| region_offset);
+        let physical_address = self.userspace_start_address + region_offset + offset;
 
         self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
             // Check that the internal buffer and the buffer that was
             // allowed are long enough.
             let active_len = cmp::min(length, buffer.len());
 
-            // self.current_app.set(Some(appid));
             match command {
                 NonvolatileCommand::UserspaceRead => {
                     self.driver.read(buffer, physical_address, active_len)
                 }
                 NonvolatileCommand::UserspaceWrite => {
+                    // Need to copy bytes if this is a write!
+                    app.buffer_write.as_mut().map(|app_buffer| {
+                        let write_len = cmp::min(active_len, app_buffer.len());
+                        let d = &app_buffer.as_mut()[0..write_len];
+                        for (i, c) in buffer[0..write_len].iter_mut().enumerate() {
+                            *c = d[i];
+                        }
+                    });
                     self.driver.write(buffer, physical_address, active_len)
                 }
                 _ => ReturnCode::FAIL,
@@ -339,6 +429,95 @@ impl NonvolatileStorage<'a> {
         })
     }
 
+    // The region size the board or the TBF header allows the app.
+    fn requested_region_size(&self, appid: AppId) -> Option<usize> {
+        appid
+            .get_storage_size()
+            .map(|size| cmp::min(size, self.max_region_size))
+            .or_else(|| {
+                let name = appid.get_package_name();
+                self.app_region_sizes
+                    .iter()
+                    .find(|&&(package_name, _)| package_name.as_bytes() == name)
+                    .map(|&(_, size)| size)
+            }).filter(|&size| size > 0)
+    }
+
+    // Look up the region of the app in the region table, and allocate one if
+    // the app does not have one yet. Returns `None` if the table is not
+    // available yet, because it is still being loaded or saved.
+    fn find_or_allocate_region(&self, appid: AppId) -> Result<Option<(usize, usize)>, ReturnCode> {
+        let size = match self.requested_region_size(appid) {
+            Some(size) => size,
+            None => return Err(ReturnCode::ENOSUPPORT),
+        };
+        if !self.region_table_loaded.get() {
+            return Ok(None);
+        }
+        let name = appid.get_package_name();
+        self.region_table.map_or(Ok(None), |table| {
+            if let Some((region_offset, region_length)) = find_region(table, name) {
+                // Only a corrupted table has regions outside the userspace
+                // memory.
+                if region_offset + region_length > self.userspace_length {
+                    return Err(ReturnCode::FAIL);
+                }
+                return Ok(Some((region_offset, region_length)));
+            }
+            match allocate_region(table, name, size, self.userspace_length) {
+                Some(region) => {
+                    self.region_table_dirty.set(true);
+                    Ok(Some(region))
+                }
+                None => Err(ReturnCode::ENOMEM),
+            }
+        })
+    }
+
+    // Start reading the region table from storage into `buffer`.
+    fn load_region_table(&self) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            if buffer.len() < REGION_TABLE_LEN {
+                self.buffer.replace(buffer);
+                return ReturnCode::ESIZE;
+            }
+            self.current_user.set(NonvolatileUser::RegionTable);
+            let return_code = self
+                .driver
+                .read(buffer, self.userspace_start_address, REGION_TABLE_LEN);
+            if return_code != ReturnCode::SUCCESS {
+                // Nothing will call back, so the storage is free again.
+                self.current_user.clear();
+            }
+            return_code
+        })
+    }
+
+    // Start writing the region table to storage from `buffer`.
+    fn save_region_table(&self) -> ReturnCode {
+        self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            if buffer.len() < REGION_TABLE_LEN {
+                self.buffer.replace(buffer);
+                return ReturnCode::ESIZE;
+            }
+            self.region_table.map(|table| {
+                buffer[0..REGION_TABLE_LEN].copy_from_slice(&table[0..REGION_TABLE_LEN]);
+            });
+            self.region_table_dirty.set(false);
+            self.current_user.set(NonvolatileUser::RegionTable);
+            let return_code = self
+                .driver
+                .write(buffer, self.userspace_start_address, REGION_TABLE_LEN);
+            if return_code != ReturnCode::SUCCESS {
+                // Nothing will call back, so the storage is free again and
+                // the table still needs to be saved.
+                self.current_user.clear();
+                self.region_table_dirty.set(true);
+            }
+            return_code
+        })
+    }
+
     fn check_queue(&self) {
         // Check if there are any pending events.
         if self.kernel_pending_command.get() {
@@ -346,7 +525,7 @@ impl NonvolatileStorage<'a> {
                 self.kernel_pending_command.set(false);
                 self.current_user.set(NonvolatileUser::Kernel);
 
-                match self.kernel_command.get() {
+                let return_code = match self.kernel_command.get() {
                     NonvolatileCommand::KernelRead => self.driver.read(
                         kernel_buffer,
                         self.kernel_readwrite_address.get(),
@@ -358,20 +537,54 @@ impl NonvolatileStorage<'a> {
                         self.kernel_readwrite_length.get(),
                     ),
                     _ => ReturnCode::FAIL,
+                };
+                if return_code != ReturnCode::SUCCESS {
+                    self.current_user.clear();
                 }
             });
         } else {
             // If the kernel is not requesting anything, check all of the apps.
             for cntr in self.apps.iter() {
                 let started_command = cntr.enter(|app, _| {
-                    if app.pending_command {
-                        app.pending_command = false;
-                        self.current_user.set(NonvolatileUser::App {
-                            app_id: app.appid(),
-                        });
-                        self.userspace_call_driver(app.command, app.offset, app.length)
-                            == ReturnCode::SUCCESS
+                    if !app.pending_command {
+                        return false;
+                    }
+                    if app.region.is_none() {
+                        if !self.region_table_loaded.get() {
+                            if self.load_region_table() == ReturnCode::SUCCESS {
+                                return true;
+                            }
+                            // Without the table the app cannot get a region.
+                            app.fail_command();
+                            return false;
+                        }
+                        match self.find_or_allocate_region(app.appid()) {
+                            Ok(region) => app.region = region,
+                            Err(_) => {
+                                app.fail_command();
+                                return false;
+                            }
+                        }
+                    }
+                    // Save new regions before anything is written to them.
+                    if self.region_table_dirty.get() {
+                        if self.save_region_table() == ReturnCode::SUCCESS {
+                            return true;
+                        }
+                        app.fail_command();
+                        return false;
+                    }
+                    app.pending_command = false;
+                    self.current_user.set(NonvolatileUser::App {
+                        app_id: app.appid(),
+                    });
+                    let (command, offset, length) = (app.command, app.offset, app.length);
+                    if self.userspace_call_driver(app, command, offset, length)
+                        == ReturnCode::SUCCESS
+                    {
+                        true
                     } else {
+                        self.current_user.clear();
                         false
                     }
                 });
@@ -381,6 +594,111 @@ impl NonvolatileStorage<'a> {
             }
         }
     }
+
+    // The region table was read into or written from `buffer`.
+    fn region_table_done(&self, buffer: &'static mut [u8]) {
+        if !self.region_table_loaded.get() {
+            self.region_table.map(|table| {
+                if buffer[0..4] == REGION_TABLE_MAGIC {
+                    table[0..REGION_TABLE_LEN].copy_from_slice(&buffer[0..REGION_TABLE_LEN]);
+                } else {
+                    // No regions were ever allocated, so start an empty table.
+                    for byte in table.iter_mut() {
+                        *byte = 0;
+                    }
+                    table[0..4].copy_from_slice(&REGION_TABLE_MAGIC);
+                }
+            });
+            self.region_table_loaded.set(true);
+        }
+        self.buffer.replace(buffer);
+    }
+}
+
+// Read a little endian u32 at `offset` in `bytes`.
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    bytes[offset] as u32
+        | (bytes[offset + 1] as u32) << 8
+        | (bytes[offset + 2] as u32) << 16
+        | (bytes[offset + 3] as u32) << 24
+}
+
+// Write `value` as a little endian u32 at `offset` in `bytes`.
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset] = value as u8;
+    bytes[offset + 1] = (value >> 8) as u8;
+    bytes[offset + 2] = (value >> 16) as u8;
+    bytes[offset + 3] = (value >> 24) as u8;
+}
+
+// FNV-1a hash of a package name, so that names longer than an entry can hold
+// are still told apart.
+fn name_hash(name: &[u8]) -> u32 {
+    name.iter().fold(0x811c9dc5, |hash, &byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+// Whether the region table entry starting at `entry` is for `name`.
+fn entry_matches(table: &[u8], entry: usize, name: &[u8]) -> bool {
+    let prefix_len = cmp::min(name.len(), REGION_NAME_LEN);
+    let stored_name = &table[entry + 8..entry + REGION_ENTRY_LEN];
+    read_u32(table, entry) == name_hash(name)
+        && stored_name[0..prefix_len] == name[0..prefix_len]
+        && stored_name[prefix_len..].iter().all(|&byte| byte == 0)
+}
+
+// Number of entries in the region table.
+fn region_count(table: &[u8]) -> usize {
+    cmp::min(read_u32(table, 4) as usize, MAX_REGIONS)
+}
+
+// The offset and length of the region of `name`, if it has one.
+fn find_region(table: &[u8], name: &[u8]) -> Option<(usize, usize)> {
+    let mut region_offset = REGION_TABLE_LEN;
+    for index in 0..region_count(table) {
+        let entry = REGION_TABLE_HEADER_LEN + index * REGION_ENTRY_LEN;
+        let region_length = read_u32(table, entry + 4) as usize;
+        if entry_matches(table, entry, name) {
+            return Some((region_offset, region_length));
+        }
+        region_offset += region_length;
+    }
+    None
+}
+
+// Add a region of `size` bytes for `name` after the existing ones. Returns
+// `None` if the table is full or there is not enough space left in the
+// `userspace_length` bytes of userspace memory.
+fn allocate_region(
+    table: &mut [u8],
+    name: &[u8],
+    size: usize,
+    userspace_length: usize,
+) -> Option<(usize, usize)> {
+    let count = region_count(table);
+    if count == MAX_REGIONS {
+        return None;
+    }
+    let mut region_offset = REGION_TABLE_LEN;
+    for index in 0..count {
+        let entry = REGION_TABLE_HEADER_LEN + index * REGION_ENTRY_LEN;
+        region_offset += read_u32(table, entry + 4) as usize;
+    }
+    if region_offset + size > userspace_length {
+        return None;
+    }
+
+    let entry = REGION_TABLE_HEADER_LEN + count * REGION_ENTRY_LEN;
+    let prefix_len = cmp::min(name.len(), REGION_NAME_LEN);
+    write_u32(table, entry, name_hash(name));
+    write_u32(table, entry + 4, size as u32);
+    for byte in table[entry + 8..entry + REGION_ENTRY_LEN].iter_mut() {
+        *byte = 0;
+    }
+    table[entry + 8..entry + 8 + prefix_len].copy_from_slice(&name[0..prefix_len]);
+    write_u32(table, 4, count as u32 + 1);
+    Some((region_offset, size))
 }
 
 /// This is the callback client for the underlying physical storage driver.
@@ -394,6 +712,7 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.read_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable => self.region_table_done(buffer),
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app, _| {
                         // Need to copy in the contents of the buffer
@@ -428,6 +747,7 @@ impl hil::nonvolatile_storage::NonvolatileStorageClient for NonvolatileStorage<'
                         client.write_done(buffer, length);
                     });
                 }
+                NonvolatileUser::RegionTable => self.region_table_done(buffer),
                 NonvolatileUser::App { app_id } => {
                     let _ = self.apps.enter(app_id, move |app, _| {
                         // Replace the buffer we used to do this write.
@@ -516,7 +836,7 @@ impl Driver for NonvolatileStorage<'a> {
     /// ### `command_num`
     ///
     /// - `0`: Return SUCCESS if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
+    /// - `1`: Return the number of bytes available to the app.
     /// - `2`: Start a read from the nonvolatile storage.
     /// - `3`: Start a write to the nonvolatile_storage.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
@@ -529,10 +849,18 @@ impl Driver for NonvolatileStorage<'a> {
                 ReturnCode::SUCCESS
             }
 
-            // How many bytes are accessible from userspace.
-            1 => ReturnCode::SuccessWithValue {
-                value: self.userspace_length,
-            },
+            // How many bytes are accessible to this app. Before its region
+            // is allocated, this is the size it asks for.
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    let size = app.region.map(|(_, length)| length).or_else(|| {
+                        self.requested_region_size(appid)
+                    });
+                    ReturnCode::SuccessWithValue {
+                        value: size.unwrap_or(0),
+                    }
+                }).unwrap_or_else(|err| err.into()),
 
             // Issue a read
             2 => {
@@ -7,6 +7,11 @@
 //! This module is designed to be used on top of any flash storage and below any
 //! user of `NonvolatileStorage`. This module handles different sized pages.
 //!
+//! Writes that do not cover a whole page have to read the page first. If the
+//! bytes being written are still erased in that page, it is programmed with
+//! `hil::flash::EraseFlash::program_page()` instead of being erased and
+//! written again.
+//!
 //! ```plain
 //! hil::nonvolatile_storage::NonvolatileStorage
 //!                ┌─────────────┐
@@ -14,7 +19,7 @@
 //!                │ This module │
 //!                │             │
 //!                └─────────────┘
-//!               hil::flash::Flash
+//!             hil::flash::EraseFlash
 //! ```
 //!
 //! Usage
@@ -46,7 +51,7 @@ enum State {
     Write,
 }
 
-pub struct NonvolatileToPages<'a, F: hil::flash::Flash + 'static> {
+pub struct NonvolatileToPages<'a, F: hil::flash::EraseFlash + 'static> {
     /// The module providing a `Flash` interface.
     driver: &'a F,
     /// Callback to the user of this capsule.
@@ -69,7 +74,7 @@ pub struct NonvolatileToPages<'a, F: hil::flash::Flash + 'static> {
     buffer_index: Cell<usize>,
 }
 
-impl<F: hil::flash::Flash> NonvolatileToPages<'a, F> {
+impl<F: hil::flash::EraseFlash> NonvolatileToPages<'a, F> {
     pub fn new(driver: &'a F, buffer: &'static mut F::Page) -> NonvolatileToPages<'a, F> {
         NonvolatileToPages {
             driver: driver,
@@ -85,7 +90,7 @@ impl<F: hil::flash::Flash> NonvolatileToPages<'a, F> {
     }
 }
 
-impl<F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage
+impl<F: hil::flash::EraseFlash> hil::nonvolatile_storage::NonvolatileStorage
     for NonvolatileToPages<'a, F>
 {
     fn set_client(&self, client: &'static hil::nonvolatile_storage::NonvolatileStorageClient) {
@@ -153,7 +158,7 @@ impl<F: hil::flash::Flash> hil::nonvolatile_storage::NonvolatileStorage
     }
 }
 
-impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'a, F> {
+impl<F: hil::flash::EraseFlash> hil::flash::Client<F> for NonvolatileToPages<'a, F> {
     fn read_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
         match self.state.get() {
             State::Read => {
@@ -206,6 +211,12 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'a, F> {
                     let buffer_index = self.buffer_index.get();
                     // Which page we read and which we are going to write back to.
                     let page_number = self.address.get() / page_size;
+                    // If the part we write is still erased the page does not
+                    // need to be erased first.
+                    let erased = self.driver.geometry().write_erases
+                        && pagebuffer.as_mut()[page_index..page_index + len]
+                            .iter()
+                            .all(|&byte| byte == hil::flash::ERASED_BYTE);
 
                     // Copy what we read from the page buffer to the user buffer.
                     for i in 0..len {
@@ -217,7 +228,11 @@ impl<F: hil::flash::Flash> hil::flash::Client<F> for NonvolatileToPages<'a, F> {
                     self.remaining_length.subtract(len);
                     self.address.add(len);
                     self.buffer_index.set(buffer_index + len);
-                    self.driver.write_page(page_number, pagebuffer);
+                    if erased {
+                        self.driver.program_page(page_number, pagebuffer);
+                    } else {
+                        self.driver.write_page(page_number, pagebuffer);
+                    }
                 });
             }
             _ => {}
@@ -0,0 +1,149 @@
+//! Extend an `Alarm` to a 64-bit `Alarm64` whose time never wraps.
+//!
+//! `OverflowAlarm` counts how many times the underlying alarm's counter has
+//! wrapped, and adds that to the counter as the upper bits of the time. To see
+//! every wrap it reads the counter at least twice per period: it keeps the
+//! underlying alarm armed at most half a period ahead, even when no 64-bit
+//! alarm is set. Clients can then set alarms any distance in the future, and
+//! the underlying alarm is set again as often as needed to reach them.
+//!
+//! The width of the underlying alarm's `Ticks` is taken into account, so
+//! counters narrower than 32 bits, like the nRF5x RTC, work too.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let overflow_alarm_virtual_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let overflow_alarm = static_init!(
+//!     capsules::overflow_alarm::OverflowAlarm<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::overflow_alarm::OverflowAlarm::new(overflow_alarm_virtual_alarm)
+//! );
+//! overflow_alarm_virtual_alarm.set_client(overflow_alarm);
+//! overflow_alarm.start();
+//! ```
+
+use core::cell::Cell;
+use kernel::common::cells::OptionalCell;
+use kernel::hil::time::{self, Alarm, Alarm64, Ticks, Ticks64, Time};
+
+/// Alarms closer than this many ticks in the future may be missed by some
+/// hardware, so they are pushed back to this distance.
+const MIN_ALARM_TICKS: u64 = 2;
+
+pub struct OverflowAlarm<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    /// Number of times the underlying counter has wrapped.
+    overflows: Cell<u64>,
+    /// Underlying counter value when it was last read.
+    last_now: Cell<A::Ticks>,
+    /// The 64-bit alarm, if one is set.
+    when: Cell<Option<Ticks64>>,
+    client: OptionalCell<&'a time::Client>,
+}
+
+impl<A: Alarm> OverflowAlarm<'a, A> {
+    pub fn new(alarm: &'a A) -> OverflowAlarm<'a, A> {
+        OverflowAlarm {
+            alarm: alarm,
+            overflows: Cell::new(0),
+            last_now: Cell::new(A::Ticks::from_u64(0)),
+            when: Cell::new(None),
+            client: OptionalCell::empty(),
+        }
+    }
+
+    pub fn set_client(&self, client: &'a time::Client) {
+        self.client.set(client);
+    }
+
+    /// Start counting from the underlying alarm's current value. This must be
+    /// called once, after this is set as the underlying alarm's client.
+    pub fn start(&self) {
+        self.last_now.set(self.alarm.now());
+        self.arm();
+    }
+
+    /// Number of ticks in one period of the underlying counter.
+    fn period(&self) -> u64 {
+        1 << A::Ticks::width()
+    }
+
+    /// Read the underlying counter, counting a wrap if it went backwards.
+    fn update(&self) -> Ticks64 {
+        let now = self.alarm.now();
+        if now.into_u64() < self.last_now.get().into_u64() {
+            self.overflows.set(self.overflows.get() + 1);
+        }
+        self.last_now.set(now);
+        Ticks64(self.overflows.get() * self.period() + now.into_u64())
+    }
+
+    /// Set the underlying alarm for the 64-bit alarm, or half a period ahead
+    /// if that comes first.
+    fn arm(&self) {
+        let now = self.update().into_u64();
+        let next_check = now + self.period() / 2;
+        let next = self.when.get().map_or(next_check, |when| {
+            if when.into_u64() < next_check {
+                when.into_u64()
+            } else {
+                next_check
+            }
+        });
+        let delta = if next > now + MIN_ALARM_TICKS {
+            next - now
+        } else {
+            MIN_ALARM_TICKS
+        };
+        let when = self.last_now.get().wrapping_add(A::Ticks::from_u64(delta));
+        self.alarm.set_alarm(when);
+    }
+}
+
+impl<A: Alarm> Time for OverflowAlarm<'a, A> {
+    type Frequency = A::Frequency;
+
+    /// Disable the 64-bit alarm. The underlying alarm stays armed to keep
+    /// counting overflows.
+    fn disable(&self) {
+        self.when.set(None);
+        self.arm();
+    }
+
+    fn is_armed(&self) -> bool {
+        self.when.get().is_some()
+    }
+}
+
+impl<A: Alarm> Alarm64 for OverflowAlarm<'a, A> {
+    fn now(&self) -> Ticks64 {
+        self.update()
+    }
+
+    fn set_alarm(&self, when: Ticks64) {
+        self.when.set(Some(when));
+        self.arm();
+    }
+
+    fn get_alarm(&self) -> Ticks64 {
+        self.when.get().unwrap_or(Ticks64(0))
+    }
+}
+
+impl<A: Alarm> time::Client for OverflowAlarm<'a, A> {
+    fn fired(&self) {
+        let now = self.update();
+        let expired = self.when.get().map_or(false, |when| when.into_u64() <= now.into_u64());
+        if expired {
+            self.when.set(None);
+        }
+        self.arm();
+        if expired {
+            self.client.map(|client| client.fired());
+        }
+    }
+}
@@ -0,0 +1,534 @@
+//! Text console over a UART for inspecting and controlling processes.
+//!
+//! This provides a small line-oriented shell that a developer can connect to
+//! with a serial terminal to debug a board without reflashing it. It can list
+//! the processes on the board, print detailed information about one of them,
+//! and stop, start or restart apps.
+//!
+//! Commands
+//! --------
+//!
+//! - `help`: List the available commands.
+//! - `list`: Print one line per process with its state, memory usage and
+//!   debugging counters.
+//! - `status <name>`: Print the memory layout, registers and debugging counters
+//!   of the app named `name`.
+//! - `stop <name>`: Stop scheduling the app named `name`.
+//! - `start <name>`: Resume an app that was stopped, or restart an app that
+//!   faulted or was terminated.
+//! - `restart <name>`: Restart the app from the beginning.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//!
+//! let process_console_uart = static_init!(UartDevice, UartDevice::new(uart_mux, true));
+//! process_console_uart.setup();
+//! let process_console = static_init!(
+//!     capsules::process_console::ProcessConsole<'static, UartDevice, ProcessMgmtCap>,
+//!     capsules::process_console::ProcessConsole::new(
+//!         process_console_uart,
+//!         115200,
+//!         &mut capsules::process_console::WRITE_BUF,
+//!         &mut capsules::process_console::READ_BUF,
+//!         &mut capsules::process_console::QUEUE_BUF,
+//!         &mut capsules::process_console::COMMAND_BUF,
+//!         board_kernel,
+//!         ProcessMgmtCap
+//!     )
+//! );
+//! hil::uart::UART::set_client(process_console_uart, process_console);
+//! process_console.start();
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use core::fmt;
+use core::str;
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::common::cells::TakeCell;
+use kernel::hil::uart::{self, Client, UART};
+use kernel::procs::{ProcessType, State};
+use kernel::{AppId, Kernel, ReturnCode};
+
+pub static mut WRITE_BUF: [u8; 256] = [0; 256];
+pub static mut READ_BUF: [u8; 1] = [0; 1];
+pub static mut QUEUE_BUF: [u8; 2048] = [0; 2048];
+pub static mut COMMAND_BUF: [u8; 32] = [0; 32];
+
+const PROMPT: &str = "tock$ ";
+
+const HELP: &str = "Commands:\r
+  help            Show this message.\r
+  list            List all processes.\r
+  status <name>   Show details about an app.\r
+  stop <name>     Stop scheduling an app.\r
+  start <name>    Resume a stopped app, or restart a faulted one.\r
+  restart <name>  Restart an app from the beginning.\r
+";
+
+/// A command entered at the console.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Command<'a> {
+    Help,
+    List,
+    Status(&'a str),
+    Stop(&'a str),
+    Start(&'a str),
+    Restart(&'a str),
+}
+
+/// Why a line entered at the console is not a valid command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// The line only contains whitespace.
+    Empty,
+    UnknownCommand,
+    /// The command needs an app name but none was given.
+    MissingName,
+    TooManyArguments,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::Empty => Ok(()),
+            ParseError::UnknownCommand => write!(f, "Unknown command, try `help`."),
+            ParseError::MissingName => write!(f, "This command needs an app name."),
+            ParseError::TooManyArguments => write!(f, "Too many arguments."),
+        }
+    }
+}
+
+/// Parse one line entered at the console.
+pub fn parse_command(line: &str) -> Result<Command, ParseError> {
+    let mut words = line.split_whitespace();
+    let command = words.next().ok_or(ParseError::Empty)?;
+    let name = words.next();
+    if words.next().is_some() {
+        return Err(ParseError::TooManyArguments);
+    }
+
+    match (command, name) {
+        ("help", None) => Ok(Command::Help),
+        ("list", None) => Ok(Command::List),
+        ("help", Some(_)) | ("list", Some(_)) => Err(ParseError::TooManyArguments),
+        ("status", Some(name)) => Ok(Command::Status(name)),
+        ("stop", Some(name)) => Ok(Command::Stop(name)),
+        ("start", Some(name)) => Ok(Command::Start(name)),
+        ("restart", Some(name)) => Ok(Command::Restart(name)),
+        ("status", None) | ("stop", None) | ("start", None) | ("restart", None) => {
+            Err(ParseError::MissingName)
+        }
+        _ => Err(ParseError::UnknownCommand),
+    }
+}
+
+/// Summary of a process, printed as one line of the `list` command.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ProcessSummary<'a> {
+    pub slot: usize,
+    pub name: &'a str,
+    pub state: State,
+    /// Total RAM allocated to the process, in bytes.
+    pub memory_size: usize,
+    /// Bytes of the process's RAM used by the kernel for grants.
+    pub grant_size: usize,
+    pub syscall_count: usize,
+    pub dropped_callback_count: usize,
+    pub restart_count: usize,
+    pub cpu_time_ms: u64,
+}
+
+impl ProcessSummary<'a> {
+    /// Header line for a table of summaries.
+    pub const HEADER: &'static str = concat!(
+        "Slot  Name              State             RAM  Grants",
+        "  Syscalls  Dropped  Restarts  CPU(ms)\r\n"
+    );
+
+    pub fn new(process: &'a ProcessType) -> ProcessSummary<'a> {
+        let memory_size = process.mem_end() as usize - process.mem_start() as usize;
+        let grant_size = process.mem_end() as usize - process.kernel_memory_break() as usize;
+        ProcessSummary {
+            slot: process.appid().idx(),
+            name: str::from_utf8(process.get_process_name()).unwrap_or("?"),
+            state: process.get_state(),
+            memory_size: memory_size,
+            grant_size: grant_size,
+            syscall_count: process.debug_syscall_count(),
+            dropped_callback_count: process.debug_dropped_callback_count(),
+            restart_count: process.debug_restart_count(),
+            cpu_time_ms: process.debug_cpu_time_us() / 1000,
+        }
+    }
+}
+
+impl fmt::Display for ProcessSummary<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // `State` only implements `Debug`, which ignores the width.
+        let state = match self.state {
+            State::Running => "Running",
+            State::Yielded => "Yielded",
+            State::StoppedRunning => "StoppedRunning",
+            State::StoppedYielded => "StoppedYielded",
+            State::Fault => "Fault",
+            State::Terminated => "Terminated",
+        };
+        write!(
+            f,
+            "{:<4}  {:<16}  {:<14}  {:>5}  {:>6}  {:>8}  {:>7}  {:>8}  {:>7}\r\n",
+            self.slot,
+            self.name,
+            state,
+            self.memory_size,
+            self.grant_size,
+            self.syscall_count,
+            self.dropped_callback_count,
+            self.restart_count,
+            self.cpu_time_ms
+        )
+    }
+}
+
+pub struct ProcessConsole<'a, U: UART, C: ProcessManagementCapability> {
+    uart: &'a U,
+    baud_rate: u32,
+    tx_in_progress: Cell<bool>,
+    tx_buffer: TakeCell<'static, [u8]>,
+    rx_buffer: TakeCell<'static, [u8]>,
+    /// Output waiting for the UART. Anything that does not fit is dropped.
+    queue_buffer: TakeCell<'static, [u8]>,
+    queue_len: Cell<usize>,
+    /// Set when output was dropped because the queue was full.
+    queue_overflowed: Cell<bool>,
+    /// The line being typed.
+    command_buffer: TakeCell<'static, [u8]>,
+    command_len: Cell<usize>,
+    kernel: &'static Kernel,
+    capability: C,
+}
+
+impl<U: UART, C: ProcessManagementCapability> ProcessConsole<'a, U, C> {
+    pub fn new(
+        uart: &'a U,
+        baud_rate: u32,
+        tx_buffer: &'static mut [u8],
+        rx_buffer: &'static mut [u8],
+        queue_buffer: &'static mut [u8],
+        command_buffer: &'static mut [u8],
+        kernel: &'static Kernel,
+        capability: C,
+    ) -> ProcessConsole<'a, U, C> {
+        ProcessConsole {
+            uart: uart,
+            baud_rate: baud_rate,
+            tx_in_progress: Cell::new(false),
+            tx_buffer: TakeCell::new(tx_buffer),
+            rx_buffer: TakeCell::new(rx_buffer),
+            queue_buffer: TakeCell::new(queue_buffer),
+            queue_len: Cell::new(0),
+            queue_overflowed: Cell::new(false),
+            command_buffer: TakeCell::new(command_buffer),
+            command_len: Cell::new(0),
+            kernel: kernel,
+            capability: capability,
+        }
+    }
+
+    /// Configure the UART, print the prompt and start waiting for input.
+    pub fn start(&self) -> ReturnCode {
+        let ret = self.uart.configure(uart::UARTParameters {
+            baud_rate: self.baud_rate,
+            stop_bits: uart::StopBits::One,
+            parity: uart::Parity::None,
+            hw_flow_control: false,
+        });
+        if ret != ReturnCode::SUCCESS {
+            return ret;
+        }
+
+        self.write_str(PROMPT);
+        self.rx_buffer.take().map(|buffer| {
+            self.uart.receive(buffer, 1);
+        });
+        ReturnCode::SUCCESS
+    }
+
+    /// Find the app called `name`, and its state.
+    fn find_app(&self, name: &str) -> Option<(AppId, State)> {
+        let found: Cell<Option<(AppId, State)>> = Cell::new(None);
+        self.kernel
+            .process_each_capability(&self.capability, |appid, process| {
+                if found.get().is_none() && process.get_process_name() == name.as_bytes() {
+                    found.set(Some((appid, process.get_state())));
+                }
+            });
+        found.get()
+    }
+
+    fn execute(&self, command: Command) {
+        match command {
+            Command::Help => self.write_str(HELP),
+            Command::List => {
+                self.write_str(ProcessSummary::HEADER);
+                self.kernel
+                    .process_each_capability(&self.capability, |_, process| {
+                        self.write_fmt(format_args!("{}", ProcessSummary::new(process)));
+                    });
+            }
+            Command::Status(name)
+            | Command::Stop(name)
+            | Command::Start(name)
+            | Command::Restart(name) => match self.find_app(name) {
+                None => {
+                    self.write_fmt(format_args!("No app named {}.\r\n", name));
+                }
+                Some((appid, state)) => {
+                    let ret = match command {
+                        Command::Status(_) => {
+                            let mut writer = self;
+                            self.kernel
+                                .process_detail_fmt(appid, &mut writer, &self.capability);
+                            self.write_str("\r\n");
+                            return;
+                        }
+                        Command::Stop(_) => self.kernel.stop_app(appid, &self.capability),
+                        Command::Start(_) => match state {
+                            // These apps cannot be resumed, only run again
+                            // from the beginning.
+                            State::Fault | State::Terminated => {
+                                self.kernel.restart_app(appid, &self.capability)
+                            }
+                            _ => self.kernel.resume_app(appid, &self.capability),
+                        },
+                        _ => self.kernel.restart_app(appid, &self.capability),
+                    };
+                    match ret {
+                        ReturnCode::SUCCESS => {}
+                        ReturnCode::EALREADY => self.write_str("Nothing to do.\r\n"),
+                        _ => {
+                            self.write_fmt(format_args!("Failed: {:?}\r\n", ret));
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    /// Handle one character typed at the terminal.
+    fn handle_byte(&self, byte: u8) {
+        match byte {
+            b'\r' | b'\n' => {
+                self.write_str("\r\n");
+                self.command_buffer.map(|command| {
+                    let len = self.command_len.get();
+                    self.command_len.set(0);
+                    match str::from_utf8(&command[..len]).map(parse_command) {
+                        Ok(Ok(cmd)) => self.execute(cmd),
+                        Ok(Err(ParseError::Empty)) => {}
+                        Ok(Err(err)) => {
+                            self.write_fmt(format_args!("{}\r\n", err));
+                        }
+                        Err(_) => self.write_str("Invalid input.\r\n"),
+                    }
+                });
+                self.write_str(PROMPT);
+            }
+            // Backspace and delete.
+            0x08 | 0x7f => {
+                let len = self.command_len.get();
+                if len > 0 {
+                    self.command_len.set(len - 1);
+                    self.write_str("\x08 \x08");
+                }
+            }
+            // Printable characters are echoed and added to the command.
+            0x20...0x7e => {
+                self.command_buffer.map(|command| {
+                    let len = self.command_len.get();
+                    if len < command.len() {
+                        command[len] = byte;
+                        self.command_len.set(len + 1);
+                        self.write_bytes(&[byte]);
+                    }
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn write_str(&self, s: &str) {
+        self.write_bytes(s.as_bytes());
+    }
+
+    fn write_fmt(&self, args: fmt::Arguments) {
+        let mut writer = self;
+        let _ = fmt::write(&mut writer, args);
+    }
+
+    /// Queue `bytes` to be sent to the UART and start sending if the UART is
+    /// idle.
+    fn write_bytes(&self, bytes: &[u8]) {
+        self.queue_buffer.map(|queue| {
+            let start = self.queue_len.get();
+            let len = cmp::min(bytes.len(), queue.len() - start);
+            queue[start..start + len].copy_from_slice(&bytes[..len]);
+            self.queue_len.set(start + len);
+            if len < bytes.len() {
+                self.queue_overflowed.set(true);
+            }
+        });
+        self.send_queued();
+    }
+
+    /// Move as much of the queue as fits into the transmit buffer and send
+    /// it, unless a transmission is already in progress.
+    fn send_queued(&self) {
+        if self.tx_in_progress.get() {
+            return;
+        }
+
+        self.tx_buffer.take().map(|tx_buffer| {
+            let len = self.queue_buffer.map_or(0, |queue| {
+                let queue_len = self.queue_len.get();
+                let len = cmp::min(queue_len, tx_buffer.len());
+                tx_buffer[..len].copy_from_slice(&queue[..len]);
+                for i in len..queue_len {
+                    queue[i - len] = queue[i];
+                }
+                self.queue_len.set(queue_len - len);
+                len
+            });
+
+            if len > 0 {
+                self.tx_in_progress.set(true);
+                self.uart.transmit(tx_buffer, len);
+            } else {
+                self.tx_buffer.replace(tx_buffer);
+            }
+        });
+    }
+}
+
+impl<U: UART, C: ProcessManagementCapability> fmt::Write for &'b ProcessConsole<'a, U, C> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        ProcessConsole::write_str(self, s);
+        Ok(())
+    }
+}
+
+impl<U: UART, C: ProcessManagementCapability> Client for ProcessConsole<'a, U, C> {
+    fn transmit_complete(&self, buffer: &'static mut [u8], _error: uart::Error) {
+        self.tx_buffer.replace(buffer);
+        self.tx_in_progress.set(false);
+
+        // Let the user know if output was lost once the queue has drained.
+        if self.queue_len.get() == 0 && self.queue_overflowed.get() {
+            self.queue_overflowed.set(false);
+            self.write_str("\r\n[output truncated]\r\n");
+        } else {
+            self.send_queued();
+        }
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, error: uart::Error) {
+        if error == uart::Error::CommandComplete && rx_len > 0 {
+            self.handle_byte(buffer[0]);
+        }
+        self.uart.receive(buffer, 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_command, Command, ParseError, ProcessSummary};
+    use core::fmt::{self, Write};
+    use core::str;
+    use kernel::procs::State;
+
+    /// Formats into a fixed buffer.
+    struct Buffer {
+        bytes: [u8; 128],
+        len: usize,
+    }
+
+    impl Buffer {
+        fn new() -> Buffer {
+            Buffer {
+                bytes: [0; 128],
+                len: 0,
+            }
+        }
+
+        fn as_str(&self) -> &str {
+            str::from_utf8(&self.bytes[..self.len]).unwrap()
+        }
+    }
+
+    impl fmt::Write for Buffer {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+            if end > self.bytes.len() {
+                return Err(fmt::Error);
+            }
+            self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+            self.len = end;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("help"), Ok(Command::Help));
+        assert_eq!(parse_command("  list \t"), Ok(Command::List));
+        assert_eq!(parse_command("status blink"), Ok(Command::Status("blink")));
+        assert_eq!(parse_command("stop  blink"), Ok(Command::Stop("blink")));
+        assert_eq!(parse_command("start blink "), Ok(Command::Start("blink")));
+        assert_eq!(parse_command("restart blink"), Ok(Command::Restart("blink")));
+    }
+
+    #[test]
+    fn rejects_invalid_commands() {
+        assert_eq!(parse_command(""), Err(ParseError::Empty));
+        assert_eq!(parse_command("   "), Err(ParseError::Empty));
+        assert_eq!(parse_command("reboot"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_command("Help"), Err(ParseError::UnknownCommand));
+        assert_eq!(parse_command("stop"), Err(ParseError::MissingName));
+        assert_eq!(parse_command("list all"), Err(ParseError::TooManyArguments));
+        assert_eq!(
+            parse_command("start blink now"),
+            Err(ParseError::TooManyArguments)
+        );
+    }
+
+    #[test]
+    fn formats_summary_under_header() {
+        let summary = ProcessSummary {
+            slot: 1,
+            name: "blink",
+            state: State::StoppedYielded,
+            memory_size: 8192,
+            grant_size: 312,
+            syscall_count: 1024,
+            dropped_callback_count: 0,
+            restart_count: 2,
+            cpu_time_ms: 35,
+        };
+        let mut line = Buffer::new();
+        write!(line, "{}", summary).unwrap();
+        assert_eq!(
+            line.as_str(),
+            concat!(
+                "1     blink             StoppedYielded   8192     312",
+                "      1024        0         2       35\r\n"
+            )
+        );
+
+        // The columns line up with the header.
+        assert_eq!(line.as_str().len(), ProcessSummary::HEADER.len());
+    }
+}
@@ -0,0 +1,197 @@
+//! Provides userspace with read-only statistics about running processes.
+//!
+//! This exposes the per-process accounting the kernel keeps (CPU time,
+//! syscall counts, timeslice expirations, etc.) so that an app can report
+//! telemetry for the whole board, for example to find apps that are using far
+//! more CPU than expected. Nothing about a process can be changed through this
+//! driver. A process can also subscribe to be told when its own callbacks are
+//! dropped because its task queue is full.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! struct ProcessMgmtCap;
+//! unsafe impl capabilities::ProcessManagementCapability for ProcessMgmtCap {}
+//! let process_info = static_init!(
+//!     capsules::process_info::ProcessInfo<ProcessMgmtCap>,
+//!     capsules::process_info::ProcessInfo::new(board_kernel, ProcessMgmtCap)
+//! );
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Stability: 0 - Draft
+//!
+//! ### Command
+//!
+//! All queries are synchronous, so they use the `command` syscall. Processes
+//! are identified by their slot in the kernel's processes array, starting at 0.
+//!
+//! #### `command_num`
+//!
+//! - `0`: Return the number of process slots on this platform.
+//!   - Return: Number of process slots.
+//! - `1`: Get a statistic for a process.
+//!   - `data1`: The process slot.
+//!   - `data2`: Which statistic, one of the `STAT_*` values.
+//!   - Return: The value, or `EINVAL` if there is no process in that slot or
+//!     the statistic does not exist.
+//! - `2`: Get the driver number of an entry in the per-driver syscall
+//!   histogram of a process.
+//!   - `data1`: The process slot.
+//!   - `data2`: The histogram entry, starting at 0.
+//!   - Return: The driver number, or `EINVAL` if the entry does not exist.
+//! - `3`: Get the number of syscalls to the driver of a histogram entry.
+//!   - `data1`: The process slot.
+//!   - `data2`: The histogram entry, starting at 0.
+//!   - Return: The syscall count, or `EINVAL` if the entry does not exist.
+//!
+//! ### Subscribe
+//!
+//! #### `subscribe_num`
+//!
+//! - `0`: Subscribe to dropped callbacks of the calling process. The callback
+//!   is queued the first time a callback for the process is dropped, and not
+//!   again until it has run. It is passed the number of callbacks dropped so
+//!   far, and the driver number and subscribe number of the latest dropped
+//!   callback (both `0xFFFFFFFF` if the kernel or IPC scheduled it).
+
+use kernel::capabilities::ProcessManagementCapability;
+use kernel::{AppId, Callback, Driver, Kernel, ReturnCode};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x10001;
+
+/// Number of syscalls the process has made.
+pub const STAT_SYSCALL_COUNT: usize = 0;
+/// Number of callbacks dropped because the task queue was full.
+pub const STAT_DROPPED_CALLBACK_COUNT: usize = 1;
+/// Number of times the process has been restarted after a fault.
+pub const STAT_RESTART_COUNT: usize = 2;
+/// Number of times the process used its entire timeslice.
+pub const STAT_TIMESLICE_EXPIRATION_COUNT: usize = 3;
+/// Total CPU time used by the process, in milliseconds.
+pub const STAT_CPU_TIME_MS: usize = 4;
+/// Longest time the process ran without returning to the kernel, in
+/// microseconds.
+pub const STAT_LONGEST_RUN_US: usize = 5;
+/// Number of driver syscalls not covered by the per-driver histogram.
+pub const STAT_UNTRACKED_DRIVER_SYSCALL_COUNT: usize = 6;
+/// Number of callbacks that can be queued for the process.
+pub const STAT_TASK_QUEUE_DEPTH: usize = 7;
+
+pub struct ProcessInfo<C: ProcessManagementCapability> {
+    kernel: &'static Kernel,
+    capability: C,
+}
+
+impl<C: ProcessManagementCapability> ProcessInfo<C> {
+    pub fn new(kernel: &'static Kernel, capability: C) -> ProcessInfo<C> {
+        ProcessInfo {
+            kernel: kernel,
+            capability: capability,
+        }
+    }
+}
+
+impl<C: ProcessManagementCapability> Driver for ProcessInfo<C> {
+    /// Subscribe to events for the calling process.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Callbacks for the process were dropped because its task queue
+    ///        was full.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.kernel.process_map_or_capability(
+                ReturnCode::FAIL,
+                app_id.idx(),
+                |process| {
+                    process.set_dropped_task_callback(callback);
+                    ReturnCode::SUCCESS
+                },
+                &self.capability,
+            ),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Query process statistics.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Returns the number of process slots. This will always be 0 or
+    ///        greater, and therefore also allows for checking for this driver.
+    /// - `1`: Returns the statistic `data2` for the process in slot `data1`.
+    /// - `2`: Returns the driver number of syscall histogram entry `data2` for
+    ///        the process in slot `data1`.
+    /// - `3`: Returns the syscall count of syscall histogram entry `data2` for
+    ///        the process in slot `data1`.
+    fn command(&self, command_num: usize, data1: usize, data2: usize, _: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SuccessWithValue {
+                value: self.kernel.number_of_process_slots(),
+            },
+
+            1 => self.kernel.process_map_or_capability(
+                ReturnCode::EINVAL,
+                data1,
+                |process| {
+                    let value = match data2 {
+                        STAT_SYSCALL_COUNT => process.debug_syscall_count(),
+                        STAT_DROPPED_CALLBACK_COUNT => process.debug_dropped_callback_count(),
+                        STAT_RESTART_COUNT => process.debug_restart_count(),
+                        STAT_TIMESLICE_EXPIRATION_COUNT => {
+                            process.debug_timeslice_expiration_count()
+                        }
+                        STAT_CPU_TIME_MS => (process.debug_cpu_time_us() / 1000) as usize,
+                        STAT_LONGEST_RUN_US => process.debug_longest_run_us() as usize,
+                        STAT_UNTRACKED_DRIVER_SYSCALL_COUNT => {
+                            process.debug_untracked_driver_syscall_count()
+                        }
+                        STAT_TASK_QUEUE_DEPTH => process.task_queue_depth(),
+                        _ => return ReturnCode::EINVAL,
+                    };
+                    ReturnCode::SuccessWithValue { value: value }
+                },
+                &self.capability,
+            ),
+
+            2 => self.kernel.process_map_or_capability(
+                ReturnCode::EINVAL,
+                data1,
+                |process| {
+                    process.debug_driver_syscall_count(data2).map_or(
+                        ReturnCode::EINVAL,
+                        |(driver_number, _)| ReturnCode::SuccessWithValue {
+                            value: driver_number,
+                        },
+                    )
+                },
+                &self.capability,
+            ),
+
+            3 => self.kernel.process_map_or_capability(
+                ReturnCode::EINVAL,
+                data1,
+                |process| {
+                    process
+                        .debug_driver_syscall_count(data2)
+                        .map_or(ReturnCode::EINVAL, |(_, count)| {
+                            ReturnCode::SuccessWithValue { value: count }
+                        })
+                },
+                &self.capability,
+            ),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -2,6 +2,14 @@
 //!
 //! This allows initialization and block reads or writes on top of SPI.
 //!
+//! Several blocks can be read or written with one command (CMD18 and CMD25),
+//! so that long transfers only pay for the command once. Before writing
+//! several blocks to an SD card, the card is told how many will follow
+//! (ACMD23), so that it can erase them ahead of time. Every command carries
+//! its CRC7, and if the card accepts CRC checking (CMD59), the CRC16 of each
+//! data block is checked on reads and sent with writes, so that a corrupted
+//! transfer is reported as an error rather than returning wrong data.
+//!
 //! Usage
 //! -----
 //!
@@ -41,7 +49,7 @@ use core::cell::Cell;
 use core::cmp;
 use kernel::common::cells::{MapCell, OptionalCell, TakeCell};
 use kernel::hil;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::{AppId, AppSlice, Callback, Driver, ReturnCode, Shared};
 
 /// Syscall driver number.
@@ -64,9 +72,12 @@ pub struct SDCard<'a, A: hil::time::Alarm> {
     alarm: &'a A,
     alarm_state: Cell<AlarmState>,
     alarm_count: Cell<u8>,
+    poll_count: Cell<u8>,
 
     is_initialized: Cell<bool>,
     card_type: Cell<SDCardType>,
+    crc_enabled: Cell<bool>,
+    size: Cell<u64>,
 
     detect_pin: Cell<Option<&'static hil::gpio::Pin>>,
 
@@ -94,6 +105,8 @@ enum SDCmd {
     CMD25_WriteMultiple = 25,             //        Write multiple blocks
     CMD55_ManufSpecificCommand = 55,      // Next command will be manufacturer specific
     CMD58_ReadOCR = 58,                   //              Read operation condition register (OCR)
+    CMD59_CrcOnOff = 59,                  //             Turn CRC checking on or off
+    ACMD23_PreErase = 0x80 + 23,          //          Number of blocks to pre-erase for a write
     ACMD41_ManufSpecificInit = 0x80 + 41, // Manufacturer specific Init
 }
 
@@ -122,6 +135,7 @@ enum SpiState {
     InitRepeatAppSpecificInit,
     InitRepeatGenericInit,
     InitSetBlocksize,
+    InitEnableCrc,
     InitComplete,
 
     StartReadBlocks { count: u32 },
@@ -130,11 +144,14 @@ enum SpiState {
     WaitReadBlocks { count: u32 },
     ReceivedBlock { count: u32 },
     ReadBlocksComplete,
+    ReadBlocksFailed,
 
+    PreEraseBlocks { address: u32, count: u32 },
     StartWriteBlocks { count: u32 },
-    WriteBlockResponse,
-    WriteBlockBusy,
-    WaitWriteBlockBusy,
+    WriteBlockResponse { count: u32, multiple: bool },
+    WriteBlockBusy { count: u32, multiple: bool },
+    WaitWriteBlockBusy { count: u32, multiple: bool },
+    StopWriteBlocks { error: Option<ErrorCode> },
 }
 
 /// Alarm states
@@ -151,7 +168,7 @@ enum AlarmState {
     WaitForDataBlock,
     WaitForDataBlocks { count: u32 },
 
-    WaitForWriteBusy,
+    WaitForWriteBusy { count: u32, multiple: bool },
 }
 
 /// Error codes returned if an SD card transaction fails
@@ -162,6 +179,7 @@ enum ErrorCode {
     ReadFailure = -3,
     WriteFailure = -4,
     TimeoutFailure = -5,
+    CrcFailure = -6,
 }
 
 /// SD card types, determined during initialization
@@ -178,6 +196,12 @@ enum SDCardType {
 const SUCCESS_STATUS: u8 = 0x00;
 const INITIALIZING_STATUS: u8 = 0x01;
 const DATA_TOKEN: u8 = 0xFE;
+const WRITE_MULTIPLE_TOKEN: u8 = 0xFC;
+const STOP_TRAN_TOKEN: u8 = 0xFD;
+
+// Number of times to check a busy card again right away, before waiting 1 ms
+// between checks
+const FAST_POLLS: u8 = 16;
 
 /// Callback functions from SDCard
 pub trait SDCardClient {
@@ -229,8 +253,11 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
             alarm: alarm,
             alarm_state: Cell::new(AlarmState::Idle),
             alarm_count: Cell::new(0),
+            poll_count: Cell::new(0),
             is_initialized: Cell::new(false),
             card_type: Cell::new(SDCardType::Uninitialized),
+            crc_enabled: Cell::new(false),
+            size: Cell::new(0),
             detect_pin: Cell::new(pin),
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
@@ -260,6 +287,12 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         );
     }
 
+    /// set the alarm to fire `ms` milliseconds from now
+    fn set_alarm_ms(&self, ms: u64) {
+        let interval = A::Ticks::from_u64(<A::Frequency>::ms_to_ticks(ms));
+        self.alarm.set_alarm(self.alarm.now().wrapping_add(interval));
+    }
+
     /// send a command over SPI and collect the response
     /// Handles encoding of command, checksum, and padding bytes. The response
     /// still needs to be parsed out of the read_buffer when complete
@@ -302,14 +335,9 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         write_buffer[5] = ((arg >> 8) & 0xFF) as u8;
         write_buffer[6] = ((arg >> 0) & 0xFF) as u8;
 
-        // CRC is ignored except for CMD0 and maybe CMD8
-        // Established practice it to just always use the CMD0 CRC unless we
-        // are sending a CMD8
-        if cmd == SDCmd::CMD8_CheckVoltage {
-            write_buffer[7] = 0x87; // valid crc for CMD8(0x1AA)
-        } else {
-            write_buffer[7] = 0x95; // valid crc for CMD0
-        }
+        // CRC7 of the command, followed by the end bit. Cards only check it
+        //  for CMD0 and CMD8 until CRC checking is turned on with CMD59
+        write_buffer[7] = (crc7(&write_buffer[2..7]) << 1) | 0x01;
 
         // append dummy bytes to transmission after command bytes
         // Limit to minimum length between write_buffer and recv_len
@@ -401,6 +429,93 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         (r1, r2, r3)
     }
 
+    /// check the card again while it is not ready
+    /// The first few checks are done right away, since cards are often ready
+    /// within a few bytes. After that, check again every millisecond
+    fn poll_again(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        state: SpiState,
+        alarm_state: AlarmState,
+    ) {
+        let polls = self.poll_count.get();
+        if polls < FAST_POLLS {
+            self.poll_count.set(polls + 1);
+            self.state.set(state);
+            self.read_bytes(write_buffer, read_buffer, 1);
+        } else {
+            // replace buffers
+            self.txbuffer.replace(write_buffer);
+            self.rxbuffer.replace(read_buffer);
+
+            // try again after 1 ms
+            self.alarm_state.set(alarm_state);
+            self.set_alarm_ms(1);
+        }
+    }
+
+    /// check the CRC16 that follows a data block, if the card sends valid ones
+    fn block_crc_valid(&self, block: &[u8]) -> bool {
+        !self.crc_enabled.get() || crc16(&block[0..512]) == get_u16_be(&block[512..514])
+    }
+
+    /// send the block of the client buffer at the current offset
+    /// Writes of several blocks use a different token for each block, and
+    ///  `count` is the number of blocks left including this one
+    fn write_data_block(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        count: u32,
+        multiple: bool,
+    ) {
+        let offset = self.client_offset.get();
+        let bytes_written = self.client_buffer.map_or(0, |buffer| {
+            // copy over data from client buffer
+            // Limit to minimum length between write_buffer, buffer, and 512
+            // (block size)
+            for (write_byte, &client_byte) in write_buffer
+                .iter_mut()
+                .skip(1)
+                .zip(buffer.iter().skip(offset))
+                .take(512)
+            {
+                *write_byte = client_byte;
+            }
+
+            // calculate number of bytes written
+            cmp::min(write_buffer.len(), cmp::min(buffer.len().saturating_sub(offset), 512))
+        });
+
+        // set a known value for remaining bytes
+        for write_byte in write_buffer
+            .iter_mut()
+            .skip(1)
+            .skip(bytes_written)
+            .take(512 - bytes_written)
+        {
+            *write_byte = 0xFF;
+        }
+
+        // set up remainder of data packet
+        write_buffer[0] = if multiple {
+            WRITE_MULTIPLE_TOKEN
+        } else {
+            DATA_TOKEN
+        };
+        let crc = crc16(&write_buffer[1..513]);
+        write_buffer[513] = (crc >> 8) as u8;
+        write_buffer[514] = (crc & 0xFF) as u8;
+
+        // write data packet
+        self.state.set(SpiState::WriteBlockResponse {
+            count: count,
+            multiple: multiple,
+        });
+        self.write_bytes(write_buffer, read_buffer, 515);
+    }
+
     /// updates SD card state on SPI transaction returns
     fn process_spi_states(
         &self,
@@ -505,9 +620,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
                     // try again after 10 ms
                     self.alarm_state.set(AlarmState::RepeatHCSInit);
-                    let interval = (10 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.set_alarm_ms(10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -532,11 +645,9 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                         self.card_type.set(SDCardType::SDv2);
                     }
 
-                    // Read CSD register
-                    // Note that the receive length needs to be increased here
-                    //  to capture the 16-byte register (plus some slack)
-                    self.state.set(SpiState::InitComplete);
-                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
+                    // turn on CRC checking
+                    self.state.set(SpiState::InitEnableCrc);
+                    self.send_command(SDCmd::CMD59_CrcOnOff, 0x1, write_buffer, read_buffer, 10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -602,9 +713,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
                     // try again after 10 ms
                     self.alarm_state.set(AlarmState::RepeatAppSpecificInit);
-                    let interval = (10 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.set_alarm_ms(10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -641,9 +750,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
                     // try again after 10 ms
                     self.alarm_state.set(AlarmState::RepeatGenericInit);
-                    let interval = (10 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.set_alarm_ms(10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -662,11 +769,9 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    // Read CSD register
-                    // Note that the receive length needs to be increased here
-                    //  to capture the 16-byte register (plus some slack)
-                    self.state.set(SpiState::InitComplete);
-                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
+                    // turn on CRC checking
+                    self.state.set(SpiState::InitEnableCrc);
+                    self.send_command(SDCmd::CMD59_CrcOnOff, 0x1, write_buffer, read_buffer, 10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -680,43 +785,42 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 }
             }
 
+            SpiState::InitEnableCrc => {
+                // check response
+                // Cards that do not support CRC checking still work without
+                //  it, but their data CRCs are not checked
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+                self.crc_enabled.set(r1 == SUCCESS_STATUS);
+
+                // Read CSD register
+                // Note that the receive length needs to be increased here
+                //  to capture the 16-byte register and its CRC (plus some
+                //  slack)
+                self.state.set(SpiState::InitComplete);
+                self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 40);
+            }
+
             SpiState::InitComplete => {
                 // check response
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
+                // find CSD register value
+                // Slide through 19-byte windows searching for beginning of
+                // the CSD register, which is followed by its CRC
+                let mut total_size: Option<u64> = None;
                 if r1 == SUCCESS_STATUS {
-                    let mut total_size: u64 = 0;
-
-                    // find CSD register value
-                    // Slide through 12-byte windows searching for beginning of
-                    // the CSD register
-                    for buf in read_buffer.windows(12) {
+                    for buf in read_buffer.windows(19) {
                         if buf[0] == DATA_TOKEN {
-                            // get total size from CSD
-                            if (buf[1] & 0xC0) == 0x00 {
-                                // CSD version 1.0
-                                let c_size = (((buf[7] & 0x03) as u32) << 10)
-                                    | (((buf[8] & 0xFF) as u32) << 2)
-                                    | (((buf[9] & 0xC0) as u32) >> 6);
-                                let c_size_mult = (((buf[10] & 0x03) as u32) << 1)
-                                    | (((buf[11] & 0x80) as u32) >> 7);
-                                let read_bl_len = (buf[6] & 0x0F) as u32;
-
-                                let block_count = (c_size + 1) * (1 << (c_size_mult + 2));
-                                let block_len = 1 << read_bl_len;
-                                total_size = block_count as u64 * block_len as u64;
-                            } else {
-                                // CSD version 2.0
-                                let c_size = (((buf[8] & 0x3F) as u32) << 16)
-                                    | (((buf[9] & 0xFF) as u32) << 8)
-                                    | ((buf[10] & 0xFF) as u32);
-                                total_size = ((c_size as u64) + 1) * 512 * 1024;
+                            let csd = &buf[1..17];
+                            if !self.crc_enabled.get() || crc16(csd) == get_u16_be(&buf[17..19]) {
+                                total_size = csd_size(csd, self.card_type.get());
                             }
-
                             break;
                         }
                     }
+                }
 
+                if let Some(total_size) = total_size {
                     // replace buffers
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
@@ -724,6 +828,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     // initialization complete
                     self.state.set(SpiState::Idle);
                     self.is_initialized.set(true);
+                    self.size.set(total_size);
 
                     // perform callback
                     self.client.map(move |client| {
@@ -773,20 +878,17 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 if read_buffer[0] == DATA_TOKEN {
                     // data ready to read. Read block plus CRC
                     self.alarm_count.set(0);
+                    self.poll_count.set(0);
                     self.state.set(SpiState::ReadBlockComplete);
                     self.read_bytes(write_buffer, read_buffer, 512 + 2);
                 } else if read_buffer[0] == 0xFF {
                     // line is idling high, data is not ready
-
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForDataBlock);
-                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.poll_again(
+                        write_buffer,
+                        read_buffer,
+                        SpiState::WaitReadBlock,
+                        AlarmState::WaitForDataBlock,
+                    );
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -801,6 +903,19 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
             }
 
             SpiState::ReadBlockComplete => {
+                if !self.block_crc_valid(read_buffer) {
+                    // error, send callback and quit
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+                    self.state.set(SpiState::Idle);
+                    self.alarm_state.set(AlarmState::Idle);
+                    self.alarm_count.set(0);
+                    self.client.map(move |client| {
+                        client.error(ErrorCode::CrcFailure as u32);
+                    });
+                    return;
+                }
+
                 // replace buffers
                 self.txbuffer.replace(write_buffer);
                 self.rxbuffer.replace(read_buffer);
@@ -831,21 +946,17 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 if read_buffer[0] == DATA_TOKEN {
                     // data ready to read. Read block plus CRC
                     self.alarm_count.set(0);
+                    self.poll_count.set(0);
                     self.state.set(SpiState::ReceivedBlock { count: count });
                     self.read_bytes(write_buffer, read_buffer, 512 + 2);
                 } else if read_buffer[0] == 0xFF {
                     // line is idling high, data is not ready
-
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // try again after 1 ms
-                    self.alarm_state
-                        .set(AlarmState::WaitForDataBlocks { count: count });
-                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    self.poll_again(
+                        write_buffer,
+                        read_buffer,
+                        SpiState::WaitReadBlocks { count: count },
+                        AlarmState::WaitForDataBlocks { count: count },
+                    );
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -860,6 +971,13 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
             }
 
             SpiState::ReceivedBlock { count } => {
+                if !self.block_crc_valid(read_buffer) {
+                    // stop the card from sending more blocks before failing
+                    self.state.set(SpiState::ReadBlocksFailed);
+                    self.send_command(SDCmd::CMD12_StopRead, 0x0, write_buffer, read_buffer, 10);
+                    return;
+                }
+
                 // copy block over to client buffer
                 self.client_buffer.map(|buffer| {
                     // copy block into client buffer
@@ -876,7 +994,7 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                     }
 
                     // update offset
-                    let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
+                    let read_len = cmp::min(buffer.len().saturating_sub(offset), 512);
                     self.client_offset.set(offset + read_len);
                 });
 
@@ -894,7 +1012,10 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
 
             SpiState::ReadBlocksComplete => {
                 // check response
-                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+                // The card keeps sending data while the command goes out,
+                //  and the byte after the command is a stuff byte, so the
+                //  response starts after both
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, &read_buffer[9..]);
 
                 if r1 == SUCCESS_STATUS {
                     // replace buffers
@@ -921,57 +1042,32 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 }
             }
 
-            SpiState::StartWriteBlocks { count } => {
+            SpiState::ReadBlocksFailed => {
+                // the card stopped sending, whatever the response is
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+                self.state.set(SpiState::Idle);
+                self.alarm_state.set(AlarmState::Idle);
+                self.alarm_count.set(0);
+                self.client.map(move |client| {
+                    client.error(ErrorCode::CrcFailure as u32);
+                });
+            }
+
+            SpiState::PreEraseBlocks { address, count } => {
                 // check response
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    if count <= 1 {
-                        let bytes_written = self.client_buffer.map_or(0, |buffer| {
-                            // copy over data from client buffer
-                            // Limit to minimum length between write_buffer,
-                            // buffer, and 512 (block size)
-                            for (write_byte, &client_byte) in
-                                write_buffer.iter_mut().skip(1).zip(buffer.iter()).take(512)
-                            {
-                                *write_byte = client_byte;
-                            }
-
-                            // calculate number of bytes written
-                            cmp::min(write_buffer.len(), cmp::min(buffer.len(), 512))
-                        });
-
-                        // set a known value for remaining bytes
-                        for write_byte in write_buffer
-                            .iter_mut()
-                            .skip(1)
-                            .skip(bytes_written)
-                            .take(512)
-                        {
-                            *write_byte = 0xFF;
-                        }
-
-                        // set up remainder of data packet
-                        write_buffer[0] = DATA_TOKEN; // Data token
-                        write_buffer[513] = 0xFF; // dummy CRC
-                        write_buffer[514] = 0xFF; // dummy CRC
-
-                        // write data packet
-                        self.state.set(SpiState::WriteBlockResponse);
-                        self.write_bytes(write_buffer, read_buffer, 515);
-                    } else {
-                        // multi-block SD card writes are unimplemented
-                        // This should have returned an error already, but if
-                        // we got here somehow, return an error and quit now
-                        self.txbuffer.replace(write_buffer);
-                        self.rxbuffer.replace(read_buffer);
-                        self.state.set(SpiState::Idle);
-                        self.alarm_state.set(AlarmState::Idle);
-                        self.alarm_count.set(0);
-                        self.client.map(move |client| {
-                            client.error(ErrorCode::WriteFailure as u32);
-                        });
-                    }
+                    // start writing the pre-erased blocks
+                    self.state.set(SpiState::StartWriteBlocks { count: count });
+                    self.send_command(
+                        SDCmd::CMD25_WriteMultiple,
+                        address,
+                        write_buffer,
+                        read_buffer,
+                        10,
+                    );
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -985,17 +1081,14 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 }
             }
 
-            SpiState::WriteBlockResponse => {
-                // Get data packet
-                self.state.set(SpiState::WriteBlockBusy);
-                self.read_bytes(write_buffer, read_buffer, 1);
-            }
+            SpiState::StartWriteBlocks { count } => {
+                // check response
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
-            SpiState::WriteBlockBusy => {
-                if (read_buffer[0] & 0x1F) == 0x05 {
-                    // check if sd card is busy
-                    self.state.set(SpiState::WaitWriteBlockBusy);
-                    self.read_bytes(write_buffer, read_buffer, 1);
+                if r1 == SUCCESS_STATUS {
+                    // send the first block. Writes of more than one block
+                    //  were started with CMD25
+                    self.write_data_block(write_buffer, read_buffer, count, count > 1);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
@@ -1009,31 +1102,115 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 }
             }
 
-            SpiState::WaitWriteBlockBusy => {
+            SpiState::WriteBlockResponse { count, multiple } => {
+                // Get data packet
+                self.state.set(SpiState::WriteBlockBusy {
+                    count: count,
+                    multiple: multiple,
+                });
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
+
+            SpiState::WriteBlockBusy { count, multiple } => {
+                if (read_buffer[0] & 0x1F) == 0x05 {
+                    // check if sd card is busy
+                    self.state.set(SpiState::WaitWriteBlockBusy {
+                        count: count,
+                        multiple: multiple,
+                    });
+                    self.read_bytes(write_buffer, read_buffer, 1);
+                } else {
+                    // error, send callback and quit
+                    // 0x0B means the card received the block with a bad CRC
+                    let error = if (read_buffer[0] & 0x1F) == 0x0B {
+                        ErrorCode::CrcFailure
+                    } else {
+                        ErrorCode::WriteFailure
+                    };
+                    if multiple {
+                        // the card ignores the rest of the blocks, but still
+                        //  has to be told the write is over
+                        write_buffer[0] = STOP_TRAN_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::StopWriteBlocks { error: Some(error) });
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    } else {
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+                        self.state.set(SpiState::Idle);
+                        self.alarm_state.set(AlarmState::Idle);
+                        self.alarm_count.set(0);
+                        self.client.map(move |client| {
+                            client.error(error as u32);
+                        });
+                    }
+                }
+            }
+
+            SpiState::WaitWriteBlockBusy { count, multiple } => {
                 // check if line is still held low (busy state)
                 if read_buffer[0] != 0x00 {
-                    // replace buffers
+                    self.alarm_count.set(0);
+                    self.poll_count.set(0);
+
+                    if count > 1 {
+                        // send the next block
+                        self.client_offset.set(self.client_offset.get() + 512);
+                        self.write_data_block(write_buffer, read_buffer, count - 1, multiple);
+                    } else if multiple {
+                        // all blocks written. Terminate multiple write, after
+                        //  which the card is busy again
+                        write_buffer[0] = STOP_TRAN_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::StopWriteBlocks { error: None });
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    } else {
+                        // replace buffers
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+
+                        // write finished, perform callback
+                        self.state.set(SpiState::Idle);
+                        self.client_buffer.take().map(move |buffer| {
+                            self.client.map(move |client| {
+                                client.write_done(buffer);
+                            });
+                        });
+                    }
+                } else {
+                    self.poll_again(
+                        write_buffer,
+                        read_buffer,
+                        SpiState::WaitWriteBlockBusy {
+                            count: count,
+                            multiple: multiple,
+                        },
+                        AlarmState::WaitForWriteBusy {
+                            count: count,
+                            multiple: multiple,
+                        },
+                    );
+                }
+            }
+
+            SpiState::StopWriteBlocks { error } => {
+                if let Some(error) = error {
+                    // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-
-                    // read finished, perform callback
                     self.state.set(SpiState::Idle);
+                    self.alarm_state.set(AlarmState::Idle);
                     self.alarm_count.set(0);
-                    self.client_buffer.take().map(move |buffer| {
-                        self.client.map(move |client| {
-                            client.write_done(buffer);
-                        });
+                    self.client.map(move |client| {
+                        client.error(error as u32);
                     });
                 } else {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForWriteBusy);
-                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
-                    let tics = self.alarm.now().wrapping_add(interval);
-                    self.alarm.set_alarm(tics);
+                    // wait for the card to finish programming
+                    self.state.set(SpiState::WaitWriteBlockBusy {
+                        count: 0,
+                        multiple: false,
+                    });
+                    self.read_bytes(write_buffer, read_buffer, 1);
                 }
             }
 
@@ -1161,12 +1338,15 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
-            AlarmState::WaitForWriteBusy => {
+            AlarmState::WaitForWriteBusy { count, multiple } => {
                 // check card initialization again
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // check if sd card is busy
-                        self.state.set(SpiState::WaitWriteBlockBusy);
+                        self.state.set(SpiState::WaitWriteBlockBusy {
+                            count: count,
+                            multiple: multiple,
+                        });
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
                 });
@@ -1197,6 +1377,24 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         self.is_initialized.get()
     }
 
+    /// Size of the card in bytes, from its CSD register. Standard capacity
+    /// cards hold up to 2 GB, SDHC cards up to 32 GB, and SDXC cards up to
+    /// 2 TB. Zero until the card is initialized.
+    pub fn size(&self) -> u64 {
+        self.size.get()
+    }
+
+    /// Whether `count` blocks starting at block `sector` are all on the card
+    fn blocks_on_card(&self, sector: u32, count: u32) -> bool {
+        count > 0 && (sector as u64 + count as u64) * 512 <= self.size.get()
+    }
+
+    /// Take back the buffer of a read or write that could not be started or
+    /// that failed with an `error()` callback.
+    pub fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        self.client_buffer.take()
+    }
+
     /// watches SD card detect pin for changes, sends callback on change
     pub fn detect_changes(&self) {
         self.detect_pin.get().map(|pin| {
@@ -1207,6 +1405,8 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
     pub fn initialize(&self) -> ReturnCode {
         // if not already, set card to uninitialized again
         self.is_initialized.set(false);
+        self.crc_enabled.set(false);
+        self.size.set(0);
 
         // no point in initializing if the card is not installed
         if self.is_installed() {
@@ -1228,18 +1428,29 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         }
     }
 
+    /// Read `count` blocks starting at block `sector` into `buffer`, which
+    /// should hold `count` blocks of 512 bytes. More than one block is read
+    /// with a single command. `read_done()` gets the number of bytes read.
     pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
+        // save the user buffer for later, where `take_buffer()` can get it
+        //  back if the read fails
+        self.client_buffer.replace(buffer);
+        self.client_offset.set(0);
+        self.poll_count.set(0);
+
         // only if initialized and installed
         if self.is_installed() {
-            if self.is_initialized() {
+            if !self.is_initialized() {
+                // sd card not initialized
+                ReturnCode::ERESERVE
+            } else if !self.blocks_on_card(sector, count) {
+                // no blocks, or past the end of the card
+                ReturnCode::EINVAL
+            } else {
                 self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
                     self.rxbuffer
                         .take()
                         .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
                             // convert block address to byte address for non-block
                             //  access cards
                             let mut address = sector;
@@ -1270,9 +1481,6 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                             ReturnCode::SUCCESS
                         })
                 })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
             }
         } else {
             // sd card not installed
@@ -1280,18 +1488,30 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
         }
     }
 
+    /// Write `count` blocks from `buffer` starting at block `sector`. More
+    /// than one block is written with a single command, after telling SD
+    /// cards how many blocks to erase ahead of time. Blocks past the end of
+    /// `buffer` are filled with 0xFF.
     pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
+        // save the user buffer for later, where `take_buffer()` can get it
+        //  back if the write fails
+        self.client_buffer.replace(buffer);
+        self.client_offset.set(0);
+        self.poll_count.set(0);
+
         // only if initialized and installed
         if self.is_installed() {
-            if self.is_initialized() {
+            if !self.is_initialized() {
+                // sd card not initialized
+                ReturnCode::ERESERVE
+            } else if !self.blocks_on_card(sector, count) {
+                // no blocks, or past the end of the card
+                ReturnCode::EINVAL
+            } else {
                 self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
                     self.rxbuffer
                         .take()
                         .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
                             // convert block address to byte address for non-block
                             //  access cards
                             let mut address = sector;
@@ -1299,8 +1519,8 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                                 address *= 512;
                             }
 
-                            self.state.set(SpiState::StartWriteBlocks { count: count });
                             if count == 1 {
+                                self.state.set(SpiState::StartWriteBlocks { count: count });
                                 self.send_command(
                                     SDCmd::CMD24_WriteSingle,
                                     address,
@@ -1308,18 +1528,40 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
                                     rxbuffer,
                                     10,
                                 );
-
-                                // command started successfully
-                                ReturnCode::SUCCESS
+                            } else if self.card_type.get() == SDCardType::MMC {
+                                // MMC cards cannot pre-erase
+                                self.state.set(SpiState::StartWriteBlocks { count: count });
+                                self.send_command(
+                                    SDCmd::CMD25_WriteMultiple,
+                                    address,
+                                    txbuffer,
+                                    rxbuffer,
+                                    10,
+                                );
                             } else {
-                                // can't write multiple blocks yet
-                                ReturnCode::ENOSUPPORT
+                                // tell the card how many blocks to pre-erase,
+                                //  then write them
+                                self.state.set(SpiState::SendManufSpecificCmd {
+                                    cmd: SDCmd::ACMD23_PreErase,
+                                    arg: count,
+                                });
+                                self.after_state.set(SpiState::PreEraseBlocks {
+                                    address: address,
+                                    count: count,
+                                });
+                                self.send_command(
+                                    SDCmd::CMD55_ManufSpecificCommand,
+                                    0x0,
+                                    txbuffer,
+                                    rxbuffer,
+                                    10,
+                                );
                             }
+
+                            // command started successfully
+                            ReturnCode::SUCCESS
                         })
                 })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
             }
         } else {
             // sd card not installed
@@ -1328,6 +1570,75 @@ impl<A: hil::time::Alarm> SDCard<'a, A> {
     }
 }
 
+/// CRC7 of the bytes of a command, as the 7 high bits of the last byte
+fn crc7(bytes: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in bytes {
+        for bit in (0..8).rev() {
+            let feedback = ((byte >> bit) ^ (crc >> 6)) & 0x01;
+            crc = (crc << 1) & 0x7F;
+            if feedback != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+/// CRC16 (CCITT) of a data block or register, which cards send after it
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if (crc & 0x8000) != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+fn get_u16_be(bytes: &[u8]) -> u16 {
+    (bytes[0] as u16) << 8 | (bytes[1] as u16)
+}
+
+/// Size of the card in bytes from the 16 bytes of its CSD register, or None
+/// if the register has a version this driver does not know
+fn csd_size(csd: &[u8], card_type: SDCardType) -> Option<u64> {
+    // MMC cards use the layout of version 1.0 for all of their versions
+    let version = if card_type == SDCardType::MMC {
+        0
+    } else {
+        csd[0] >> 6
+    };
+    match version {
+        0 => {
+            // CSD version 1.0, standard capacity
+            let c_size = (((csd[6] & 0x03) as u32) << 10)
+                | (((csd[7] & 0xFF) as u32) << 2)
+                | (((csd[8] & 0xC0) as u32) >> 6);
+            let c_size_mult = (((csd[9] & 0x03) as u32) << 1) | (((csd[10] & 0x80) as u32) >> 7);
+            let read_bl_len = (csd[5] & 0x0F) as u32;
+
+            let block_count = (c_size + 1) * (1 << (c_size_mult + 2));
+            let block_len = 1 << read_bl_len;
+            Some(block_count as u64 * block_len as u64)
+        }
+        1 => {
+            // CSD version 2.0, high (SDHC) and extended (SDXC) capacity
+            // The 22 bit size counts units of 512 KB
+            let c_size = (((csd[7] & 0x3F) as u32) << 16)
+                | (((csd[8] & 0xFF) as u32) << 8)
+                | ((csd[9] & 0xFF) as u32);
+            Some(((c_size as u64) + 1) * 512 * 1024)
+        }
+        _ => None,
+    }
+}
+
 /// Handle callbacks from the SPI peripheral
 impl<A: hil::time::Alarm> hil::spi::SpiMasterClient for SDCard<'a, A> {
     fn read_write_done(
@@ -1374,9 +1685,7 @@ impl<A: hil::time::Alarm> hil::gpio::Client for SDCard<'a, A> {
 
         // run a timer for 500 ms in order to let the sd card settle
         self.alarm_state.set(AlarmState::DetectionChange);
-        let interval = (500 as u32) * <A::Frequency>::frequency() / 1000;
-        let tics = self.alarm.now().wrapping_add(interval);
-        self.alarm.set_alarm(tics);
+        self.set_alarm_ms(500);
     }
 }
 
@@ -1578,3 +1887,128 @@ impl<A: hil::time::Alarm> Driver for SDCardDriver<'a, A> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use super::{crc16, crc7, SDCard};
+    use kernel::hil::spi::{ClockPhase, ClockPolarity, SpiMasterDevice};
+    use kernel::hil::time::{Alarm, Freq32KHz, Ticks32, Time};
+    use kernel::ReturnCode;
+
+    /// A bus that is never used, as the tests only check received blocks.
+    struct NoSpi;
+
+    impl SpiMasterDevice for NoSpi {
+        fn configure(&self, _: ClockPolarity, _: ClockPhase, _: u32) {}
+
+        fn read_write_bytes(
+            &self,
+            _: &'static mut [u8],
+            _: Option<&'static mut [u8]>,
+            _: usize,
+        ) -> ReturnCode {
+            ReturnCode::FAIL
+        }
+
+        fn set_polarity(&self, _: ClockPolarity) {}
+
+        fn set_phase(&self, _: ClockPhase) {}
+
+        fn set_rate(&self, _: u32) {}
+
+        fn get_polarity(&self) -> ClockPolarity {
+            ClockPolarity::IdleLow
+        }
+
+        fn get_phase(&self) -> ClockPhase {
+            ClockPhase::SampleLeading
+        }
+
+        fn get_rate(&self) -> u32 {
+            0
+        }
+    }
+
+    struct NoAlarm;
+
+    impl Time for NoAlarm {
+        type Frequency = Freq32KHz;
+
+        fn disable(&self) {}
+
+        fn is_armed(&self) -> bool {
+            false
+        }
+    }
+
+    impl Alarm for NoAlarm {
+        type Ticks = Ticks32;
+
+        fn now(&self) -> Ticks32 {
+            Ticks32(0)
+        }
+
+        fn set_alarm(&self, _: Ticks32) {}
+
+        fn get_alarm(&self) -> Ticks32 {
+            Ticks32(0)
+        }
+    }
+
+    /// The CRC byte that ends a command: its CRC7 followed by the end bit.
+    fn command_crc(command: u8, arg: u32) -> u8 {
+        let bytes = [
+            0x40 | command,
+            (arg >> 24) as u8,
+            (arg >> 16) as u8,
+            (arg >> 8) as u8,
+            arg as u8,
+        ];
+        (crc7(&bytes) << 1) | 0x01
+    }
+
+    #[test]
+    fn command_crcs_match_the_spec() {
+        // The two commands cards check the CRC of before CRC checking is on.
+        assert_eq!(command_crc(0, 0), 0x95);
+        assert_eq!(command_crc(8, 0x1AA), 0x87);
+        // CMD17 reading block 0, from the SD specification's examples.
+        assert_eq!(command_crc(17, 0), 0x55);
+    }
+
+    #[test]
+    fn data_crc_matches_xmodem() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(&[0xFF; 512]), 0x7FA1);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[test]
+    fn checks_block_crcs_when_enabled() {
+        let sdcard = SDCard::new(
+            &NoSpi,
+            &NoAlarm,
+            None,
+            Box::leak(Box::new([0; 515])),
+            Box::leak(Box::new([0; 515])),
+        );
+        let mut block = [0xFF; 514];
+        block[512] = 0x7F;
+        block[513] = 0xA1;
+
+        sdcard.crc_enabled.set(true);
+        assert!(sdcard.block_crc_valid(&block));
+        block[100] = 0xFE;
+        assert!(!sdcard.block_crc_valid(&block));
+        block[100] = 0xFF;
+        block[513] = 0xA0;
+        assert!(!sdcard.block_crc_valid(&block));
+
+        // Without CRC checking, cards send no meaningful CRC.
+        sdcard.crc_enabled.set(false);
+        assert!(sdcard.block_crc_valid(&block));
+    }
+}
@@ -21,10 +21,9 @@
 //! Notes
 //! -----
 //!
-//! This capsule requires a timer, but the timer is only there to defer the
-//! `transmit_complete` callback until the next scheduler loop. In the future,
-//! if there is support for software interrupts or deferred calls in capsules,
-//! this timer should be removed.
+//! Writing a message completes immediately, so this capsule uses a deferred
+//! call to issue the `transmit_complete` callback from the main loop rather
+//! than from inside `transmit()`.
 //!
 //! Todo
 //! ----
@@ -37,24 +36,14 @@
 //! ```
 //! pub struct Platform {
 //!     // Other fields omitted for clarity
-//!     console: &'static capsules::console::Console<
-//!         'static,
-//!         capsules::segger_rtt::SeggerRtt<
-//!             'static,
-//!             capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
-//!         >,
-//!     >,
+//!     console: &'static capsules::console::Console<'static, capsules::segger_rtt::SeggerRtt>,
 //! }
 //! ```
 //!
-//! In `reset_handler()`:
+//! In `reset_handler()`, with a `DynamicDeferredCall` that is also passed to
+//! `Kernel::set_dynamic_deferred_call()`:
 //!
 //! ```
-//! let virtual_alarm_rtt = static_init!(
-//!     capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
-//!     capsules::virtual_alarm::VirtualMuxAlarm::new(mux_alarm)
-//! );
-//!
 //! let rtt_memory = static_init!(
 //!     capsules::segger_rtt::SeggerRttMemory,
 //!     capsules::segger_rtt::SeggerRttMemory::new(b"Terminal\0",
@@ -64,20 +53,19 @@
 //! );
 //!
 //! let rtt = static_init!(
-//!     capsules::segger_rtt::SeggerRtt<VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>>,
-//!     capsules::segger_rtt::SeggerRtt::new(virtual_alarm_rtt, rtt_memory,
+//!     capsules::segger_rtt::SeggerRtt,
+//!     capsules::segger_rtt::SeggerRtt::new(dynamic_deferred_caller, rtt_memory,
 //!         &mut capsules::segger_rtt::UP_BUFFER,
 //!         &mut capsules::segger_rtt::DOWN_BUFFER)
 //! );
-//! virtual_alarm_rtt.set_client(rtt);
+//! rtt.initialize_callback_handle(
+//!     dynamic_deferred_caller
+//!         .register(rtt, DeferredCallPriority::Normal)
+//!         .expect("no deferred call slot available for RTT"),
+//! );
 //!
 //! let console = static_init!(
-//!     capsules::console::Console<
-//!         'static,
-//!         capsules::segger_rtt::SeggerRtt<
-//!             capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
-//!         >,
-//!     >,
+//!     capsules::console::Console<'static, capsules::segger_rtt::SeggerRtt>,
 //!     capsules::console::Console::new(
 //!         rtt,
 //!         0, // Baud rate is meaningless with RTT
@@ -91,8 +79,10 @@
 //! ```
 
 use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::common::dynamic_deferred_call::{
+    DeferredCallHandle, DynamicDeferredCall, DynamicDeferredCallClient,
+};
 use kernel::hil;
-use kernel::hil::time::Frequency;
 use kernel::ReturnCode;
 
 /// Buffer for transmitting to the host.
@@ -155,8 +145,9 @@ impl SeggerRttMemory {
     }
 }
 
-pub struct SeggerRtt<'a, A: hil::time::Alarm> {
-    alarm: &'a A, // Dummy alarm so we can get a callback.
+pub struct SeggerRtt<'a> {
+    deferred_caller: &'a DynamicDeferredCall,
+    handle: OptionalCell<DeferredCallHandle>,
     config: TakeCell<'static, SeggerRttMemory>,
     up_buffer: TakeCell<'static, [u8]>,
     _down_buffer: TakeCell<'static, [u8]>,
@@ -164,15 +155,16 @@ pub struct SeggerRtt<'a, A: hil::time::Alarm> {
     client_buffer: TakeCell<'static, [u8]>,
 }
 
-impl<A: hil::time::Alarm> SeggerRtt<'a, A> {
+impl SeggerRtt<'a> {
     pub fn new(
-        alarm: &'a A,
+        deferred_caller: &'a DynamicDeferredCall,
         config: &'static mut SeggerRttMemory,
         up_buffer: &'static mut [u8],
         down_buffer: &'static mut [u8],
-    ) -> SeggerRtt<'a, A> {
+    ) -> SeggerRtt<'a> {
         SeggerRtt {
-            alarm: alarm,
+            deferred_caller: deferred_caller,
+            handle: OptionalCell::empty(),
             config: TakeCell::new(config),
             up_buffer: TakeCell::new(up_buffer),
             _down_buffer: TakeCell::new(down_buffer),
@@ -180,9 +172,15 @@ impl<A: hil::time::Alarm> SeggerRtt<'a, A> {
             client_buffer: TakeCell::empty(),
         }
     }
+
+    /// Set the handle this capsule was registered with `deferred_caller`
+    /// under. Must be called before transmitting.
+    pub fn initialize_callback_handle(&self, handle: DeferredCallHandle) {
+        self.handle.set(handle);
+    }
 }
 
-impl<A: hil::time::Alarm> hil::uart::UART for SeggerRtt<'a, A> {
+impl hil::uart::UART for SeggerRtt<'a> {
     fn set_client(&self, client: &'static hil::uart::Client) {
         self.client.set(client);
     }
@@ -212,11 +210,8 @@ impl<A: hil::time::Alarm> hil::uart::UART for SeggerRtt<'a, A> {
         // Save the client buffer so we can pass it back with the callback.
         self.client_buffer.replace(tx_data);
 
-        // Start a short timer so that we get a callback and can issue the
-        // callback to the client.
-        let interval = (100 as u32) * <A::Frequency>::frequency() / 1000000;
-        let tics = self.alarm.now().wrapping_add(interval);
-        self.alarm.set_alarm(tics);
+        // Issue the callback to the client from the main loop.
+        self.handle.map(|handle| self.deferred_caller.set(*handle));
     }
 
     fn receive(&self, _rx_buf: &'static mut [u8], _rx_len: usize) {}
@@ -224,8 +219,8 @@ impl<A: hil::time::Alarm> hil::uart::UART for SeggerRtt<'a, A> {
     fn abort_receive(&self) {}
 }
 
-impl<A: hil::time::Alarm> hil::time::Client for SeggerRtt<'a, A> {
-    fn fired(&self) {
+impl DynamicDeferredCallClient for SeggerRtt<'a> {
+    fn call(&self, _handle: DeferredCallHandle) {
         self.client.map(|client| {
             self.client_buffer.take().map(|buffer| {
                 client.transmit_complete(buffer, hil::uart::Error::CommandComplete);
@@ -38,7 +38,7 @@ use kernel;
 use kernel::common::cells::{OptionalCell, TakeCell};
 use kernel::hil::i2c;
 use kernel::hil::time;
-use kernel::hil::time::Frequency;
+use kernel::hil::time::{Frequency, Ticks};
 use kernel::ReturnCode;
 
 // Buffer to use for I2C messages
@@ -132,7 +132,7 @@ impl<A: time::Alarm> SI7021<'a, A> {
     fn init_measurement(&self, buffer: &'static mut [u8]) {
         let interval = (20 as u32) * <A::Frequency>::frequency() / 1000;
 
-        let tics = self.alarm.now().wrapping_add(interval);
+        let tics = self.alarm.now().wrapping_add(A::Ticks::from_u64(interval as u64));
         self.alarm.set_alarm(tics);
 
         // Now wait for timer to expire
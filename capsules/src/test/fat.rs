@@ -0,0 +1,451 @@
+//! Test the FAT file system on a disk image in RAM.
+//!
+//! `DiskImage` serves the sectors of a byte slice as a `BlockDevice`. A read
+//! or write the file system starts is parked until `run_pending()` carries it
+//! out and calls back, so whoever drives the test decides when the "card"
+//! answers: a board can call it from its main loop, and a host test from a
+//! plain loop that stops once it returns `false`. The slice has to hold a
+//! FAT16 or FAT32 file system, with or without a partition table.
+//! `fat16.img`, which has one, and `fat32.img`, which does not, are two such
+//! images. `tools/make_fat_image.py` made them, leaving out the zeros at
+//! their end, so they have to be padded to their full size before use. The
+//! `#[test]`s in `fat.rs` run on them. A read or write can also be made to
+//! fail, as when the card is pulled out.
+//!
+//! The test writes a CSV file `TEST.CSV` in the root directory, appends to
+//! it, reads it back and finds it by listing the directory, then makes it
+//! empty and writes it again.
+//!
+//! ```rust
+//! let disk = static_init!(
+//!     capsules::test::fat::DiskImage,
+//!     capsules::test::fat::DiskImage::new(image)
+//! );
+//! let fat = static_init!(
+//!     FatFs<'static, capsules::test::fat::DiskImage>,
+//!     FatFs::new(disk, grant, &mut capsules::fat::CACHE, &mut capsules::fat::BUFFER)
+//! );
+//! disk.set_client(fat);
+//! let test = static_init!(
+//!     TestFat,
+//!     TestFat::new(fat, disk, static_init!([u8; 300], [0; 300]))
+//! );
+//! fat.set_client(test);
+//! test.run();
+//! while disk.run_pending() {}
+//! assert_eq!(test.result(), Some(Ok(())));
+//! ```
+
+use core::cell::Cell;
+use fat::{BlockDevice, DirEntry, FatClient, FatFs, File, Mode, SECTOR_SIZE};
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::ReturnCode;
+use sdcard::SDCardClient;
+
+/// Number of writes to the file after creating it, and of appends after
+/// opening it again.
+const NUM_WRITES: usize = 20;
+
+/// Length of the file after it was made empty and written again.
+const SHORT_LEN: usize = 100;
+
+/// Length of the lines of the file, which look like `00042,00294`.
+const LINE_LEN: usize = 12;
+
+#[derive(Copy, Clone)]
+enum MockOperation {
+    Initialize,
+    Read(u32),
+    Write(u32),
+}
+
+/// Blocks in RAM.
+pub struct DiskImage {
+    image: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'static SDCardClient>,
+    pending: Cell<Option<MockOperation>>,
+    buffer: TakeCell<'static, [u8]>,
+    /// Fail the next read or write.
+    fail_next: Cell<bool>,
+}
+
+impl DiskImage {
+    /// Make a device of `image.len() / SECTOR_SIZE` blocks.
+    pub fn new(image: &'static mut [u8]) -> DiskImage {
+        DiskImage {
+            image: TakeCell::new(image),
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            fail_next: Cell::new(false),
+        }
+    }
+
+    pub fn set_client<C: SDCardClient>(&self, client: &'static C) {
+        self.client.set(client);
+    }
+
+    /// Fail the next read or write, as a card that is pulled out does.
+    pub fn fail_next(&self) {
+        self.fail_next.set(true);
+    }
+
+    /// Take the image, to look at it after the test.
+    pub fn take_image(&self) -> Option<&'static mut [u8]> {
+        self.image.take()
+    }
+
+    /// Finish the pending operation, if there is one. Returns whether there
+    /// was.
+    pub fn run_pending(&self) -> bool {
+        let operation = match self.pending.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+        if let MockOperation::Initialize = operation {
+            self.client.map(|client| client.init_done(SECTOR_SIZE as u32, 0));
+            return true;
+        }
+        if self.fail_next.take() {
+            // The buffer stays here until it is taken back.
+            self.client.map(|client| client.error(0));
+            return true;
+        }
+        self.buffer.take().map(|buffer| match operation {
+            MockOperation::Read(block) => {
+                self.image.map(|image| {
+                    let start = block as usize * SECTOR_SIZE;
+                    buffer[0..SECTOR_SIZE].copy_from_slice(&image[start..start + SECTOR_SIZE]);
+                });
+                self.client
+                    .map(move |client| client.read_done(buffer, SECTOR_SIZE));
+            }
+            MockOperation::Write(block) => {
+                self.image.map(|image| {
+                    let start = block as usize * SECTOR_SIZE;
+                    image[start..start + SECTOR_SIZE].copy_from_slice(&buffer[0..SECTOR_SIZE]);
+                });
+                self.client.map(move |client| client.write_done(buffer));
+            }
+            MockOperation::Initialize => {}
+        });
+        true
+    }
+
+    /// Start `operation` on `block` with `buffer`.
+    fn start(&self, operation: MockOperation, block: u32, buffer: &'static mut [u8]) -> ReturnCode {
+        let num_blocks = self.image.map_or(0, |image| image.len() / SECTOR_SIZE);
+        self.buffer.replace(buffer);
+        if self.pending.get().is_some() {
+            ReturnCode::EBUSY
+        } else if block as usize >= num_blocks {
+            ReturnCode::EINVAL
+        } else {
+            self.pending.set(Some(operation));
+            ReturnCode::SUCCESS
+        }
+    }
+}
+
+impl BlockDevice for DiskImage {
+    fn is_ready(&self) -> bool {
+        self.image.is_some()
+    }
+
+    fn initialize(&self) -> ReturnCode {
+        self.pending.set(Some(MockOperation::Initialize));
+        ReturnCode::SUCCESS
+    }
+
+    fn read_block(&self, buffer: &'static mut [u8], block: u32) -> ReturnCode {
+        self.start(MockOperation::Read(block), block, buffer)
+    }
+
+    fn write_block(&self, buffer: &'static mut [u8], block: u32) -> ReturnCode {
+        self.start(MockOperation::Write(block), block, buffer)
+    }
+
+    fn take_buffer(&self) -> Option<&'static mut [u8]> {
+        self.buffer.take()
+    }
+}
+
+/// Steps of the test, in order.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Step {
+    Mount,
+    OpenMissing,
+    Create,
+    Write(usize),
+    /// A write during which the card fails.
+    FailedWrite,
+    OpenAppend,
+    Append(usize),
+    OpenRead,
+    Read,
+    /// List the entry with this number.
+    List(usize),
+    Truncate,
+    WriteShort,
+    OpenShort,
+    ReadShort,
+    Done,
+}
+
+/// Writes, appends to and reads back a file, and checks the results. Writes
+/// are as long as the buffer.
+pub struct TestFat {
+    fs: &'static FatFs<'static, DiskImage>,
+    disk: &'static DiskImage,
+    buffer: TakeCell<'static, [u8]>,
+    step: Cell<Step>,
+    file: Cell<File>,
+    /// Bytes the file should have, and bytes read back so far.
+    written: Cell<usize>,
+    read: Cell<usize>,
+    /// The step that failed, if one did.
+    failed: OptionalCell<usize>,
+}
+
+impl TestFat {
+    pub fn new(
+        fs: &'static FatFs<'static, DiskImage>,
+        disk: &'static DiskImage,
+        buffer: &'static mut [u8],
+    ) -> TestFat {
+        TestFat {
+            fs: fs,
+            disk: disk,
+            buffer: TakeCell::new(buffer),
+            step: Cell::new(Step::Mount),
+            file: Cell::new(File::default()),
+            written: Cell::new(0),
+            read: Cell::new(0),
+            failed: OptionalCell::empty(),
+        }
+    }
+
+    pub fn run(&self) {
+        self.step.set(Step::Mount);
+        self.written.set(0);
+        self.failed.clear();
+        self.start_step();
+    }
+
+    /// `None` while the test runs, then `Ok(())` if it passed or the number
+    /// of the step that failed.
+    pub fn result(&self) -> Option<Result<(), usize>> {
+        match self.failed.map(|step| *step) {
+            Some(step) => Some(Err(step)),
+            None if self.step.get() == Step::Done => Some(Ok(())),
+            None => None,
+        }
+    }
+
+    fn step_num(&self) -> usize {
+        match self.step.get() {
+            Step::Mount => 0,
+            Step::OpenMissing => 1,
+            Step::Create => 2,
+            Step::Write(_) => 3,
+            Step::FailedWrite => 4,
+            Step::OpenAppend => 5,
+            Step::Append(_) => 6,
+            Step::OpenRead => 7,
+            Step::Read => 8,
+            Step::List(_) => 9,
+            Step::Truncate => 10,
+            Step::WriteShort => 11,
+            Step::OpenShort => 12,
+            Step::ReadShort => 13,
+            Step::Done => 14,
+        }
+    }
+
+    fn start_step(&self) {
+        let result = match self.step.get() {
+            Step::Mount => self.fs.mount(),
+            Step::OpenMissing => self.fs.open(b"missing.txt", Mode::Read),
+            Step::Create | Step::Truncate => self.fs.open(b"/TEST.CSV", Mode::Write),
+            Step::OpenAppend => self.fs.open(b"test.csv", Mode::Append),
+            Step::OpenRead | Step::OpenShort => self.fs.open(b"test.csv", Mode::Read),
+            Step::List(index) => self.fs.list(b"/", index),
+            Step::Write(_) | Step::FailedWrite | Step::Append(_) | Step::WriteShort => {
+                let offset = self.written.get();
+                let buffer = match self.buffer.take() {
+                    Some(buffer) => buffer,
+                    None => return self.fail(),
+                };
+                for (i, byte) in buffer.iter_mut().enumerate() {
+                    *byte = expected_byte(offset + i);
+                }
+                let length = if self.step.get() == Step::WriteShort {
+                    SHORT_LEN
+                } else {
+                    buffer.len()
+                };
+                if self.step.get() == Step::FailedWrite {
+                    self.disk.fail_next();
+                }
+                match self.fs.write(self.file.get(), buffer, length) {
+                    Ok(()) => ReturnCode::SUCCESS,
+                    Err((result, buffer)) => {
+                        self.buffer.replace(buffer);
+                        result
+                    }
+                }
+            }
+            Step::Read | Step::ReadShort => match self.buffer.take() {
+                Some(buffer) => {
+                    let length = buffer.len();
+                    match self.fs.read(self.file.get(), buffer, length) {
+                        Ok(()) => ReturnCode::SUCCESS,
+                        Err((result, buffer)) => {
+                            self.buffer.replace(buffer);
+                            result
+                        }
+                    }
+                }
+                None => ReturnCode::ENOMEM,
+            },
+            Step::Done => ReturnCode::SUCCESS,
+        };
+        if result != ReturnCode::SUCCESS {
+            self.fail();
+        }
+    }
+
+    /// Check the result of the current step, and start `next`.
+    fn step_complete(&self, result: ReturnCode, expected: ReturnCode, next: Step) {
+        if self.failed.is_some() {
+            return;
+        }
+        if result != expected {
+            return self.fail();
+        }
+        self.step.set(next);
+        self.start_step();
+    }
+
+    /// The step after the current one, when it worked.
+    fn next_step(&self) -> Step {
+        match self.step.get() {
+            Step::Mount => Step::OpenMissing,
+            Step::OpenMissing => Step::Create,
+            Step::Create => Step::Write(0),
+            Step::Write(n) if n + 1 < NUM_WRITES => Step::Write(n + 1),
+            Step::Write(_) => Step::FailedWrite,
+            Step::FailedWrite => Step::OpenAppend,
+            Step::OpenAppend => Step::Append(0),
+            Step::Append(n) if n + 1 < NUM_WRITES => Step::Append(n + 1),
+            Step::Append(_) => Step::OpenRead,
+            Step::OpenRead => Step::Read,
+            Step::Read => Step::List(0),
+            Step::List(n) => Step::List(n + 1),
+            Step::Truncate => Step::WriteShort,
+            Step::WriteShort => Step::OpenShort,
+            Step::OpenShort => Step::ReadShort,
+            Step::ReadShort | Step::Done => Step::Done,
+        }
+    }
+
+    fn fail(&self) {
+        self.failed.set(self.step_num());
+    }
+}
+
+impl FatClient for TestFat {
+    fn mount_done(&self, result: ReturnCode) {
+        self.step_complete(result, ReturnCode::SUCCESS, Step::OpenMissing);
+    }
+
+    fn open_done(&self, result: ReturnCode, file: File) {
+        let expected = match self.step.get() {
+            Step::OpenMissing => ReturnCode::FAIL,
+            _ => ReturnCode::SUCCESS,
+        };
+        let size = match self.step.get() {
+            Step::Create | Step::Truncate => 0,
+            Step::OpenShort => SHORT_LEN,
+            _ => self.written.get(),
+        };
+        if result == ReturnCode::SUCCESS {
+            if file.size() != size {
+                return self.fail();
+            }
+            self.file.set(file);
+            self.written.set(size);
+            self.read.set(0);
+        }
+        self.step_complete(result, expected, self.next_step());
+    }
+
+    fn list_done(&self, result: ReturnCode, entry: DirEntry) {
+        if result != ReturnCode::SUCCESS {
+            // The file was not in the directory.
+            return self.fail();
+        }
+        let next = if entry.name() == b"TEST.CSV" {
+            if entry.is_directory() || entry.size() != self.written.get() {
+                return self.fail();
+            }
+            Step::Truncate
+        } else {
+            self.next_step()
+        };
+        self.step_complete(result, ReturnCode::SUCCESS, next);
+    }
+
+    fn read_done(&self, result: ReturnCode, file: File, buffer: &'static mut [u8], length: usize) {
+        let offset = self.read.get();
+        let correct = (0..length).all(|i| buffer[i] == expected_byte(offset + i));
+        self.buffer.replace(buffer);
+        self.file.set(file);
+        self.read.set(offset + length);
+        if !correct || offset + length > self.written.get() {
+            return self.fail();
+        }
+        let next = if length == 0 {
+            // The end of the file, which has to be all of it.
+            if offset != self.written.get() {
+                return self.fail();
+            }
+            self.next_step()
+        } else {
+            self.step.get()
+        };
+        self.step_complete(result, ReturnCode::SUCCESS, next);
+    }
+
+    fn write_done(&self, result: ReturnCode, file: File, buffer: &'static mut [u8], length: usize) {
+        let expected_length = match self.step.get() {
+            Step::WriteShort => SHORT_LEN,
+            _ => buffer.len(),
+        };
+        self.buffer.replace(buffer);
+        self.file.set(file);
+        if self.step.get() == Step::FailedWrite {
+            // What was written is lost, and the file is as it was.
+            return self.step_complete(result, ReturnCode::FAIL, self.next_step());
+        }
+        if length != expected_length {
+            return self.fail();
+        }
+        self.written.set(self.written.get() + length);
+        self.step_complete(result, ReturnCode::SUCCESS, self.next_step());
+    }
+}
+
+/// The byte at `offset` in the file: the number of its line, a comma, a
+/// number computed from it, and a newline.
+fn expected_byte(offset: usize) -> u8 {
+    let line = offset / LINE_LEN;
+    let column = offset % LINE_LEN;
+    let digit = |value: usize, place: usize| b'0' + (value / 10usize.pow(place as u32) % 10) as u8;
+    match column {
+        0..=4 => digit(line, 4 - column),
+        5 => b',',
+        6..=10 => digit(line * 7 % 100000, 10 - column),
+        _ => b'\n',
+    }
+}
@@ -0,0 +1,486 @@
+//! Test the key-value store on an in-memory flash.
+//!
+//! `MockFlash` keeps its pages in a RAM buffer and queues each operation
+//! until `run_pending()` is called, so the test can run anywhere, including
+//! on the host, by calling `run_pending()` until it returns `false`. Like NOR
+//! flash, programming can only clear bits, so it fails when it would have to
+//! change a byte that is not erased. It can also cut a write short, or drop
+//! the pending operation, to check that the store recovers from power loss.
+//!
+//! ```rust
+//! let mock_flash = static_init!(
+//!     capsules::test::kv_store::MockFlash<'static>,
+//!     capsules::test::kv_store::MockFlash::new(
+//!         static_init!([u8; 4 * MOCK_PAGE_SIZE], [0; 4 * MOCK_PAGE_SIZE])
+//!     )
+//! );
+//! let kv_store = static_init!(
+//!     KVStore<'static, capsules::test::kv_store::MockFlash<'static>>,
+//!     KVStore::new(
+//!         mock_flash,
+//!         grant,
+//!         0,
+//!         static_init!([u32; 4], [0; 4]),
+//!         static_init!([KVIndexEntry; 8], [KVIndexEntry::default(); 8]),
+//!         static_init!(MockPage, MockPage::new())
+//!     )
+//! );
+//! mock_flash.set_client(kv_store);
+//! let test = static_init!(
+//!     TestKVStore,
+//!     TestKVStore::new(
+//!         kv_store,
+//!         mock_flash,
+//!         static_init!([u8; 2], [0; 2]),
+//!         static_init!([u8; 8], [0; 8])
+//!     )
+//! );
+//! kv_store.set_client(test);
+//! kv_store.initialize();
+//! test.run();
+//! while mock_flash.run_pending() {}
+//! assert_eq!(test.result(), Some(Ok(())));
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::common::cells::{OptionalCell, TakeCell};
+use kernel::hil;
+use kernel::ReturnCode;
+use kv_store::{KVStore, KVStoreClient};
+
+/// Size of the pages of `MockFlash`.
+pub const MOCK_PAGE_SIZE: usize = 512;
+
+/// Number of times the test sets a key to make the store garbage collect.
+const NUM_UPDATES: usize = 64;
+
+pub struct MockPage(pub [u8; MOCK_PAGE_SIZE]);
+
+impl MockPage {
+    pub const fn new() -> MockPage {
+        MockPage([0; MOCK_PAGE_SIZE])
+    }
+}
+
+impl AsMut<[u8]> for MockPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[derive(Copy, Clone)]
+enum MockOperation {
+    Read(usize),
+    Write(usize),
+    Program(usize),
+    /// Erase a number of pages from the first one.
+    Erase(usize, usize),
+}
+
+/// Flash in RAM, starting out erased.
+pub struct MockFlash<'a> {
+    storage: TakeCell<'static, [u8]>,
+    client: OptionalCell<&'a hil::flash::Client<MockFlash<'a>>>,
+    pending: Cell<Option<MockOperation>>,
+    buffer: TakeCell<'static, MockPage>,
+    /// Write only this many bytes of the next page written or programmed, not
+    /// counting the erased bytes at the start of the buffer, and fail.
+    torn_write: Cell<Option<usize>>,
+}
+
+impl MockFlash<'a> {
+    /// Make a flash of `storage.len() / MOCK_PAGE_SIZE` pages.
+    pub fn new(storage: &'static mut [u8]) -> MockFlash<'a> {
+        for byte in storage.iter_mut() {
+            *byte = 0xff;
+        }
+        MockFlash {
+            storage: TakeCell::new(storage),
+            client: OptionalCell::empty(),
+            pending: Cell::new(None),
+            buffer: TakeCell::empty(),
+            torn_write: Cell::new(None),
+        }
+    }
+
+    /// Cut the next write short after `len` bytes, as if power was lost.
+    /// Erased bytes at the start of the buffer, which a program leaves alone,
+    /// do not count.
+    pub fn tear_next_write(&self, len: usize) {
+        self.torn_write.set(Some(len));
+    }
+
+    /// Drop the pending operation without running it or calling the client,
+    /// as if power was lost before it started.
+    pub fn lose_power(&self) {
+        self.pending.set(None);
+        self.buffer.take();
+    }
+
+    /// Finish the pending operation, if there is one. Returns whether there
+    /// was.
+    pub fn run_pending(&self) -> bool {
+        let operation = match self.pending.take() {
+            Some(operation) => operation,
+            None => return false,
+        };
+        let mut error = hil::flash::Error::CommandComplete;
+        match operation {
+            MockOperation::Read(page_number) => {
+                self.buffer.take().map(|buffer| {
+                    self.storage.map(|storage| {
+                        let start = page_number * MOCK_PAGE_SIZE;
+                        buffer
+                            .0
+                            .copy_from_slice(&storage[start..start + MOCK_PAGE_SIZE]);
+                    });
+                    self.client.map(move |client| client.read_complete(buffer, error));
+                });
+            }
+            MockOperation::Write(page_number) | MockOperation::Program(page_number) => {
+                self.buffer.take().map(|buffer| {
+                    let skip = buffer.0.iter().take_while(|&&byte| byte == 0xff).count();
+                    let len = match self.torn_write.take() {
+                        Some(len) => {
+                            error = hil::flash::Error::FlashError;
+                            cmp::min(skip + len, MOCK_PAGE_SIZE)
+                        }
+                        None => MOCK_PAGE_SIZE,
+                    };
+                    self.storage.map(|storage| {
+                        let start = page_number * MOCK_PAGE_SIZE;
+                        let page = &mut storage[start..start + MOCK_PAGE_SIZE];
+                        if let MockOperation::Write(_) = operation {
+                            // Writing erases the page first.
+                            for byte in page.iter_mut() {
+                                *byte = 0xff;
+                            }
+                        }
+                        for (byte, &new) in page[0..len].iter_mut().zip(buffer.0.iter()) {
+                            if new != 0xff && *byte != 0xff {
+                                error = hil::flash::Error::FlashError;
+                            }
+                            *byte &= new;
+                        }
+                    });
+                    self.client.map(move |client| client.write_complete(buffer, error));
+                });
+            }
+            MockOperation::Erase(page_number, num_pages) => {
+                self.storage.map(|storage| {
+                    let start = page_number * MOCK_PAGE_SIZE;
+                    let end = start + num_pages * MOCK_PAGE_SIZE;
+                    for byte in storage[start..end].iter_mut() {
+                        *byte = 0xff;
+                    }
+                });
+                self.client.map(|client| client.erase_complete(error));
+            }
+        }
+        true
+    }
+
+    fn num_pages(&self) -> usize {
+        self.storage.map_or(0, |storage| storage.len() / MOCK_PAGE_SIZE)
+    }
+
+    /// Check that a new operation on `page_number` can start.
+    fn check(&self, page_number: usize) -> ReturnCode {
+        if self.pending.get().is_some() {
+            ReturnCode::EBUSY
+        } else if page_number >= self.num_pages() {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'a, C> for MockFlash<'a> {
+    fn set_client(&'a self, client: &'a C) {
+        self.client.set(client);
+    }
+}
+
+impl hil::flash::Flash for MockFlash<'a> {
+    type Page = MockPage;
+
+    fn read_page(&self, page_number: usize, buf: &'static mut MockPage) -> ReturnCode {
+        let result = self.check(page_number);
+        if result == ReturnCode::SUCCESS {
+            self.buffer.replace(buf);
+            self.pending.set(Some(MockOperation::Read(page_number)));
+        }
+        result
+    }
+
+    fn write_page(&self, page_number: usize, buf: &'static mut MockPage) -> ReturnCode {
+        let result = self.check(page_number);
+        if result == ReturnCode::SUCCESS {
+            self.buffer.replace(buf);
+            self.pending.set(Some(MockOperation::Write(page_number)));
+        }
+        result
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        let result = self.check(page_number);
+        if result == ReturnCode::SUCCESS {
+            self.pending.set(Some(MockOperation::Erase(page_number, 1)));
+        }
+        result
+    }
+}
+
+impl hil::flash::EraseFlash for MockFlash<'a> {
+    fn geometry(&self) -> hil::flash::Geometry {
+        hil::flash::Geometry {
+            page_size: MOCK_PAGE_SIZE,
+            num_pages: self.num_pages(),
+            program_size: 4,
+            erase_size: MOCK_PAGE_SIZE,
+            max_erase_size: MOCK_PAGE_SIZE,
+            write_erases: true,
+        }
+    }
+
+    fn program_page(&self, page_number: usize, buf: &'static mut MockPage) -> ReturnCode {
+        let result = self.check(page_number);
+        if result == ReturnCode::SUCCESS {
+            self.buffer.replace(buf);
+            self.pending.set(Some(MockOperation::Program(page_number)));
+        }
+        result
+    }
+
+    fn erase_pages(&self, page_number: usize, num_pages: usize) -> ReturnCode {
+        let result = self.check(page_number);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+        if num_pages == 0 || page_number + num_pages > self.num_pages() {
+            return ReturnCode::EINVAL;
+        }
+        self.pending
+            .set(Some(MockOperation::Erase(page_number, num_pages)));
+        ReturnCode::SUCCESS
+    }
+}
+
+/// Steps of the test, in order.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Step {
+    SetFirst,
+    SetSecond,
+    GetFirst,
+    DeleteFirst,
+    GetDeleted,
+    /// Set the third key `NUM_UPDATES` times, which fills every page.
+    Update(usize),
+    GetSecond,
+    TornSet,
+    GetAfterTornSet,
+    SetAgain,
+    GetAgain,
+    Done,
+}
+
+/// Sets, gets and deletes keys, and checks the results. The key buffer is
+/// the length of the keys used.
+pub struct TestKVStore {
+    store: &'static KVStore<'static, MockFlash<'static>>,
+    flash: &'static MockFlash<'static>,
+    key: TakeCell<'static, [u8]>,
+    value: TakeCell<'static, [u8]>,
+    step: Cell<Step>,
+    /// The step that failed, if one did.
+    failed: OptionalCell<usize>,
+}
+
+impl TestKVStore {
+    pub fn new(
+        store: &'static KVStore<'static, MockFlash<'static>>,
+        flash: &'static MockFlash<'static>,
+        key: &'static mut [u8],
+        value: &'static mut [u8],
+    ) -> TestKVStore {
+        TestKVStore {
+            store: store,
+            flash: flash,
+            key: TakeCell::new(key),
+            value: TakeCell::new(value),
+            step: Cell::new(Step::SetFirst),
+            failed: OptionalCell::empty(),
+        }
+    }
+
+    pub fn run(&self) {
+        self.step.set(Step::SetFirst);
+        self.failed.clear();
+        self.start_step();
+    }
+
+    /// `None` while the test runs, then `Ok(())` if it passed or the number
+    /// of the step that failed.
+    pub fn result(&self) -> Option<Result<(), usize>> {
+        match self.failed.map(|step| *step) {
+            Some(step) => Some(Err(step)),
+            None if self.step.get() == Step::Done => Some(Ok(())),
+            None => None,
+        }
+    }
+
+    fn step_num(&self) -> usize {
+        match self.step.get() {
+            Step::SetFirst => 0,
+            Step::SetSecond => 1,
+            Step::GetFirst => 2,
+            Step::DeleteFirst => 3,
+            Step::GetDeleted => 4,
+            Step::Update(_) => 5,
+            Step::GetSecond => 6,
+            Step::TornSet => 7,
+            Step::GetAfterTornSet => 8,
+            Step::SetAgain => 9,
+            Step::GetAgain => 10,
+            Step::Done => 11,
+        }
+    }
+
+    /// The key and value of each step. Values are filled with one byte.
+    fn key_value(&self) -> (u8, u8) {
+        match self.step.get() {
+            Step::SetFirst | Step::GetFirst | Step::DeleteFirst | Step::GetDeleted => (b'a', 1),
+            Step::SetSecond | Step::GetSecond | Step::TornSet | Step::GetAfterTornSet => (b'b', 2),
+            Step::Update(n) => (b'c', n as u8),
+            Step::SetAgain | Step::GetAgain => (b'b', 3),
+            Step::Done => (0, 0),
+        }
+    }
+
+    fn start_step(&self) {
+        let (key_byte, value_byte) = self.key_value();
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => return self.fail(),
+        };
+        let value = match self.value.take() {
+            Some(value) => value,
+            None => return self.fail(),
+        };
+        for byte in key.iter_mut() {
+            *byte = key_byte;
+        }
+        for byte in value.iter_mut() {
+            *byte = 0;
+        }
+
+        let result = match self.step.get() {
+            Step::SetFirst | Step::SetSecond | Step::Update(_) | Step::SetAgain => {
+                for byte in value.iter_mut() {
+                    *byte = value_byte;
+                }
+                self.store.set(key, value)
+            }
+            Step::TornSet => {
+                for byte in value.iter_mut() {
+                    *byte = value_byte + 1;
+                }
+                // Lose power 16 bytes into the write, in the middle of the
+                // record.
+                self.flash.tear_next_write(16);
+                self.store.set(key, value)
+            }
+            Step::GetFirst
+            | Step::GetDeleted
+            | Step::GetSecond
+            | Step::GetAfterTornSet
+            | Step::GetAgain => self.store.get(key, value),
+            Step::DeleteFirst => {
+                self.value.replace(value);
+                if let Err((_, key)) = self.store.delete(key) {
+                    self.key.replace(key);
+                    self.fail();
+                }
+                return;
+            }
+            Step::Done => {
+                self.key.replace(key);
+                self.value.replace(value);
+                return;
+            }
+        };
+        if let Err((_, key, value)) = result {
+            self.key.replace(key);
+            self.value.replace(value);
+            self.fail();
+        }
+    }
+
+    /// Check the result of the current step, and start the next one.
+    fn step_complete(&self, result: ReturnCode, expected: ReturnCode) {
+        if self.failed.is_some() {
+            return;
+        }
+        if result != expected {
+            return self.fail();
+        }
+        let next = match self.step.get() {
+            Step::SetFirst => Step::SetSecond,
+            Step::SetSecond => Step::GetFirst,
+            Step::GetFirst => Step::DeleteFirst,
+            Step::DeleteFirst => Step::GetDeleted,
+            Step::GetDeleted => Step::Update(0),
+            Step::Update(n) if n + 1 < NUM_UPDATES => Step::Update(n + 1),
+            Step::Update(_) => Step::GetSecond,
+            Step::GetSecond => Step::TornSet,
+            Step::TornSet => Step::GetAfterTornSet,
+            Step::GetAfterTornSet => Step::SetAgain,
+            Step::SetAgain => Step::GetAgain,
+            Step::GetAgain | Step::Done => Step::Done,
+        };
+        self.step.set(next);
+        self.start_step();
+    }
+
+    fn fail(&self) {
+        self.failed.set(self.step_num());
+    }
+}
+
+impl KVStoreClient for TestKVStore {
+    fn get_complete(
+        &self,
+        result: ReturnCode,
+        key: &'static mut [u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) {
+        let (_, value_byte) = self.key_value();
+        let found = result == ReturnCode::SUCCESS;
+        let correct = length == value.len() && value.iter().all(|&byte| byte == value_byte);
+        self.key.replace(key);
+        self.value.replace(value);
+        match self.step.get() {
+            Step::GetDeleted => self.step_complete(result, ReturnCode::FAIL),
+            _ if found && !correct => self.fail(),
+            _ => self.step_complete(result, ReturnCode::SUCCESS),
+        }
+    }
+
+    fn set_complete(&self, result: ReturnCode, key: &'static mut [u8], value: &'static mut [u8]) {
+        self.key.replace(key);
+        self.value.replace(value);
+        let expected = match self.step.get() {
+            Step::TornSet => ReturnCode::FAIL,
+            _ => ReturnCode::SUCCESS,
+        };
+        self.step_complete(result, expected);
+    }
+
+    fn delete_complete(&self, result: ReturnCode, key: &'static mut [u8]) {
+        self.key.replace(key);
+        self.step_complete(result, ReturnCode::SUCCESS);
+    }
+}
@@ -1,3 +1,5 @@
 pub mod aes;
 pub mod aes_ccm;
+pub mod fat;
+pub mod kv_store;
 pub mod virtual_uart;
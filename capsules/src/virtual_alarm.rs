@@ -1,14 +1,18 @@
 //! Virtualize the Alarm interface to enable multiple users of an underlying
 //! alarm hardware peripheral.
+//!
+//! Times are compared relative to when the underlying alarm was last set or
+//! fired, using `Ticks` arithmetic, so they work across the counter wrapping
+//! at any width.
 
 use core::cell::Cell;
 use kernel::common::cells::OptionalCell;
 use kernel::common::{List, ListLink, ListNode};
-use kernel::hil::time::{self, Alarm, Time};
+use kernel::hil::time::{self, Alarm, Ticks, Time};
 
 pub struct VirtualMuxAlarm<'a, Alrm: Alarm> {
     mux: &'a MuxAlarm<'a, Alrm>,
-    when: Cell<u32>,
+    when: Cell<Alrm::Ticks>,
     armed: Cell<bool>,
     next: ListLink<'a, VirtualMuxAlarm<'a, Alrm>>,
     client: OptionalCell<&'a time::Client>,
@@ -24,7 +28,7 @@ impl<Alrm: Alarm> VirtualMuxAlarm<'a, Alrm> {
     pub fn new(mux_alarm: &'a MuxAlarm<'a, Alrm>) -> VirtualMuxAlarm<'a, Alrm> {
         VirtualMuxAlarm {
             mux: mux_alarm,
-            when: Cell::new(0),
+            when: Cell::new(Alrm::Ticks::from_u64(0)),
             armed: Cell::new(false),
             next: ListLink::empty(),
             client: OptionalCell::empty(),
@@ -33,7 +37,7 @@ impl<Alrm: Alarm> VirtualMuxAlarm<'a, Alrm> {
 
     pub fn set_client(&'a self, client: &'a time::Client) {
         self.mux.virtual_alarms.push_head(self);
-        self.when.set(0);
+        self.when.set(Alrm::Ticks::from_u64(0));
         self.armed.set(false);
         self.client.set(client);
     }
@@ -65,11 +69,13 @@ impl<Alrm: Alarm> Time for VirtualMuxAlarm<'a, Alrm> {
 }
 
 impl<Alrm: Alarm> Alarm for VirtualMuxAlarm<'a, Alrm> {
-    fn now(&self) -> u32 {
+    type Ticks = Alrm::Ticks;
+
+    fn now(&self) -> Alrm::Ticks {
         self.mux.alarm.now()
     }
 
-    fn set_alarm(&self, when: u32) {
+    fn set_alarm(&self, when: Alrm::Ticks) {
         let enabled = self.mux.enabled.get();
 
         if !self.is_armed() {
@@ -81,7 +87,8 @@ impl<Alrm: Alarm> Alarm for VirtualMuxAlarm<'a, Alrm> {
             let cur_alarm = self.mux.alarm.get_alarm();
             let now = self.now();
 
-            if cur_alarm.wrapping_sub(now) > when.wrapping_sub(now) {
+            // Move the underlying alarm earlier if `when` comes first.
+            if when.within_range(now, cur_alarm) {
                 self.mux.prev.set(self.mux.alarm.now());
                 self.mux.alarm.set_alarm(when);
             }
@@ -93,7 +100,7 @@ impl<Alrm: Alarm> Alarm for VirtualMuxAlarm<'a, Alrm> {
         self.when.set(when);
     }
 
-    fn get_alarm(&self) -> u32 {
+    fn get_alarm(&self) -> Alrm::Ticks {
         self.when.get()
     }
 }
@@ -109,23 +116,24 @@ impl<Alrm: Alarm> time::Client for VirtualMuxAlarm<'a, Alrm> {
 pub struct MuxAlarm<'a, Alrm: Alarm> {
     virtual_alarms: List<'a, VirtualMuxAlarm<'a, Alrm>>,
     enabled: Cell<usize>,
-    prev: Cell<u32>,
+    prev: Cell<Alrm::Ticks>,
     alarm: &'a Alrm,
 }
 
 impl<Alrm: Alarm> MuxAlarm<'a, Alrm> {
-    pub const fn new(alarm: &'a Alrm) -> MuxAlarm<'a, Alrm> {
+    pub fn new(alarm: &'a Alrm) -> MuxAlarm<'a, Alrm> {
         MuxAlarm {
             virtual_alarms: List::new(),
             enabled: Cell::new(0),
-            prev: Cell::new(0),
+            prev: Cell::new(Alrm::Ticks::from_u64(0)),
             alarm: alarm,
         }
     }
 }
 
-fn has_expired(alarm: u32, now: u32, prev: u32) -> bool {
-    now.wrapping_sub(prev) >= alarm.wrapping_sub(prev)
+/// Whether `alarm` has passed by `now`, given that it had not yet at `prev`.
+fn has_expired<T: Ticks>(alarm: T, now: T, prev: T) -> bool {
+    !now.within_range(prev, alarm)
 }
 
 impl<Alrm: Alarm> time::Client for MuxAlarm<'a, Alrm> {
@@ -156,7 +164,7 @@ impl<Alrm: Alarm> time::Client for MuxAlarm<'a, Alrm> {
             .virtual_alarms
             .iter()
             .filter(|cur| cur.armed.get())
-            .min_by_key(|cur| cur.when.get().wrapping_sub(now));
+            .min_by_key(|cur| cur.when.get().wrapping_sub(now).into_u64());
 
         self.prev.set(now);
         // If there is an alarm to fire, set the underlying alarm to it
@@ -2,6 +2,7 @@ use cortexm4::{self, nvic};
 use gpio;
 use i2c;
 use kernel;
+use kernel::power::SleepState;
 use peripheral_interrupts;
 use rtc;
 use uart;
@@ -56,7 +57,11 @@ impl kernel::Chip for Cc26X2 {
         unsafe { nvic::has_pending() }
     }
 
-    fn sleep(&self) {
+    fn sleep_state(&self) -> SleepState {
+        SleepState::Idle
+    }
+
+    fn sleep(&self, _state: SleepState) {
         unsafe {
             cortexm4::support::wfi();
         }
@@ -3,7 +3,7 @@
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite};
 use kernel::common::StaticRef;
-use kernel::hil::time::{self, Alarm, Frequency, Time};
+use kernel::hil::time::{self, Alarm, Frequency, Ticks32, Time};
 
 #[repr(C)]
 struct RtcRegisters {
@@ -175,22 +175,24 @@ impl Time for Rtc {
 }
 
 impl Alarm for Rtc {
-    fn now(&self) -> u32 {
-        self.read_counter()
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32(self.read_counter())
     }
 
-    fn set_alarm(&self, tics: u32) {
+    fn set_alarm(&self, tics: Ticks32) {
         let regs = &*self.registers;
 
         regs.ctl.modify(Control::COMB_EV_MASK::Channel1);
-        regs.channel1_cmp.set(tics);
+        regs.channel1_cmp.set(tics.0);
         regs.channel_ctl.modify(ChannelControl::CH1_EN::SET);
 
         regs.sync.get();
     }
 
-    fn get_alarm(&self) -> u32 {
+    fn get_alarm(&self) -> Ticks32 {
         let regs = &*self.registers;
-        regs.channel1_cmp.get()
+        Ticks32(regs.channel1_cmp.get())
     }
 }
@@ -1,7 +1,9 @@
+use clock;
 use cortexm0;
 use cortexm0::nvic;
 use i2c;
 use kernel;
+use kernel::power::SleepState;
 use nrf5x;
 use nrf5x::peripheral_interrupts;
 use radio;
@@ -86,9 +88,32 @@ impl kernel::Chip for NRF51 {
         unsafe { nvic::has_pending() }
     }
 
-    fn sleep(&self) {
+    fn sleep_state(&self) -> SleepState {
+        unsafe { clock::POWER_CONSTRAINTS.deepest_allowed() }
+    }
+
+    fn sleep(&self, state: SleepState) {
         unsafe {
-            cortexm0::support::wfi();
+            match state {
+                SleepState::Idle | SleepState::Sleep => {
+                    cortexm0::support::wfi();
+                }
+                SleepState::DeepSleep => {
+                    // Nothing needs the crystal, so stop it while asleep and
+                    // run from the internal oscillator.
+                    let xtal = clock::CLOCK.high_xtal_running();
+                    if xtal {
+                        clock::CLOCK.high_stop();
+                    }
+                    cortexm0::support::wfi();
+                    // Whatever woke us may use the crystal as soon as this
+                    // returns, so wait for it to be running again.
+                    if xtal {
+                        clock::CLOCK.high_start();
+                        while !clock::CLOCK.high_xtal_running() {}
+                    }
+                }
+            }
         }
     }
 
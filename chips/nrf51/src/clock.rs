@@ -14,9 +14,15 @@
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
+use kernel::power;
 
 pub static mut CLOCK: Clock = Clock::new();
 
+/// Constraints peripherals hold on how deeply the chip can sleep. Deep sleep
+/// stops the high frequency crystal, so peripherals that need it, such as the
+/// radio, must hold a constraint while they use it.
+pub static mut POWER_CONSTRAINTS: power::PowerManager = power::PowerManager::new();
+
 #[repr(C)]
 struct ClockRegisters {
     hfclkstart: WriteOnly<u32, Task::Register>,      // 0x000
@@ -246,6 +252,14 @@ impl Clock {
         regs.xtalfreq.set(freq as u32);
     }
 
+    /// Check if the high frequency clock is running from the crystal
+    pub fn high_xtal_running(&self) -> bool {
+        match self.high_source() {
+            HighClockSource::XTAL => self.high_running(),
+            HighClockSource::RC => false,
+        }
+    }
+
     pub fn high_running(&self) -> bool {
         let regs = &*self.registers;
         (regs.hfclkstat.get() & ClockRunning::RUN as u32) == ClockRunning::RUN as u32
@@ -10,6 +10,7 @@
 //! * Fredrik Nilsson <frednils@student.chalmers.se>
 //! * Date: June 22, 2017
 
+use clock;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
@@ -18,6 +19,7 @@ use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::power::{PowerConstraint, SleepState};
 use kernel::ReturnCode;
 use nrf5x;
 use nrf5x::constants::TxPower;
@@ -408,6 +410,9 @@ pub struct Radio {
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'static ble_advertising::RxClient>,
     tx_client: OptionalCell<&'static ble_advertising::TxClient>,
+    /// Held while the radio is on, because it needs the high frequency
+    /// crystal.
+    power_constraint: PowerConstraint,
 }
 
 impl Radio {
@@ -417,6 +422,7 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            power_constraint: PowerConstraint::new(SleepState::Sleep),
         }
     }
 
@@ -527,6 +533,9 @@ impl Radio {
 
     fn radio_on(&self) {
         let regs = &*self.registers;
+        unsafe {
+            clock::POWER_CONSTRAINTS.set_active(&self.power_constraint, true);
+        }
         // reset and enable power
         regs.power.write(Power::POWER::CLEAR);
         regs.power.write(Power::POWER::SET);
@@ -535,6 +544,9 @@ impl Radio {
     fn radio_off(&self) {
         let regs = &*self.registers;
         regs.power.write(Power::POWER::CLEAR);
+        unsafe {
+            clock::POWER_CONSTRAINTS.set_active(&self.power_constraint, false);
+        }
     }
 
     // pre-condition validated before arriving here
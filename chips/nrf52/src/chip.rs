@@ -1,9 +1,11 @@
 use adc;
+use clock;
 use cortexm4::{self, nvic};
 use deferred_call_tasks::DeferredCallTask;
 use i2c;
 use kernel;
 use kernel::common::deferred_call;
+use kernel::power::SleepState;
 use nrf5x;
 use nrf5x::peripheral_interrupts;
 use nvmc;
@@ -104,9 +106,32 @@ impl kernel::Chip for NRF52 {
         unsafe { nvic::has_pending() || deferred_call::has_tasks() }
     }
 
-    fn sleep(&self) {
+    fn sleep_state(&self) -> SleepState {
+        unsafe { clock::POWER_CONSTRAINTS.deepest_allowed() }
+    }
+
+    fn sleep(&self, state: SleepState) {
         unsafe {
-            cortexm4::support::wfi();
+            match state {
+                SleepState::Idle | SleepState::Sleep => {
+                    cortexm4::support::wfi();
+                }
+                SleepState::DeepSleep => {
+                    // Nothing needs the crystal, so stop it while asleep and
+                    // run from the internal oscillator.
+                    let xtal = clock::CLOCK.high_xtal_running();
+                    if xtal {
+                        clock::CLOCK.high_stop();
+                    }
+                    cortexm4::support::wfi();
+                    // Whatever woke us may use the crystal as soon as this
+                    // returns, so wait for it to be running again.
+                    if xtal {
+                        clock::CLOCK.high_start();
+                        while !clock::CLOCK.high_xtal_running() {}
+                    }
+                }
+            }
         }
     }
 
@@ -19,6 +19,7 @@
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
+use kernel::power;
 
 #[repr(C)]
 struct ClockRegisters {
@@ -158,6 +159,11 @@ pub trait ClockClient {
 
 pub static mut CLOCK: Clock = Clock::new();
 
+/// Constraints peripherals hold on how deeply the chip can sleep. Deep sleep
+/// stops the high frequency crystal, so peripherals that need it, such as the
+/// radio, must hold a constraint while they use it.
+pub static mut POWER_CONSTRAINTS: power::PowerManager = power::PowerManager::new();
+
 impl Clock {
     /// Constructor
     pub const fn new() -> Clock {
@@ -223,6 +229,14 @@ impl Clock {
         }
     }
 
+    /// Check if the high frequency clock is running from the crystal
+    pub fn high_xtal_running(&self) -> bool {
+        match self.high_source() {
+            HighClockSource::XTAL => self.high_running(),
+            HighClockSource::RC => false,
+        }
+    }
+
     /// Check if the high frequency clock is running
     pub fn high_running(&self) -> bool {
         let regs = &*self.registers;
@@ -302,6 +302,12 @@ impl Ficr {
         }
     }
 
+    /// Number of pages of code flash.
+    pub fn code_pages(&self) -> usize {
+        let regs = &*self.registers;
+        regs.codesize.read(CodeSize::CODESIZE) as usize
+    }
+
     fn flash(&self) -> Flash {
         let regs = &*self.registers;
         match regs.info_flash.get() {
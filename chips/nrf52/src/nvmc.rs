@@ -13,6 +13,8 @@ use kernel::common::StaticRef;
 use kernel::hil;
 use kernel::ReturnCode;
 
+use ficr;
+
 use deferred_call_tasks::DeferredCallTask;
 
 const NVMC_BASE: StaticRef<NvmcRegisters> =
@@ -303,11 +305,15 @@ impl Nvmc {
     }
 
     fn write_page(&self, page_number: usize, data: &'static mut NrfPage) -> ReturnCode {
-        let regs = &*self.registers;
-
         // Need to erase the page first.
         self.erase_page_helper(page_number);
 
+        self.program_page(page_number, data)
+    }
+
+    fn program_page(&self, page_number: usize, data: &'static mut NrfPage) -> ReturnCode {
+        let regs = &*self.registers;
+
         // Put the NVMC in write mode.
         regs.config.write(Configuration::WEN::Wen);
 
@@ -317,6 +323,11 @@ impl Nvmc {
                 | (data[i + 2] as u32) << 16
                 | (data[i + 3] as u32) << 24;
 
+            // Writing an erased word would not change it.
+            if word == 0xffffffff {
+                continue;
+            }
+
             let address = ((page_number * PAGE_SIZE) + i) as u32;
             let location = unsafe { &*(address as *const VolatileCell<u32>) };
             location.set(word);
@@ -348,6 +359,26 @@ impl Nvmc {
 
         ReturnCode::SUCCESS
     }
+
+    fn erase_pages(&self, page_number: usize, num_pages: usize) -> ReturnCode {
+        if num_pages == 0 || page_number + num_pages > self.num_pages() {
+            return ReturnCode::EINVAL;
+        }
+
+        // The NVMC erases one page at a time.
+        for page in page_number..page_number + num_pages {
+            self.erase_page_helper(page);
+        }
+
+        self.state.set(FlashState::Erase);
+        DEFERRED_CALL.set();
+
+        ReturnCode::SUCCESS
+    }
+
+    fn num_pages(&self) -> usize {
+        unsafe { ficr::FICR_INSTANCE.code_pages() }
+    }
 }
 
 impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for Nvmc {
@@ -371,3 +402,24 @@ impl hil::flash::Flash for Nvmc {
         self.erase_page(page_number)
     }
 }
+
+impl hil::flash::EraseFlash for Nvmc {
+    fn geometry(&self) -> hil::flash::Geometry {
+        hil::flash::Geometry {
+            page_size: PAGE_SIZE,
+            num_pages: self.num_pages(),
+            program_size: 4,
+            erase_size: PAGE_SIZE,
+            max_erase_size: PAGE_SIZE,
+            write_erases: true,
+        }
+    }
+
+    fn program_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.program_page(page_number, buf)
+    }
+
+    fn erase_pages(&self, page_number: usize, num_pages: usize) -> ReturnCode {
+        self.erase_pages(page_number, num_pages)
+    }
+}
@@ -33,6 +33,7 @@
 //!
 //! * CRC - 3 bytes
 
+use clock;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
@@ -41,6 +42,7 @@ use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::power::{PowerConstraint, SleepState};
 use kernel::ReturnCode;
 use nrf5x;
 use nrf5x::constants::TxPower;
@@ -535,6 +537,9 @@ pub struct Radio {
     tx_power: Cell<TxPower>,
     rx_client: OptionalCell<&'static ble_advertising::RxClient>,
     tx_client: OptionalCell<&'static ble_advertising::TxClient>,
+    /// Held while the radio is on, because it needs the high frequency
+    /// crystal.
+    power_constraint: PowerConstraint,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -546,6 +551,7 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: OptionalCell::empty(),
             tx_client: OptionalCell::empty(),
+            power_constraint: PowerConstraint::new(SleepState::Sleep),
         }
     }
 
@@ -573,6 +579,9 @@ impl Radio {
 
     fn radio_on(&self) {
         let regs = &*self.registers;
+        unsafe {
+            clock::POWER_CONSTRAINTS.set_active(&self.power_constraint, true);
+        }
         // reset and enable power
         regs.power.write(Task::ENABLE::CLEAR);
         regs.power.write(Task::ENABLE::SET);
@@ -581,6 +590,9 @@ impl Radio {
     fn radio_off(&self) {
         let regs = &*self.registers;
         regs.power.write(Task::ENABLE::CLEAR);
+        unsafe {
+            clock::POWER_CONSTRAINTS.set_active(&self.power_constraint, false);
+        }
     }
 
     fn set_tx_power(&self) {
@@ -3,7 +3,7 @@
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
-use kernel::hil::time::{self, Alarm, Freq32KHz, Time};
+use kernel::hil::time::{self, Alarm, Freq32KHz, Ticks24, Time};
 use kernel::hil::Controller;
 
 const RTC1_BASE: StaticRef<RtcRegisters> =
@@ -148,18 +148,21 @@ impl Time for Rtc {
 }
 
 impl Alarm for Rtc {
-    fn now(&self) -> u32 {
-        self.registers.counter.get()
+    // COUNTER is 24 bits wide.
+    type Ticks = Ticks24;
+
+    fn now(&self) -> Ticks24 {
+        Ticks24(self.registers.counter.get())
     }
 
-    fn set_alarm(&self, tics: u32) {
+    fn set_alarm(&self, tics: Ticks24) {
         // Similarly to the disable function, here we don't restart the timer
         // Instead, we just listen for it again
-        self.registers.cc[0].write(CC::CC.val(tics));
+        self.registers.cc[0].write(CC::CC.val(tics.0));
         self.registers.intenset.write(Inte::COMPARE0::SET);
     }
 
-    fn get_alarm(&self) -> u32 {
-        self.registers.cc[0].read(CC::CC)
+    fn get_alarm(&self) -> Ticks24 {
+        Ticks24(self.registers.cc[0].read(CC::CC))
     }
 }
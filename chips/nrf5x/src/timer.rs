@@ -26,6 +26,7 @@ use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{self, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::hil::time::Ticks32;
 
 const INSTANCES: [StaticRef<TimerRegisters>; 3] = unsafe {
     [
@@ -328,18 +329,20 @@ impl hil::time::Time for TimerAlarm {
 }
 
 impl hil::time::Alarm for TimerAlarm {
-    fn now(&self) -> u32 {
-        self.value()
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32(self.value())
     }
 
-    fn set_alarm(&self, tics: u32) {
+    fn set_alarm(&self, tics: Ticks32) {
         self.disable_interrupts();
-        self.registers.cc[ALARM_COMPARE].write(CC::CC.val(tics));
+        self.registers.cc[ALARM_COMPARE].write(CC::CC.val(tics.0));
         self.clear_alarm();
         self.enable_interrupts();
     }
 
-    fn get_alarm(&self) -> u32 {
-        self.registers.cc[ALARM_COMPARE].read(CC::CC)
+    fn get_alarm(&self) -> Ticks32 {
+        Ticks32(self.registers.cc[ALARM_COMPARE].read(CC::CC))
     }
 }
@@ -24,6 +24,7 @@ use kernel::common::math;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::power::{PowerConstraint, SleepState};
 use kernel::ReturnCode;
 use pm::{self, Clock, PBAClock};
 use scif;
@@ -114,6 +115,10 @@ pub struct Adc {
     dma_running: Cell<bool>,
     cpu_clock: Cell<bool>,
 
+    // held while sampling, since samples are lost if the ADC's clock or the
+    // PDCA stop
+    sampling_constraint: PowerConstraint,
+
     // timer fire counting for slow sampling rates
     timer_repeats: Cell<u8>,
     timer_counts: Cell<u8>,
@@ -366,6 +371,7 @@ impl Adc {
             continuous: Cell::new(false),
             dma_running: Cell::new(false),
             cpu_clock: Cell::new(false),
+            sampling_constraint: PowerConstraint::new(SleepState::Idle),
 
             // timer repeating state for slow sampling rates
             timer_repeats: Cell::new(0),
@@ -384,6 +390,14 @@ impl Adc {
         }
     }
 
+    /// Records whether the ADC is sampling.
+    fn set_active(&self, active: bool) {
+        self.active.set(active);
+        unsafe {
+            pm::POWER_CONSTRAINTS.set_active(&self.sampling_constraint, active);
+        }
+    }
+
     /// Sets the client for this driver.
     ///
     /// - `client`: reference to capsule which handles responses
@@ -424,7 +438,7 @@ impl Adc {
                         self.timer_counts.set(0);
                     } else {
                         // single sampling, disable interrupt and set inactive
-                        self.active.set(false);
+                        self.set_active(false);
                         regs.idr.write(Interrupt::SEOC::SET);
                     }
                 } else {
@@ -622,7 +636,7 @@ impl hil::adc::Adc for Adc {
             // only one operation at a time
             ReturnCode::EBUSY
         } else {
-            self.active.set(true);
+            self.set_active(true);
             self.continuous.set(false);
             self.timer_repeats.set(0);
             self.timer_counts.set(0);
@@ -675,7 +689,7 @@ impl hil::adc::Adc for Adc {
             // limit sampling frequencies to a valid range
             ReturnCode::EINVAL
         } else {
-            self.active.set(true);
+            self.set_active(true);
             self.continuous.set(true);
 
             // adc sequencer configuration
@@ -763,7 +777,7 @@ impl hil::adc::Adc for Adc {
             ReturnCode::EINVAL
         } else {
             // clean up state
-            self.active.set(false);
+            self.set_active(false);
             self.continuous.set(false);
             self.dma_running.set(false);
 
@@ -861,7 +875,7 @@ impl hil::adc::AdcHighSpeed for Adc {
             // samples. Otherwise, what are we doing here?
             (ReturnCode::EINVAL, Some(buffer1), Some(buffer2))
         } else {
-            self.active.set(true);
+            self.set_active(true);
             self.continuous.set(true);
 
             // store the second buffer for later use
@@ -7,7 +7,7 @@
 use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
-use kernel::hil::time::{self, Alarm, Freq16KHz, Time};
+use kernel::hil::time::{self, Alarm, Freq16KHz, Ticks32, Time};
 use kernel::hil::Controller;
 use pm::{self, PBDClock};
 
@@ -316,12 +316,15 @@ impl Time for Ast<'a> {
 }
 
 impl Alarm for Ast<'a> {
-    fn now(&self) -> u32 {
-        self.get_counter()
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
+        Ticks32(self.get_counter())
     }
 
-    fn set_alarm(&self, mut tics: u32) {
+    fn set_alarm(&self, tics: Ticks32) {
         let regs: &AstRegisters = &*self.registers;
+        let mut tics = tics.0;
         let now = self.get_counter();
         if tics.wrapping_sub(now) <= ALARM0_SYNC_TICS {
             tics = now.wrapping_add(ALARM0_SYNC_TICS);
@@ -334,9 +337,9 @@ impl Alarm for Ast<'a> {
         self.enable();
     }
 
-    fn get_alarm(&self) -> u32 {
+    fn get_alarm(&self) -> Ticks32 {
         let regs: &AstRegisters = &*self.registers;
         while self.busy() {}
-        regs.ar0.read(Value::VALUE)
+        Ticks32(regs.ar0.read(Value::VALUE))
     }
 }
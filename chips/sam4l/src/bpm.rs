@@ -159,6 +159,14 @@ pub enum PowerScaling {
     PS2,
 }
 
+/// Sleep modes the core enters when it sleeps without SLEEPDEEP set.
+pub enum SleepMode {
+    CpuStopped = 0,
+    CpuAhbStopped = 1,
+    CpuAhbPbGclkStopped = 2,
+    CpuAhbPbGclkClockStopped = 3,
+}
+
 pub enum CK32Source {
     OSC32K = 0,
     RC32K = 1,
@@ -172,6 +180,13 @@ pub unsafe fn set_ck32source(source: CK32Source) {
         .modify_no_read(control, PowerModeControl::CK32S.val(source as u32));
 }
 
+pub unsafe fn set_sleep_mode(mode: SleepMode) {
+    let control = BPM.pmcon.extract();
+    unlock_register(0x1c); // Control
+    BPM.pmcon
+        .modify_no_read(control, PowerModeControl::SLEEP.val(mode as u32));
+}
+
 unsafe fn unlock_register(register_offset: u32) {
     BPM.unlock
         .write(Unlock::KEY.val(BPM_UNLOCK_KEY) + Unlock::ADDR.val(register_offset));
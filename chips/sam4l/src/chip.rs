@@ -4,6 +4,7 @@ use acifc;
 use adc;
 use aes;
 use ast;
+use bpm;
 use cortexm4;
 use crccu;
 use dac;
@@ -13,6 +14,7 @@ use flashcalw;
 use gpio;
 use i2c;
 use kernel::common::deferred_call;
+use kernel::power::SleepState;
 use kernel::Chip;
 use nvic;
 use pm;
@@ -163,14 +165,31 @@ impl Chip for Sam4l {
         &self.systick
     }
 
-    fn sleep(&self) {
+    fn sleep_state(&self) -> SleepState {
+        // Peripherals with their clocks on keep the chip awake whether or not
+        // they have a constraint.
+        let allowed = unsafe { pm::POWER_CONSTRAINTS.deepest_allowed() };
         if pm::deep_sleep_ready() {
-            unsafe {
-                cortexm4::scb::set_sleepdeep();
-            }
+            allowed
         } else {
-            unsafe {
-                cortexm4::scb::unset_sleepdeep();
+            SleepState::Idle
+        }
+    }
+
+    fn sleep(&self, state: SleepState) {
+        unsafe {
+            match state {
+                SleepState::Idle => {
+                    bpm::set_sleep_mode(bpm::SleepMode::CpuStopped);
+                    cortexm4::scb::unset_sleepdeep();
+                }
+                SleepState::Sleep => {
+                    bpm::set_sleep_mode(bpm::SleepMode::CpuAhbStopped);
+                    cortexm4::scb::unset_sleepdeep();
+                }
+                SleepState::DeepSleep => {
+                    cortexm4::scb::set_sleepdeep();
+                }
             }
         }
 
@@ -349,14 +349,15 @@ enum FlashCMD {
 /// FlashState is used to track the current state and command of the flash.
 #[derive(Clone, Copy, PartialEq)]
 enum FlashState {
-    Unconfigured,                 // Flash is unconfigured, call configure().
-    Ready,                        // Flash is ready to complete a command.
-    Read,                         // Performing a read operation.
-    WriteUnlocking { page: i32 }, // Started a write operation.
-    WriteErasing { page: i32 },   // Waiting on the page to erase.
-    WriteWriting,                 // Waiting on the page to actually be written.
-    EraseUnlocking { page: i32 }, // Started an erase operation.
-    EraseErasing,                 // Waiting on the erase to finish.
+    Unconfigured,                           // Flash is unconfigured, call configure().
+    Ready,                                  // Flash is ready to complete a command.
+    Read,                                   // Performing a read operation.
+    WriteUnlocking { page: i32 },           // Started a write operation.
+    WriteErasing { page: i32 },             // Waiting on the page to erase.
+    WriteWriting,                           // Waiting on the page to actually be written.
+    ProgramUnlocking { page: i32 },         // Started a write without erase.
+    EraseUnlocking { page: i32, end: i32 }, // Started erasing pages up to end.
+    EraseErasing { page: i32, end: i32 },   // Waiting on the erase to finish.
 }
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
@@ -509,12 +510,13 @@ impl FLASHCALW {
                 }
                 FlashState::WriteUnlocking { .. }
                 | FlashState::WriteErasing { .. }
-                | FlashState::WriteWriting => {
+                | FlashState::WriteWriting
+                | FlashState::ProgramUnlocking { .. } => {
                     self.buffer.take().map(|buffer| {
                         client.write_complete(buffer, hil::flash::Error::FlashError);
                     });
                 }
-                FlashState::EraseUnlocking { .. } | FlashState::EraseErasing => {
+                FlashState::EraseUnlocking { .. } | FlashState::EraseErasing { .. } => {
                     client.erase_complete(hil::flash::Error::FlashError);
                 }
                 _ => {}
@@ -537,7 +539,7 @@ impl FLASHCALW {
                     .set(FlashState::WriteErasing { page: page });
                 self.flashcalw_erase_page(page);
             }
-            FlashState::WriteErasing { page } => {
+            FlashState::WriteErasing { page } | FlashState::ProgramUnlocking { page } => {
                 //  Write page buffer isn't really a command, and
                 //  clear page buffer doesn't trigger an interrupt thus
                 //  I'm combining these with an actual command, write_page,
@@ -560,11 +562,20 @@ impl FLASHCALW {
                     });
                 });
             }
-            FlashState::EraseUnlocking { page } => {
-                self.current_state.set(FlashState::EraseErasing);
+            FlashState::EraseUnlocking { page, end } => {
+                self.current_state
+                    .set(FlashState::EraseErasing { page: page, end: end });
                 self.flashcalw_erase_page(page);
             }
-            FlashState::EraseErasing => {
+            FlashState::EraseErasing { page, end } if page + 1 < end => {
+                // Go on with the next page of the range.
+                self.current_state.set(FlashState::EraseUnlocking {
+                    page: page + 1,
+                    end: end,
+                });
+                self.lock_page_region(page + 1, false);
+            }
+            FlashState::EraseErasing { .. } => {
                 self.current_state.set(FlashState::Ready);
 
                 self.client.map(|client| {
@@ -899,11 +910,55 @@ impl FLASHCALW {
             return ReturnCode::EBUSY;
         }
 
+        self.current_state.set(FlashState::EraseUnlocking {
+            page: page_num,
+            end: page_num + 1,
+        });
+        self.lock_page_region(page_num, false);
+        ReturnCode::SUCCESS
+    }
+
+    fn program_page(&self, page_num: i32, data: &'static mut Sam4lPage) -> ReturnCode {
+        // Enable clock in case it's off.
+        pm::enable_clock(self.ahb_clock);
+
+        match self.current_state.get() {
+            FlashState::Unconfigured => return ReturnCode::FAIL,
+            FlashState::Ready => {}
+            // If we're not ready don't take the command
+            _ => return ReturnCode::EBUSY,
+        }
+
+        // Save the buffer for the future write.
+        self.buffer.replace(data);
+
         self.current_state
-            .set(FlashState::EraseUnlocking { page: page_num });
+            .set(FlashState::ProgramUnlocking { page: page_num });
+        self.lock_page_region(page_num, false);
+        ReturnCode::SUCCESS
+    }
+
+    fn erase_pages(&self, page_num: i32, num_pages: i32) -> ReturnCode {
+        // Enable AHB clock (in case it was off).
+        pm::enable_clock(self.ahb_clock);
+        if self.current_state.get() != FlashState::Ready {
+            return ReturnCode::EBUSY;
+        }
+        if num_pages <= 0 || page_num + num_pages > self.num_pages() as i32 {
+            return ReturnCode::EINVAL;
+        }
+
+        self.current_state.set(FlashState::EraseUnlocking {
+            page: page_num,
+            end: page_num + num_pages,
+        });
         self.lock_page_region(page_num, false);
         ReturnCode::SUCCESS
     }
+
+    fn num_pages(&self) -> usize {
+        (self.get_flash_size() / PAGE_SIZE) as usize
+    }
 }
 
 impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for FLASHCALW {
@@ -927,3 +982,26 @@ impl hil::flash::Flash for FLASHCALW {
         self.erase_page(page_number as i32)
     }
 }
+
+impl hil::flash::EraseFlash for FLASHCALW {
+    fn geometry(&self) -> hil::flash::Geometry {
+        // Enable AHB clock (in case it was off).
+        pm::enable_clock(self.ahb_clock);
+        hil::flash::Geometry {
+            page_size: PAGE_SIZE as usize,
+            num_pages: self.num_pages(),
+            program_size: PAGE_SIZE as usize,
+            erase_size: PAGE_SIZE as usize,
+            max_erase_size: PAGE_SIZE as usize,
+            write_erases: true,
+        }
+    }
+
+    fn program_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+        self.program_page(page_number as i32, buf)
+    }
+
+    fn erase_pages(&self, page_number: usize, num_pages: usize) -> ReturnCode {
+        self.erase_pages(page_number as i32, num_pages as i32)
+    }
+}
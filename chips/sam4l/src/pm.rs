@@ -8,6 +8,7 @@ use flashcalw;
 use gpio;
 use kernel::common::registers::{FieldValue, ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
+use kernel::power;
 use kernel::ClockInterface;
 use scif;
 
@@ -566,6 +567,10 @@ pub struct PowerManager {
     system_initial_configs: Cell<bool>,
 }
 
+/// Constraints peripherals hold on how deeply the chip can sleep, for the
+/// cases the clock masks checked by `deep_sleep_ready()` do not cover.
+pub static mut POWER_CONSTRAINTS: power::PowerManager = power::PowerManager::new();
+
 pub static mut PM: PowerManager = PowerManager {
     /// Set to the RCSYS by default.
     system_clock_source: Cell::new(SystemClockSource::RcsysAt115kHz),
@@ -1067,8 +1072,8 @@ macro_rules! get_clock {
 /// A special note here regarding the PDCA (Peripheral DMA Controller) clock.
 /// If the core deep sleeps while a DMA operation is active, it is transparently paused
 /// and resumed when the core wakes again. If a peripheral needs a DMA operation to complete
-/// before sleeping, the peripheral should inhibit sleep. The rationale here is to allow deep
-/// sleep for an I2C Slave peripheral configured to use DMA.
+/// before sleeping, the peripheral should inhibit sleep with a constraint in `POWER_CONSTRAINTS`.
+/// The rationale here is to allow deep sleep for an I2C Slave peripheral configured to use DMA.
 ///
 /// We also special case GPIO (which is in PBCMASK), and just see if any interrupts are pending
 /// through the INTERRUPT_COUNT variable.
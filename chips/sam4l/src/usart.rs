@@ -9,6 +9,7 @@ use kernel::common::cells::OptionalCell;
 use kernel::common::registers::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::power::{PowerConstraint, SleepState};
 use kernel::ReturnCode;
 
 use dma;
@@ -377,6 +378,9 @@ pub struct USART {
 
     usart_tx_state: Cell<USARTStateTX>,
     usart_rx_state: Cell<USARTStateRX>,
+    /// Held while receiving, because reception needs the PDCA, which stops
+    /// in every sleep mode but the lightest.
+    rx_constraint: PowerConstraint,
 
     rx_dma: Cell<Option<&'static dma::DMAChannel>>,
     rx_dma_peripheral: dma::DMAPeripheral,
@@ -430,6 +434,7 @@ impl USART {
             usart_mode: Cell::new(UsartMode::Unused),
 
             usart_rx_state: Cell::new(USARTStateRX::Idle),
+            rx_constraint: PowerConstraint::new(SleepState::Idle),
             usart_tx_state: Cell::new(USARTStateTX::Idle),
 
             // these get defined later by `chip.rs`
@@ -448,6 +453,14 @@ impl USART {
         }
     }
 
+    fn set_rx_state(&self, state: USARTStateRX) {
+        self.usart_rx_state.set(state);
+        unsafe {
+            pm::POWER_CONSTRAINTS
+                .set_active(&self.rx_constraint, state == USARTStateRX::DMA_Receiving);
+        }
+    }
+
     pub fn set_dma(&self, rx_dma: &'static dma::DMAChannel, tx_dma: &'static dma::DMAChannel) {
         self.rx_dma.set(Some(rx_dma));
         self.tx_dma.set(Some(tx_dma));
@@ -484,7 +497,7 @@ impl USART {
 
     fn disable_rx(&self, usart: &USARTRegManager) {
         usart.registers.cr.write(Control::RXDIS::SET);
-        self.usart_rx_state.set(USARTStateRX::Idle);
+        self.set_rx_state(USARTStateRX::Idle);
     }
 
     fn disable_tx(&self, usart: &USARTRegManager) {
@@ -496,7 +509,7 @@ impl USART {
         if self.usart_rx_state.get() == USARTStateRX::DMA_Receiving {
             self.disable_rx_interrupts(usart);
             self.disable_rx(usart);
-            self.usart_rx_state.set(USARTStateRX::Idle);
+            self.set_rx_state(USARTStateRX::Idle);
 
             // get buffer
             let mut length = 0;
@@ -753,7 +766,7 @@ impl dma::DMAClient for USART {
                     // disable RX and RX interrupts
                     self.disable_rx_interrupts(usart);
                     self.disable_rx(usart);
-                    self.usart_rx_state.set(USARTStateRX::Idle);
+                    self.set_rx_state(USARTStateRX::Idle);
 
                     // get buffer
                     let buffer = self.rx_dma.get().map_or(None, |rx_dma| {
@@ -807,7 +820,7 @@ impl dma::DMAClient for USART {
 
                     // The RX is either already idle and disabled (we didn't
                     // do a read) or it is now safe to do this.
-                    self.usart_rx_state.set(USARTStateRX::Idle);
+                    self.set_rx_state(USARTStateRX::Idle);
                     self.disable_rx(usart);
                 }
             }
@@ -892,7 +905,7 @@ impl hil::uart::UART for USART {
         // enable RX
         self.enable_rx(usart);
         self.enable_rx_error_interrupts(usart);
-        self.usart_rx_state.set(USARTStateRX::DMA_Receiving);
+        self.set_rx_state(USARTStateRX::DMA_Receiving);
         // set up dma transfer and start reception
         self.rx_dma.get().map(move |dma| {
             dma.enable();
@@ -918,7 +931,7 @@ impl hil::uart::UARTReceiveAdvanced for USART {
         // enable RX
         self.enable_rx(usart);
         self.enable_rx_error_interrupts(usart);
-        self.usart_rx_state.set(USARTStateRX::DMA_Receiving);
+        self.set_rx_state(USARTStateRX::DMA_Receiving);
 
         // enable receive timeout
         self.enable_rx_timeout(usart, interbyte_timeout);
@@ -1009,12 +1022,12 @@ impl hil::spi::SpiMaster for USART {
 
                         // Start the write transaction.
                         self.usart_tx_state.set(USARTStateTX::DMA_Transmitting);
-                        self.usart_rx_state.set(USARTStateRX::Idle);
+                        self.set_rx_state(USARTStateRX::Idle);
                         dma.enable();
                         dma.do_transfer(self.tx_dma_peripheral, write_buffer, count);
 
                         // Start the read transaction.
-                        self.usart_rx_state.set(USARTStateRX::DMA_Receiving);
+                        self.set_rx_state(USARTStateRX::DMA_Receiving);
                         read.enable();
                         read.do_transfer(self.rx_dma_peripheral, rbuf, count);
                     });
@@ -1024,7 +1037,7 @@ impl hil::spi::SpiMaster for USART {
             // We are just writing.
             self.tx_dma.get().map(move |dma| {
                 self.usart_tx_state.set(USARTStateTX::DMA_Transmitting);
-                self.usart_rx_state.set(USARTStateRX::Idle);
+                self.set_rx_state(USARTStateRX::Idle);
                 dma.enable();
                 dma.do_transfer(self.tx_dma_peripheral, write_buffer, count);
             });
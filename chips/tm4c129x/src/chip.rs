@@ -2,6 +2,7 @@
 
 use cortexm4;
 use gpt;
+use kernel::power::SleepState;
 use kernel::Chip;
 use uart;
 
@@ -58,7 +59,11 @@ impl Chip for Tm4c129x {
         &self.systick
     }
 
-    fn sleep(&self) {
+    fn sleep_state(&self) -> SleepState {
+        SleepState::Idle
+    }
+
+    fn sleep(&self, _state: SleepState) {
         /*if pm::deep_sleep_ready() {
             unsafe {
                 cortexm4::scb::set_sleepdeep();
@@ -1,6 +1,7 @@
 use kernel::common::cells::{OptionalCell, VolatileCell};
 use kernel::common::StaticRef;
 use kernel::hil;
+use kernel::hil::time::Ticks32;
 use sysctl;
 
 #[repr(C)]
@@ -115,19 +116,21 @@ impl hil::time::Time for AlarmTimer {
 }
 
 impl hil::time::Alarm for AlarmTimer {
-    fn now(&self) -> u32 {
+    type Ticks = Ticks32;
+
+    fn now(&self) -> Ticks32 {
         let regs = &*self.registers;
-        regs.tar.get()
+        Ticks32(regs.tar.get())
     }
 
-    fn set_alarm(&self, tics: u32) {
+    fn set_alarm(&self, tics: Ticks32) {
         let regs = &*self.registers;
-        regs.tamatchr.set(tics);
+        regs.tamatchr.set(tics.0);
         regs.tamr.set(regs.tamr.get() | (1 << 5));
     }
 
-    fn get_alarm(&self) -> u32 {
+    fn get_alarm(&self) -> Ticks32 {
         let regs = &*self.registers;
-        regs.tamatchr.get()
+        Ticks32(regs.tamatchr.get())
     }
 }
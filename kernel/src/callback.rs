@@ -50,6 +50,30 @@ impl AppId {
             (start, end)
         })
     }
+
+    /// Returns the package name of the app, or an empty name if the app no
+    /// longer exists.
+    pub fn get_package_name(&self) -> &'static [u8] {
+        self.kernel
+            .process_map_or(&[], self.idx, |process| process.get_process_name())
+    }
+
+    /// Returns how many bytes of nonvolatile storage the app's TBF header asks
+    /// for, if it has a Storage Size TLV.
+    pub fn get_storage_size(&self) -> Option<usize> {
+        self.kernel
+            .process_map_or(None, self.idx, |process| process.get_storage_size())
+    }
+}
+
+/// The driver and subscribe number a callback was registered with.
+///
+/// Upcalls with the same `CallbackId` for a process are duplicates of each
+/// other, for example two interrupts from the same GPIO pin.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CallbackId {
+    pub driver_num: usize,
+    pub subscribe_num: usize,
 }
 
 /// Type for calling a callback in a process.
@@ -58,24 +82,50 @@ impl AppId {
 #[derive(Clone, Copy)]
 pub struct Callback {
     app_id: AppId,
+    callback_id: CallbackId,
     appdata: usize,
     fn_ptr: NonNull<*mut ()>,
 }
 
 impl Callback {
-    crate fn new(appid: AppId, appdata: usize, fn_ptr: NonNull<*mut ()>) -> Callback {
+    crate fn new(
+        appid: AppId,
+        callback_id: CallbackId,
+        appdata: usize,
+        fn_ptr: NonNull<*mut ()>,
+    ) -> Callback {
         Callback {
             app_id: appid,
+            callback_id: callback_id,
             appdata: appdata,
             fn_ptr: fn_ptr,
         }
     }
 
+    /// The driver and subscribe number this callback was registered with.
+    pub fn callback_id(&self) -> CallbackId {
+        self.callback_id
+    }
+
+    /// The `FunctionCall` that runs this callback with arguments `r0-r2`.
+    crate fn function_call(&self, r0: usize, r1: usize, r2: usize) -> process::FunctionCall {
+        process::FunctionCall {
+            source: process::FunctionCallSource::Driver(self.callback_id),
+            argument0: r0,
+            argument1: r1,
+            argument2: r2,
+            argument3: self.appdata,
+            pc: self.fn_ptr.as_ptr() as usize,
+        }
+    }
+
     /// Actually trigger the callback.
     ///
     /// This will queue the `Callback` for the associated process. It returns
     /// `false` if the queue for the process is full and the callback could not
-    /// be scheduled.
+    /// be scheduled. If the process asked for upcalls to be coalesced, a
+    /// callback that replaces the arguments of one already queued with the
+    /// same `CallbackId` also returns `true`.
     ///
     /// The arguments (`r0-r2`) are the values passed back to the process and
     /// are specific to the individual `Driver` interfaces.
@@ -83,13 +133,7 @@ impl Callback {
         self.app_id
             .kernel
             .process_map_or(false, self.app_id.idx(), |process| {
-                process.enqueue_task(process::Task::FunctionCall(process::FunctionCall {
-                    argument0: r0,
-                    argument1: r1,
-                    argument2: r2,
-                    argument3: self.appdata,
-                    pc: self.fn_ptr.as_ptr() as usize,
-                }))
+                process.enqueue_task(process::Task::FunctionCall(self.function_call(r0, r1, r2)))
             })
     }
 }
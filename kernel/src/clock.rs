@@ -0,0 +1,72 @@
+//! Kernel-wide monotonic clock.
+//!
+//! The kernel clock is a `hil::time::Alarm64`, usually a chip's RTC extended
+//! to 64 bits with `capsules::overflow_alarm::OverflowAlarm`, so it counts
+//! ticks without ever wrapping. Boards pass it to `Kernel::set_kernel_clock()`.
+//! The kernel then enforces timeslices with a one-shot alarm at the end of
+//! each timeslice instead of the chip's `SysTick`. While the kernel sleeps the
+//! only alarm left is the one that tracks counter overflows, which fires once
+//! every half period of the counter, so the chip is not woken up periodically.
+//!
+//! Capsules that need timestamps comparable across the system can hold a
+//! reference to the same clock and call `KernelTimer::now()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust,ignore
+//! let kernel_clock_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm)
+//! );
+//! let kernel_clock = static_init!(
+//!     OverflowAlarm<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     OverflowAlarm::new(kernel_clock_alarm)
+//! );
+//! kernel_clock_alarm.set_client(kernel_clock);
+//! kernel_clock.start();
+//! board_kernel.set_kernel_clock(kernel_clock, &main_loop_capability);
+//! ```
+
+use hil::time::{Alarm64, Frequency, Ticks, Ticks64};
+
+/// Interface the kernel uses to read the clock and end timeslices.
+pub trait KernelTimer {
+    /// Ticks since the clock started.
+    fn now(&self) -> u64;
+
+    /// Ticks per second.
+    fn frequency(&self) -> u32;
+
+    /// Make sure an interrupt happens when the clock reaches `deadline`, or
+    /// stop waiting for the previous deadline with `None`.
+    fn set_deadline(&self, deadline: Option<u64>);
+
+    /// Convert `us` microseconds to ticks, rounding down.
+    fn us_to_ticks(&self, us: u32) -> u64 {
+        us as u64 * self.frequency() as u64 / 1_000_000
+    }
+
+    /// Convert `ticks` to microseconds, rounding down.
+    fn ticks_to_us(&self, ticks: u64) -> u64 {
+        let frequency = self.frequency() as u64;
+        ticks / frequency * 1_000_000 + ticks % frequency * 1_000_000 / frequency
+    }
+}
+
+impl<A: Alarm64> KernelTimer for A {
+    fn now(&self) -> u64 {
+        Alarm64::now(self).into_u64()
+    }
+
+    fn frequency(&self) -> u32 {
+        A::Frequency::frequency()
+    }
+
+    fn set_deadline(&self, deadline: Option<u64>) {
+        match deadline {
+            Some(deadline) => self.set_alarm(Ticks64(deadline)),
+            None => self.disable(),
+        }
+    }
+}
@@ -2,7 +2,8 @@
 //!
 //! This is a tool to allow chip peripherals to schedule "interrupts"
 //! in the chip scheduler if the hardware doesn't support interrupts where
-//! they are needed.
+//! they are needed. Capsules, which cannot add tasks to a chip, use
+//! `common::dynamic_deferred_call` instead.
 
 use core::cell::UnsafeCell;
 use core::convert::Into;
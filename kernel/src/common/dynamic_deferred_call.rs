@@ -0,0 +1,189 @@
+//! Deferred calls that capsules can register at runtime.
+//!
+//! A deferred call runs a function from the kernel's main loop some time
+//! after it was requested, outside of the current call stack. This is useful
+//! for drivers that need to call back their client but have no hardware
+//! interrupt to do it from, for example because the operation completes
+//! synchronously.
+//!
+//! Unlike `common::deferred_call`, which only chips can use because each chip
+//! defines an enum of its tasks, clients of `DynamicDeferredCall` register
+//! themselves and get a `DeferredCallHandle` back. The board decides how many
+//! clients there can be by the number of `DynamicDeferredCallClientState`s it
+//! passes in, and gives the `DynamicDeferredCall` to the kernel with
+//! `Kernel::set_dynamic_deferred_call()`. Pending calls are serviced from
+//! `Kernel::kernel_loop()` after interrupts, in order of their
+//! `DeferredCallPriority`. Calls with the same priority take turns.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # #[macro_use]
+//! # extern crate kernel;
+//! # extern crate core;
+//! # use kernel::common::dynamic_deferred_call::{
+//! #     DynamicDeferredCall, DynamicDeferredCallClientState};
+//! # fn main() { unsafe {
+//! let dynamic_deferred_call_clients =
+//!     static_init!([DynamicDeferredCallClientState; 4], Default::default());
+//! let dynamic_deferred_caller = static_init!(
+//!     DynamicDeferredCall,
+//!     DynamicDeferredCall::new(dynamic_deferred_call_clients)
+//! );
+//! # }}
+//! ```
+//!
+//! A client then registers with the `DynamicDeferredCall`, and requests a call
+//! with the handle it gets back:
+//!
+//! ```rust,ignore
+//! let handle = dynamic_deferred_caller.register(client, DeferredCallPriority::Normal);
+//! handle.map(|handle| dynamic_deferred_caller.set(handle));
+//! ```
+
+use core::cell::Cell;
+
+use common::cells::OptionalCell;
+
+/// Implemented by users of `DynamicDeferredCall` to be called back.
+pub trait DynamicDeferredCallClient {
+    /// The deferred call requested with `handle` is running.
+    fn call(&self, handle: DeferredCallHandle);
+}
+
+/// Identifies a registered client of a `DynamicDeferredCall`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DeferredCallHandle(usize);
+
+/// Order in which pending deferred calls run.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum DeferredCallPriority {
+    /// Runs before all other calls, for work that is latency sensitive.
+    High,
+    /// Default for most clients.
+    Normal,
+    /// Runs only when no other calls are pending.
+    Low,
+}
+
+/// Slot for one client in a `DynamicDeferredCall`. Boards allocate an array of
+/// these, one per client.
+pub struct DynamicDeferredCallClientState {
+    client: OptionalCell<&'static DynamicDeferredCallClient>,
+    priority: Cell<DeferredCallPriority>,
+    scheduled: Cell<bool>,
+}
+
+impl Default for DynamicDeferredCallClientState {
+    fn default() -> DynamicDeferredCallClientState {
+        DynamicDeferredCallClientState {
+            client: OptionalCell::empty(),
+            priority: Cell::new(DeferredCallPriority::Normal),
+            scheduled: Cell::new(false),
+        }
+    }
+}
+
+/// Deferred calls for any number of dynamically registered clients.
+pub struct DynamicDeferredCall {
+    client_states: &'static [DynamicDeferredCallClientState],
+    /// Number of slots in `client_states` that are registered.
+    registered: Cell<usize>,
+    /// Number of scheduled calls.
+    pending: Cell<usize>,
+    /// Slot of the call that ran last, so that calls with the same priority
+    /// take turns.
+    last_called: Cell<usize>,
+}
+
+impl DynamicDeferredCall {
+    pub fn new(client_states: &'static [DynamicDeferredCallClientState]) -> DynamicDeferredCall {
+        DynamicDeferredCall {
+            client_states: client_states,
+            registered: Cell::new(0),
+            pending: Cell::new(0),
+            last_called: Cell::new(0),
+        }
+    }
+
+    /// Register `client` to run its deferred calls with `priority`.
+    ///
+    /// Returns `None` if all client slots are taken.
+    pub fn register(
+        &self,
+        client: &'static DynamicDeferredCallClient,
+        priority: DeferredCallPriority,
+    ) -> Option<DeferredCallHandle> {
+        let index = self.registered.get();
+        self.client_states.get(index).map(|state| {
+            state.client.set(client);
+            state.priority.set(priority);
+            self.registered.set(index + 1);
+            DeferredCallHandle(index)
+        })
+    }
+
+    /// Request a call for the client registered as `handle`.
+    ///
+    /// Returns `false` if a call was already pending, in which case the client
+    /// is still only called once, or `None` if `handle` is not registered.
+    pub fn set(&self, handle: DeferredCallHandle) -> Option<bool> {
+        if handle.0 >= self.registered.get() {
+            return None;
+        }
+        let state = &self.client_states[handle.0];
+        if state.scheduled.get() {
+            Some(false)
+        } else {
+            state.scheduled.set(true);
+            self.pending.set(self.pending.get() + 1);
+            Some(true)
+        }
+    }
+
+    /// Whether any deferred calls are waiting to run.
+    pub fn has_pending(&self) -> bool {
+        self.pending.get() > 0
+    }
+
+    /// Run pending calls, highest priority first, for as long as
+    /// `should_continue` returns `true`.
+    crate fn call_while<F: Fn() -> bool>(&self, should_continue: F) {
+        while self.has_pending() && should_continue() {
+            match self.next_pending() {
+                Some(index) => {
+                    let state = &self.client_states[index];
+                    state.scheduled.set(false);
+                    self.pending.set(self.pending.get() - 1);
+                    self.last_called.set(index);
+                    state
+                        .client
+                        .map(|client| client.call(DeferredCallHandle(index)));
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// The slot of the pending call to run next: the highest priority one
+    /// closest after the last call that ran.
+    fn next_pending(&self) -> Option<usize> {
+        let registered = self.registered.get();
+        let mut next: Option<usize> = None;
+        for offset in 1..registered + 1 {
+            let index = (self.last_called.get() + offset) % registered;
+            let state = &self.client_states[index];
+            if !state.scheduled.get() {
+                continue;
+            }
+            let better = next.map_or(true, |next| {
+                state.priority.get() < self.client_states[next].priority.get()
+            });
+            if better {
+                next = Some(index);
+            }
+        }
+        next
+    }
+}
@@ -10,9 +10,11 @@
 pub use tock_registers::{macros, registers};
 
 pub mod deferred_call;
+pub mod dynamic_deferred_call;
 pub mod list;
 pub mod math;
 pub mod peripherals;
+pub mod sha256;
 pub mod utils;
 
 mod queue;
@@ -16,6 +16,19 @@ impl<T: Copy> RingBuffer<'a, T> {
             ring: ring,
         }
     }
+
+    /// Returns the oldest element in the buffer for which `f` returns `true`.
+    pub fn find_mut<F: Fn(&T) -> bool>(&mut self, f: F) -> Option<&mut T> {
+        let len = self.ring.len();
+        let mut index = self.head;
+        while index != self.tail {
+            if f(&self.ring[index]) {
+                return Some(&mut self.ring[index]);
+            }
+            index = (index + 1) % len;
+        }
+        None
+    }
 }
 
 impl<T: Copy> queue::Queue<T> for RingBuffer<'a, T> {
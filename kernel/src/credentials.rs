@@ -0,0 +1,330 @@
+//! Checking app credentials before loading an app.
+//!
+//! An app can carry credentials in Credentials TLVs in its TBF header: a
+//! SHA-256 hash of its image, or a signature over it. A credential covers the
+//! whole TBF image except for the header checksum and the Credentials TLVs
+//! themselves, so that the hash or signature can be computed before it is
+//! added to the header.
+//!
+//! The kernel always rejects an app if one of its credentials that the kernel
+//! can check is wrong. Beyond that, the board selects which apps are loaded
+//! with an `AppCredentialsPolicy`, and provides a `SignatureVerifier` for the
+//! signature formats and keys it trusts. Both are set with
+//! `Kernel::set_app_credentials_policy()` before loading processes.
+
+use common::sha256::{self, Sha256};
+use tbfheader::{TbfHeader, MAX_CREDENTIALS};
+use tock_tbf;
+
+/// The kinds of credentials an app can have.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CredentialsFormat {
+    /// SHA-256 hash of the covered image, 32 bytes.
+    Sha256,
+    /// ECDSA signature with curve P-256 and SHA-256. The 64 byte signature is
+    /// `r` followed by `s`, both big endian.
+    EcdsaP256,
+    /// Ed25519 signature, 64 bytes.
+    Ed25519,
+}
+
+impl CredentialsFormat {
+    /// The format with identifier `format` in a Credentials TLV.
+    fn from_tlv(format: u32) -> Option<CredentialsFormat> {
+        match format {
+            tock_tbf::CREDENTIALS_SHA256 => Some(CredentialsFormat::Sha256),
+            tock_tbf::CREDENTIALS_ECDSA_P256 => Some(CredentialsFormat::EcdsaP256),
+            tock_tbf::CREDENTIALS_ED25519 => Some(CredentialsFormat::Ed25519),
+            _ => None,
+        }
+    }
+}
+
+/// Which apps the kernel loads, based on their credentials.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AppCredentialsPolicy {
+    /// Load apps without credentials. Apps with a credential that is wrong are
+    /// still rejected. This is the default.
+    CheckIfPresent,
+
+    /// Only load apps with a correct hash or signature. This catches corrupted
+    /// images, but not tampered ones.
+    RequireIntegrity,
+
+    /// Only load apps with a signature the board's `SignatureVerifier`
+    /// accepts.
+    RequireSignature,
+}
+
+/// The bytes of a TBF image that its credentials cover.
+pub struct SignedImage {
+    image: &'static [u8],
+    /// Offset and length of each Credentials TLV, in increasing order.
+    excluded: [(usize, usize); MAX_CREDENTIALS],
+    excluded_count: usize,
+}
+
+impl SignedImage {
+    fn new(header: &TbfHeader, image: &'static [u8]) -> SignedImage {
+        let mut signed_image = SignedImage {
+            image: image,
+            excluded: [(0, 0); MAX_CREDENTIALS],
+            excluded_count: 0,
+        };
+        for credential in header.get_credentials().iter().filter_map(|c| *c) {
+            signed_image.excluded[signed_image.excluded_count] =
+                (credential.tlv_offset, credential.tlv_length);
+            signed_image.excluded_count += 1;
+        }
+        signed_image
+    }
+
+    /// Call `f` with each contiguous piece of the covered bytes, in order.
+    pub fn for_each_chunk<F: FnMut(&[u8])>(&self, mut f: F) {
+        // Skip the checksum, which is the fourth word of the header.
+        f(&self.image[..12]);
+        let mut start = 16;
+        for &(offset, length) in self.excluded[..self.excluded_count].iter() {
+            f(&self.image[start..offset]);
+            start = offset + length;
+        }
+        f(&self.image[start..]);
+    }
+
+    fn sha256(&self) -> [u8; sha256::DIGEST_LEN] {
+        let mut hasher = Sha256::new();
+        self.for_each_chunk(|chunk| hasher.update(chunk));
+        hasher.finish()
+    }
+}
+
+/// Checks app signatures.
+///
+/// Boards implement this with hardware crypto or a software library. The
+/// implementation also decides which public keys are trusted.
+pub trait SignatureVerifier {
+    /// Whether this verifier can check signatures in `format`.
+    fn supports(&self, format: CredentialsFormat) -> bool;
+
+    /// Whether `signature` is a valid signature over `image` by a trusted key.
+    fn verify(&self, format: CredentialsFormat, signature: &[u8], image: &SignedImage) -> bool;
+}
+
+/// Check the credentials of the app with `header` and TBF image `image`, and
+/// return whether `policy` allows loading it.
+crate fn check_credentials(
+    header: &TbfHeader,
+    image: &'static [u8],
+    policy: AppCredentialsPolicy,
+    verifier: Option<&SignatureVerifier>,
+) -> bool {
+    let signed_image = SignedImage::new(header, image);
+    let mut has_integrity = false;
+    let mut has_signature = false;
+
+    for credential in header.get_credentials().iter().filter_map(|c| *c) {
+        match CredentialsFormat::from_tlv(credential.format) {
+            Some(CredentialsFormat::Sha256) => {
+                if credential.data != &signed_image.sha256()[..] {
+                    return false;
+                }
+                has_integrity = true;
+            }
+            Some(format) => match verifier {
+                Some(verifier) if verifier.supports(format) => {
+                    if !verifier.verify(format, credential.data, &signed_image) {
+                        return false;
+                    }
+                    has_integrity = true;
+                    has_signature = true;
+                }
+                // Signatures the board cannot check do not count either way.
+                _ => {}
+            },
+            // So do credentials in formats this kernel does not know.
+            None => {}
+        }
+    }
+
+    match policy {
+        AppCredentialsPolicy::CheckIfPresent => true,
+        AppCredentialsPolicy::RequireIntegrity => has_integrity,
+        AppCredentialsPolicy::RequireSignature => has_signature,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use self::std::vec::Vec;
+    use super::{check_credentials, AppCredentialsPolicy, CredentialsFormat, SignatureVerifier};
+    use super::SignedImage;
+    use common::sha256::{self, Sha256};
+    use core::slice;
+    use tbfheader::{self, TbfHeader};
+    use tock_tbf;
+
+    const POLICIES: [AppCredentialsPolicy; 3] = [
+        AppCredentialsPolicy::CheckIfPresent,
+        AppCredentialsPolicy::RequireIntegrity,
+        AppCredentialsPolicy::RequireSignature,
+    ];
+
+    /// Accepts Ed25519 "signatures" that are the SHA-256 hash of the image.
+    struct HashVerifier;
+
+    impl SignatureVerifier for HashVerifier {
+        fn supports(&self, format: CredentialsFormat) -> bool {
+            format == CredentialsFormat::Ed25519
+        }
+
+        fn verify(&self, _: CredentialsFormat, signature: &[u8], image: &SignedImage) -> bool {
+            signature == &image.sha256()[..]
+        }
+    }
+
+    fn sha256(data: &[u8]) -> [u8; sha256::DIGEST_LEN] {
+        let mut hasher = Sha256::new();
+        hasher.update(data);
+        hasher.finish()
+    }
+
+    fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+        bytes.push(value as u8);
+        bytes.push((value >> 8) as u8);
+    }
+
+    fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+        push_u16(bytes, value as u16);
+        push_u16(bytes, (value >> 16) as u16);
+    }
+
+    /// A TBF image with one Credentials TLV in `format` holding the hash of
+    /// the image, followed by a 100 byte binary. If `tamper` is set, a byte of
+    /// the binary is changed after the hash was computed.
+    fn image(format: u32, tamper: bool) -> &'static [u8] {
+        let header_size = 16 + 4 + 4 + sha256::DIGEST_LEN;
+        let mut bytes = Vec::new();
+        push_u16(&mut bytes, tock_tbf::HEADER_VERSION);
+        push_u16(&mut bytes, header_size as u16);
+        push_u32(&mut bytes, header_size as u32 + 100);
+        push_u32(&mut bytes, tock_tbf::FLAG_ENABLED);
+        push_u32(&mut bytes, 0);
+        let tlv_offset = bytes.len();
+        push_u16(&mut bytes, tock_tbf::TbfHeaderTypes::TbfHeaderCredentials as u16);
+        push_u16(&mut bytes, 4 + sha256::DIGEST_LEN as u16);
+        push_u32(&mut bytes, format);
+        bytes.extend((0..sha256::DIGEST_LEN).map(|_| 0));
+        bytes.extend((0..100).map(|i| i as u8));
+
+        // The credential covers everything but the checksum and itself.
+        let mut hasher = Sha256::new();
+        hasher.update(&bytes[..12]);
+        hasher.update(&bytes[16..tlv_offset]);
+        hasher.update(&bytes[header_size..]);
+        bytes[tlv_offset + 8..header_size].copy_from_slice(&hasher.finish());
+        let checksum = tock_tbf::checksum(&bytes[..header_size]);
+        for i in 0..4 {
+            bytes[12 + i] = (checksum >> (8 * i)) as u8;
+        }
+
+        if tamper {
+            bytes[header_size + 50] ^= 1;
+        }
+
+        // Headers are read in place, so the image must be word aligned.
+        let words: Vec<u32> = (0..bytes.len() / 4).map(|_| 0).collect();
+        let words = Box::leak(words.into_boxed_slice());
+        let image =
+            unsafe { slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, bytes.len()) };
+        image.copy_from_slice(&bytes);
+        image
+    }
+
+    fn header(image: &'static [u8]) -> TbfHeader {
+        unsafe { tbfheader::parse_and_validate_tbf_header(image.as_ptr()).unwrap() }
+    }
+
+    #[test]
+    fn sha256_matches_fips_180_2_vectors() {
+        assert_eq!(
+            sha256(b""),
+            [
+                0xe3, 0xb0, 0xc4, 0x42, 0x98, 0xfc, 0x1c, 0x14, 0x9a, 0xfb, 0xf4, 0xc8, 0x99, 0x6f,
+                0xb9, 0x24, 0x27, 0xae, 0x41, 0xe4, 0x64, 0x9b, 0x93, 0x4c, 0xa4, 0x95, 0x99, 0x1b,
+                0x78, 0x52, 0xb8, 0x55,
+            ]
+        );
+        assert_eq!(
+            sha256(b"abc"),
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ]
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            [
+                0x24, 0x8d, 0x6a, 0x61, 0xd2, 0x06, 0x38, 0xb8, 0xe5, 0xc0, 0x26, 0x93, 0x0c, 0x3e,
+                0x60, 0x39, 0xa3, 0x3c, 0xe4, 0x59, 0x64, 0xff, 0x21, 0x67, 0xf6, 0xec, 0xed, 0xd4,
+                0x19, 0xdb, 0x06, 0xc1,
+            ]
+        );
+    }
+
+    #[test]
+    fn sha256_is_the_same_in_pieces() {
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut hasher = Sha256::new();
+        for piece in data.chunks(37) {
+            hasher.update(piece);
+        }
+        assert_eq!(hasher.finish(), sha256(&data));
+    }
+
+    #[test]
+    fn hash_credential_checks_image() {
+        let image = image(tock_tbf::CREDENTIALS_SHA256, false);
+        let header = header(image);
+        let allowed: Vec<bool> = POLICIES
+            .iter()
+            .map(|policy| check_credentials(&header, image, *policy, None))
+            .collect();
+        assert_eq!(allowed, [true, true, false]);
+    }
+
+    #[test]
+    fn signature_credential_checks_image() {
+        let image = image(tock_tbf::CREDENTIALS_ED25519, false);
+        let header = header(image);
+        for policy in POLICIES.iter() {
+            assert!(check_credentials(&header, image, *policy, Some(&HashVerifier)));
+        }
+        // Without a verifier the signature does not count.
+        assert!(!check_credentials(
+            &header,
+            image,
+            AppCredentialsPolicy::RequireIntegrity,
+            None
+        ));
+    }
+
+    #[test]
+    fn tampered_image_fails_under_every_policy() {
+        for format in [tock_tbf::CREDENTIALS_SHA256, tock_tbf::CREDENTIALS_ED25519].iter() {
+            let image = image(*format, true);
+            let header = header(image);
+            for policy in POLICIES.iter() {
+                assert!(
+                    !check_credentials(&header, image, *policy, Some(&HashVerifier)),
+                    "format {} allowed under {:?}",
+                    format,
+                    policy
+                );
+            }
+        }
+    }
+}
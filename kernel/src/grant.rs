@@ -1,9 +1,10 @@
 //! Data structure to store a list of userspace applications.
 
+use core::cell::Cell;
 use core::marker::PhantomData;
-use core::mem::size_of;
+use core::mem::{size_of, size_of_val};
 use core::ops::{Deref, DerefMut};
-use core::ptr::{write, write_volatile, Unique};
+use core::ptr::{self, write, write_volatile, Unique};
 
 use callback::AppId;
 use process::Error;
@@ -17,6 +18,7 @@ pub struct Grant<T: Default> {
 
 pub struct AppliedGrant<T> {
     appid: AppId,
+    grant_num: usize,
     grant: *mut T,
     _phantom: PhantomData<T>,
 }
@@ -24,29 +26,41 @@ pub struct AppliedGrant<T> {
 impl<T> AppliedGrant<T> {
     pub fn enter<F, R>(self, fun: F) -> R
     where
-        F: FnOnce(&mut Owned<T>, &mut Allocator) -> R,
+        F: FnOnce(&mut Borrowed<T>, &mut Allocator) -> R,
         R: Copy,
     {
-        let mut allocator = Allocator { appid: self.appid };
-        let mut root = unsafe { Owned::new(self.grant, self.appid) };
+        let mut allocator = Allocator {
+            appid: self.appid,
+            grant_num: self.grant_num,
+        };
+        let mut root = unsafe { Borrowed::new(&mut *self.grant, self.appid) };
         fun(&mut root, &mut allocator)
     }
 }
 
+/// Allocates memory for a grant in the grant region of a process, in addition
+/// to the grant's own memory. The memory is freed when the returned `Owned`
+/// is dropped, or when the process is restarted or terminated.
 pub struct Allocator {
     appid: AppId,
+    grant_num: usize,
 }
 
 pub struct Owned<T: ?Sized> {
     data: Unique<T>,
     appid: AppId,
+    grant_num: usize,
+    /// The grant generation of the process when this was allocated.
+    generation: usize,
 }
 
 impl<T: ?Sized> Owned<T> {
-    unsafe fn new(data: *mut T, appid: AppId) -> Owned<T> {
+    unsafe fn new(data: *mut T, appid: AppId, grant_num: usize, generation: usize) -> Owned<T> {
         Owned {
             data: Unique::new_unchecked(data),
             appid: appid,
+            grant_num: grant_num,
+            generation: generation,
         }
     }
 
@@ -59,10 +73,16 @@ impl<T: ?Sized> Drop for Owned<T> {
     fn drop(&mut self) {
         unsafe {
             let data = self.data.as_ptr() as *mut u8;
+            let size = size_of_val(self.data.as_ref());
             self.appid
                 .kernel
                 .process_map_or((), self.appid.idx(), |process| {
-                    process.free(data);
+                    // If the process was restarted or terminated since this was
+                    // allocated, the memory was already freed with the rest of
+                    // its grant region.
+                    if process.grant_generation() == self.generation {
+                        process.free(data, size, self.grant_num);
+                    }
                 });
         }
     }
@@ -87,15 +107,21 @@ impl Allocator {
             self.appid
                 .kernel
                 .process_map_or(Err(Error::NoSuchApp), self.appid.idx(), |process| {
-                    process
-                        .alloc(size_of::<T>())
-                        .map_or(Err(Error::OutOfMemory), |arr| {
+                    process.alloc(size_of::<T>(), self.grant_num).map_or(
+                        Err(Error::OutOfMemory),
+                        |arr| {
                             let ptr = arr.as_mut_ptr() as *mut T;
                             // We use `ptr::write` to avoid `Drop`ping the uninitialized memory in
                             // case `T` implements the `Drop` trait.
                             write(ptr, data);
-                            Ok(Owned::new(ptr, self.appid))
-                        })
+                            Ok(Owned::new(
+                                ptr,
+                                self.appid,
+                                self.grant_num,
+                                process.grant_generation(),
+                            ))
+                        },
+                    )
                 })
         }
     }
@@ -150,6 +176,7 @@ impl<T: Default> Grant<T> {
                 } else {
                     Some(AppliedGrant {
                         appid: appid,
+                        grant_num: self.grant_num,
                         grant: cntr,
                         _phantom: PhantomData,
                     })
@@ -201,7 +228,7 @@ impl<T: Default> Grant<T> {
                     // If the pointer at that location is NULL then the grant
                     // memory needs to be allocated.
                     let new_grant = if (*ctr_ptr).is_null() {
-                        process.alloc(size_of::<T>()).map(|root_arr| {
+                        process.alloc(size_of::<T>(), self.grant_num).map(|root_arr| {
                             let root_ptr = root_arr.as_mut_ptr() as *mut T;
                             // Initialize the grant contents using ptr::write, to
                             // ensure that we don't try to drop the contents of
@@ -221,7 +248,10 @@ impl<T: Default> Grant<T> {
                     new_grant.map_or(Err(Error::OutOfMemory), move |root_ptr| {
                         let root_ptr = root_ptr as *mut T;
                         let mut root = Borrowed::new(&mut *root_ptr, appid);
-                        let mut allocator = Allocator { appid: appid };
+                        let mut allocator = Allocator {
+                            appid: appid,
+                            grant_num: self.grant_num,
+                        };
                         let res = fun(&mut root, &mut allocator);
                         Ok(res)
                     })
@@ -231,18 +261,26 @@ impl<T: Default> Grant<T> {
 
     pub fn each<F>(&self, fun: F)
     where
-        F: Fn(&mut Owned<T>),
+        F: Fn(&mut Borrowed<T>),
     {
         self.kernel
             .process_each_enumerate(|app_id, process| unsafe {
                 let root_ptr = *(process.grant_ptr(self.grant_num) as *mut *mut T);
                 if !root_ptr.is_null() {
-                    let mut root = Owned::new(root_ptr, AppId::new(self.kernel, app_id));
+                    let mut root = Borrowed::new(&mut *root_ptr, AppId::new(self.kernel, app_id));
                     fun(&mut root);
                 }
             });
     }
 
+    /// How many bytes of the grant region of process `appid` this grant uses,
+    /// including memory allocated with its `Allocator`.
+    pub fn memory_used(&self, appid: AppId) -> usize {
+        appid.kernel.process_map_or(0, appid.idx(), |process| {
+            process.grant_memory_used(self.grant_num)
+        })
+    }
+
     pub fn iter(&self) -> Iter<T> {
         Iter {
             grant: self,
@@ -273,3 +311,320 @@ impl<T: Default> Iterator for Iter<'a, T> {
         None
     }
 }
+
+/// Allocations in the grant region are rounded up to a multiple of this and
+/// aligned to it. Freed memory holds a `FreeBlock`, so this is at least the
+/// size of one.
+const GRANT_ALIGN: usize = 2 * size_of::<usize>();
+
+/// How much of the grant region an allocation of `size` bytes takes up.
+crate fn grant_alloc_size(size: usize) -> usize {
+    if size == 0 {
+        GRANT_ALIGN
+    } else {
+        (size + GRANT_ALIGN - 1) & !(GRANT_ALIGN - 1)
+    }
+}
+
+/// Header stored at the start of each free block in a grant region.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+/// Allocator for the grant region of a process.
+///
+/// The grant region grows down from the top of process memory, towards the
+/// process's heap, by moving the kernel memory break. Freed memory is kept in
+/// a free list sorted by address, with adjacent free blocks coalesced, and is
+/// reused first-fit. Free memory at the bottom of the grant region is given
+/// back by moving the kernel memory break up again.
+crate struct GrantAllocator {
+    free_list: Cell<*mut FreeBlock>,
+    /// Bytes in the free list.
+    free_bytes: Cell<usize>,
+    /// Blocks in the free list.
+    free_blocks: Cell<usize>,
+    /// Start and length of the bytes skipped to align the highest allocation
+    /// when the kernel memory break was not aligned.
+    padding: Cell<(usize, usize)>,
+}
+
+impl GrantAllocator {
+    crate fn new() -> GrantAllocator {
+        GrantAllocator {
+            free_list: Cell::new(ptr::null_mut()),
+            free_bytes: Cell::new(0),
+            free_blocks: Cell::new(0),
+            padding: Cell::new((0, 0)),
+        }
+    }
+
+    /// Forget about all free memory, because the whole grant region was
+    /// freed.
+    crate fn reset(&self) {
+        self.free_list.set(ptr::null_mut());
+        self.free_bytes.set(0);
+        self.free_blocks.set(0);
+        self.padding.set((0, 0));
+    }
+
+    crate fn free_bytes(&self) -> usize {
+        self.free_bytes.get()
+    }
+
+    crate fn free_blocks(&self) -> usize {
+        self.free_blocks.get()
+    }
+
+    /// Allocate `size` bytes, growing the grant region down to no lower than
+    /// `app_break` if there is no free block large enough.
+    crate unsafe fn alloc(
+        &self,
+        size: usize,
+        kernel_memory_break: &Cell<*const u8>,
+        app_break: *const u8,
+    ) -> Option<*mut u8> {
+        let size = grant_alloc_size(size);
+
+        // Use the first free block that is large enough.
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut block = self.free_list.get();
+        while !block.is_null() {
+            if (*block).size >= size {
+                let remaining = (*block).size - size;
+                self.free_bytes.set(self.free_bytes.get() - size);
+                if remaining == 0 {
+                    self.set_next(prev, (*block).next);
+                    self.free_blocks.set(self.free_blocks.get() - 1);
+                    return Some(block as *mut u8);
+                } else {
+                    // Keep the start of the block free and use its end.
+                    (*block).size = remaining;
+                    return Some((block as *mut u8).offset(remaining as isize));
+                }
+            }
+            prev = block;
+            block = (*block).next;
+        }
+
+        // Otherwise grow the grant region.
+        let kernel_memory_break_addr = kernel_memory_break.get() as usize;
+        if kernel_memory_break_addr < app_break as usize + size {
+            return None;
+        }
+        let new_break = (kernel_memory_break_addr - size) & !(GRANT_ALIGN - 1);
+        if new_break < app_break as usize {
+            return None;
+        }
+        // Remember the bytes skipped to align the allocation, so that they
+        // are given back when the grant region shrinks past it.
+        let padding = kernel_memory_break_addr - new_break - size;
+        if padding > 0 {
+            self.padding.set((new_break + size, padding));
+        }
+        kernel_memory_break.set(new_break as *const u8);
+        Some(new_break as *mut u8)
+    }
+
+    /// Free `size` bytes at `ptr`, which `alloc()` returned.
+    crate unsafe fn free(&self, ptr: *mut u8, size: usize, kernel_memory_break: &Cell<*const u8>) {
+        let start = ptr as usize;
+        let size = grant_alloc_size(size);
+
+        // Find where the block goes in the free list.
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.free_list.get();
+        while !next.is_null() && (next as usize) < start {
+            prev = next;
+            next = (*next).next;
+        }
+
+        // Merge with the next block if they touch.
+        let mut merged_size = size;
+        if !next.is_null() && start + size == next as usize {
+            merged_size += (*next).size;
+            self.free_bytes.set(self.free_bytes.get() - (*next).size);
+            self.free_blocks.set(self.free_blocks.get() - 1);
+            next = (*next).next;
+        }
+
+        // Merge with the previous block if they touch, or insert a new block.
+        if !prev.is_null() && prev as usize + (*prev).size == start {
+            (*prev).size += merged_size;
+            (*prev).next = next;
+        } else {
+            let block = ptr as *mut FreeBlock;
+            write(
+                block,
+                FreeBlock {
+                    size: merged_size,
+                    next: next,
+                },
+            );
+            self.set_next(prev, block);
+            self.free_blocks.set(self.free_blocks.get() + 1);
+        }
+        self.free_bytes.set(self.free_bytes.get() + merged_size);
+
+        // Shrink the grant region if its lowest block is free.
+        let first = self.free_list.get();
+        if first as usize == kernel_memory_break.get() as usize {
+            let mut new_break = first as usize + (*first).size;
+            let (padding_start, padding) = self.padding.get();
+            if new_break == padding_start {
+                new_break += padding;
+            }
+            kernel_memory_break.set(new_break as *const u8);
+            self.free_bytes.set(self.free_bytes.get() - (*first).size);
+            self.free_blocks.set(self.free_blocks.get() - 1);
+            self.free_list.set((*first).next);
+        }
+    }
+
+    unsafe fn set_next(&self, prev: *mut FreeBlock, next: *mut FreeBlock) {
+        if prev.is_null() {
+            self.free_list.set(next);
+        } else {
+            (*prev).next = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use self::std::boxed::Box;
+    use self::std::vec::Vec;
+    use super::{grant_alloc_size, GrantAllocator, GRANT_ALIGN};
+    use core::cell::Cell;
+    use core::ptr;
+
+    const REGION_LEN: usize = 256;
+
+    /// An aligned grant region of `REGION_LEN` bytes, as its start and end.
+    fn region() -> (*const u8, *const u8) {
+        let words: Vec<u64> = (0..REGION_LEN / 8).map(|_| 0).collect();
+        let start = Box::leak(words.into_boxed_slice()).as_ptr() as *const u8;
+        (start, start.wrapping_offset(REGION_LEN as isize))
+    }
+
+    /// Allocate in a region with enough room, and check the alignment.
+    fn alloc(allocator: &GrantAllocator, size: usize, brk: &Cell<*const u8>) -> usize {
+        let ptr = unsafe { allocator.alloc(size, brk, ptr::null()) }.unwrap() as usize;
+        assert_eq!(ptr % GRANT_ALIGN, 0);
+        ptr
+    }
+
+    #[test]
+    fn rounds_sizes_up() {
+        assert_eq!(grant_alloc_size(0), GRANT_ALIGN);
+        assert_eq!(grant_alloc_size(1), GRANT_ALIGN);
+        assert_eq!(grant_alloc_size(GRANT_ALIGN), GRANT_ALIGN);
+        assert_eq!(grant_alloc_size(GRANT_ALIGN + 1), 2 * GRANT_ALIGN);
+    }
+
+    #[test]
+    fn allocates_down_from_the_break() {
+        let (start, end) = region();
+        let allocator = GrantAllocator::new();
+        let brk = Cell::new(end);
+        let mut top = end as usize;
+        for size in [1, 7, 13, 0, GRANT_ALIGN + 3].iter() {
+            let ptr = unsafe { allocator.alloc(*size, &brk, start) }.unwrap() as usize;
+            assert_eq!(ptr, top - grant_alloc_size(*size));
+            assert_eq!(brk.get() as usize, ptr);
+            top = ptr;
+        }
+        assert_eq!(allocator.free_bytes(), 0);
+    }
+
+    #[test]
+    fn fails_without_room() {
+        let (start, end) = region();
+        let allocator = GrantAllocator::new();
+        let brk = Cell::new(end);
+        let app_break = end.wrapping_offset(-(3 * GRANT_ALIGN as isize));
+        for _ in 0..3 {
+            assert!(unsafe { allocator.alloc(1, &brk, app_break) }.is_some());
+        }
+        assert!(unsafe { allocator.alloc(1, &brk, app_break) }.is_none());
+        assert_eq!(brk.get(), app_break);
+        assert!(unsafe { allocator.alloc(REGION_LEN, &brk, start) }.is_none());
+    }
+
+    #[test]
+    fn coalesces_freed_blocks() {
+        let (_, end) = region();
+        let allocator = GrantAllocator::new();
+        let brk = Cell::new(end);
+        let a = alloc(&allocator, 5, &brk);
+        let b = alloc(&allocator, GRANT_ALIGN + 1, &brk);
+        let c = alloc(&allocator, 9, &brk);
+        let d = alloc(&allocator, 3, &brk);
+
+        unsafe {
+            allocator.free(a as *mut u8, 5, &brk);
+            allocator.free(c as *mut u8, 9, &brk);
+            assert_eq!(allocator.free_blocks(), 2);
+            assert_eq!(allocator.free_bytes(), 2 * GRANT_ALIGN);
+
+            // Freeing `b` joins it with both of its neighbors.
+            allocator.free(b as *mut u8, GRANT_ALIGN + 1, &brk);
+            assert_eq!(allocator.free_blocks(), 1);
+            assert_eq!(allocator.free_bytes(), 4 * GRANT_ALIGN);
+            assert_eq!(brk.get() as usize, d);
+
+            // Freeing the block at the break gives everything back.
+            allocator.free(d as *mut u8, 3, &brk);
+        }
+        assert_eq!(allocator.free_blocks(), 0);
+        assert_eq!(allocator.free_bytes(), 0);
+        assert_eq!(brk.get(), end);
+    }
+
+    #[test]
+    fn reuses_freed_blocks() {
+        let (_, end) = region();
+        let allocator = GrantAllocator::new();
+        let brk = Cell::new(end);
+        let _a = alloc(&allocator, 1, &brk);
+        let b = alloc(&allocator, 3 * GRANT_ALIGN, &brk);
+        let _c = alloc(&allocator, 1, &brk);
+        let low = brk.get();
+
+        unsafe { allocator.free(b as *mut u8, 3 * GRANT_ALIGN, &brk) };
+        // Smaller allocations come from the end of the free block.
+        assert_eq!(alloc(&allocator, 2, &brk), b + 2 * GRANT_ALIGN);
+        assert_eq!(alloc(&allocator, GRANT_ALIGN + 2, &brk), b);
+        assert_eq!(allocator.free_bytes(), 0);
+        assert_eq!(allocator.free_blocks(), 0);
+        assert_eq!(brk.get(), low);
+    }
+
+    #[test]
+    fn gives_back_padding_of_a_misaligned_break() {
+        let (_, end) = region();
+        let top = end.wrapping_offset(-3);
+        let allocator = GrantAllocator::new();
+        let brk = Cell::new(top);
+        let a = alloc(&allocator, 6, &brk);
+        let b = alloc(&allocator, 6, &brk);
+        assert_eq!(a, end as usize - 2 * GRANT_ALIGN);
+        assert_eq!(b, a - GRANT_ALIGN);
+
+        unsafe {
+            allocator.free(a as *mut u8, 6, &brk);
+            assert_eq!(brk.get() as usize, b);
+            allocator.free(b as *mut u8, 6, &brk);
+        }
+        assert_eq!(brk.get(), top);
+        assert_eq!(allocator.free_bytes(), 0);
+
+        // The same memory is handed out again.
+        assert_eq!(alloc(&allocator, 6, &brk), a);
+        unsafe { allocator.free(a as *mut u8, 6, &brk) };
+        assert_eq!(brk.get(), top);
+    }
+}
@@ -62,6 +62,32 @@
 //! }
 //! ```
 //!
+//! Page-sized reads and writes hide how the flash is laid out. Many flash
+//! chips erase more than a page at a time, program less than a page at a
+//! time, or can write a page that is already erased without erasing it again.
+//! Flash that implements `EraseFlash` describes this with a `Geometry`, and
+//! offers `program_page()` to write without erasing and `erase_pages()` to
+//! erase many pages with as few erase commands as possible. Users can then
+//! skip erases that are not needed, which is both faster and less wear:
+//!
+//! ```rust
+//! use kernel::hil::flash::Geometry;
+//!
+//! // An 8 MiB SPI NOR flash with 4 KiB sectors, 64 KiB blocks and 256 byte
+//! // program pages, whose `Page` is a sector.
+//! let geometry = Geometry {
+//!     page_size: 4096,
+//!     num_pages: 2048,
+//!     program_size: 256,
+//!     erase_size: 4096,
+//!     max_erase_size: 65536,
+//!     write_erases: true,
+//! };
+//! assert_eq!(geometry.size(), 8 * 1024 * 1024);
+//! assert_eq!(geometry.pages_per_erase(), 1);
+//! assert_eq!(geometry.pages_per_max_erase(), 16);
+//! ```
+//!
 //! A user of this flash interface might look like:
 //!
 //! ```rust
@@ -122,6 +148,65 @@ pub trait Flash {
     fn erase_page(&self, page_number: usize) -> ReturnCode;
 }
 
+/// The value of every byte of erased flash.
+pub const ERASED_BYTE: u8 = 0xff;
+
+/// How a flash device is laid out. Sizes are in bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Geometry {
+    /// Size of a `Flash::Page`.
+    pub page_size: usize,
+    /// Number of pages of the device.
+    pub num_pages: usize,
+    /// Smallest amount the device programs at once. Parts of a page this size
+    /// that are erased in the buffer do not need to be programmed.
+    pub program_size: usize,
+    /// Smallest amount the device erases at once. This is a whole number of
+    /// pages.
+    pub erase_size: usize,
+    /// Largest amount the device erases with one command, for example a
+    /// block of sectors. This is a whole number of `erase_size`.
+    pub max_erase_size: usize,
+    /// Whether `Flash::write_page()` erases the page before writing it. If it
+    /// does not, pages have to be erased before they are written.
+    pub write_erases: bool,
+}
+
+impl Geometry {
+    /// Size of the device.
+    pub fn size(&self) -> usize {
+        self.page_size * self.num_pages
+    }
+
+    /// Number of pages erased at once.
+    pub fn pages_per_erase(&self) -> usize {
+        self.erase_size / self.page_size
+    }
+
+    /// Number of pages erased by the largest erase command.
+    pub fn pages_per_max_erase(&self) -> usize {
+        self.max_erase_size / self.page_size
+    }
+}
+
+/// Flash that exposes its geometry and erases only when asked to.
+pub trait EraseFlash: Flash {
+    /// How the flash is laid out.
+    fn geometry(&self) -> Geometry;
+
+    /// Write a page of flash from the buffer without erasing it first. This
+    /// can only change bits from the erased value, so the page should be
+    /// erased where the buffer is not `ERASED_BYTE`. Calls
+    /// `Client::write_complete()` when done.
+    fn program_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode;
+
+    /// Erase `num_pages` pages starting at `page_number`, with the largest
+    /// erase commands that fit. Both must be multiples of
+    /// `Geometry::pages_per_erase()`, or this returns `EINVAL`. Calls
+    /// `Client::erase_complete()` once, when all of them are erased.
+    fn erase_pages(&self, page_number: usize, num_pages: usize) -> ReturnCode;
+}
+
 /// Implement `Client` to receive callbacks from `Flash`.
 pub trait Client<F: Flash> {
     /// Flash read complete.
@@ -22,7 +22,7 @@
 //!
 //! ```
 //! use kernel::hil;
-//! use kernel::hil::time::Frequency;
+//! use kernel::hil::time::{Frequency, Ticks};
 //!
 //! struct RngTest<'a, A: 'a + hil::time::Alarm> {
 //!     rng: &'a hil::rng::RNG,
@@ -31,7 +31,7 @@
 //!
 //! impl<'a, A: hil::time::Alarm> RngTest<'a, A> {
 //!     pub fn initialize(&self) {
-//!         let interval = 1 * <A::Frequency>::frequency();
+//!         let interval = A::Ticks::from_u64(<A::Frequency>::ms_to_ticks(1000));
 //!         let tics = self.alarm.now().wrapping_add(interval);
 //!         self.alarm.set_alarm(tics);
 //!     }
@@ -48,7 +48,7 @@
 //!         match randomness.next() {
 //!             Some(random) => {
 //!                 println!("Rand {}", random);
-//!                 let interval = 1 * <A::Frequency>::frequency();
+//!                 let interval = A::Ticks::from_u64(<A::Frequency>::ms_to_ticks(1000));
 //!                 let tics = self.alarm.now().wrapping_add(interval);
 //!                 self.alarm.set_alarm(tics);
 //!                 hil::rng::Continue::Done
@@ -1,4 +1,28 @@
 //! Hardware agnostic interfaces for counter-like resources.
+//!
+//! Hardware counters are 32 bits wide or less, so they wrap quickly: a 32-bit
+//! counter at 16 MHz wraps in under 5 minutes. An `Alarm` counts in its own
+//! `Ticks` type, which does wrapping arithmetic at the counter's width, and
+//! `Alarm64` is for alarms whose time never wraps, such as
+//! `capsules::overflow_alarm::OverflowAlarm`, which extends any `Alarm` to 64
+//! bits.
+//!
+//! Use `Frequency` to convert between ticks and milliseconds:
+//!
+//! ```rust
+//! use kernel::hil::time::{Freq32KHz, Frequency, Ticks, Ticks24};
+//!
+//! assert_eq!(Freq32KHz::ms_to_ticks(1000), 32768);
+//! assert_eq!(Freq32KHz::ticks_to_ms(16384), 500);
+//!
+//! // A 24-bit counter wraps after 0xFFFFFF.
+//! let before_wrap = Ticks24::from_u64(0xFFFFF0);
+//! let after_wrap = before_wrap.wrapping_add(Ticks24::from_u64(0x20));
+//! assert_eq!(after_wrap.into_u64(), 0x10);
+//! assert!(Ticks24::from_u64(0xFFFFFF).within_range(before_wrap, after_wrap));
+//! ```
+
+use core::fmt::Debug;
 
 pub trait Time {
     type Frequency: Frequency;
@@ -16,8 +40,111 @@ pub trait Time {
 /// convert native cycles to real-time values.
 pub trait Frequency {
     fn frequency() -> u32;
+
+    /// Convert `ticks` to milliseconds, rounding down.
+    fn ticks_to_ms(ticks: u64) -> u64 {
+        let frequency = Self::frequency() as u64;
+        // Split the seconds off first so that the multiplication cannot
+        // overflow.
+        ticks / frequency * 1000 + ticks % frequency * 1000 / frequency
+    }
+
+    /// Convert `ms` milliseconds to ticks, rounding down.
+    fn ms_to_ticks(ms: u64) -> u64 {
+        let frequency = Self::frequency() as u64;
+        ms / 1000 * frequency + ms % 1000 * frequency / 1000
+    }
+}
+
+/// A value of a counter that wraps at `width()` bits.
+///
+/// Comparing two values of a wrapping counter with `<` is wrong as soon as
+/// the counter wraps in between, so `Ticks` only offers comparisons that take
+/// wrapping into account, such as `within_range()`.
+pub trait Ticks: Copy + Clone + Debug + Eq + PartialEq {
+    /// Number of bits the counter counts with.
+    fn width() -> u32;
+
+    /// The value `value` wraps to.
+    fn from_u64(value: u64) -> Self;
+
+    /// The counter value, which is less than 2^`width()`.
+    fn into_u64(self) -> u64;
+
+    /// The largest value the counter reaches before it wraps to 0.
+    fn max_value() -> Self {
+        Self::from_u64(u64::max_value())
+    }
+
+    /// Add, wrapping at `width()` bits.
+    fn wrapping_add(self, other: Self) -> Self {
+        Self::from_u64(self.into_u64().wrapping_add(other.into_u64()))
+    }
+
+    /// Subtract, wrapping at `width()` bits. This is the number of ticks from
+    /// `other` to `self`.
+    fn wrapping_sub(self, other: Self) -> Self {
+        Self::from_u64(self.into_u64().wrapping_sub(other.into_u64()))
+    }
+
+    /// Whether a counter counting up from `start` reaches `self` before it
+    /// reaches `end`. The range wraps if `end` is less than `start`.
+    fn within_range(self, start: Self, end: Self) -> bool {
+        self.wrapping_sub(start).into_u64() < end.wrapping_sub(start).into_u64()
+    }
+}
+
+macro_rules! ticks_type {
+    ($(#[$attr:meta])* $name:ident, $int:ty, $width:expr) => {
+        $(#[$attr])*
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        pub struct $name(pub $int);
+
+        impl Ticks for $name {
+            fn width() -> u32 {
+                $width
+            }
+
+            fn from_u64(value: u64) -> $name {
+                if $width == 64 {
+                    $name(value as $int)
+                } else {
+                    $name((value & ((1 << ($width % 64)) - 1)) as $int)
+                }
+            }
+
+            fn into_u64(self) -> u64 {
+                self.0 as u64
+            }
+        }
+    };
 }
 
+ticks_type!(
+    /// Ticks of a 16-bit counter.
+    Ticks16,
+    u16,
+    16
+);
+ticks_type!(
+    /// Ticks of a 24-bit counter, such as the nRF5x RTC.
+    Ticks24,
+    u32,
+    24
+);
+ticks_type!(
+    /// Ticks of a 32-bit counter.
+    Ticks32,
+    u32,
+    32
+);
+ticks_type!(
+    /// Ticks of a 64-bit counter, which in practice never wraps.
+    Ticks64,
+    u64,
+    64
+);
+
 /// 16MHz `Frequency`
 #[derive(Debug)]
 pub struct Freq16MHz;
@@ -62,8 +189,11 @@ impl Frequency for Freq1KHz {
 /// [`Client`](trait.Client.html) trait to signal when the counter has
 /// reached a pre-specified value set in [`set_alarm`](#tymethod.set_alarm).
 pub trait Alarm: Time {
+    /// The counter's values, which wrap at the counter's width.
+    type Ticks: Ticks;
+
     /// Returns the current time in hardware clock units.
-    fn now(&self) -> u32;
+    fn now(&self) -> Self::Ticks;
 
     /// Sets a one-shot alarm fire when the clock reaches `tics`.
     ///
@@ -73,17 +203,34 @@ pub trait Alarm: Time {
     /// # Examples
     ///
     /// ```ignore
-    /// let delta = 1337;
+    /// let delta = A::Ticks::from_u64(A::Frequency::ms_to_ticks(100));
     /// let tics = alarm.now().wrapping_add(delta);
     /// alarm.set_alarm(tics);
     /// ```
-    fn set_alarm(&self, tics: u32);
+    fn set_alarm(&self, tics: Self::Ticks);
+
+    /// Returns the value set in [`set_alarm`](#tymethod.set_alarm)
+    fn get_alarm(&self) -> Self::Ticks;
+}
+
+/// An alarm whose counter is 64 bits wide, so that it never wraps.
+///
+/// Clients of an `Alarm64` can set alarms any distance in the future and
+/// compare times with `<`.
+pub trait Alarm64: Time {
+    /// Returns the current time in hardware clock units.
+    fn now(&self) -> Ticks64;
+
+    /// Sets a one-shot alarm to fire when the clock reaches `when`. If `when`
+    /// has already passed, the alarm fires as soon as possible.
+    fn set_alarm(&self, when: Ticks64);
 
     /// Returns the value set in [`set_alarm`](#tymethod.set_alarm)
-    fn get_alarm(&self) -> u32;
+    fn get_alarm(&self) -> Ticks64;
 }
 
-/// A client of an implementor of the [`Alarm`](trait.Alarm.html) trait.
+/// A client of an implementor of the [`Alarm`](trait.Alarm.html) or
+/// [`Alarm64`](trait.Alarm64.html) trait.
 pub trait Client {
     /// Callback signaled when the alarm's clock reaches the value set in
     /// [`Alarm#set_alarm`](trait.Alarm.html#tymethod.set_alarm).
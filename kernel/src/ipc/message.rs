@@ -0,0 +1,440 @@
+//! Message passing between processes.
+//!
+//! Unlike shared memory IPC, processes do not share their buffers. When a
+//! process sends a message, the kernel copies it from the sender's send buffer
+//! into a slot in the receiver's mailbox, which lives in the receiver's grant.
+//! The receiver then asks the kernel to copy the oldest message into its
+//! receive buffer.
+//!
+//! A message can ask for a reply. Sending it then reserves a slot in the
+//! sender's own mailbox, so that the receiver can always reply. If the
+//! receiver is terminated or restarted before it replies, the slot is freed
+//! again. If the receiver's mailbox is full the send fails with `EBUSY`, and
+//! the sender should try again later.
+//!
+//! Processes are identified by the same ids as in shared memory IPC: their
+//! process index plus one.
+
+use callback::{AppId, Callback};
+use capabilities::MemoryAllocationCapability;
+use driver::Driver;
+use grant::{Allocator, Grant, Owned};
+use mem::{AppSlice, Shared};
+use process;
+use returncode::ReturnCode;
+use sched::Kernel;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x00010003;
+
+/// The longest message that can be sent.
+pub const MAX_MESSAGE_LEN: usize = 64;
+
+/// The most slots a mailbox can have.
+pub const MAX_MAILBOX_SIZE: usize = 16;
+
+// Kinds of message, passed to the receiver's callback.
+const MESSAGE: usize = 0;
+const REQUEST: usize = 1;
+const REPLY: usize = 2;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SlotState {
+    Free,
+    /// Kept for the reply to a request sent to `peer`.
+    Reserved,
+    /// Holds a message of this kind from `peer`.
+    Queued(usize),
+}
+
+/// One message in a mailbox.
+struct Slot {
+    state: SlotState,
+    /// Index of the process the message is from, or for a reserved slot the
+    /// process the reply will come from.
+    peer: usize,
+    /// For a reserved slot, the grant generation of `peer` when the request
+    /// was sent. It changes if `peer` is terminated or restarted, after which
+    /// the reply will never come.
+    peer_generation: usize,
+    /// When the message was queued, for receiving messages in order.
+    sequence: usize,
+    len: usize,
+    data: [u8; MAX_MESSAGE_LEN],
+    next: Option<Owned<Slot>>,
+}
+
+#[derive(Default)]
+struct App {
+    callback: Option<Callback>,
+    send_buffer: Option<AppSlice<Shared, u8>>,
+    receive_buffer: Option<AppSlice<Shared, u8>>,
+    /// The slots of the mailbox, or `None` if the process has not opened it.
+    slots: Option<Owned<Slot>>,
+    next_sequence: usize,
+}
+
+impl App {
+    /// Find the first slot `matches` is true for.
+    fn find_slot<F>(&mut self, matches: F) -> Option<&mut Slot>
+    where
+        F: Fn(&Slot) -> bool,
+    {
+        let mut slot = self.slots.as_mut();
+        while let Some(s) = slot {
+            if matches(s) {
+                return Some(s);
+            }
+            slot = s.next.as_mut();
+        }
+        None
+    }
+
+    /// The oldest message in the mailbox.
+    fn oldest_message(&mut self) -> Option<&mut Slot> {
+        let mut oldest = None;
+        {
+            let mut slot = self.slots.as_ref();
+            while let Some(s) = slot {
+                if let SlotState::Queued(_) = s.state {
+                    if oldest.map_or(true, |sequence| s.sequence < sequence) {
+                        oldest = Some(s.sequence);
+                    }
+                }
+                slot = s.next.as_ref();
+            }
+        }
+        match oldest {
+            Some(sequence) => self.find_slot(|s| {
+                s.state != SlotState::Free
+                    && s.state != SlotState::Reserved
+                    && s.sequence == sequence
+            }),
+            None => None,
+        }
+    }
+
+    /// Put a message from process `peer` in a free slot, or in the slot
+    /// reserved for it if `reserved`, and tell the process about it.
+    fn queue(&mut self, kind: usize, peer: usize, message: &[u8], reserved: bool) -> ReturnCode {
+        let sequence = self.next_sequence;
+        let wanted = if reserved {
+            SlotState::Reserved
+        } else {
+            SlotState::Free
+        };
+        let queued = self
+            .find_slot(|s| s.state == wanted && (!reserved || s.peer == peer))
+            .map(|slot| {
+                slot.state = SlotState::Queued(kind);
+                slot.peer = peer;
+                slot.sequence = sequence;
+                slot.len = message.len();
+                slot.data[..message.len()].copy_from_slice(message);
+            }).is_some();
+        if !queued {
+            return ReturnCode::EBUSY;
+        }
+
+        self.next_sequence = sequence.wrapping_add(1);
+        self.callback
+            .map(|mut callback| callback.schedule(peer + 1, message.len(), kind));
+        ReturnCode::SUCCESS
+    }
+
+    /// Free the slots reserved for replies from processes that were
+    /// terminated or restarted since the request was sent.
+    fn free_abandoned_slots(&mut self, kernel: &Kernel) {
+        let mut slot = self.slots.as_mut();
+        while let Some(s) = slot {
+            if s.state == SlotState::Reserved {
+                let waiting = kernel.process_map_or(false, s.peer, |process| {
+                    process.get_state() != process::State::Terminated
+                        && process.grant_generation() == s.peer_generation
+                });
+                if !waiting {
+                    s.state = SlotState::Free;
+                }
+            }
+            slot = s.next.as_mut();
+        }
+    }
+
+    fn open_mailbox(&mut self, size: usize, allocator: &mut Allocator) -> ReturnCode {
+        if self.slots.is_some() {
+            return ReturnCode::EALREADY;
+        }
+        if size == 0 || size > MAX_MAILBOX_SIZE {
+            return ReturnCode::EINVAL;
+        }
+        for _ in 0..size {
+            let slot = allocator.alloc(Slot {
+                state: SlotState::Free,
+                peer: 0,
+                peer_generation: 0,
+                sequence: 0,
+                len: 0,
+                data: [0; MAX_MESSAGE_LEN],
+                next: self.slots.take(),
+            });
+            match slot {
+                Ok(slot) => self.slots = Some(slot),
+                Err(_) => {
+                    // Free the slots that were allocated.
+                    self.slots = None;
+                    return ReturnCode::ENOMEM;
+                }
+            }
+        }
+        ReturnCode::SUCCESS
+    }
+}
+
+pub struct MessageIPC {
+    apps: Grant<App>,
+}
+
+impl MessageIPC {
+    pub fn new(kernel: &'static Kernel, capability: &MemoryAllocationCapability) -> MessageIPC {
+        MessageIPC {
+            apps: kernel.create_grant(capability),
+        }
+    }
+
+    /// The index and grant generation of the process with id `target_id`, if
+    /// there is one that has not been terminated.
+    fn target(&self, target_id: usize) -> Option<(usize, usize)> {
+        if target_id == 0 {
+            return None;
+        }
+        self.apps
+            .kernel
+            .process_map_or(None, target_id - 1, |process| {
+                if process.get_state() == process::State::Terminated {
+                    None
+                } else {
+                    Some((target_id - 1, process.grant_generation()))
+                }
+            })
+    }
+
+    /// Copy the send buffer of `appid` into `message`, returning its length.
+    fn copy_send_buffer(
+        &self,
+        appid: AppId,
+        message: &mut [u8; MAX_MESSAGE_LEN],
+    ) -> Result<usize, ReturnCode> {
+        self.apps
+            .enter(appid, |app, _| match app.send_buffer {
+                Some(ref buffer) if buffer.len() <= MAX_MESSAGE_LEN => {
+                    message[..buffer.len()].copy_from_slice(buffer.as_ref());
+                    Ok(buffer.len())
+                }
+                Some(_) => Err(ReturnCode::ESIZE),
+                None => Err(ReturnCode::EINVAL),
+            }).unwrap_or_else(|err| Err(err.into()))
+    }
+
+    /// Send the send buffer of `appid` to `target_id`, reserving a slot for
+    /// the reply if `expect_reply`.
+    fn send(&self, appid: AppId, target_id: usize, expect_reply: bool) -> ReturnCode {
+        let (target, target_generation) = match self.target(target_id) {
+            Some(target) => target,
+            None => return ReturnCode::EINVAL,
+        };
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = match self.copy_send_buffer(appid, &mut message) {
+            Ok(len) => len,
+            Err(err) => return err,
+        };
+
+        // Reserve the slot for the reply first, so it is not used up if the
+        // process sends to itself.
+        if expect_reply {
+            let kernel = self.apps.kernel;
+            let reserved = self
+                .apps
+                .enter(appid, |app, _| {
+                    app.free_abandoned_slots(kernel);
+                    app.find_slot(|s| s.state == SlotState::Free)
+                        .map(|slot| {
+                            slot.state = SlotState::Reserved;
+                            slot.peer = target;
+                            slot.peer_generation = target_generation;
+                        }).is_some()
+                }).unwrap_or(false);
+            if !reserved {
+                return ReturnCode::ENOMEM;
+            }
+        }
+
+        let kind = if expect_reply { REQUEST } else { MESSAGE };
+        let ret = self
+            .apps
+            .enter(AppId::new(self.apps.kernel, target), |app, _| {
+                if app.slots.is_none() {
+                    ReturnCode::ENOSUPPORT
+                } else {
+                    app.free_abandoned_slots(self.apps.kernel);
+                    app.queue(kind, appid.idx(), &message[..len], false)
+                }
+            }).unwrap_or_else(|err| err.into());
+
+        if ret != ReturnCode::SUCCESS && expect_reply {
+            self.cancel(appid, target);
+        }
+        ret
+    }
+
+    /// Reply to a request from `target_id` with the send buffer of `appid`.
+    fn reply(&self, appid: AppId, target_id: usize) -> ReturnCode {
+        let target = match self.target(target_id) {
+            Some((target, _)) => target,
+            None => return ReturnCode::EINVAL,
+        };
+        let mut message = [0; MAX_MESSAGE_LEN];
+        let len = match self.copy_send_buffer(appid, &mut message) {
+            Ok(len) => len,
+            Err(err) => return err,
+        };
+
+        // The requester reserved a slot for this, so only fails if there was
+        // no request, or this process was restarted since.
+        self.apps
+            .enter(AppId::new(self.apps.kernel, target), |app, _| {
+                app.free_abandoned_slots(self.apps.kernel);
+                match app.queue(REPLY, appid.idx(), &message[..len], true) {
+                    ReturnCode::EBUSY => ReturnCode::EINVAL,
+                    ret => ret,
+                }
+            }).unwrap_or_else(|err| err.into())
+    }
+
+    /// Free the slots `appid` reserved for replies from process `target`.
+    fn cancel(&self, appid: AppId, target: usize) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                while let Some(slot) =
+                    app.find_slot(|s| s.state == SlotState::Reserved && s.peer == target)
+                {
+                    slot.state = SlotState::Free;
+                }
+                ReturnCode::SUCCESS
+            }).unwrap_or_else(|err| err.into())
+    }
+
+    /// Copy the oldest message of `appid` into its receive buffer.
+    fn receive(&self, appid: AppId) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                let mut message = [0; MAX_MESSAGE_LEN];
+                let (len, peer) = match app.oldest_message() {
+                    Some(slot) => {
+                        message[..slot.len].copy_from_slice(&slot.data[..slot.len]);
+                        (slot.len, slot.peer)
+                    }
+                    None => return ReturnCode::FAIL,
+                };
+                let ret = match app.receive_buffer {
+                    Some(ref mut buffer) if buffer.len() >= len => {
+                        buffer.as_mut()[..len].copy_from_slice(&message[..len]);
+                        ReturnCode::SuccessWithValue { value: peer + 1 }
+                    }
+                    Some(_) => ReturnCode::ESIZE,
+                    None => ReturnCode::EINVAL,
+                };
+                if ret != ReturnCode::ESIZE && ret != ReturnCode::EINVAL {
+                    app.oldest_message().map(|slot| slot.state = SlotState::Free);
+                }
+                ret
+            }).unwrap_or_else(|err| err.into())
+    }
+}
+
+impl Driver for MessageIPC {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The buffer messages are sent from. A message is the whole
+    ///   buffer, and can be at most `MAX_MESSAGE_LEN` bytes.
+    /// - `1`: The buffer messages are received into.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match allow_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.send_buffer = slice;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            1 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.receive_buffer = slice;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Called when a message arrives in the mailbox, with the id of the
+    ///   sender, the length of the message, and whether it is a message (0), a
+    ///   request that expects a reply (1) or a reply (2).
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        appid: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self
+                .apps
+                .enter(appid, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Send and receive messages.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Driver check.
+    /// - `1`: Open the mailbox, with `arg1` slots for messages.
+    /// - `2`: Send the send buffer to process `arg1`. If `arg2` is 1 the
+    ///   message is a request, and a slot in the sender's mailbox is kept for
+    ///   the reply. Returns `EBUSY` if the receiver's mailbox is full.
+    /// - `3`: Copy the oldest message in the mailbox into the receive buffer
+    ///   and remove it from the mailbox. Returns the id of the sender.
+    /// - `4`: Reply to a request from process `arg1` with the send buffer.
+    /// - `5`: Stop waiting for replies from process `arg1`, freeing the slots
+    ///   kept for them.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => ReturnCode::SUCCESS,
+            1 => self
+                .apps
+                .enter(appid, |app, allocator| app.open_mailbox(arg1, allocator))
+                .unwrap_or_else(|err| err.into()),
+            2 => self.send(appid, arg1, arg2 == 1),
+            3 => self.receive(appid),
+            4 => self.reply(appid, arg1),
+            5 => match self.target(arg1) {
+                Some((target, _)) => self.cancel(appid, target),
+                None => ReturnCode::EINVAL,
+            },
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Inter-process communication mechanism for Tock.
+//!
+//! This is a special syscall driver that allows userspace applications to
+//! share memory.
+//!
+//! Services register a name and a version with the kernel, and clients
+//! discover them by name. Processes that do not register are still found by
+//! their package name, with version 0. Each process keeps the state it has for
+//! each of its peers in its grant, so the number of peers is only limited by
+//! the process's memory.
+//!
+//! The `message` module is a second IPC driver, where the kernel copies
+//! messages between processes instead of them sharing memory.
+
+pub mod message;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x00010000;
+
+/// The longest service name that can be registered. Longer package names can
+/// still be discovered.
+pub const MAX_SERVICE_NAME_LEN: usize = 32;
+
+use core::cell::Cell;
+
+use callback::{AppId, Callback};
+use capabilities::MemoryAllocationCapability;
+use driver::Driver;
+use grant::{Allocator, Grant, Owned};
+use mem::{AppSlice, Shared};
+use process;
+use returncode::ReturnCode;
+use sched::Kernel;
+
+// Operations of the registry command, command number 0.
+const REGISTER_SERVICE: usize = 0;
+const UNREGISTER_SERVICE: usize = 1;
+const DISCOVER_SERVICE: usize = 2;
+const SERVICE_VERSION: usize = 3;
+
+/// What a process has for one other process.
+struct Peer {
+    /// Index of the other process.
+    process_idx: usize,
+    /// Buffer this process shared with the other process.
+    shared_memory: Option<AppSlice<Shared, u8>>,
+    /// Callback for when the other process, as a service, notifies this
+    /// process.
+    client_callback: Option<Callback>,
+    next: Option<Owned<Peer>>,
+}
+
+struct IPCData {
+    /// Service name that `allow(0)` shared, for the registry commands.
+    name: Option<AppSlice<Shared, u8>>,
+    /// Name this process registered as a service, if any.
+    service_name: [u8; MAX_SERVICE_NAME_LEN],
+    service_name_len: usize,
+    service_version: usize,
+    registered: bool,
+    peers: Option<Owned<Peer>>,
+    callback: Option<Callback>,
+}
+
+impl Default for IPCData {
+    fn default() -> IPCData {
+        IPCData {
+            name: None,
+            service_name: [0; MAX_SERVICE_NAME_LEN],
+            service_name_len: 0,
+            service_version: 0,
+            registered: false,
+            peers: None,
+            callback: None,
+        }
+    }
+}
+
+impl IPCData {
+    fn peer(&self, process_idx: usize) -> Option<&Peer> {
+        let mut peer = self.peers.as_ref();
+        while let Some(p) = peer {
+            if p.process_idx == process_idx {
+                return Some(p);
+            }
+            peer = p.next.as_ref();
+        }
+        None
+    }
+
+    fn peer_mut(&mut self, process_idx: usize) -> Option<&mut Peer> {
+        let mut peer = self.peers.as_mut();
+        while let Some(p) = peer {
+            if p.process_idx == process_idx {
+                return Some(p);
+            }
+            peer = p.next.as_mut();
+        }
+        None
+    }
+
+    /// Get the peer for `process_idx`, allocating it if this process has none
+    /// yet.
+    fn peer_or_alloc(
+        &mut self,
+        process_idx: usize,
+        allocator: &mut Allocator,
+    ) -> Option<&mut Peer> {
+        if self.peer(process_idx).is_none() {
+            let peer = allocator
+                .alloc(Peer {
+                    process_idx: process_idx,
+                    shared_memory: None,
+                    client_callback: None,
+                    next: self.peers.take(),
+                }).ok()?;
+            self.peers = Some(peer);
+        }
+        self.peer_mut(process_idx)
+    }
+
+    /// Whether this process provides the service `name`. If it did not
+    /// register a name, it provides its package name.
+    fn provides(&self, name: &[u8], package_name: &[u8]) -> bool {
+        if self.registered {
+            &self.service_name[..self.service_name_len] == name
+        } else {
+            package_name == name
+        }
+    }
+}
+
+pub struct IPC {
+    data: Grant<IPCData>,
+}
+
+impl IPC {
+    pub fn new(kernel: &'static Kernel, capability: &MemoryAllocationCapability) -> IPC {
+        IPC {
+            data: kernel.create_grant(capability),
+        }
+    }
+
+    pub unsafe fn schedule_callback(
+        &self,
+        appid: AppId,
+        otherapp: AppId,
+        cb_type: process::IPCType,
+    ) {
+        self.data
+            .enter(appid, |mydata, _| {
+                let callback = match cb_type {
+                    process::IPCType::Service => mydata.callback,
+                    process::IPCType::Client => mydata
+                        .peer(otherapp.idx())
+                        .and_then(|peer| peer.client_callback),
+                };
+                callback
+                    .map(|mut callback| {
+                        self.data
+                            .enter(otherapp, |otherdata, _| {
+                                match otherdata
+                                    .peer(appid.idx())
+                                    .and_then(|peer| peer.shared_memory.as_ref())
+                                {
+                                    Some(slice) => {
+                                        slice.expose_to(appid);
+                                        callback.schedule(
+                                            otherapp.idx() + 1,
+                                            slice.len(),
+                                            slice.ptr() as usize,
+                                        );
+                                    }
+                                    None => {
+                                        callback.schedule(otherapp.idx() + 1, 0, 0);
+                                    }
+                                }
+                            }).unwrap_or(());
+                    }).unwrap_or(());
+            }).unwrap_or(());
+    }
+
+    /// Whether `target_id`, as used by processes, names a process.
+    fn valid_target(&self, target_id: usize) -> bool {
+        target_id >= 1 && self.data.kernel.process_map_or(false, target_id - 1, |_| true)
+    }
+
+    /// The process that provides the service `name`, and its version.
+    ///
+    /// Registered names take precedence over package names, and if more than
+    /// one process provides `name` the one with the highest version is found.
+    fn find_service(&self, name: &[u8]) -> Option<(usize, usize)> {
+        let mut found: Option<(bool, usize, usize)> = None;
+        for idx in 0..self.data.kernel.number_of_process_slots() {
+            // Whether the process registered a name, and its version.
+            let service = self.data.kernel.process_map_or(None, idx, |process| {
+                if process.get_state() == process::State::Terminated {
+                    return None;
+                }
+                let package_name = process.get_process_name();
+                match self.data.grant(AppId::new(self.data.kernel, idx)) {
+                    Some(grant) => grant.enter(|data, _| {
+                        if data.provides(name, package_name) {
+                            Some((data.registered, data.service_version))
+                        } else {
+                            None
+                        }
+                    }),
+                    None if package_name == name => Some((false, 0)),
+                    None => None,
+                }
+            });
+
+            if let Some((registered, version)) = service {
+                let better = found.map_or(true, |(found_registered, found_version, _)| {
+                    (registered, version) > (found_registered, found_version)
+                });
+                if better {
+                    found = Some((registered, version, idx));
+                }
+            }
+        }
+        found.map(|(_, version, idx)| (idx, version))
+    }
+
+    /// Register `appid` as the service named in its `allow(0)` buffer, with
+    /// `version`.
+    fn register(&self, appid: AppId, version: usize) -> ReturnCode {
+        let mut name = [0; MAX_SERVICE_NAME_LEN];
+        let name_len = match self.data.enter(appid, |data, _| match data.name {
+            Some(ref slice) if slice.len() > 0 && slice.len() <= MAX_SERVICE_NAME_LEN => {
+                name[..slice.len()].copy_from_slice(slice.as_ref());
+                Some(slice.len())
+            }
+            _ => None,
+        }) {
+            Ok(Some(name_len)) => name_len,
+            Ok(None) => return ReturnCode::EINVAL,
+            Err(err) => return err.into(),
+        };
+        let name = &name[..name_len];
+
+        // Names can only be registered once.
+        let taken = self.data.iter().any(|grant| {
+            grant.enter(|data, _| {
+                data.registered && &data.service_name[..data.service_name_len] == name
+            })
+        });
+        let mine = self
+            .data
+            .enter(appid, |data, _| {
+                data.registered && &data.service_name[..data.service_name_len] == name
+            }).unwrap_or(false);
+        if taken && !mine {
+            return ReturnCode::EBUSY;
+        }
+
+        // Nor can a process take the package name of another process, which
+        // would hide that process from clients looking for it by name.
+        let package_name_taken = Cell::new(false);
+        self.data.kernel.process_each_enumerate(|idx, process| {
+            if idx != appid.idx() && process.get_process_name() == name {
+                package_name_taken.set(true);
+            }
+        });
+        if package_name_taken.get() {
+            return ReturnCode::EBUSY;
+        }
+
+        self.data
+            .enter(appid, |data, _| {
+                data.service_name[..name_len].copy_from_slice(name);
+                data.service_name_len = name_len;
+                data.service_version = version;
+                data.registered = true;
+                ReturnCode::SUCCESS
+            }).unwrap_or_else(|err| err.into())
+    }
+
+    /// Find the service named in the `allow(0)` buffer of `appid`, with at
+    /// least version `min_version`.
+    ///
+    /// Names of any length are looked up, as package names are not limited to
+    /// `MAX_SERVICE_NAME_LEN` bytes.
+    fn discover(&self, appid: AppId, min_version: usize) -> ReturnCode {
+        // Take the buffer out of the grant while looking at every process's
+        // grant, and put it back afterwards.
+        let mut name = None;
+        if let Err(err) = self.data.enter(appid, |data, _| name = data.name.take()) {
+            return err.into();
+        }
+        let ret = match name {
+            Some(ref name) if name.len() > 0 => match self.find_service(name.as_ref()) {
+                Some((idx, version)) if version >= min_version => {
+                    ReturnCode::SuccessWithValue { value: idx + 1 }
+                }
+                Some(_) => ReturnCode::ENOSUPPORT,
+                None => ReturnCode::EINVAL,
+            },
+            _ => ReturnCode::EINVAL,
+        };
+        let _ = self.data.enter(appid, |data, _| data.name = name);
+        ret
+    }
+}
+
+impl Driver for IPC {
+    /// subscribe enables processes using IPC to register callbacks that fire
+    /// when notify() is called.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            // subscribe(0)
+            //
+            // Subscribe with subscribe_num == 0 is how a process registers
+            // itself as an IPC service. Each process can only register as a
+            // single IPC service. The identifier for the IPC service is the
+            // name registered with the registry command, or the application
+            // name stored in the TBF header of the application. The callback
+            // that is passed to subscribe is called when another process
+            // notifies the server process.
+            0 => self
+                .data
+                .enter(app_id, |data, _| {
+                    data.callback = callback;
+                    ReturnCode::SUCCESS
+                }).unwrap_or(ReturnCode::EBUSY),
+
+            // subscribe(>=1)
+            //
+            // Subscribe with subscribe_num >= 1 is how a client registers
+            // a callback for a given service. The service number (passed
+            // here as subscribe_num) is returned from discovery. Once
+            // subscribed, the client will receive callbacks when the
+            // service process calls notify_client().
+            svc_id => {
+                if !self.valid_target(svc_id) {
+                    return ReturnCode::EINVAL;
+                }
+                self.data
+                    .enter(app_id, |data, allocator| {
+                        data.peer_or_alloc(svc_id - 1, allocator)
+                            .map_or(ReturnCode::ENOMEM, |peer| {
+                                peer.client_callback = callback;
+                                ReturnCode::SUCCESS
+                            })
+                    }).unwrap_or(ReturnCode::EBUSY)
+            }
+        }
+    }
+
+    /// command is how notify() is implemented.
+    /// Notifying an IPC service is done by setting client_or_svc to 0,
+    /// and notifying an IPC client is done by setting client_or_svc to 1.
+    /// In either case, the target_id is the same number as provided in a notify
+    /// callback or as returned by discovery.
+    ///
+    /// Returns EINVAL if the other process doesn't exist.
+    ///
+    /// Command number 0 is the service registry. Its first argument selects
+    /// the operation and the second is its value:
+    ///
+    /// - `0`: Register as the service named in the `allow(0)` buffer, with the
+    ///   version in the second argument. Returns EBUSY if another process
+    ///   registered that name or has it as its package name.
+    /// - `1`: Stop being a registered service.
+    /// - `2`: Discover the service named in the `allow(0)` buffer, which must
+    ///   have at least the version in the second argument. Returns its id, or
+    ///   ENOSUPPORT if its version is too old.
+    /// - `3`: Get the version of the service with the id in the second
+    ///   argument.
+    fn command(
+        &self,
+        target_id: usize,
+        client_or_svc: usize,
+        arg: usize,
+        appid: AppId,
+    ) -> ReturnCode {
+        if target_id == 0 {
+            return match client_or_svc {
+                REGISTER_SERVICE => self.register(appid, arg),
+                UNREGISTER_SERVICE => self
+                    .data
+                    .enter(appid, |data, _| {
+                        data.registered = false;
+                        ReturnCode::SUCCESS
+                    }).unwrap_or_else(|err| err.into()),
+                DISCOVER_SERVICE => self.discover(appid, arg),
+                SERVICE_VERSION if self.valid_target(arg) => self
+                    .data
+                    .grant(AppId::new(self.data.kernel, arg - 1))
+                    .map_or(ReturnCode::SuccessWithValue { value: 0 }, |grant| {
+                        grant.enter(|data, _| ReturnCode::SuccessWithValue {
+                            value: if data.registered {
+                                data.service_version
+                            } else {
+                                0
+                            },
+                        })
+                    }),
+                SERVICE_VERSION => ReturnCode::EINVAL,
+                _ => ReturnCode::ENOSUPPORT,
+            };
+        }
+
+        let cb_type = if client_or_svc == 0 {
+            process::IPCType::Service
+        } else {
+            process::IPCType::Client
+        };
+
+        self.data
+            .kernel
+            .process_map_or(ReturnCode::EINVAL, target_id - 1, |target| {
+                let ret = target.enqueue_task(process::Task::IPC((appid, cb_type)));
+                match ret {
+                    true => ReturnCode::SUCCESS,
+                    false => ReturnCode::FAIL,
+                }
+            })
+    }
+
+    /// allow enables processes to discover IPC services on the platform or
+    /// share buffers with existing services.
+    ///
+    /// If allow is called with target_id == 0, the slice holds the name of a
+    /// service for the registry command. For compatibility with clients that
+    /// discover services this way, allow also returns the id of the service
+    /// with that name if there is one, and EINVAL otherwise.
+    ///
+    /// If allow is called with target_id >= 1, it is a share command where the
+    /// application is explicitly sharing a slice with an IPC service (as
+    /// specified by the target_id). allow() simply allows both processes to
+    /// access the buffer, it does not signal the service.
+    fn allow(
+        &self,
+        appid: AppId,
+        target_id: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        if target_id == 0 {
+            let have_name = slice.as_ref().map_or(false, |slice| slice.len() > 0);
+            let ret = self
+                .data
+                .enter(appid, |data, _| {
+                    data.name = slice;
+                    ReturnCode::SUCCESS
+                }).unwrap_or(ReturnCode::EBUSY);
+            if ret != ReturnCode::SUCCESS {
+                return ret;
+            }
+            if have_name {
+                return self.discover(appid, 0);
+            }
+            return ReturnCode::EINVAL; /* AppSlice must have non-zero length */
+        }
+        if !self.valid_target(target_id) {
+            return ReturnCode::EINVAL; /* Target process does not exist */
+        }
+        return self
+            .data
+            .enter(appid, |data, allocator| {
+                data.peer_or_alloc(target_id - 1, allocator)
+                    .map_or(ReturnCode::ENOMEM, |peer| {
+                        peer.shared_memory = slice;
+                        ReturnCode::SUCCESS
+                    })
+            }).unwrap_or(ReturnCode::EBUSY);
+    }
+}
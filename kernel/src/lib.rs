@@ -8,16 +8,26 @@
 
 #![feature(asm, core_intrinsics, ptr_internals, const_fn)]
 #![feature(use_extern_macros, try_from, used, panic_info_message)]
-#![feature(in_band_lifetimes, crate_visibility_modifier)]
+#![feature(in_band_lifetimes, crate_visibility_modifier, as_cell)]
 #![warn(unreachable_pub)]
 #![no_std]
 
 extern crate tock_cells;
 extern crate tock_registers;
+extern crate tock_tbf;
 
 pub use tock_registers::{register_bitfields, register_bitmasks};
 
+/// Major version of the kernel's interface to apps. Apps can require a kernel
+/// version in their TBF header, and are only loaded by kernels with the same
+/// major version.
+pub const KERNEL_MAJOR_VERSION: u16 = 1;
+/// Minor version of the kernel's interface to apps. Apps are only loaded by
+/// kernels with at least the minor version they require.
+pub const KERNEL_MINOR_VERSION: u16 = 2;
+
 pub mod capabilities;
+pub mod clock;
 #[macro_use]
 pub mod common;
 pub mod component;
@@ -28,6 +38,7 @@ pub mod ipc;
 pub mod syscall;
 
 mod callback;
+mod credentials;
 mod driver;
 mod grant;
 mod mem;
@@ -38,20 +49,35 @@ mod returncode;
 mod sched;
 mod tbfheader;
 
-pub use callback::{AppId, Callback};
+pub use callback::{AppId, Callback, CallbackId};
 pub use driver::Driver;
 pub use grant::Grant;
 pub use mem::{AppPtr, AppSlice, Private, Shared};
 pub use platform::systick::SysTick;
-pub use platform::{mpu, Chip, Platform};
+pub use platform::{mpu, power, Chip, Platform};
 pub use platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
 pub use returncode::ReturnCode;
-pub use sched::Kernel;
+pub use sched::{Kernel, ProcessSlots, Scheduler, SchedulingDecision, StoppedExecutingReason};
 
 // Export only select items from the process module. To remove the name conflict
 // this cannot be called `process`, so we use a shortened version. These
 // functions and types are used by board files to setup the platform and setup
 // processes.
 pub mod procs {
-    pub use process::{load_processes, FaultResponse, FunctionCall, Process, ProcessType};
+    pub use credentials::{
+        AppCredentialsPolicy, CredentialsFormat, SignatureVerifier, SignedImage,
+    };
+    pub use process::{
+        load_processes, DynamicProcessLoader, FaultResponse, FunctionCall, FunctionCallSource,
+        GrantUsage, Process, ProcessFaultPolicy, ProcessLoadError, ProcessLoader, ProcessType,
+        State, TaskQueueConfig,
+    };
+}
+
+/// The scheduler implementations that boards can choose from to pass to
+/// `Kernel::kernel_loop()`.
+pub mod schedulers {
+    pub use sched::mlfq::{MLFQProcessState, MLFQSched};
+    pub use sched::priority::PrioritySched;
+    pub use sched::round_robin::RoundRobinSched;
 }
@@ -3,6 +3,7 @@
 use driver::Driver;
 
 pub mod mpu;
+pub mod power;
 crate mod systick;
 
 /// Interface for individual boards.
@@ -23,7 +24,14 @@ pub trait Chip {
     fn has_pending_interrupts(&self) -> bool;
     fn mpu(&self) -> &Self::MPU;
     fn systick(&self) -> &Self::SysTick;
-    fn sleep(&self);
+
+    /// The deepest sleep state the chip's peripherals allow right now, usually
+    /// from the chip's `power::PowerManager`.
+    fn sleep_state(&self) -> power::SleepState;
+
+    /// Sleep until an interrupt, no deeper than `state`.
+    fn sleep(&self, state: power::SleepState);
+
     unsafe fn atomic<F, R>(&self, f: F) -> R
     where
         F: FnOnce() -> R;
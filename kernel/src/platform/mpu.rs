@@ -20,6 +20,7 @@ pub enum ExecutePermission {
     ExecutionNotPermitted = 0b1,
 }
 
+#[derive(Copy, Clone, Debug)]
 pub struct Region {
     base_address: u32,
     attributes: u32,
@@ -49,6 +50,76 @@ impl Region {
     }
 }
 
+/// The most regions an `MpuConfig` can hold.
+pub const MAX_CONFIG_REGIONS: usize = 8;
+
+/// MPU configuration for one process, as computed by
+/// `MPU::process_config()`.
+///
+/// The kernel keeps the configuration of each process and passes it back to
+/// `MPU::configure()` each time it switches to the process, until the layout
+/// of the process's memory changes. What the regions contain is up to the MPU.
+#[derive(Copy, Clone, Debug)]
+pub struct MpuConfig {
+    regions: [Region; MAX_CONFIG_REGIONS],
+    len: usize,
+}
+
+impl MpuConfig {
+    pub fn new() -> MpuConfig {
+        MpuConfig {
+            regions: [Region {
+                base_address: 0,
+                attributes: 0,
+            }; MAX_CONFIG_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Add a region. Returns false if the configuration is full.
+    pub fn push(&mut self, region: Region) -> bool {
+        if self.len < MAX_CONFIG_REGIONS {
+            self.regions[self.len] = region;
+            self.len += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+}
+
+/// Where a process's memory is, for `MPU::process_config()`. All addresses are
+/// absolute.
+///
+/// ```text
+/// ram_start + ram_size ─┬─────────
+///                       │ Grant (kernel only)
+/// kernel_memory_break  ─┼─────────
+///                       │ Unused
+/// app_break            ─┼─────────
+///                       │ Heap, data, stack
+/// ram_start            ─┴─────────
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct ProcessMemoryLayout<'a> {
+    pub flash_start: usize,
+    pub flash_size: usize,
+    pub ram_start: usize,
+    pub ram_size: usize,
+    /// End of the memory the process has asked for with `brk` or `sbrk`.
+    pub app_break: usize,
+    /// Start of the grant region, which runs to the end of RAM. The process
+    /// must not be able to access it.
+    pub kernel_memory_break: usize,
+    /// Buffers in other processes that were shared with this process over
+    /// IPC, as start address and size. The process may read and write them.
+    pub ipc_regions: &'a [(usize, usize)],
+}
+
 pub trait MPU {
     /// Enable the MPU.
     ///
@@ -61,45 +132,374 @@ pub trait MPU {
     /// Completely disable the MPU.
     fn disable_mpu(&self);
 
+    /// Compute the MPU configuration that protects a process with the memory
+    /// `layout`.
+    ///
+    /// The process must be able to execute and read its flash, and read and
+    /// write its RAM below the kernel memory break and its IPC regions. It
+    /// must not be able to access the grant region. Returns `None` if this MPU
+    /// cannot enforce that for the layout.
+    fn process_config(&self, layout: &ProcessMemoryLayout) -> Option<MpuConfig>;
+
+    /// Load a configuration returned by `process_config()` into the MPU.
+    fn configure(&self, config: &MpuConfig);
+}
+
+/// Noop implementation of MPU trait
+impl MPU for () {
+    fn enable_mpu(&self) {}
+
+    fn disable_mpu(&self) {}
+
+    fn process_config(&self, _: &ProcessMemoryLayout) -> Option<MpuConfig> {
+        Some(MpuConfig::new())
+    }
+
+    fn configure(&self, _: &MpuConfig) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MpuConfig, Region, MAX_CONFIG_REGIONS};
+
+    #[test]
+    fn config_holds_up_to_max_regions() {
+        let mut config = MpuConfig::new();
+        assert!(config.regions().is_empty());
+        for i in 0..MAX_CONFIG_REGIONS {
+            assert!(config.push(unsafe { Region::new(i as u32, 1) }));
+        }
+        assert!(!config.push(Region::empty(0)));
+        assert_eq!(config.regions().len(), MAX_CONFIG_REGIONS);
+        assert_eq!(config.regions()[5].base_address(), 5);
+    }
+
+    #[test]
+    fn empty_region_is_disabled() {
+        let region = Region::empty(6);
+        assert_eq!(region.base_address(), 0x16);
+        assert_eq!(region.attributes() & 1, 0);
+    }
+}
+
+/// The memory protection unit of ARMv7-M chips, such as the Cortex-M3 and
+/// Cortex-M4.
+///
+/// Only the layout of the regions is here, so that it can be tested on the
+/// host. The Cortex-M crates write the regions to the MPU's registers.
+pub mod cortexm {
+    use super::{AccessPermission, ExecutePermission, MpuConfig, ProcessMemoryLayout, Region};
+    use common::math::PowerOfTwo;
+
+    /// The number of MPU regions.
+    pub const NUM_REGIONS: usize = 8;
+
     /// Creates a new MPU-specific memory protection region
     ///
     /// `region_num`: an MPU region number 0-7
-    /// `start_addr`: the region base address. Lower bits will be masked
+    /// `start`     : the region base address. Lower bits will be masked
     ///               according to the region size.
-    /// `len`       : region size as a PowerOfTwo (e.g. `16` for 64KB)
+    /// `len`       : region size in bytes
     /// `execute`   : whether to enable code execution from this region
-    /// `ap`        : access permissions as defined in Table 4.47 of the user
+    /// `access`    : access permissions as defined in Table 4.47 of the user
     ///               guide.
-    fn create_region(
-        &self,
+    pub fn create_region(
         region_num: usize,
         start: usize,
         len: usize,
         execute: ExecutePermission,
         access: AccessPermission,
-    ) -> Option<Region>;
+    ) -> Option<Region> {
+        if region_num >= NUM_REGIONS {
+            // There are only 8 (0-indexed) regions available
+            return None;
+        }
+        if len == 0 {
+            // There are no empty regions.
+            return None;
+        }
 
-    /// Sets the base address, size and access attributes of the given MPU
-    /// region number.
-    fn set_mpu(&self, region: Region);
-}
+        // There are two possibilities we support:
+        //
+        // 1. The base address is aligned exactly to the size of the region,
+        //    which uses an MPU region with the exact base address and size of
+        //    the memory region.
+        //
+        // 2. Otherwise, we can use a larger MPU region and expose only MPU
+        //    subregions, as long as the memory region's base address is aligned
+        //    to 1/8th of a larger region size.
 
-/// Noop implementation of MPU trait
-impl MPU for () {
-    fn enable_mpu(&self) {}
+        if start % len == 0 {
+            // Memory base aligned to memory size - straight forward case
+            let region_len = PowerOfTwo::floor(len as u32);
+            if region_len.exp::<u32>() < 5 {
+                // Region sizes must be 32 Bytes or larger
+                return None;
+            } else if region_len.exp::<u32>() > 32 {
+                // Region sizes must be 4GB or smaller
+                return None;
+            }
 
-    fn disable_mpu(&self) {}
+            let xn = execute as u32;
+            let ap = access as u32;
+            Some(unsafe {
+                Region::new(
+                    (start | 1 << 4 | (region_num & 0xf)) as u32,
+                    1 | (region_len.exp::<u32>() - 1) << 1 | ap << 24 | xn << 28,
+                )
+            })
+        } else {
+            // Memory base not aligned to memory size
 
-    fn create_region(
-        &self,
-        _: usize,
-        _: usize,
-        _: usize,
-        _: ExecutePermission,
-        _: AccessPermission,
-    ) -> Option<Region> {
-        Some(Region::empty(0))
+            // Which (power-of-two) subregion size would align with the base
+            // address?
+            //
+            // We find this by taking smallest binary substring of the base
+            // address with exactly one bit:
+            //
+            //      1 << (start.trailing_zeros())
+            let subregion_size = {
+                let tz = start.trailing_zeros();
+                // `start` should never be 0 because of that's taken care of by
+                // the previous branch, but in case it is, do the right thing
+                // anyway.
+                if tz < 32 {
+                    (1 as usize) << tz
+                } else {
+                    0
+                }
+            };
+
+            // Once we have a subregion size, we get a region size by
+            // multiplying it by the number of subregions per region.
+            let region_size = subregion_size * 8;
+            // Finally, we calculate the region base by finding the nearest
+            // address below `start` that aligns with the region size.
+            let region_start = start - (start % region_size);
+
+            if region_size + region_start - start < len {
+                // Sanity check that the amount left over space in the region
+                // after `start` is at least as large as the memory region we
+                // want to reference.
+                return None;
+            }
+            if len % subregion_size != 0 {
+                // Sanity check that there is some integer X such that
+                // subregion_size * X == len so none of `len` is left over when
+                // we take the max_subregion.
+                return None;
+            }
+
+            // The index of the first subregion to activate is the number of
+            // regions between `region_start` (MPU) and `start` (memory).
+            let min_subregion = (start - region_start) / subregion_size;
+            // The index of the last subregion to activate is the number of
+            // regions that fit in `len`, plus the `min_subregion`, minus one
+            // (because subregions are zero-indexed).
+            let max_subregion = min_subregion + len / subregion_size - 1;
+
+            let region_len = PowerOfTwo::floor(region_size as u32);
+            if region_len.exp::<u32>() < 7 {
+                // Subregions only supported for regions sizes 128 bytes and up.
+                return None;
+            } else if region_len.exp::<u32>() > 32 {
+                // Region sizes must be 4GB or smaller
+                return None;
+            }
+
+            // Turn the min/max subregion into a bitfield where all bits are `1`
+            // except for the bits whose index lie within
+            // [min_subregion, max_subregion]
+            //
+            // Note: Rust ranges are minimum inclusive, maximum exclusive, hence
+            // max_subregion + 1.
+            let subregion_mask =
+                (min_subregion..(max_subregion + 1)).fold(!0, |res, i| res & !(1 << i)) & 0xff;
+
+            let xn = execute as u32;
+            let ap = access as u32;
+            Some(unsafe {
+                Region::new(
+                    (region_start | 1 << 4 | (region_num & 0xf)) as u32,
+                    1 | subregion_mask << 8
+                        | (region_len.exp::<u32>() - 1) << 1
+                        | ap << 24
+                        | xn << 28,
+                )
+            })
+        }
     }
 
-    fn set_mpu(&self, _: Region) {}
+    /// The configuration `MPU::process_config()` loads for a process with the
+    /// memory `layout`. Regions are laid out as:
+    ///
+    /// - 0: the process's flash, read and execute.
+    /// - 1: all of the process's RAM, read and write.
+    /// - 2: the grant region rounded up to a power of two, privileged only.
+    ///   Higher numbered regions take priority, so this overrides region 1.
+    /// - 3-7: IPC regions, read and write.
+    ///
+    /// Regions must be a power of two in size and aligned to either their size
+    /// or, using subregions, to an eighth of the next larger power of two.
+    pub fn process_config(layout: &ProcessMemoryLayout) -> Option<MpuConfig> {
+        let mut config = MpuConfig::new();
+
+        // Flash segment read/execute (no write)
+        config.push(create_region(
+            0,
+            layout.flash_start,
+            layout.flash_size,
+            ExecutePermission::ExecutionPermitted,
+            AccessPermission::ReadOnly,
+        )?);
+
+        config.push(create_region(
+            1,
+            layout.ram_start,
+            layout.ram_size,
+            ExecutePermission::ExecutionPermitted,
+            AccessPermission::ReadWrite,
+        )?);
+
+        // Disallow access to grant region
+        let ram_end = layout.ram_start + layout.ram_size;
+        let grant_len =
+            PowerOfTwo::ceiling((ram_end - layout.kernel_memory_break) as u32).as_num::<u32>()
+                as usize;
+        if grant_len > layout.ram_size {
+            return None;
+        }
+        config.push(create_region(
+            2,
+            ram_end - grant_len,
+            grant_len,
+            ExecutePermission::ExecutionNotPermitted,
+            AccessPermission::PrivilegedOnly,
+        )?);
+
+        // Setup IPC MPU regions
+        for (i, &(start, len)) in layout.ipc_regions.iter().enumerate() {
+            let region = create_region(
+                i + 3,
+                start,
+                len,
+                ExecutePermission::ExecutionPermitted,
+                AccessPermission::ReadWrite,
+            )?;
+            if !config.push(region) {
+                return None;
+            }
+        }
+
+        Some(config)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::process_config;
+        use platform::mpu::{ProcessMemoryLayout, Region};
+
+        /// The fields of a region: base address, size in bytes, subregion disable
+        /// bits, access permission and execute never.
+        fn decode(region: &Region) -> (usize, usize, u32, u32, u32) {
+            let attributes = region.attributes();
+            assert_eq!(attributes & 1, 1);
+            (
+                (region.base_address() & !0x1f) as usize,
+                1 << (((attributes >> 1) & 0x1f) + 1),
+                (attributes >> 8) & 0xff,
+                (attributes >> 24) & 0b111,
+                (attributes >> 28) & 1,
+            )
+        }
+
+        fn layout(ipc_regions: &[(usize, usize)]) -> ProcessMemoryLayout {
+            ProcessMemoryLayout {
+                flash_start: 0x30000,
+                flash_size: 0x10000,
+                ram_start: 0x20004000,
+                ram_size: 0x4000,
+                app_break: 0x20005000,
+                kernel_memory_break: 0x20007D00,
+                ipc_regions: ipc_regions,
+            }
+        }
+
+        #[test]
+        fn aligned_flash_and_ram() {
+            let config = process_config(&layout(&[])).unwrap();
+            let regions = config.regions();
+            assert_eq!(regions.len(), 3);
+            for (i, region) in regions.iter().enumerate() {
+                assert_eq!(region.base_address() & 0x1f, 0x10 | i as u32);
+            }
+
+            assert_eq!(decode(&regions[0]), (0x30000, 0x10000, 0, 0b110, 0));
+            assert_eq!(decode(&regions[1]), (0x20004000, 0x4000, 0, 0b011, 0));
+            // The 0x300 byte grant region rounds up to 0x400.
+            assert_eq!(decode(&regions[2]), (0x20007C00, 0x400, 0, 0b001, 1));
+        }
+
+        #[test]
+        fn unaligned_regions_use_subregions() {
+            let mut layout = layout(&[]);
+            layout.flash_start = 0x30600;
+            layout.flash_size = 0x400;
+            // 0x1800 bytes in the 0x4000 byte region at 0x20000000.
+            layout.ram_start = 0x20000800;
+            layout.ram_size = 0x1800;
+            layout.kernel_memory_break = 0x20001F00;
+
+            let config = process_config(&layout).unwrap();
+            let regions = config.regions();
+            // Subregions 3 and 4 of the 0x1000 byte region at 0x30000.
+            assert_eq!(decode(&regions[0]), (0x30000, 0x1000, 0xE7, 0b110, 0));
+            // Subregions 1 to 3.
+            assert_eq!(decode(&regions[1]), (0x20000000, 0x4000, 0xF1, 0b011, 0));
+            assert_eq!(decode(&regions[2]), (0x20001F00, 0x100, 0, 0b001, 1));
+        }
+
+        #[test]
+        fn ipc_regions_follow_grant() {
+            let ipc = [
+                (0x20010000, 0x100),
+                (0x20011000, 0x1000),
+                (0x20012000, 0x40),
+                (0x20012100, 0x80),
+                (0x20012400, 0x400),
+            ];
+            let config = process_config(&layout(&ipc)).unwrap();
+            let regions = config.regions();
+            assert_eq!(regions.len(), 8);
+            for (region, &(start, len)) in regions[3..].iter().zip(ipc.iter()) {
+                assert_eq!(decode(region), (start, len, 0, 0b011, 0));
+            }
+            assert_eq!(regions[7].base_address() & 0xf, 7);
+
+            // There are only 8 regions.
+            let too_many = [(0x20010000, 0x100); 6];
+            assert!(process_config(&layout(&too_many)).is_none());
+        }
+
+        #[test]
+        fn infeasible_layouts() {
+            // Regions must be at least 32 bytes.
+            assert!(process_config(&layout(&[(0x20010000, 0x10)])).is_none());
+            assert!(process_config(&layout(&[(0x20010000, 0)])).is_none());
+
+            // Flash that cannot be covered with subregions of one region.
+            let mut flash = layout(&[]);
+            flash.flash_start = 0x30200;
+            flash.flash_size = 0x10000;
+            assert!(process_config(&flash).is_none());
+
+            // The grant region rounded up to a power of two would be larger than
+            // RAM.
+            let mut grant = layout(&[]);
+            grant.ram_start = 0x20000800;
+            grant.ram_size = 0x1800;
+            grant.kernel_memory_break = 0x20001000 - 1;
+            assert!(process_config(&grant).is_none());
+        }
+    }
 }
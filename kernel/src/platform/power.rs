@@ -0,0 +1,201 @@
+//! Interface for choosing how deeply a chip sleeps.
+//!
+//! Deeper sleep states save more power, but stop more of the chip, so a
+//! peripheral that is in use has to keep the chip out of the states that
+//! would break it. For example a UART that is receiving needs its clock, and
+//! a radio that is on needs the high frequency crystal.
+//!
+//! Each such peripheral owns a `PowerConstraint` that names the deepest
+//! `SleepState` it works in, and turns it on and off with a `PowerManager` as
+//! it starts and stops being active. Before the kernel puts the chip to sleep
+//! it asks the chip for its `Chip::sleep_state()`, which is the deepest state
+//! no active constraint rules out, and passes that to `Chip::sleep()`. Chips
+//! decide what each `SleepState` means for their hardware.
+//!
+//! The constraint bookkeeping does not touch hardware, so it works the same
+//! on any target:
+//!
+//! ```rust
+//! use kernel::power::{PowerConstraint, PowerManager, SleepState};
+//!
+//! let manager = PowerManager::new();
+//! let uart_rx = PowerConstraint::new(SleepState::Sleep);
+//! let radio = PowerConstraint::new(SleepState::Idle);
+//! assert_eq!(manager.deepest_allowed(), SleepState::DeepSleep);
+//!
+//! manager.set_active(&uart_rx, true);
+//! manager.set_active(&radio, true);
+//! // Setting a constraint twice counts it once.
+//! manager.set_active(&radio, true);
+//! assert_eq!(manager.deepest_allowed(), SleepState::Idle);
+//!
+//! manager.set_active(&radio, false);
+//! assert_eq!(manager.deepest_allowed(), SleepState::Sleep);
+//! manager.set_active(&uart_rx, false);
+//! assert_eq!(manager.deepest_allowed(), SleepState::DeepSleep);
+//! ```
+
+use core::cell::Cell;
+
+/// Number of `SleepState`s.
+const NUM_SLEEP_STATES: usize = 3;
+
+/// How deeply the chip sleeps, from lightest to deepest.
+#[derive(Copy, Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum SleepState {
+    /// Only the CPU stops. Every peripheral, clock and DMA transfer keeps
+    /// running.
+    Idle = 0,
+    /// The CPU and high speed buses stop, so DMA stops too. Peripheral clocks
+    /// keep running and peripherals can wake the chip.
+    Sleep = 1,
+    /// All high frequency clocks stop. Only low frequency timers and wakeup
+    /// sources such as GPIO interrupts run.
+    DeepSleep = 2,
+}
+
+impl SleepState {
+    /// The deeper of the two states.
+    pub fn deeper(self, other: SleepState) -> SleepState {
+        if other > self {
+            other
+        } else {
+            self
+        }
+    }
+
+    /// The lighter of the two states.
+    pub fn lighter(self, other: SleepState) -> SleepState {
+        if other < self {
+            other
+        } else {
+            self
+        }
+    }
+}
+
+/// Something that keeps the chip out of sleep states deeper than `limit`
+/// while it is active.
+pub struct PowerConstraint {
+    limit: SleepState,
+    active: Cell<bool>,
+}
+
+impl PowerConstraint {
+    pub const fn new(limit: SleepState) -> PowerConstraint {
+        PowerConstraint {
+            limit: limit,
+            active: Cell::new(false),
+        }
+    }
+
+    /// The deepest state the chip can sleep in while this is active.
+    pub fn limit(&self) -> SleepState {
+        self.limit
+    }
+
+    /// Whether this is active.
+    pub fn is_active(&self) -> bool {
+        self.active.get()
+    }
+}
+
+/// Combines the `PowerConstraint`s of a chip's peripherals.
+pub struct PowerManager {
+    /// Number of active constraints for each limit, indexed by `SleepState`.
+    active: [Cell<usize>; NUM_SLEEP_STATES],
+}
+
+impl PowerManager {
+    pub const fn new() -> PowerManager {
+        PowerManager {
+            active: [Cell::new(0), Cell::new(0), Cell::new(0)],
+        }
+    }
+
+    /// Turn `constraint` on or off. Turning on a constraint that is already
+    /// on, or off one that is already off, does nothing.
+    pub fn set_active(&self, constraint: &PowerConstraint, active: bool) {
+        if constraint.active.get() == active {
+            return;
+        }
+        constraint.active.set(active);
+        let count = &self.active[constraint.limit as usize];
+        if active {
+            count.set(count.get() + 1);
+        } else {
+            count.set(count.get() - 1);
+        }
+    }
+
+    /// The deepest state no active constraint rules out.
+    pub fn deepest_allowed(&self) -> SleepState {
+        if self.active[SleepState::Idle as usize].get() > 0 {
+            SleepState::Idle
+        } else if self.active[SleepState::Sleep as usize].get() > 0 {
+            SleepState::Sleep
+        } else {
+            SleepState::DeepSleep
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PowerConstraint, PowerManager, SleepState};
+
+    #[test]
+    fn orders_sleep_states() {
+        assert!(SleepState::Idle < SleepState::Sleep);
+        assert!(SleepState::Sleep < SleepState::DeepSleep);
+
+        let states = [SleepState::Idle, SleepState::Sleep, SleepState::DeepSleep];
+        for (i, a) in states.iter().enumerate() {
+            for (j, b) in states.iter().enumerate() {
+                assert_eq!(a.deeper(*b), states[i.max(j)]);
+                assert_eq!(a.lighter(*b), states[i.min(j)]);
+            }
+        }
+    }
+
+    #[test]
+    fn lightest_active_constraint_wins() {
+        let manager = PowerManager::new();
+        let idle = PowerConstraint::new(SleepState::Idle);
+        let sleep = PowerConstraint::new(SleepState::Sleep);
+        let deep_sleep = PowerConstraint::new(SleepState::DeepSleep);
+
+        manager.set_active(&deep_sleep, true);
+        assert_eq!(manager.deepest_allowed(), SleepState::DeepSleep);
+        manager.set_active(&sleep, true);
+        manager.set_active(&idle, true);
+        assert_eq!(manager.deepest_allowed(), SleepState::Idle);
+
+        // Constraints can end in any order.
+        manager.set_active(&sleep, false);
+        assert_eq!(manager.deepest_allowed(), SleepState::Idle);
+        manager.set_active(&idle, false);
+        assert_eq!(manager.deepest_allowed(), SleepState::DeepSleep);
+        manager.set_active(&sleep, true);
+        assert_eq!(manager.deepest_allowed(), SleepState::Sleep);
+    }
+
+    #[test]
+    fn constraints_with_the_same_limit_count_separately() {
+        let manager = PowerManager::new();
+        let uart = PowerConstraint::new(SleepState::Sleep);
+        let spi = PowerConstraint::new(SleepState::Sleep);
+
+        manager.set_active(&uart, true);
+        manager.set_active(&spi, true);
+        manager.set_active(&uart, false);
+        // Turning a constraint off twice does not end the other one.
+        manager.set_active(&uart, false);
+        assert!(!uart.is_active());
+        assert!(spi.is_active());
+        assert_eq!(manager.deepest_allowed(), SleepState::Sleep);
+
+        manager.set_active(&spi, false);
+        assert_eq!(manager.deepest_allowed(), SleepState::DeepSleep);
+    }
+}
@@ -24,6 +24,12 @@ pub trait SysTick {
     /// Returns true if the timer has expired
     fn overflowed(&self) -> bool;
 
+    /// Returns the number of microseconds left before the timer expires.
+    ///
+    /// Used by the scheduler to account for how much of its timeslice a
+    /// process actually used.
+    fn get_value(&self) -> u32;
+
     /// Resets the timer
     ///
     /// Resets the timer to 0 and disables it
@@ -53,6 +59,10 @@ impl SysTick for () {
         false
     }
 
+    fn get_value(&self) -> u32 {
+        u32::max_value()
+    }
+
     fn greater_than(&self, _: u32) -> bool {
         true
     }
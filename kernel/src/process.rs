@@ -3,51 +3,64 @@
 use core::cell::Cell;
 use core::fmt::Write;
 use core::ptr::write_volatile;
-use core::{mem, ptr, slice, str};
+use core::{cmp, mem, ptr, slice, str, usize};
 
-use callback::AppId;
+use callback::{AppId, Callback, CallbackId};
 use capabilities::ProcessManagementCapability;
 use common::cells::MapCell;
 use common::math;
 use common::{Queue, RingBuffer};
+use grant::{self, GrantAllocator};
 use platform::mpu;
 use returncode::ReturnCode;
 use sched::Kernel;
 use syscall::{self, Syscall, UserspaceKernelBoundary};
 use tbfheader;
+use tock_tbf::{self, TbfHeaderStruct};
 
-/// Helper function to load processes from flash into an array of active
-/// processes. This is the default template for loading processes, but a board
-/// is able to create its own `load_processes()` function and use that instead.
+/// Helper function to load processes from flash into the process slots the
+/// board gave to `Kernel::new()`.
 ///
 /// Processes are found in flash starting from the given address and iterating
 /// through Tock Binary Format headers. Processes are given memory out of the
-/// `app_memory` buffer until either the memory is exhausted or the allocated
-/// number of processes are created, with process structures placed in the
-/// provided array. How process faults are handled by the kernel is also
-/// selected.
+/// `app_memory` buffer until either the memory is exhausted or every process
+/// slot is filled. How process faults are handled by the kernel is also
+/// selected, either with a fixed `FaultResponse` or a more involved
+/// `ProcessFaultPolicy`. Apps that this kernel cannot run, such as apps whose
+/// credentials are rejected (see `Kernel::set_app_credentials_policy()`) or
+/// that need a newer kernel, are skipped with a debug message.
 pub fn load_processes<S: UserspaceKernelBoundary>(
     kernel: &'static Kernel,
     syscall: &'static S,
     start_of_flash: *const u8,
     app_memory: &mut [u8],
-    procs: &'static mut [Option<&'static ProcessType>],
-    fault_response: FaultResponse,
+    fault_policy: &'static ProcessFaultPolicy,
     _capability: &ProcessManagementCapability,
 ) {
     let mut apps_in_flash_ptr = start_of_flash;
     let mut app_memory_ptr = app_memory.as_mut_ptr();
     let mut app_memory_size = app_memory.len();
-    for i in 0..procs.len() {
+    for i in 0..kernel.number_of_process_slots() {
         unsafe {
-            let (process, flash_offset, memory_offset) = Process::create(
+            let (process, flash_offset, memory_offset) = match Process::create(
                 kernel,
                 syscall,
                 apps_in_flash_ptr,
                 app_memory_ptr,
                 app_memory_size,
-                fault_response,
-            );
+                fault_policy,
+                i,
+            ) {
+                Ok(result) => result,
+                Err(err) if err.skips_app() => {
+                    // Skip the app, but keep loading the apps after it.
+                    debug!("Skipping app at {:?}: {:?}", apps_in_flash_ptr, err);
+                    let flash_size = tbfheader::parse_and_validate_tbf_header(apps_in_flash_ptr)
+                        .map_or(0, |header| header.get_total_size() as usize);
+                    (None, flash_size, 0)
+                }
+                Err(err) => panic!("App at {:?} failed to load: {:?}", apps_in_flash_ptr, err),
+            };
 
             if process.is_none() {
                 // We did not get a valid process, but we may have gotten a disabled
@@ -58,7 +71,7 @@ pub fn load_processes<S: UserspaceKernelBoundary>(
                     break;
                 }
             } else {
-                procs[i] = process;
+                process.map(|process| kernel.set_process(i, process));
             }
 
             apps_in_flash_ptr = apps_in_flash_ptr.offset(flash_offset as isize);
@@ -68,6 +81,196 @@ pub fn load_processes<S: UserspaceKernelBoundary>(
     }
 }
 
+/// Why a process could not be created from its TBF image.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProcessLoadError {
+    /// The app needs more RAM than is left for processes.
+    NotEnoughMemory { requested: usize, available: usize },
+
+    /// The app's `init_fn` address does not end in 1, so it is not a Thumb
+    /// address.
+    InvalidInitFunction { address: usize },
+
+    /// The app has a credential that is wrong, or lacks a credential the
+    /// kernel's `AppCredentialsPolicy` requires.
+    CredentialsRejected,
+
+    /// The app needs a kernel version this kernel is not compatible with.
+    IncompatibleKernelVersion { major: u16, minor: u16 },
+
+    /// The app is not position independent and must be at a different
+    /// address in flash.
+    IncorrectFlashAddress { actual: usize, expected: usize },
+
+    /// The app is not position independent and needs its RAM at a different
+    /// address than the next free process memory.
+    IncorrectRamAddress { actual: usize, expected: usize },
+}
+
+impl ProcessLoadError {
+    /// Whether the app cannot run on this kernel at all, as opposed to there
+    /// being a problem with the kernel's configuration.
+    crate fn skips_app(&self) -> bool {
+        match *self {
+            ProcessLoadError::NotEnoughMemory { .. } => false,
+            ProcessLoadError::InvalidInitFunction { .. } => false,
+            ProcessLoadError::CredentialsRejected => true,
+            ProcessLoadError::IncompatibleKernelVersion { .. } => true,
+            ProcessLoadError::IncorrectFlashAddress { .. } => true,
+            ProcessLoadError::IncorrectRamAddress { .. } => true,
+        }
+    }
+}
+
+/// Loads processes while the kernel is running, after `load_processes()`
+/// created the processes that were in flash at boot.
+///
+/// The TBF images live in a flash region the board reserves for this. Whoever
+/// is loading a new app first writes its image at `next_free_flash_address()`
+/// and then calls `load_process()` to start it in an empty process slot. If
+/// the region directly follows the apps loaded at boot, apps loaded this way
+/// are also found by `load_processes()` after a reboot.
+pub trait DynamicProcessLoader {
+    /// The flash region for dynamically loaded apps, as its start address and
+    /// length in bytes.
+    fn flash_region(&self) -> (usize, usize);
+
+    /// The first address in the flash region after the last valid TBF image,
+    /// i.e. where the next app should be written.
+    fn next_free_flash_address(&self) -> usize;
+
+    /// Validate the TBF image at `address` and start it as a new process.
+    ///
+    /// Returns `EINVAL` if there is no valid, enabled app at `address`, it
+    /// does not fit in the flash region or it cannot run at the addresses it
+    /// would get, `ENOMEM` if there is no empty process
+    /// slot or not enough memory for the app, and `FAIL` if the app could not
+    /// be created for another reason.
+    fn load_process(&self, address: usize) -> Result<AppId, ReturnCode>;
+}
+
+/// The kernel's `DynamicProcessLoader`. Processes get their memory from a
+/// separate region than the one passed to `load_processes()`.
+pub struct ProcessLoader<S: 'static + UserspaceKernelBoundary> {
+    kernel: &'static Kernel,
+    syscall: &'static S,
+    flash: &'static [u8],
+    /// Start of the app memory that has not been given to a process yet.
+    app_memory_ptr: Cell<*mut u8>,
+    app_memory_size: Cell<usize>,
+    fault_policy: &'static ProcessFaultPolicy,
+}
+
+impl<S: 'static + UserspaceKernelBoundary> ProcessLoader<S> {
+    pub fn new(
+        kernel: &'static Kernel,
+        syscall: &'static S,
+        flash: &'static [u8],
+        app_memory: &'static mut [u8],
+        fault_policy: &'static ProcessFaultPolicy,
+        _capability: &ProcessManagementCapability,
+    ) -> ProcessLoader<S> {
+        ProcessLoader {
+            kernel: kernel,
+            syscall: syscall,
+            flash: flash,
+            app_memory_ptr: Cell::new(app_memory.as_mut_ptr()),
+            app_memory_size: Cell::new(app_memory.len()),
+            fault_policy: fault_policy,
+        }
+    }
+
+    /// The end of the flash region, i.e. the first address after it.
+    fn flash_end(&self) -> usize {
+        self.flash.as_ptr() as usize + self.flash.len()
+    }
+
+    /// Parse the TBF header at `address`, if the whole header and the image it
+    /// describes fit before the end of the flash region.
+    fn parse_header(&self, address: usize) -> Option<tbfheader::TbfHeader> {
+        let base_header_size = mem::size_of::<tock_tbf::TbfHeaderV2Base>();
+        if address < self.flash.as_ptr() as usize || address + base_header_size > self.flash_end()
+        {
+            return None;
+        }
+
+        // Check the sizes in the base header before the checksum and the
+        // TLVs are read, so that nothing past the flash region is touched.
+        let offset = address - self.flash.as_ptr() as usize;
+        let base =
+            tock_tbf::TbfHeaderV2Base::from_bytes(&self.flash[offset..offset + base_header_size])?;
+        let available = self.flash.len() - offset;
+        if base.header_size as usize > available || base.total_size as usize > available {
+            return None;
+        }
+
+        unsafe { tbfheader::parse_and_validate_tbf_header(address as *const u8) }
+    }
+}
+
+impl<S: 'static + UserspaceKernelBoundary> DynamicProcessLoader for ProcessLoader<S> {
+    fn flash_region(&self) -> (usize, usize) {
+        (self.flash.as_ptr() as usize, self.flash.len())
+    }
+
+    fn next_free_flash_address(&self) -> usize {
+        let mut address = self.flash.as_ptr() as usize;
+        while let Some(header) = self.parse_header(address) {
+            let total_size = header.get_total_size() as usize;
+            if total_size == 0 || address + total_size > self.flash_end() {
+                break;
+            }
+            address += total_size;
+        }
+        address
+    }
+
+    fn load_process(&self, address: usize) -> Result<AppId, ReturnCode> {
+        // Check the image before creating the process, so that an image that
+        // claims to extend past the flash region is never used.
+        match self.parse_header(address) {
+            Some(ref header)
+                if header.is_app()
+                    && header.enabled()
+                    && address + header.get_total_size() as usize <= self.flash_end() => {}
+            _ => return Err(ReturnCode::EINVAL),
+        }
+
+        let index = self.kernel.free_process_slot().ok_or(ReturnCode::ENOMEM)?;
+        let result = unsafe {
+            Process::create(
+                self.kernel,
+                self.syscall,
+                address as *const u8,
+                self.app_memory_ptr.get(),
+                self.app_memory_size.get(),
+                self.fault_policy,
+                index,
+            )
+        };
+
+        match result {
+            Ok((Some(process), _, memory_offset)) => {
+                unsafe {
+                    self.app_memory_ptr
+                        .set(self.app_memory_ptr.get().offset(memory_offset as isize));
+                }
+                self.kernel.set_process(index, process);
+                self.app_memory_size
+                    .set(self.app_memory_size.get() - memory_offset);
+                Ok(process.appid())
+            }
+            Ok((None, _, _)) => Err(ReturnCode::EINVAL),
+            Err(ProcessLoadError::NotEnoughMemory { .. }) => Err(ReturnCode::ENOMEM),
+            Err(ProcessLoadError::InvalidInitFunction { .. }) => Err(ReturnCode::FAIL),
+            Err(ProcessLoadError::CredentialsRejected) => Err(ReturnCode::EINVAL),
+            Err(ProcessLoadError::IncompatibleKernelVersion { .. }) => Err(ReturnCode::ENOSUPPORT),
+            Err(ProcessLoadError::IncorrectFlashAddress { .. }) => Err(ReturnCode::EINVAL),
+            Err(ProcessLoadError::IncorrectRamAddress { .. }) => Err(ReturnCode::EINVAL),
+        }
+    }
+}
+
 /// This trait is implemented by process structs.
 pub trait ProcessType {
     /// Queue a `Task` for the process. This will be added to a per-process
@@ -86,10 +289,36 @@ pub trait ProcessType {
     /// `None`.
     fn dequeue_task(&self) -> Option<Task>;
 
+    /// Set the callback the process gets when `Task`s for it are dropped
+    /// because its queue is full, or remove it with `None`.
+    ///
+    /// The callback is passed the number of tasks dropped so far, and the
+    /// driver and subscribe number of the latest dropped upcall (both
+    /// `usize::MAX` if it was not from a driver). Only one notification is
+    /// queued at a time, and there is always room for it in the queue.
+    fn set_dropped_task_callback(&self, callback: Option<Callback>);
+
+    /// How many `Task`s can be queued for this process.
+    fn task_queue_depth(&self) -> usize;
+
+    /// Returns the `AppId` of this process.
+    fn appid(&self) -> AppId;
+
     /// Returns the current state the process is in. Common states are "running"
     /// or "yielded".
     fn get_state(&self) -> State;
 
+    /// Returns whether this process is ready to execute, that is, it is
+    /// either running or has a `Task` waiting to be handled.
+    fn ready(&self) -> bool;
+
+    /// Get the scheduling priority requested in the TBF header, if any. Lower
+    /// values are higher priority.
+    fn get_priority(&self) -> Option<u32>;
+
+    /// Get the app version from the TBF header, if any.
+    fn get_app_version(&self) -> Option<u32>;
+
     /// Move this process from the running state to the yielded state.
     fn set_yielded_state(&self);
 
@@ -97,8 +326,46 @@ pub trait ProcessType {
     /// `FaultResponse` for this process to occur.
     fn set_fault_state(&self);
 
-    /// Get the name of the process. Used for IPC.
-    fn get_process_name(&self) -> &[u8];
+    /// Stop scheduling this process. All of its state is kept, and callbacks
+    /// are still queued for it, but it will not run until it is resumed.
+    ///
+    /// Returns `EALREADY` if the process is already stopped and `EINVAL` if it
+    /// is not in a state that can be stopped.
+    fn stop(&self) -> ReturnCode;
+
+    /// Allow a stopped process to be scheduled again.
+    ///
+    /// Returns `EALREADY` if the process is not stopped and `EINVAL` if it is
+    /// in a state that cannot be resumed.
+    fn resume(&self) -> ReturnCode;
+
+    /// Restart the process from its `init_fn`, as if it had just been loaded.
+    /// Its grants and queued callbacks are discarded. This works from any
+    /// state, including after the process has been terminated. This does not
+    /// count as a restart in `debug_restart_count()`.
+    fn restart(&self);
+
+    /// Restart the process because it faulted, and count the restart in
+    /// `debug_restart_count()`. Returns `EINVAL`, and does nothing, if the
+    /// process is not in the `Fault` state.
+    fn restart_after_fault(&self) -> ReturnCode;
+
+    /// Terminate the process. Its grants and queued callbacks are discarded,
+    /// and the slot is treated as empty until the process is restarted.
+    fn terminate(&self);
+
+    /// Get the name of the process. Used for IPC and to find the process's
+    /// nonvolatile storage.
+    fn get_process_name(&self) -> &'static [u8];
+
+    /// How many bytes of nonvolatile storage the TBF header asks for, if any.
+    fn get_storage_size(&self) -> Option<usize>;
+
+    /// Check whether the TBF header permits the process to use syscall driver
+    /// `driver_num`, and command `command_num` of it if given. Returns
+    /// `SUCCESS` if it does, or the error to return to the process otherwise.
+    fn check_driver_permission(&self, driver_num: usize, command_num: Option<usize>)
+        -> ReturnCode;
 
     // memop operations
 
@@ -164,10 +431,23 @@ pub trait ProcessType {
 
     // grants
 
-    /// Create new memory in the grant region.
-    unsafe fn alloc(&self, size: usize) -> Option<&mut [u8]>;
+    /// Create new memory in the grant region for grant `grant_num`.
+    unsafe fn alloc(&self, size: usize, grant_num: usize) -> Option<&mut [u8]>;
+
+    /// Return memory that `alloc()` created for grant `grant_num` to the grant
+    /// region. `size` must be the size it was allocated with.
+    unsafe fn free(&self, ptr: *mut u8, size: usize, grant_num: usize);
 
-    unsafe fn free(&self, _: *mut u8);
+    /// Changes each time the whole grant region is freed, because the process
+    /// was terminated or restarted. Memory allocated in an earlier generation
+    /// must not be passed to `free()`.
+    fn grant_generation(&self) -> usize;
+
+    /// How much of the grant region is in use.
+    fn grant_usage(&self) -> GrantUsage;
+
+    /// How many bytes of the grant region grant `grant_num` uses.
+    fn grant_memory_used(&self, grant_num: usize) -> usize;
 
     /// Get a pointer to the grant pointer for this grant number.
     unsafe fn grant_ptr(&self, grant_num: usize) -> *mut *mut u8;
@@ -193,6 +473,48 @@ pub trait ProcessType {
 
     unsafe fn fault_fmt(&self, writer: &mut Write);
     unsafe fn process_detail_fmt(&self, writer: &mut Write);
+
+    // debug
+
+    /// Record that the process ran for `execution_time_us` microseconds
+    /// without returning to the kernel. Called by the kernel loop each time
+    /// the process switches back to the kernel, with the time since it was
+    /// switched to, so time the kernel spends handling its system calls is
+    /// not counted.
+    fn debug_process_ran(&self, execution_time_us: u32);
+
+    /// Record that the process was pre-empted because its timeslice expired.
+    fn debug_timeslice_expired(&self);
+
+    /// Returns how many syscalls this process has called since it started.
+    fn debug_syscall_count(&self) -> usize;
+
+    /// Returns how many callbacks for this process were dropped because its
+    /// task queue was full.
+    fn debug_dropped_callback_count(&self) -> usize;
+
+    /// Returns how many times this process has been restarted after a fault.
+    fn debug_restart_count(&self) -> usize;
+
+    /// Returns how many times this process exceeded its timeslice.
+    fn debug_timeslice_expiration_count(&self) -> usize;
+
+    /// Returns the total time, in microseconds, this process has executed.
+    fn debug_cpu_time_us(&self) -> u64;
+
+    /// Returns the longest time, in microseconds, this process ran before
+    /// returning control to the kernel.
+    fn debug_longest_run_us(&self) -> u32;
+
+    /// Get an entry of the per-driver syscall histogram. Returns the driver
+    /// number and how many `subscribe`, `command` and `allow` calls the
+    /// process made to that driver, or `None` if `index` is past the last
+    /// entry.
+    fn debug_driver_syscall_count(&self, index: usize) -> Option<(usize, usize)>;
+
+    /// Returns how many driver syscalls went to drivers that did not fit in
+    /// the per-driver syscall histogram.
+    fn debug_untracked_driver_syscall_count(&self) -> usize;
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -212,17 +534,78 @@ impl From<Error> for ReturnCode {
     }
 }
 
+/// The scheduling state of a process.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum State {
+    /// The process is executing, or was pre-empted and should continue.
     Running,
+
+    /// The process is waiting for a callback.
     Yielded,
+
+    /// The process was `Running` when it was stopped. It will not be
+    /// scheduled until it is resumed.
+    StoppedRunning,
+
+    /// The process was `Yielded` when it was stopped. Callbacks are still
+    /// queued for it, but it will not be scheduled until it is resumed.
+    StoppedYielded,
+
+    /// The process faulted and is not restarting.
     Fault,
+
+    /// The process was terminated and its grants freed. The process slot is
+    /// treated as empty until the process is restarted.
+    Terminated,
 }
 
+/// How the kernel responds to a process fault.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FaultResponse {
+    /// Panic the kernel and print information about the faulting process.
     Panic,
+
+    /// Restart the process from its `init_fn`.
     Restart,
+
+    /// Leave the process in the `Fault` state. It will not run again unless
+    /// it is restarted through the process management interface.
+    Stop,
+}
+
+/// Decides how the kernel responds when a process faults.
+///
+/// Boards pass a policy to `load_processes()`. A `FaultResponse` is itself a
+/// policy that responds the same way to every fault. A fault policy in an
+/// app's TBF header can only make the board's policy stricter: it can stop a
+/// process the board would restart, but it cannot make the kernel panic, or
+/// restart a process more times than the board's policy does.
+pub trait ProcessFaultPolicy {
+    /// Called after `process` faulted. The process is already in the `Fault`
+    /// state, and the kernel carries out the returned response. A policy that
+    /// delays restarts can return `FaultResponse::Stop` and restart the
+    /// process later with `Kernel::restart_faulted_app()`.
+    fn action(&self, process: &ProcessType) -> FaultResponse;
+
+    /// Called instead of `action()` when the TBF header of `process` asks for
+    /// it not to be restarted after this fault. Returns `FaultResponse::Panic`
+    /// if the board's policy panics on this fault, and otherwise
+    /// `FaultResponse::Stop`; it must not arrange for the process to be
+    /// restarted. By default this is `FaultResponse::Stop` unless `action()`
+    /// would panic, so policies whose `action()` has side effects should
+    /// override it.
+    fn stop_action(&self, process: &ProcessType) -> FaultResponse {
+        match self.action(process) {
+            FaultResponse::Panic => FaultResponse::Panic,
+            _ => FaultResponse::Stop,
+        }
+    }
+}
+
+impl ProcessFaultPolicy for FaultResponse {
+    fn action(&self, _process: &ProcessType) -> FaultResponse {
+        *self
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -237,6 +620,15 @@ pub enum Task {
     IPC((AppId, IPCType)),
 }
 
+/// Where a `FunctionCall` came from.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FunctionCallSource {
+    /// The kernel, for example to start the process.
+    Kernel,
+    /// A callback the process subscribed to.
+    Driver(CallbackId),
+}
+
 /// Struct that defines a callback that can be passed to a process. The callback
 /// takes four arguments that are `Driver` and callback specific, so they are
 /// represented generically here.
@@ -245,6 +637,7 @@ pub enum Task {
 /// values, but this is architecture-dependent.
 #[derive(Copy, Clone, Debug)]
 pub struct FunctionCall {
+    pub source: FunctionCallSource,
     pub argument0: usize,
     pub argument1: usize,
     pub argument2: usize,
@@ -252,6 +645,53 @@ pub struct FunctionCall {
     pub pc: usize,
 }
 
+/// How many `Task`s the kernel queues for each process, set by the board with
+/// `Kernel::set_task_queue_config()`.
+///
+/// Apps can ask for a different depth, and for coalescing, with the Task Queue
+/// TLV in their TBF header. Each queued task takes RAM from the process's
+/// memory.
+#[derive(Copy, Clone, Debug)]
+pub struct TaskQueueConfig {
+    /// Depth for apps that do not ask for one.
+    pub default_depth: usize,
+    /// Largest depth an app can ask for.
+    pub max_depth: usize,
+    /// Whether upcalls are coalesced for apps that do not ask for it. When
+    /// the queue is full, an upcall with the same driver and subscribe number
+    /// as a queued one replaces its arguments rather than being dropped.
+    pub coalesce: bool,
+}
+
+impl Default for TaskQueueConfig {
+    fn default() -> TaskQueueConfig {
+        TaskQueueConfig {
+            default_depth: 9,
+            max_depth: 64,
+            coalesce: false,
+        }
+    }
+}
+
+/// How much of a process's grant region is in use, as returned by
+/// `ProcessType::grant_usage()`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct GrantUsage {
+    /// Bytes the grant region has grown by since the process started, not
+    /// counting the kernel's own state for the process.
+    pub allocated: usize,
+    /// Bytes of `allocated` that were freed and can be reused.
+    pub free: usize,
+    /// How many blocks the free memory is split into. Many small blocks mean
+    /// the grant region is fragmented.
+    pub free_blocks: usize,
+}
+
+/// How many different drivers the per-process syscall histogram tracks.
+/// Syscalls to any further drivers are only counted in
+/// `ProcessDebug::other_driver_syscall_count`.
+const SYSCALL_HISTOGRAM_SIZE: usize = 8;
+
 /// State for helping with debugging apps.
 ///
 /// These pointers and counters are not strictly required for kernel operation,
@@ -281,6 +721,53 @@ struct ProcessDebug {
     /// How many times this process has entered into a fault condition and the
     /// kernel has restarted it.
     restart_count: usize,
+
+    /// How many times this process has been pre-empted because it used its
+    /// entire timeslice.
+    timeslice_expiration_count: usize,
+
+    /// Total time the process has spent executing, not counting its system
+    /// calls, in microseconds.
+    cpu_time_us: u64,
+
+    /// The longest the process has executed before returning control to the
+    /// kernel, in microseconds.
+    longest_run_us: u32,
+
+    /// Number of `subscribe`, `command`, and `allow` syscalls per driver, as
+    /// (driver number, count) pairs. Entries with a count of zero are unused.
+    driver_syscall_counts: [(usize, usize); SYSCALL_HISTOGRAM_SIZE],
+
+    /// Syscalls to drivers that did not fit in `driver_syscall_counts`.
+    other_driver_syscall_count: usize,
+}
+
+impl ProcessDebug {
+    /// Reset the counters that describe the current run of the process.
+    fn reset_counters(&mut self) {
+        self.syscall_count = 0;
+        self.last_syscall = None;
+        self.dropped_callback_count = 0;
+        self.timeslice_expiration_count = 0;
+        self.cpu_time_us = 0;
+        self.longest_run_us = 0;
+        self.driver_syscall_counts = [(0, 0); SYSCALL_HISTOGRAM_SIZE];
+        self.other_driver_syscall_count = 0;
+    }
+
+    /// Count a syscall to `driver_number` in the histogram.
+    fn record_driver_syscall(&mut self, driver_number: usize) {
+        for entry in self.driver_syscall_counts.iter_mut() {
+            if entry.1 == 0 {
+                *entry = (driver_number, 1);
+                return;
+            } else if entry.0 == driver_number {
+                entry.1 += 1;
+                return;
+            }
+        }
+        self.other_driver_syscall_count += 1;
+    }
 }
 
 pub struct Process<'a, S: 'static + UserspaceKernelBoundary> {
@@ -320,6 +807,16 @@ pub struct Process<'a, S: 'static + UserspaceKernelBoundary> {
     /// Pointer to the end of the allocated (and MPU protected) grant region.
     kernel_memory_break: Cell<*const u8>,
 
+    /// Tracks memory freed in the grant region so it can be reused.
+    grant_allocator: GrantAllocator,
+
+    /// Bytes of the grant region used by each grant. Stored in the process's
+    /// memory next to the grant pointers.
+    grant_usage: MapCell<&'a mut [usize]>,
+
+    /// Incremented each time the grant region is freed.
+    grant_generation: Cell<usize>,
+
     /// Copy of where the kernel memory break is when the app is first started.
     /// This is handy if the app is restarted so we know where to reset
     /// the kernel_memory break to without having to recalculate it.
@@ -347,8 +844,11 @@ pub struct Process<'a, S: 'static + UserspaceKernelBoundary> {
     /// Whether the scheduler can schedule this app.
     state: Cell<State>,
 
+    /// The identifier of this process, i.e. its slot in the processes array.
+    app_id: AppId,
+
     /// How to deal with Faults occurring in the process
-    fault_response: FaultResponse,
+    fault_policy: &'static ProcessFaultPolicy,
 
     /// MPU regions are saved as a pointer-size pair.
     ///
@@ -363,10 +863,28 @@ pub struct Process<'a, S: 'static + UserspaceKernelBoundary> {
     /// 32-byte aligned.
     mpu_regions: [Cell<(*const u8, math::PowerOfTwo)>; 5],
 
+    /// MPU configuration for the current layout of the process's memory, or
+    /// `None` if it has to be computed again before the process next runs.
+    mpu_config: Cell<Option<mpu::MpuConfig>>,
+
     /// Essentially a list of callbacks that want to call functions in the
-    /// process.
+    /// process. It has room for `task_queue_depth` tasks plus the dropped
+    /// task notification.
     tasks: MapCell<RingBuffer<'a, Task>>,
 
+    /// How many tasks can be queued before they are dropped.
+    task_queue_depth: usize,
+
+    /// Whether a full queue coalesces upcalls from the same driver and
+    /// subscribe number.
+    coalesce_tasks: bool,
+
+    /// Callback notifying the process that tasks were dropped.
+    dropped_task_callback: Cell<Option<Callback>>,
+
+    /// Whether the dropped task notification is in the queue.
+    dropped_task_notified: Cell<bool>,
+
     /// Name of the app.
     process_name: &'static str,
 
@@ -376,31 +894,72 @@ pub struct Process<'a, S: 'static + UserspaceKernelBoundary> {
 
 impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
     fn enqueue_task(&self, task: Task) -> bool {
-        // If this app is in the `Fault` state then we shouldn't schedule
-        // any work for it.
-        if self.state.get() == State::Fault {
+        // If this app is in the `Fault` or `Terminated` state then we
+        // shouldn't schedule any work for it.
+        if self.state.get() == State::Fault || self.state.get() == State::Terminated {
             return false;
         }
 
-        self.kernel.increment_work();
-
-        let ret = self.tasks.map_or(false, |tasks| tasks.enqueue(task));
+        let mut coalesced = false;
+        let ret = self.tasks.map_or(false, |tasks| {
+            if tasks.len() < self.task_queue_depth {
+                tasks.enqueue(task)
+            } else {
+                coalesced = self.coalesce_tasks && coalesce_task(tasks, task);
+                coalesced
+            }
+        });
 
-        // Make a note that we lost this callback if the enqueue function
-        // fails.
-        if ret == false {
-            self.debug.map(|debug| {
+        if ret {
+            // A coalesced task took the place of one that is already counted.
+            if !coalesced {
+                self.add_task_work();
+            }
+        } else {
+            // Make a note that we lost this callback if the enqueue function
+            // fails, and tell the process if it asked.
+            let dropped_count = self.debug.map_or(0, |debug| {
                 debug.dropped_callback_count += 1;
+                debug.dropped_callback_count
             });
+            self.notify_dropped_task(task, dropped_count);
         }
 
         ret
     }
 
+    fn set_dropped_task_callback(&self, callback: Option<Callback>) {
+        self.dropped_task_callback.set(callback);
+    }
+
+    fn task_queue_depth(&self) -> usize {
+        self.task_queue_depth
+    }
+
+    fn appid(&self) -> AppId {
+        self.app_id
+    }
+
     fn get_state(&self) -> State {
         self.state.get()
     }
 
+    fn ready(&self) -> bool {
+        match self.state.get() {
+            State::Running => true,
+            State::Yielded => self.tasks.map_or(false, |tasks| tasks.has_elements()),
+            _ => false,
+        }
+    }
+
+    fn get_priority(&self) -> Option<u32> {
+        self.header.get_priority()
+    }
+
+    fn get_app_version(&self) -> Option<u32> {
+        self.header.get_app_version()
+    }
+
     fn set_yielded_state(&self) {
         if self.state.get() == State::Running {
             self.state.set(State::Yielded);
@@ -409,80 +968,157 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
     }
 
     fn set_fault_state(&self) {
-        self.state.set(State::Fault);
+        self.set_state(State::Fault);
+
+        // The TBF header comes from the app, so it can only make the board's
+        // policy stricter. It can stop the process instead of restarting it,
+        // or stop it after fewer restarts, but a header asking for a panic
+        // only stops the process, and a header asking for restarts is still
+        // held to the board's limit by `action()`.
+        let header_stops = match self.header.get_fault_response() {
+            None => false,
+            Some(FaultResponse::Restart) => self
+                .header
+                .get_max_restarts()
+                .map_or(false, |max_restarts| self.debug_restart_count() >= max_restarts),
+            Some(FaultResponse::Stop) | Some(FaultResponse::Panic) => true,
+        };
+        let response = if header_stops {
+            match self.fault_policy.stop_action(self) {
+                FaultResponse::Panic => FaultResponse::Panic,
+                _ => FaultResponse::Stop,
+            }
+        } else {
+            self.fault_policy.action(self)
+        };
 
-        match self.fault_response {
+        match response {
             FaultResponse::Panic => {
                 // process faulted. Panic and print status
                 panic!("Process {} had a fault", self.process_name);
             }
             FaultResponse::Restart => {
-                // Remove the tasks that were scheduled for the app from the
-                // amount of work queue.
-                let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
-                for _ in 0..tasks_len {
-                    self.kernel.decrement_work();
-                }
+                self.restart_after_fault();
+            }
+            FaultResponse::Stop => {
+                // The process stays in the `Fault` state.
+            }
+        }
+    }
 
-                // And remove those tasks
-                self.tasks.map(|tasks| {
-                    tasks.empty();
-                });
+    fn restart_after_fault(&self) -> ReturnCode {
+        if self.state.get() != State::Fault {
+            return ReturnCode::EINVAL;
+        }
+        self.debug.map(|debug| {
+            debug.restart_count += 1;
+        });
+        self.restart();
+        ReturnCode::SUCCESS
+    }
 
-                // Update debug information
-                self.debug.map(|debug| {
-                    // Mark that we restarted this process.
-                    debug.restart_count += 1;
+    fn stop(&self) -> ReturnCode {
+        match self.state.get() {
+            State::Running => {
+                self.set_state(State::StoppedRunning);
+                ReturnCode::SUCCESS
+            }
+            State::Yielded => {
+                self.set_state(State::StoppedYielded);
+                ReturnCode::SUCCESS
+            }
+            State::StoppedRunning | State::StoppedYielded => ReturnCode::EALREADY,
+            State::Fault | State::Terminated => ReturnCode::EINVAL,
+        }
+    }
 
-                    // Reset some state for the process.
-                    debug.syscall_count = 0;
-                    debug.last_syscall = None;
-                    debug.dropped_callback_count = 0;
-                });
+    fn resume(&self) -> ReturnCode {
+        match self.state.get() {
+            State::StoppedRunning => {
+                self.set_state(State::Running);
+                ReturnCode::SUCCESS
+            }
+            State::StoppedYielded => {
+                self.set_state(State::Yielded);
+                ReturnCode::SUCCESS
+            }
+            State::Running | State::Yielded => ReturnCode::EALREADY,
+            State::Fault | State::Terminated => ReturnCode::EINVAL,
+        }
+    }
 
-                // We are going to start this process over again, so need
-                // the init_fn location.
-                let app_flash_address = self.flash_start();
-                let init_fn = unsafe {
-                    app_flash_address.offset(self.header.get_init_function_offset() as isize)
-                        as usize
-                };
-                self.state.set(State::Yielded);
+    fn restart(&self) {
+        // Drop everything the process was doing. This also removes its
+        // outstanding work from the kernel.
+        self.terminate();
 
-                // Need to reset the grant region.
-                unsafe {
-                    self.grant_ptrs_reset();
-                }
-                self.kernel_memory_break
-                    .set(self.original_kernel_memory_break);
-
-                // Reset other memory pointers.
-                self.app_break.set(self.original_app_break);
-                self.current_stack_pointer.set(self.original_stack_pointer);
-
-                // And queue up this app to be restarted.
-                let flash_protected_size = self.header.get_protected_size() as usize;
-                let flash_app_start = app_flash_address as usize + flash_protected_size;
-
-                self.tasks.map(|tasks| {
-                    tasks.enqueue(Task::FunctionCall(FunctionCall {
-                        pc: init_fn,
-                        argument0: flash_app_start,
-                        argument1: self.memory.as_ptr() as usize,
-                        argument2: self.memory.len() as usize,
-                        argument3: self.app_break.get() as usize,
-                    }));
-                });
+        // Reset the counters that describe the previous run.
+        self.debug.map(|debug| {
+            debug.reset_counters();
+        });
 
-                self.kernel.increment_work();
-            }
+        // We are going to start this process over again, so need the init_fn
+        // location.
+        let app_flash_address = self.flash_start();
+        let init_fn = unsafe {
+            app_flash_address.offset(self.header.get_init_function_offset() as isize) as usize
+        };
+        self.set_state(State::Yielded);
+
+        // Reset other memory pointers.
+        self.app_break.set(self.original_app_break);
+        self.current_stack_pointer.set(self.original_stack_pointer);
+
+        // And queue up this app to be restarted.
+        let flash_protected_size = self.header.get_protected_size() as usize;
+        let flash_app_start = app_flash_address as usize + flash_protected_size;
+
+        self.enqueue_task(Task::FunctionCall(FunctionCall {
+            source: FunctionCallSource::Kernel,
+            pc: init_fn,
+            argument0: flash_app_start,
+            argument1: self.memory.as_ptr() as usize,
+            argument2: self.memory.len() as usize,
+            argument3: self.app_break.get() as usize,
+        }));
+    }
+
+    fn terminate(&self) {
+        // Changing the state first removes any work this process had
+        // outstanding with the kernel.
+        self.set_state(State::Terminated);
+
+        // Remove the tasks that were scheduled for the app. Like its other
+        // callbacks, the app has to subscribe to dropped tasks again.
+        self.tasks.map(|tasks| {
+            tasks.empty();
+        });
+        self.dropped_task_callback.set(None);
+        self.dropped_task_notified.set(false);
+
+        // Free the grant region.
+        unsafe {
+            self.grant_ptrs_reset();
         }
+        self.kernel_memory_break
+            .set(self.original_kernel_memory_break);
+        self.grant_allocator.reset();
+        self.grant_usage.map(|grant_usage| {
+            for used in grant_usage.iter_mut() {
+                *used = 0;
+            }
+        });
+        self.grant_generation.set(self.grant_generation.get() + 1);
+        self.mpu_config.set(None);
     }
 
     fn dequeue_task(&self) -> Option<Task> {
         self.tasks.map_or(None, |tasks| {
             tasks.dequeue().map(|cb| {
                 self.kernel.decrement_work();
+                if self.is_dropped_task_notification(&cb) {
+                    self.dropped_task_notified.set(false);
+                }
                 cb
             })
         })
@@ -541,92 +1177,40 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
     }
 
     fn setup_mpu(&self, mpu: &mpu::MPU) {
-        // Flash segment read/execute (no write)
-        let flash_start = self.flash.as_ptr() as usize;
-        let flash_len = self.flash.len();
-
-        match mpu.create_region(
-            0,
-            flash_start,
-            flash_len,
-            mpu::ExecutePermission::ExecutionPermitted,
-            mpu::AccessPermission::ReadOnly,
-        ) {
-            None => panic!(
-                "Infeasible MPU allocation. Base {:#x}, Length: {:#x}",
-                flash_start, flash_len
-            ),
-            Some(region) => mpu.set_mpu(region),
-        }
-
-        let data_start = self.memory.as_ptr() as usize;
-        let data_len = self.memory.len();
-
-        match mpu.create_region(
-            1,
-            data_start,
-            data_len,
-            mpu::ExecutePermission::ExecutionPermitted,
-            mpu::AccessPermission::ReadWrite,
-        ) {
-            None => panic!(
-                "Infeasible MPU allocation. Base {:#x}, Length: {:#x}",
-                data_start, data_len
-            ),
-            Some(region) => mpu.set_mpu(region),
-        }
-
-        // Disallow access to grant region
-        let grant_len = unsafe {
-            math::PowerOfTwo::ceiling(
-                self.memory.as_ptr().offset(self.memory.len() as isize) as u32
-                    - (self.kernel_memory_break.get() as u32),
-            ).as_num::<u32>()
-        };
-        let grant_base = unsafe {
-            self.memory
-                .as_ptr()
-                .offset(self.memory.len() as isize)
-                .offset(-(grant_len as isize))
-        };
-
-        match mpu.create_region(
-            2,
-            grant_base as usize,
-            grant_len as usize,
-            mpu::ExecutePermission::ExecutionNotPermitted,
-            mpu::AccessPermission::PrivilegedOnly,
-        ) {
-            None => panic!(
-                "Infeasible MPU allocation. Base {:#x}, Length: {:#x}",
-                grant_base as usize, grant_len
-            ),
-            Some(region) => mpu.set_mpu(region),
-        }
+        let config = match self.mpu_config.get() {
+            Some(config) => config,
+            None => {
+                // Only pass the IPC regions that are in use.
+                let mut ipc_regions = [(0, 0); 5];
+                let mut num_ipc_regions = 0;
+                for region in self.mpu_regions.iter() {
+                    let (base, size) = region.get();
+                    if !base.is_null() {
+                        ipc_regions[num_ipc_regions] =
+                            (base as usize, size.as_num::<u32>() as usize);
+                        num_ipc_regions += 1;
+                    }
+                }
 
-        // Setup IPC MPU regions
-        for (i, region) in self.mpu_regions.iter().enumerate() {
-            if region.get().0.is_null() {
-                mpu.set_mpu(mpu::Region::empty(i + 3));
-                continue;
-            }
-            match mpu.create_region(
-                i + 3,
-                region.get().0 as usize,
-                region.get().1.as_num::<u32>() as usize,
-                mpu::ExecutePermission::ExecutionPermitted,
-                mpu::AccessPermission::ReadWrite,
-            ) {
-                None => panic!(
-                    "Unexpected: Infeasible MPU allocation: Num: {}, \
-                     Base: {:#x}, Length: {:#x}",
-                    i + 3,
-                    region.get().0 as usize,
-                    region.get().1.as_num::<u32>()
-                ),
-                Some(region) => mpu.set_mpu(region),
+                let layout = mpu::ProcessMemoryLayout {
+                    flash_start: self.flash.as_ptr() as usize,
+                    flash_size: self.flash.len(),
+                    ram_start: self.memory.as_ptr() as usize,
+                    ram_size: self.memory.len(),
+                    app_break: self.app_break.get() as usize,
+                    kernel_memory_break: self.kernel_memory_break.get() as usize,
+                    ipc_regions: &ipc_regions[..num_ipc_regions],
+                };
+                match mpu.process_config(&layout) {
+                    None => panic!("Infeasible MPU configuration: {:#x?}", layout),
+                    Some(config) => {
+                        self.mpu_config.set(Some(config));
+                        config
+                    }
+                }
             }
-        }
+        };
+        mpu.configure(&config);
     }
 
     fn add_mpu_region(&self, base: *const u8, size: u32) -> bool {
@@ -635,10 +1219,12 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
             for region in self.mpu_regions.iter() {
                 if region.get().0 == ptr::null() {
                     region.set((base, mpu_size));
+                    self.mpu_config.set(None);
                     return true;
                 } else if region.get().0 == base {
                     if region.get().1 < mpu_size {
                         region.set((base, mpu_size));
+                        self.mpu_config.set(None);
                     }
                     return true;
                 }
@@ -660,6 +1246,7 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
         } else {
             let old_break = self.app_break.get();
             self.app_break.set(new_break);
+            self.mpu_config.set(None);
             Ok(old_break)
         }
     }
@@ -677,27 +1264,80 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
             && buf_end_addr <= self.mem_break()
     }
 
-    unsafe fn alloc(&self, size: usize) -> Option<&mut [u8]> {
-        let new_break = self.kernel_memory_break.get().offset(-(size as isize));
-        if new_break < self.app_break.get() {
-            None
-        } else {
-            self.kernel_memory_break.set(new_break);
-            Some(slice::from_raw_parts_mut(new_break as *mut u8, size))
+    unsafe fn alloc(&self, size: usize, grant_num: usize) -> Option<&mut [u8]> {
+        // The kernel memory break may move.
+        self.mpu_config.set(None);
+        self.grant_allocator
+            .alloc(size, &self.kernel_memory_break, self.app_break.get())
+            .map(|ptr| {
+                self.grant_usage.map(|grant_usage| {
+                    if let Some(used) = grant_usage.get_mut(grant_num) {
+                        *used += grant::grant_alloc_size(size);
+                    }
+                });
+                slice::from_raw_parts_mut(ptr, size)
+            })
+    }
+
+    unsafe fn free(&self, ptr: *mut u8, size: usize, grant_num: usize) {
+        // Ignore memory that is not in the grant region.
+        let end = ptr.wrapping_offset(grant::grant_alloc_size(size) as isize);
+        if (ptr as *const u8) < self.kernel_memory_break.get()
+            || end as *const u8 > self.original_kernel_memory_break
+        {
+            return;
+        }
+
+        self.grant_allocator
+            .free(ptr, size, &self.kernel_memory_break);
+        self.mpu_config.set(None);
+        self.grant_usage.map(|grant_usage| {
+            if let Some(used) = grant_usage.get_mut(grant_num) {
+                *used = used.saturating_sub(grant::grant_alloc_size(size));
+            }
+        });
+    }
+
+    fn grant_generation(&self) -> usize {
+        self.grant_generation.get()
+    }
+
+    fn grant_usage(&self) -> GrantUsage {
+        GrantUsage {
+            allocated: self.original_kernel_memory_break as usize
+                - self.kernel_memory_break.get() as usize,
+            free: self.grant_allocator.free_bytes(),
+            free_blocks: self.grant_allocator.free_blocks(),
         }
     }
 
-    unsafe fn free(&self, _: *mut u8) {}
+    fn grant_memory_used(&self, grant_num: usize) -> usize {
+        self.grant_usage.map_or(0, |grant_usage| {
+            grant_usage.get(grant_num).map_or(0, |used| *used)
+        })
+    }
 
     unsafe fn grant_ptr(&self, grant_num: usize) -> *mut *mut u8 {
         let grant_num = grant_num as isize;
         (self.mem_end() as *mut *mut u8).offset(-(grant_num + 1))
     }
 
-    fn get_process_name(&self) -> &[u8] {
+    fn get_process_name(&self) -> &'static [u8] {
         self.process_name.as_bytes()
     }
 
+    fn get_storage_size(&self) -> Option<usize> {
+        self.header.get_storage_size()
+    }
+
+    fn check_driver_permission(
+        &self,
+        driver_num: usize,
+        command_num: Option<usize>,
+    ) -> ReturnCode {
+        self.header.check_driver_permission(driver_num, command_num)
+    }
+
     unsafe fn get_syscall(&self) -> Option<Syscall> {
         let last_syscall = self.syscall.get_syscall(self.sp());
 
@@ -705,6 +1345,15 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
         self.debug.map(|debug| {
             debug.syscall_count += 1;
             debug.last_syscall = last_syscall;
+
+            match last_syscall {
+                Some(Syscall::SUBSCRIBE { driver_number, .. })
+                | Some(Syscall::COMMAND { driver_number, .. })
+                | Some(Syscall::ALLOW { driver_number, .. }) => {
+                    debug.record_driver_syscall(driver_number);
+                }
+                _ => {}
+            }
         });
 
         last_syscall
@@ -818,7 +1467,8 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
         let sram_start = self.memory.as_ptr() as usize;
 
         // SRAM sizes
-        let sram_grant_size = sram_end - sram_grant_start;
+        let grant_usage = self.grant_usage();
+        let sram_grant_size = sram_end - sram_grant_start - grant_usage.free;
         let sram_heap_size = sram_heap_end - sram_heap_start;
         let sram_data_size = sram_heap_start - sram_stack_start;
         let sram_stack_size = sram_stack_start - sram_stack_bottom;
@@ -847,20 +1497,45 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
         let last_syscall = self.debug.map(|debug| debug.last_syscall);
         let dropped_callback_count = self.debug.map_or(0, |debug| debug.dropped_callback_count);
         let restart_count = self.debug.map_or(0, |debug| debug.restart_count);
+        let timeslice_expiration_count =
+            self.debug.map_or(0, |debug| debug.timeslice_expiration_count);
+        let cpu_time_us = self.debug.map_or(0, |debug| debug.cpu_time_us);
+        let longest_run_us = self.debug.map_or(0, |debug| debug.longest_run_us);
 
         let _ = writer.write_fmt(format_args!(
             "\
              App: {}   -   [{:?}]\
-             \r\n Events Queued: {}   Syscall Count: {}   Dropped Callback Count: {}\
-             \n Restart Count: {}\n",
+             \r\n Events Queued: {}/{}   Syscall Count: {}   Dropped Callback Count: {}\
+             \r\n Restart Count: {}\
+             \r\n CPU Time (us): {}   Longest Run (us): {}   Timeslice Expirations: {}\r\n",
             self.process_name,
             self.state.get(),
             events_queued,
+            self.task_queue_depth,
             syscall_count,
             dropped_callback_count,
             restart_count,
+            cpu_time_us,
+            longest_run_us,
+            timeslice_expiration_count,
         ));
 
+        if let Some(version) = self.header.get_app_version() {
+            let _ = writer.write_fmt(format_args!(" App Version: {}\r\n", version));
+        }
+
+        let _ = writer.write_fmt(format_args!(
+            " Grant Memory: {} allocated, {} free in {} blocks\r\n",
+            grant_usage.allocated, grant_usage.free, grant_usage.free_blocks,
+        ));
+        self.grant_usage.map(|grant_usage| {
+            for (grant_num, used) in grant_usage.iter().enumerate() {
+                if *used > 0 {
+                    let _ = writer.write_fmt(format_args!("  Grant {}: {}\r\n", grant_num, used));
+                }
+            }
+        });
+
         let _ = match last_syscall {
             Some(syscall) => writer.write_fmt(format_args!(" Last Syscall: {:?}", syscall)),
             None => writer.write_fmt(format_args!(" Last Syscall: None")),
@@ -917,6 +1592,66 @@ impl<S: UserspaceKernelBoundary> ProcessType for Process<'a, S> {
             sram_start, flash_init_fn
         ));
     }
+
+    fn debug_process_ran(&self, execution_time_us: u32) {
+        self.debug.map(|debug| {
+            debug.cpu_time_us += execution_time_us as u64;
+            if execution_time_us > debug.longest_run_us {
+                debug.longest_run_us = execution_time_us;
+            }
+        });
+    }
+
+    fn debug_timeslice_expired(&self) {
+        self.debug.map(|debug| {
+            debug.timeslice_expiration_count += 1;
+        });
+    }
+
+    fn debug_syscall_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.syscall_count)
+    }
+
+    fn debug_dropped_callback_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.dropped_callback_count)
+    }
+
+    fn debug_restart_count(&self) -> usize {
+        self.debug.map_or(0, |debug| debug.restart_count)
+    }
+
+    fn debug_timeslice_expiration_count(&self) -> usize {
+        self.debug
+            .map_or(0, |debug| debug.timeslice_expiration_count)
+    }
+
+    fn debug_cpu_time_us(&self) -> u64 {
+        self.debug.map_or(0, |debug| debug.cpu_time_us)
+    }
+
+    fn debug_longest_run_us(&self) -> u32 {
+        self.debug.map_or(0, |debug| debug.longest_run_us)
+    }
+
+    fn debug_driver_syscall_count(&self, index: usize) -> Option<(usize, usize)> {
+        self.debug.map_or(None, |debug| {
+            debug
+                .driver_syscall_counts
+                .get(index)
+                .and_then(|&(driver_number, count)| {
+                    if count == 0 {
+                        None
+                    } else {
+                        Some((driver_number, count))
+                    }
+                })
+        })
+    }
+
+    fn debug_untracked_driver_syscall_count(&self) -> usize {
+        self.debug
+            .map_or(0, |debug| debug.other_driver_syscall_count)
+    }
 }
 
 impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
@@ -926,15 +1661,51 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
         app_flash_address: *const u8,
         remaining_app_memory: *mut u8,
         remaining_app_memory_size: usize,
-        fault_response: FaultResponse,
-    ) -> (Option<&'static ProcessType>, usize, usize) {
+        fault_policy: &'static ProcessFaultPolicy,
+        index: usize,
+    ) -> Result<(Option<&'static ProcessType>, usize, usize), ProcessLoadError> {
         if let Some(tbf_header) = tbfheader::parse_and_validate_tbf_header(app_flash_address) {
             let app_flash_size = tbf_header.get_total_size() as usize;
 
             // If this isn't an app (i.e. it is padding) or it is an app but it
             // isn't enabled, then we can skip it but increment past its flash.
             if !tbf_header.is_app() || !tbf_header.enabled() {
-                return (None, app_flash_size, 0);
+                return Ok((None, app_flash_size, 0));
+            }
+
+            // Only load apps whose credentials check out.
+            let app_flash = slice::from_raw_parts(app_flash_address, app_flash_size);
+            if !kernel.app_credentials_valid(&tbf_header, app_flash) {
+                return Err(ProcessLoadError::CredentialsRejected);
+            }
+
+            // Only load apps that work with this kernel.
+            if let Some((major, minor)) = tbf_header.get_kernel_version() {
+                if major != ::KERNEL_MAJOR_VERSION || minor > ::KERNEL_MINOR_VERSION {
+                    return Err(ProcessLoadError::IncompatibleKernelVersion {
+                        major: major,
+                        minor: minor,
+                    });
+                }
+            }
+
+            // Apps that are not position independent can only run where they
+            // were linked to.
+            if let Some(flash_address) = tbf_header.get_fixed_address_flash() {
+                if flash_address as usize != app_flash_address as usize {
+                    return Err(ProcessLoadError::IncorrectFlashAddress {
+                        actual: app_flash_address as usize,
+                        expected: flash_address as usize,
+                    });
+                }
+            }
+            if let Some(ram_address) = tbf_header.get_fixed_address_ram() {
+                if ram_address as usize != remaining_app_memory as usize {
+                    return Err(ProcessLoadError::IncorrectRamAddress {
+                        actual: remaining_app_memory as usize,
+                        expected: ram_address as usize,
+                    });
+                }
             }
 
             // Otherwise, actually load the app.
@@ -943,6 +1714,10 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
             let init_fn =
                 app_flash_address.offset(tbf_header.get_init_function_offset() as isize) as usize;
 
+            if (init_fn & 0x1) != 1 {
+                return Err(ProcessLoadError::InvalidInitFunction { address: init_fn });
+            }
+
             // Set the initial process stack and memory to 128 bytes.
             let initial_stack_pointer = remaining_app_memory.offset(128);
             let initial_sbrk_pointer = remaining_app_memory.offset(128);
@@ -956,9 +1731,22 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
             let grant_ptrs_num = kernel.get_grant_count_and_finalize();
             let grant_ptrs_offset = grant_ptrs_num * grant_ptr_size;
 
-            // Allocate memory for callback ring buffer.
+            // Make room for how much memory each grant uses.
+            let grant_usage_offset = grant_ptrs_num * mem::size_of::<usize>();
+
+            // Allocate memory for callback ring buffer. Besides the queued
+            // tasks it needs a slot for the dropped task notification, and the
+            // ring buffer always leaves one slot empty.
+            let task_queue_config = kernel.task_queue_config();
+            let task_queue_depth = match tbf_header.get_task_queue_depth() {
+                Some(depth) if depth > 0 => cmp::min(depth, task_queue_config.max_depth),
+                _ => task_queue_config.default_depth,
+            };
+            let coalesce_tasks = tbf_header
+                .get_task_queue_coalesce()
+                .unwrap_or(task_queue_config.coalesce);
             let callback_size = mem::size_of::<Task>();
-            let callback_len = 10;
+            let callback_len = task_queue_depth + 2;
             let callbacks_offset = callback_len * callback_size;
 
             // Make room to store this process's metadata.
@@ -966,11 +1754,10 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
 
             // Need to make sure that the amount of memory we allocate for
             // this process at least covers this state.
-            if min_app_ram_size
-                < (grant_ptrs_offset + callbacks_offset + process_struct_offset) as u32
-            {
-                min_app_ram_size =
-                    (grant_ptrs_offset + callbacks_offset + process_struct_offset) as u32;
+            let kernel_state_size =
+                grant_ptrs_offset + grant_usage_offset + callbacks_offset + process_struct_offset;
+            if min_app_ram_size < kernel_state_size as u32 {
+                min_app_ram_size = kernel_state_size as u32;
             }
 
             // TODO round app_ram_size up to a closer MPU unit.
@@ -980,10 +1767,10 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
 
             // Check that we can actually give this app this much memory.
             if app_ram_size > remaining_app_memory_size {
-                panic!(
-                    "{:?} failed to load. Insufficient memory. Requested {} have {}",
-                    process_name, app_ram_size, remaining_app_memory_size
-                );
+                return Err(ProcessLoadError::NotEnoughMemory {
+                    requested: app_ram_size,
+                    available: remaining_app_memory_size,
+                });
             }
 
             let app_memory = slice::from_raw_parts_mut(remaining_app_memory, app_ram_size);
@@ -1002,6 +1789,14 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
                 *opt = ptr::null()
             }
 
+            // Then the grant usage counters, which start at zero.
+            kernel_memory_break = kernel_memory_break.offset(-(grant_usage_offset as isize));
+            let grant_usage =
+                slice::from_raw_parts_mut(kernel_memory_break as *mut usize, grant_ptrs_num);
+            for used in grant_usage.iter_mut() {
+                *used = 0;
+            }
+
             // Now that we know we have the space we can setup the memory
             // for the callbacks.
             kernel_memory_break = kernel_memory_break.offset(-(callbacks_offset as isize));
@@ -1031,6 +1826,9 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
             process.header = tbf_header;
             process.kernel_memory_break = Cell::new(kernel_memory_break);
             process.original_kernel_memory_break = kernel_memory_break;
+            process.grant_allocator = GrantAllocator::new();
+            process.grant_usage = MapCell::new(grant_usage);
+            process.grant_generation = Cell::new(0);
             process.app_break = Cell::new(initial_sbrk_pointer);
             process.original_app_break = initial_sbrk_pointer;
             process.current_stack_pointer = Cell::new(initial_stack_pointer);
@@ -1040,7 +1838,8 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
 
             process.stored_state = Cell::new(Default::default());
             process.state = Cell::new(State::Yielded);
-            process.fault_response = fault_response;
+            process.app_id = AppId::new(kernel, index);
+            process.fault_policy = fault_policy;
 
             process.mpu_regions = [
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
@@ -1049,7 +1848,12 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
                 Cell::new((ptr::null(), math::PowerOfTwo::zero())),
             ];
+            process.mpu_config = Cell::new(None);
             process.tasks = MapCell::new(tasks);
+            process.task_queue_depth = task_queue_depth;
+            process.coalesce_tasks = coalesce_tasks;
+            process.dropped_task_callback = Cell::new(None);
+            process.dropped_task_notified = Cell::new(false);
             process.process_name = process_name;
 
             process.debug = MapCell::new(ProcessDebug {
@@ -1060,21 +1864,19 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
                 last_syscall: None,
                 dropped_callback_count: 0,
                 restart_count: 0,
+                timeslice_expiration_count: 0,
+                cpu_time_us: 0,
+                longest_run_us: 0,
+                driver_syscall_counts: [(0, 0); SYSCALL_HISTOGRAM_SIZE],
+                other_driver_syscall_count: 0,
             });
 
-            if (init_fn & 0x1) != 1 {
-                panic!(
-                    "{:?} process image invalid. \
-                     init_fn address must end in 1 to be Thumb, got {:#X}",
-                    process_name, init_fn
-                );
-            }
-
             let flash_protected_size = process.header.get_protected_size() as usize;
             let flash_app_start = app_flash_address as usize + flash_protected_size;
 
             process.tasks.map(|tasks| {
                 tasks.enqueue(Task::FunctionCall(FunctionCall {
+                    source: FunctionCallSource::Kernel,
                     pc: init_fn,
                     argument0: flash_app_start,
                     argument1: process.memory.as_ptr() as usize,
@@ -1085,15 +1887,87 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
 
             kernel.increment_work();
 
-            return (Some(process), app_flash_size, app_ram_size);
+            return Ok((Some(process), app_flash_size, app_ram_size));
         }
-        (None, 0, 0)
+        Ok((None, 0, 0))
     }
 
     fn mem_break(&self) -> *const u8 {
         self.kernel_memory_break.get()
     }
 
+    /// How many units of outstanding work this process contributes to the
+    /// kernel's work counter: one for each queued task and one if it is
+    /// running. Processes that cannot be scheduled contribute nothing.
+    fn outstanding_work(&self) -> usize {
+        let tasks_len = self.tasks.map_or(0, |tasks| tasks.len());
+        match self.state.get() {
+            State::Running => tasks_len + 1,
+            State::Yielded => tasks_len,
+            _ => 0,
+        }
+    }
+
+    /// Change the state of the process and update the kernel's work counter to
+    /// match.
+    fn set_state(&self, state: State) {
+        let before = self.outstanding_work();
+        self.state.set(state);
+        let after = self.outstanding_work();
+
+        for _ in after..before {
+            self.kernel.decrement_work();
+        }
+        for _ in before..after {
+            self.kernel.increment_work();
+        }
+    }
+
+    /// Count a newly queued task as work for the kernel. Stopped processes
+    /// keep their callbacks, but they are not work for the kernel until the
+    /// process is resumed.
+    fn add_task_work(&self) {
+        if self.state.get() == State::Running || self.state.get() == State::Yielded {
+            self.kernel.increment_work();
+        }
+    }
+
+    /// Queue the dropped task notification, if the process subscribed to it
+    /// and the last one has been delivered. It goes in the slot the queue
+    /// keeps free for it.
+    fn notify_dropped_task(&self, dropped: Task, dropped_count: usize) {
+        if self.dropped_task_notified.get() {
+            return;
+        }
+        let source = match dropped {
+            Task::FunctionCall(FunctionCall {
+                source: FunctionCallSource::Driver(callback_id),
+                ..
+            }) => (callback_id.driver_num, callback_id.subscribe_num),
+            _ => (usize::MAX, usize::MAX),
+        };
+        self.dropped_task_callback.get().map(|callback| {
+            let notification = callback.function_call(dropped_count, source.0, source.1);
+            if self
+                .tasks
+                .map_or(false, |tasks| tasks.enqueue(Task::FunctionCall(notification)))
+            {
+                self.dropped_task_notified.set(true);
+                self.add_task_work();
+            }
+        });
+    }
+
+    /// Whether `task` is the dropped task notification.
+    fn is_dropped_task_notification(&self, task: &Task) -> bool {
+        match (*task, self.dropped_task_callback.get()) {
+            (Task::FunctionCall(function_call), Some(callback)) => {
+                function_call.source == FunctionCallSource::Driver(callback.callback_id())
+            }
+            _ => false,
+        }
+    }
+
     fn sp(&self) -> *const usize {
         self.current_stack_pointer.get() as *const usize
     }
@@ -1116,3 +1990,24 @@ impl<S: 'static + UserspaceKernelBoundary> Process<'a, S> {
         });
     }
 }
+
+/// Replace a queued upcall from the same driver and subscribe number as
+/// `task` with `task`. Returns `false` if there is none.
+fn coalesce_task(tasks: &mut RingBuffer<Task>, task: Task) -> bool {
+    let source = match task {
+        Task::FunctionCall(FunctionCall {
+            source: source @ FunctionCallSource::Driver(_),
+            ..
+        }) => source,
+        _ => return false,
+    };
+    tasks
+        .find_mut(|queued| match *queued {
+            Task::FunctionCall(function_call) => function_call.source == source,
+            _ => false,
+        })
+        .map_or(false, |queued| {
+            *queued = task;
+            true
+        })
+}
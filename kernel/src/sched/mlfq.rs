@@ -0,0 +1,228 @@
+//! Multilevel feedback queue scheduler.
+//!
+//! Processes start in the highest priority queue. A process that uses its
+//! entire timeslice is moved down one queue, where it gets a longer timeslice
+//! but only runs when no process in a higher queue is ready. This favors
+//! processes that do a little work and yield (e.g. sensing apps) over
+//! processes that compute or transfer data for long periods. To avoid
+//! starvation, every process is periodically moved back to the top queue.
+//!
+//! The scheduler needs a `MLFQProcessState` for every process slot:
+//!
+//! ```ignore
+//! static mut MLFQ_STATE: [kernel::schedulers::MLFQProcessState; NUM_PROCS] = [
+//!     kernel::schedulers::MLFQProcessState::new(),
+//!     kernel::schedulers::MLFQProcessState::new(),
+//! ];
+//!
+//! let scheduler = static_init!(
+//!     kernel::schedulers::MLFQSched<'static>,
+//!     kernel::schedulers::MLFQSched::new(&MLFQ_STATE)
+//! );
+//! ```
+
+use core::cell::Cell;
+
+use sched::{ProcessSlots, Scheduler, SchedulingDecision, StoppedExecutingReason};
+
+/// Timeslice of each queue, from highest to lowest priority.
+const QUEUE_TIMESLICES_US: [u32; 3] = [10000, 20000, 50000];
+
+/// How much process execution time passes between moving every process back
+/// to the highest priority queue.
+const PRIORITY_BOOST_PERIOD_US: u32 = 5_000_000;
+
+/// Per-process scheduling state for the MLFQ scheduler.
+pub struct MLFQProcessState {
+    /// Which queue the process is currently in. 0 is the highest priority.
+    queue: Cell<usize>,
+}
+
+impl MLFQProcessState {
+    pub const fn new() -> MLFQProcessState {
+        MLFQProcessState {
+            queue: Cell::new(0),
+        }
+    }
+}
+
+pub struct MLFQSched<'a> {
+    /// One entry per process slot, indexed the same as the processes array.
+    processes: &'a [MLFQProcessState],
+    /// Process execution time since the last priority boost.
+    time_since_boost_us: Cell<u32>,
+    /// The slot after the most recently run process. Used to rotate between
+    /// processes in the same queue.
+    next_index: Cell<usize>,
+}
+
+impl MLFQSched<'a> {
+    pub fn new(processes: &'a [MLFQProcessState]) -> MLFQSched<'a> {
+        MLFQSched {
+            processes: processes,
+            time_since_boost_us: Cell::new(0),
+            next_index: Cell::new(0),
+        }
+    }
+
+    /// Queue the process in `process_index` is in. Slots without state are
+    /// treated as being in the lowest priority queue.
+    fn queue(&self, process_index: usize) -> usize {
+        self.processes
+            .get(process_index)
+            .map_or(QUEUE_TIMESLICES_US.len() - 1, |state| state.queue.get())
+    }
+
+    fn priority_boost(&self) {
+        for state in self.processes.iter() {
+            state.queue.set(0);
+        }
+        self.time_since_boost_us.set(0);
+    }
+}
+
+impl Scheduler for MLFQSched<'a> {
+    fn next(&self, processes: &ProcessSlots) -> SchedulingDecision {
+        let num_slots = processes.number_of_process_slots();
+
+        // Find the ready process in the highest priority queue, rotating
+        // between processes in the same queue.
+        let mut selected: Option<(usize, usize)> = None;
+        for offset in 0..num_slots {
+            let index = (self.next_index.get() + offset) % num_slots;
+            if !processes.process_ready(index) {
+                continue;
+            }
+
+            let queue = self.queue(index);
+            if selected.map_or(true, |(_, best)| queue < best) {
+                selected = Some((index, queue));
+            }
+        }
+
+        match selected {
+            Some((index, queue)) => SchedulingDecision::RunProcess {
+                process_index: index,
+                timeslice_us: QUEUE_TIMESLICES_US[queue],
+            },
+            None => SchedulingDecision::TrySleep,
+        }
+    }
+
+    fn result(&self, process_index: usize, result: StoppedExecutingReason, execution_time_us: u32) {
+        match result {
+            StoppedExecutingReason::TimesliceExpired => {
+                // Used its full timeslice, so demote it.
+                self.processes.get(process_index).map(|state| {
+                    let queue = state.queue.get();
+                    if queue + 1 < QUEUE_TIMESLICES_US.len() {
+                        state.queue.set(queue + 1);
+                    }
+                });
+                self.next_index.set(process_index + 1);
+            }
+            // Let a process that was interrupted by the kernel finish its turn.
+            StoppedExecutingReason::KernelPreemption => {}
+            _ => self.next_index.set(process_index + 1),
+        }
+
+        let time_since_boost_us = self
+            .time_since_boost_us
+            .get()
+            .saturating_add(execution_time_us);
+        if time_since_boost_us >= PRIORITY_BOOST_PERIOD_US {
+            self.priority_boost();
+        } else {
+            self.time_since_boost_us.set(time_since_boost_us);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MLFQProcessState, MLFQSched, PRIORITY_BOOST_PERIOD_US, QUEUE_TIMESLICES_US};
+    use sched::test::{MockProcess, MockProcesses};
+    use sched::{Scheduler, SchedulingDecision, StoppedExecutingReason};
+
+    fn run(process_index: usize, queue: usize) -> SchedulingDecision {
+        SchedulingDecision::RunProcess {
+            process_index: process_index,
+            timeslice_us: QUEUE_TIMESLICES_US[queue],
+        }
+    }
+
+    #[test]
+    fn demotes_processes_that_use_their_timeslice() {
+        let slots = [
+            Some(MockProcess::new(true, None)),
+            Some(MockProcess::new(true, None)),
+        ];
+        let processes = MockProcesses(&slots);
+        let states = [MLFQProcessState::new(), MLFQProcessState::new()];
+        let sched = MLFQSched::new(&states);
+
+        assert_eq!(sched.next(&processes), run(0, 0));
+        sched.result(0, StoppedExecutingReason::TimesliceExpired, 10000);
+
+        // Process 1 is still in the top queue, so it keeps running while it
+        // yields before its timeslice ends.
+        assert_eq!(sched.next(&processes), run(1, 0));
+        sched.result(1, StoppedExecutingReason::NoWorkLeft, 100);
+        assert_eq!(sched.next(&processes), run(1, 0));
+        sched.result(1, StoppedExecutingReason::KernelPreemption, 100);
+        assert_eq!(sched.next(&processes), run(1, 0));
+
+        // Once it is not ready, the demoted process runs with a longer
+        // timeslice, and drops to the lowest queue at most.
+        slots[1].as_ref().unwrap().ready.set(false);
+        assert_eq!(sched.next(&processes), run(0, 1));
+        for _ in 0..QUEUE_TIMESLICES_US.len() {
+            sched.result(0, StoppedExecutingReason::TimesliceExpired, 10);
+        }
+        assert_eq!(
+            sched.next(&processes),
+            run(0, QUEUE_TIMESLICES_US.len() - 1)
+        );
+    }
+
+    #[test]
+    fn boosts_every_process_periodically() {
+        let slots = [
+            Some(MockProcess::new(true, None)),
+            Some(MockProcess::new(false, None)),
+        ];
+        let processes = MockProcesses(&slots);
+        let states = [MLFQProcessState::new(), MLFQProcessState::new()];
+        let sched = MLFQSched::new(&states);
+
+        sched.result(0, StoppedExecutingReason::TimesliceExpired, 10000);
+        sched.result(0, StoppedExecutingReason::TimesliceExpired, 10000);
+        assert_eq!(sched.next(&processes), run(0, 2));
+
+        sched.result(0, StoppedExecutingReason::NoWorkLeft, PRIORITY_BOOST_PERIOD_US);
+        assert_eq!(sched.next(&processes), run(0, 0));
+    }
+
+    #[test]
+    fn slots_without_state_are_lowest_priority() {
+        let slots = [
+            Some(MockProcess::new(true, None)),
+            Some(MockProcess::new(true, None)),
+        ];
+        let processes = MockProcesses(&slots);
+        let states = [MLFQProcessState::new()];
+        let sched = MLFQSched::new(&states);
+
+        sched.result(1, StoppedExecutingReason::NoWorkLeft, 10);
+        assert_eq!(sched.next(&processes), run(0, 0));
+        slots[0].as_ref().unwrap().ready.set(false);
+        assert_eq!(
+            sched.next(&processes),
+            run(1, QUEUE_TIMESLICES_US.len() - 1)
+        );
+        assert_eq!(
+            MLFQSched::new(&states).next(&MockProcesses(&[None])),
+            SchedulingDecision::TrySleep
+        );
+    }
+}
@@ -0,0 +1,953 @@
+//! Tock core scheduler.
+//!
+//! The kernel loop itself lives here, while the policy for choosing which
+//! process runs next is provided by an implementation of the `Scheduler`
+//! trait that the board passes to `Kernel::kernel_loop()`.
+
+use core::cell::Cell;
+use core::fmt::Write;
+use core::ptr;
+use core::ptr::NonNull;
+
+use callback;
+use callback::{AppId, Callback, CallbackId};
+use capabilities;
+use clock::KernelTimer;
+use common::cells::{NumericCellExt, OptionalCell};
+use common::dynamic_deferred_call::DynamicDeferredCall;
+use credentials::{self, AppCredentialsPolicy, SignatureVerifier};
+use grant::Grant;
+use ipc;
+use mem::AppSlice;
+use memop;
+use platform::mpu::MPU;
+use platform::power::SleepState;
+use platform::systick::SysTick;
+use platform::{Chip, Platform};
+use process::{self, Task, TaskQueueConfig};
+use returncode::ReturnCode;
+use syscall::{ContextSwitchReason, Syscall};
+use tbfheader::TbfHeader;
+
+crate mod mlfq;
+crate mod priority;
+crate mod round_robin;
+
+/// The time a process is permitted to run before being pre-empted
+crate const KERNEL_TICK_DURATION_US: u32 = 10000;
+/// Skip re-scheduling a process if its quanta is nearly exhausted
+const MIN_QUANTA_THRESHOLD_US: u32 = 500;
+
+/// What the kernel loop should do next, as decided by the `Scheduler`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SchedulingDecision {
+    /// Run the process in the given slot of the processes array for at most
+    /// `timeslice_us` microseconds.
+    RunProcess {
+        process_index: usize,
+        timeslice_us: u32,
+    },
+
+    /// No process is ready to run, so the kernel should go to sleep if there
+    /// is no other pending work.
+    TrySleep,
+}
+
+/// Why a process stopped executing and control returned to the kernel loop.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StoppedExecutingReason {
+    /// The process yielded and has no more callbacks to handle.
+    NoWorkLeft,
+    /// The process used up its entire timeslice.
+    TimesliceExpired,
+    /// An interrupt or deferred call became pending, so the kernel took
+    /// control back before the timeslice expired.
+    KernelPreemption,
+    /// The process is no longer in a state where it can be scheduled.
+    Stopped,
+}
+
+/// The processes a `Scheduler` chooses between, by their slot in the
+/// processes array. `Kernel` implements this for the processes of the board.
+pub trait ProcessSlots {
+    /// How many slots there are, including empty ones.
+    fn number_of_process_slots(&self) -> usize;
+
+    /// Whether the slot holds a process that is ready to run.
+    fn process_ready(&self, process_index: usize) -> bool;
+
+    /// Priority of the process in the slot from its TBF header, where lower
+    /// values are higher priority. `None` if it has none.
+    fn process_priority(&self, process_index: usize) -> Option<u32>;
+}
+
+/// Policy for choosing which process the kernel runs next.
+///
+/// Boards pick an implementation and pass it to `Kernel::kernel_loop()`. The
+/// kernel calls `next()` whenever it is ready to run a process, and reports
+/// back via `result()` once the chosen process stops executing.
+pub trait Scheduler {
+    /// Decide which process to run next.
+    ///
+    /// Implementations must only return processes that are ready to run
+    /// (i.e. `ProcessSlots::process_ready()` returns true), and must return
+    /// `TrySleep` if there are none.
+    fn next(&self, processes: &ProcessSlots) -> SchedulingDecision;
+
+    /// Inform the scheduler why the process it selected stopped executing and
+    /// for how many microseconds it ran.
+    fn result(&self, process_index: usize, result: StoppedExecutingReason, execution_time_us: u32);
+}
+
+/// Main object for the kernel. Each board will need to create one.
+pub struct Kernel {
+    /// How many "to-do" items exist at any given time. These include
+    /// outstanding callbacks and processes in the Running state.
+    work: Cell<usize>,
+    /// This holds a pointer to the static array of Process pointers. The
+    /// slots are cells so that processes can be added after boot.
+    processes: &'static [Cell<Option<&'static process::ProcessType>>],
+    /// How many grant regions have been setup. This is incremented on every
+    /// call to `create_grant()`. We need to explicitly track this so that when
+    /// processes are created they can allocated pointers for each grant.
+    grant_counter: Cell<usize>,
+    /// Flag to mark that grants have been finalized. This means that the kernel
+    /// cannot support creating new grants because processes have already been
+    /// created and the data structures for grants have already been
+    /// established.
+    grants_finalized: Cell<bool>,
+    /// Which apps to load based on their credentials.
+    credentials_policy: Cell<AppCredentialsPolicy>,
+    /// Checks signatures in app credentials, if the board provides one.
+    signature_verifier: OptionalCell<&'static SignatureVerifier>,
+    /// How many tasks are queued for processes that are loaded.
+    task_queue_config: Cell<TaskQueueConfig>,
+    /// Deferred calls capsules registered, serviced by the main loop.
+    dynamic_deferred_call: OptionalCell<&'static DynamicDeferredCall>,
+    /// Deepest sleep state the board allows, whatever the chip allows.
+    max_sleep_state: Cell<SleepState>,
+    /// Clock that ends timeslices, if the board set one. Without one the
+    /// chip's `SysTick` is used.
+    kernel_clock: OptionalCell<&'static KernelTimer>,
+}
+
+impl Kernel {
+    /// Create the kernel. `processes` holds the board's process slots, which
+    /// `load_processes()` fills in.
+    pub fn new(processes: &'static mut [Option<&'static process::ProcessType>]) -> Kernel {
+        Kernel {
+            work: Cell::new(0),
+            processes: Cell::from_mut(processes).as_slice_of_cells(),
+            grant_counter: Cell::new(0),
+            grants_finalized: Cell::new(false),
+            credentials_policy: Cell::new(AppCredentialsPolicy::CheckIfPresent),
+            signature_verifier: OptionalCell::empty(),
+            task_queue_config: Cell::new(TaskQueueConfig::default()),
+            dynamic_deferred_call: OptionalCell::empty(),
+            max_sleep_state: Cell::new(SleepState::DeepSleep),
+            kernel_clock: OptionalCell::empty(),
+        }
+    }
+
+    /// Something was scheduled for a process, so there is more work to do.
+    crate fn increment_work(&self) {
+        self.work.increment();
+    }
+
+    /// Something finished for a process, so we decrement how much work there is
+    /// to do.
+    crate fn decrement_work(&self) {
+        self.work.decrement();
+    }
+
+    /// Helper function for determining if we should service processes or go to
+    /// sleep.
+    fn processes_blocked(&self) -> bool {
+        self.work.get() == 0
+    }
+
+    /// Run a closure on a specific process if it exists. If the process does
+    /// not exist (i.e. it is `None` in the `processes` array) then `default`
+    /// will be returned. Otherwise the closure will executed and passed a
+    /// reference to the process.
+    crate fn process_map_or<F, R>(&self, default: R, process_index: usize, closure: F) -> R
+    where
+        F: FnOnce(&process::ProcessType) -> R,
+    {
+        if process_index >= self.processes.len() {
+            return default;
+        }
+        self.processes[process_index]
+            .get()
+            .map_or(default, |process| closure(process))
+    }
+
+    /// Find a slot for a new process: an empty one, or one whose process was
+    /// terminated.
+    crate fn free_process_slot(&self) -> Option<usize> {
+        self.processes.iter().position(|slot| {
+            slot.get().map_or(true, |process| {
+                process.get_state() == process::State::Terminated
+            })
+        })
+    }
+
+    /// Put a newly created process in slot `index` of the processes array.
+    ///
+    /// A process that was in the slot is terminated first, so that it holds
+    /// no grants, tasks or work once it can no longer be reached.
+    crate fn set_process(&self, index: usize, process: &'static process::ProcessType) {
+        if let Some(old) = self.processes[index].get() {
+            old.terminate();
+        }
+        self.processes[index].set(Some(process));
+    }
+
+    /// Run a closure on every valid process. This will iterate the array of
+    /// processes and call the closure on every process that exists and has not
+    /// been terminated.
+    crate fn process_each_enumerate<F>(&self, closure: F)
+    where
+        F: Fn(usize, &process::ProcessType),
+    {
+        for (i, slot) in self.processes.iter().enumerate() {
+            match slot.get() {
+                Some(p) if p.get_state() != process::State::Terminated => {
+                    closure(i, p);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Run a closure on a specific process if it exists, or return `default`.
+    ///
+    /// This is the same as `process_map_or()`, but is available outside of the
+    /// kernel crate to holders of the `ProcessManagementCapability`, since it
+    /// gives the caller access to the process itself.
+    pub fn process_map_or_capability<F, R, C>(
+        &self,
+        default: R,
+        process_index: usize,
+        closure: F,
+        _capability: &C,
+    ) -> R
+    where
+        F: FnOnce(&process::ProcessType) -> R,
+        C: capabilities::ProcessManagementCapability,
+    {
+        self.process_map_or(default, process_index, closure)
+    }
+
+    /// Return how many processes this board supports.
+    pub fn number_of_process_slots(&self) -> usize {
+        self.processes.len()
+    }
+
+    /// Create a new grant. This is used in board initialization to setup grants
+    /// that capsules use to interact with processes.
+    ///
+    /// Grants **must** only be created _before_ processes are initialized.
+    /// Processes use the number of grants that have been allocated to correctly
+    /// initialize the process's memory with a pointer for each grant. If a
+    /// grant is created after processes are initialized this will panic.
+    ///
+    /// Calling this function is restricted to only certain users, and to
+    /// enforce this calling this function requires the
+    /// `MemoryAllocationCapability` capability.
+    pub fn create_grant<T: Default>(
+        &'static self,
+        _capability: &capabilities::MemoryAllocationCapability,
+    ) -> Grant<T> {
+        if self.grants_finalized.get() {
+            panic!("Grants finalized. Cannot create a new grant.");
+        }
+
+        // Create and return a new grant.
+        let grant_index = self.grant_counter.get();
+        self.grant_counter.increment();
+        Grant::new(self, grant_index)
+    }
+
+    /// Returns the number of grants that have been setup in the system and
+    /// marks the grants as "finalized". This means that no more grants can
+    /// be created because data structures have been setup based on the number
+    /// of grants when this function is called.
+    ///
+    /// In practice, this is called when processes are created, and the process
+    /// memory is setup based on the number of current grants.
+    crate fn get_grant_count_and_finalize(&self) -> usize {
+        self.grants_finalized.set(true);
+        self.grant_counter.get()
+    }
+
+    /// Choose which apps are loaded based on their credentials, and how to
+    /// check signatures in app credentials.
+    ///
+    /// This must be called before loading processes to have an effect. By
+    /// default the kernel uses `AppCredentialsPolicy::CheckIfPresent` and does
+    /// not check signatures.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn set_app_credentials_policy<C: capabilities::ProcessManagementCapability>(
+        &self,
+        policy: AppCredentialsPolicy,
+        verifier: Option<&'static SignatureVerifier>,
+        _c: &C,
+    ) {
+        self.credentials_policy.set(policy);
+        self.signature_verifier.insert(verifier);
+    }
+
+    /// Whether the credentials of the app with `header` and TBF image `image`
+    /// allow loading it.
+    crate fn app_credentials_valid(&self, header: &TbfHeader, image: &'static [u8]) -> bool {
+        credentials::check_credentials(
+            header,
+            image,
+            self.credentials_policy.get(),
+            self.signature_verifier.map(|verifier| *verifier),
+        )
+    }
+
+    /// Set how many tasks are queued for each process, and whether a full
+    /// queue coalesces upcalls. This only affects processes loaded after the
+    /// call, so boards should call it before loading processes.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn set_task_queue_config<C: capabilities::ProcessManagementCapability>(
+        &self,
+        config: TaskQueueConfig,
+        _c: &C,
+    ) {
+        self.task_queue_config.set(config);
+    }
+
+    /// The task queue configuration for processes that are being loaded.
+    crate fn task_queue_config(&self) -> TaskQueueConfig {
+        self.task_queue_config.get()
+    }
+
+    /// Set the `DynamicDeferredCall` whose pending calls `kernel_loop()`
+    /// services. Without one, only chip deferred calls are supported.
+    ///
+    /// Only callers with the `MainLoopCapability` can call this function.
+    pub fn set_dynamic_deferred_call(
+        &self,
+        dynamic_deferred_call: &'static DynamicDeferredCall,
+        _capability: &capabilities::MainLoopCapability,
+    ) {
+        self.dynamic_deferred_call.set(dynamic_deferred_call);
+    }
+
+    /// Limit how deeply the kernel lets the chip sleep, for example to keep a
+    /// debugger attached. By default the chip sleeps as deeply as its
+    /// peripherals allow.
+    ///
+    /// Only callers with the `MainLoopCapability` can call this function.
+    pub fn set_max_sleep_state(
+        &self,
+        state: SleepState,
+        _capability: &capabilities::MainLoopCapability,
+    ) {
+        self.max_sleep_state.set(state);
+    }
+
+    /// Enforce timeslices with `kernel_clock` instead of the chip's `SysTick`.
+    /// The clock only interrupts the chip at the end of a timeslice, so the
+    /// kernel sleeps without periodic wakeups.
+    ///
+    /// Only callers with the `MainLoopCapability` can call this function.
+    pub fn set_kernel_clock(
+        &self,
+        kernel_clock: &'static KernelTimer,
+        _capability: &capabilities::MainLoopCapability,
+    ) {
+        self.kernel_clock.set(kernel_clock);
+    }
+
+    /// Whether there are deferred calls registered with the kernel waiting to
+    /// run.
+    fn has_pending_deferred_calls(&self) -> bool {
+        self.dynamic_deferred_call
+            .map_or(false, |dynamic_deferred_call| {
+                dynamic_deferred_call.has_pending()
+            })
+    }
+
+    /// Cause all apps to fault.
+    ///
+    /// This will call `set_fault_state()` on each app, causing the app to enter
+    /// the state as if it had crashed (for example with an MPU violation). If
+    /// the process is configured to be restarted it will be.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function. This restricts general capsules from being able to call this
+    /// function, since capsules should not be able to arbitrarily restart all
+    /// apps.
+    pub fn hardfault_all_apps<C: capabilities::ProcessManagementCapability>(&self, _c: &C) {
+        self.process_each_enumerate(|_, process| {
+            process.set_fault_state();
+        });
+    }
+
+    /// Run a closure on every process that exists and has not been
+    /// terminated, along with its `AppId`.
+    ///
+    /// This lets trusted capsules find the `AppId` of a process to pass to the
+    /// other process management functions. Only callers with the
+    /// `ProcessManagementCapability` can call this function.
+    pub fn process_each_capability<F, C>(&'static self, _c: &C, closure: F)
+    where
+        F: Fn(AppId, &process::ProcessType),
+        C: capabilities::ProcessManagementCapability,
+    {
+        self.process_each_enumerate(|i, process| {
+            closure(AppId::new(self, i), process);
+        });
+    }
+
+    /// Stop scheduling an app. The app keeps all of its state and callbacks
+    /// are still queued for it, but it will not run until `resume_app()` is
+    /// called.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn stop_app<C: capabilities::ProcessManagementCapability>(
+        &self,
+        appid: AppId,
+        _c: &C,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid.idx(), |process| {
+            process.stop()
+        })
+    }
+
+    /// Resume an app previously stopped with `stop_app()`.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn resume_app<C: capabilities::ProcessManagementCapability>(
+        &self,
+        appid: AppId,
+        _c: &C,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid.idx(), |process| {
+            process.resume()
+        })
+    }
+
+    /// Restart an app from the beginning. Its grants and any queued callbacks
+    /// are discarded. This also starts apps that were terminated.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn restart_app<C: capabilities::ProcessManagementCapability>(
+        &self,
+        appid: AppId,
+        _c: &C,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid.idx(), |process| {
+            process.restart();
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// Restart an app that faulted, for fault policies that restart apps some
+    /// time after the fault. Unlike `restart_app()`, this counts towards the
+    /// app's restart count, and returns `EINVAL` if the app is no longer in
+    /// the `Fault` state because something else restarted or terminated it.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn restart_faulted_app<C: capabilities::ProcessManagementCapability>(
+        &self,
+        appid: AppId,
+        _c: &C,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid.idx(), |process| {
+            process.restart_after_fault()
+        })
+    }
+
+    /// Terminate an app. Its grants and any queued callbacks are discarded and
+    /// its slot is treated as empty. A terminated app can only run again if it
+    /// is restarted with `restart_app()`.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn terminate_app<C: capabilities::ProcessManagementCapability>(
+        &self,
+        appid: AppId,
+        _c: &C,
+    ) -> ReturnCode {
+        self.process_map_or(ReturnCode::EINVAL, appid.idx(), |process| {
+            process.terminate();
+            ReturnCode::SUCCESS
+        })
+    }
+
+    /// Write a detailed description of an app to `writer`. This includes its
+    /// memory layout, saved registers and debugging counters.
+    ///
+    /// Only callers with the `ProcessManagementCapability` can call this
+    /// function.
+    pub fn process_detail_fmt<C: capabilities::ProcessManagementCapability>(
+        &self,
+        appid: AppId,
+        writer: &mut Write,
+        _c: &C,
+    ) {
+        self.process_map_or((), appid.idx(), |process| unsafe {
+            process.process_detail_fmt(writer);
+        });
+    }
+
+    /// Main loop.
+    ///
+    /// The `scheduler` decides the order in which processes run and how long
+    /// each is allowed to run for.
+    pub fn kernel_loop<P: Platform, C: Chip, SC: Scheduler>(
+        &'static self,
+        platform: &P,
+        chip: &mut C,
+        ipc: Option<&ipc::IPC>,
+        scheduler: &SC,
+        _capability: &capabilities::MainLoopCapability,
+    ) {
+        loop {
+            unsafe {
+                chip.service_pending_interrupts();
+
+                // Deferred calls run after interrupts, which are more urgent,
+                // and before processes.
+                self.dynamic_deferred_call.map(|dynamic_deferred_call| {
+                    dynamic_deferred_call.call_while(|| !chip.has_pending_interrupts());
+                });
+
+                // Keep running processes until there is an interrupt or a
+                // deferred call to handle, or the scheduler has nothing left
+                // to run.
+                while !chip.has_pending_interrupts() && !self.has_pending_deferred_calls() {
+                    match scheduler.next(self) {
+                        SchedulingDecision::RunProcess {
+                            process_index,
+                            timeslice_us,
+                        } => {
+                            let (reason, execution_time_us) = self.process_map_or(
+                                (StoppedExecutingReason::Stopped, 0),
+                                process_index,
+                                |process| {
+                                    self.do_process(
+                                        platform,
+                                        chip,
+                                        process,
+                                        callback::AppId::new(self, process_index),
+                                        ipc,
+                                        timeslice_us,
+                                    )
+                                },
+                            );
+                            scheduler.result(process_index, reason, execution_time_us);
+                        }
+                        SchedulingDecision::TrySleep => break,
+                    }
+                }
+
+                chip.atomic(|| {
+                    if !chip.has_pending_interrupts()
+                        && !self.has_pending_deferred_calls()
+                        && self.processes_blocked()
+                    {
+                        let state = chip.sleep_state().lighter(self.max_sleep_state.get());
+                        chip.sleep(state);
+                    }
+                });
+            };
+        }
+    }
+
+    /// Run a single process until it has no more work, its timeslice expires,
+    /// or an interrupt needs handling. Returns why the process stopped and how
+    /// many microseconds of its timeslice it used.
+    unsafe fn do_process<P: Platform, C: Chip>(
+        &self,
+        platform: &P,
+        chip: &mut C,
+        process: &process::ProcessType,
+        appid: AppId,
+        ipc: Option<&::ipc::IPC>,
+        timeslice_us: u32,
+    ) -> (StoppedExecutingReason, u32) {
+        let timeslice = Timeslice::start(
+            chip.systick(),
+            self.kernel_clock.map(|kernel_clock| *kernel_clock),
+            timeslice_us,
+        );
+
+        let reason = loop {
+            // Check the timeslice first: when the kernel clock ends it, the
+            // clock's alarm interrupt is pending too.
+            if timeslice.expired() {
+                break StoppedExecutingReason::TimesliceExpired;
+            }
+            if chip.has_pending_interrupts() || self.has_pending_deferred_calls() {
+                break StoppedExecutingReason::KernelPreemption;
+            }
+
+            match process.get_state() {
+                process::State::Running => {
+                    // Running means that this process expects to be running,
+                    // so go ahead and set things up and switch to executing
+                    // the process.
+                    process.setup_mpu(chip.mpu());
+                    chip.mpu().enable_mpu();
+                    let switched_at_us = timeslice.used_us(timeslice_us);
+                    timeslice.set_running(true);
+                    let context_switch_reason = process.switch_to();
+                    timeslice.set_running(false);
+                    process.debug_process_ran(
+                        timeslice
+                            .used_us(timeslice_us)
+                            .saturating_sub(switched_at_us),
+                    );
+                    chip.mpu().disable_mpu();
+
+                    // Now the process has returned back to the kernel. Check
+                    // why and handle the process as appropriate.
+                    match context_switch_reason {
+                        Some(ContextSwitchReason::Fault) => {
+                            // Let process deal with it as appropriate.
+                            process.set_fault_state();
+                        }
+                        Some(ContextSwitchReason::SyscallFired) => {
+                            // Handle each of the syscalls.
+                            match process.get_syscall() {
+                                Some(Syscall::MEMOP { operand, arg0 }) => {
+                                    let res = memop::memop(process, operand, arg0);
+                                    process.set_syscall_return_value(res.into());
+                                }
+                                Some(Syscall::YIELD) => {
+                                    process.set_yielded_state();
+                                    process.pop_syscall_stack_frame();
+
+                                    // There might be already enqueued callbacks
+                                    continue;
+                                }
+                                Some(Syscall::SUBSCRIBE {
+                                    driver_number,
+                                    subdriver_number,
+                                    callback_ptr,
+                                    appdata,
+                                }) => {
+                                    let callback_id = CallbackId {
+                                        driver_num: driver_number,
+                                        subscribe_num: subdriver_number,
+                                    };
+                                    let callback_ptr = NonNull::new(callback_ptr);
+                                    let callback = callback_ptr.map(|ptr| {
+                                        Callback::new(appid, callback_id, appdata, ptr.cast())
+                                    });
+
+                                    // Drivers the process is not permitted to use
+                                    // return an error without being called.
+                                    let permission =
+                                        process.check_driver_permission(driver_number, None);
+                                    let res =
+                                        platform.with_driver(
+                                            driver_number,
+                                            |driver| match driver {
+                                                _ if permission != ReturnCode::SUCCESS => {
+                                                    permission
+                                                }
+                                                Some(d) => {
+                                                    d.subscribe(subdriver_number, callback, appid)
+                                                }
+                                                None => ReturnCode::ENODEVICE,
+                                            },
+                                        );
+                                    process.set_syscall_return_value(res.into());
+                                }
+                                Some(Syscall::COMMAND {
+                                    driver_number,
+                                    subdriver_number,
+                                    arg0,
+                                    arg1,
+                                }) => {
+                                    let permission = process.check_driver_permission(
+                                        driver_number,
+                                        Some(subdriver_number),
+                                    );
+                                    let res =
+                                        platform.with_driver(
+                                            driver_number,
+                                            |driver| match driver {
+                                                _ if permission != ReturnCode::SUCCESS => {
+                                                    permission
+                                                }
+                                                Some(d) => {
+                                                    d.command(subdriver_number, arg0, arg1, appid)
+                                                }
+                                                None => ReturnCode::ENODEVICE,
+                                            },
+                                        );
+                                    process.set_syscall_return_value(res.into());
+                                }
+                                Some(Syscall::ALLOW {
+                                    driver_number,
+                                    subdriver_number,
+                                    allow_address,
+                                    allow_size,
+                                }) => {
+                                    let permission =
+                                        process.check_driver_permission(driver_number, None);
+                                    let res = platform.with_driver(driver_number, |driver| {
+                                        match driver {
+                                            _ if permission != ReturnCode::SUCCESS => permission,
+                                            Some(d) => {
+                                                if allow_address != ptr::null_mut() {
+                                                    if process.in_app_owned_memory(
+                                                        allow_address,
+                                                        allow_size,
+                                                    ) {
+                                                        let slice = AppSlice::new(
+                                                            allow_address,
+                                                            allow_size,
+                                                            appid,
+                                                        );
+                                                        d.allow(
+                                                            appid,
+                                                            subdriver_number,
+                                                            Some(slice),
+                                                        )
+                                                    } else {
+                                                        ReturnCode::EINVAL /* memory not allocated to process */
+                                                    }
+                                                } else {
+                                                    d.allow(appid, subdriver_number, None)
+                                                }
+                                            }
+                                            None => ReturnCode::ENODEVICE,
+                                        }
+                                    });
+                                    process.set_syscall_return_value(res.into());
+                                }
+                                _ => {}
+                            }
+                        }
+                        Some(ContextSwitchReason::TimesliceExpired) => {
+                            // break to handle other processes.
+                            break StoppedExecutingReason::TimesliceExpired;
+                        }
+                        None => {
+                            // Something went wrong when switching to this
+                            // process. Indicate this by putting it in a fault
+                            // state.
+                            process.set_fault_state();
+                        }
+                    }
+                }
+                process::State::Yielded => match process.dequeue_task() {
+                    // If the process is yielded it might be waiting for a
+                    // callback. If there is a task scheduled for this process
+                    // go ahead and set the process to execute it.
+                    None => break StoppedExecutingReason::NoWorkLeft,
+                    Some(cb) => match cb {
+                        Task::FunctionCall(ccb) => {
+                            process.push_function_call(ccb);
+                        }
+                        Task::IPC((otherapp, ipc_type)) => {
+                            ipc.map_or_else(
+                                || {
+                                    assert!(
+                                        false,
+                                        "Kernel consistency error: IPC Task with no IPC"
+                                    );
+                                },
+                                |ipc| {
+                                    ipc.schedule_callback(appid, otherapp, ipc_type);
+                                },
+                            );
+                        }
+                    },
+                },
+                process::State::Fault => {
+                    // We should never be scheduling a process in fault.
+                    panic!("Attempted to schedule a faulty process");
+                }
+                process::State::StoppedRunning
+                | process::State::StoppedYielded
+                | process::State::Terminated => {
+                    // The process was stopped or terminated, possibly by a
+                    // syscall it just made, so go back to the scheduler.
+                    break StoppedExecutingReason::Stopped;
+                }
+            }
+        };
+
+        // Account for how much of the timeslice the process actually used.
+        let execution_time_us = timeslice.finish(timeslice_us);
+
+        if reason == StoppedExecutingReason::TimesliceExpired {
+            process.debug_timeslice_expired();
+        }
+
+        (reason, execution_time_us)
+    }
+}
+
+impl ProcessSlots for Kernel {
+    fn number_of_process_slots(&self) -> usize {
+        self.processes.len()
+    }
+
+    fn process_ready(&self, process_index: usize) -> bool {
+        self.process_map_or(false, process_index, |process| process.ready())
+    }
+
+    fn process_priority(&self, process_index: usize) -> Option<u32> {
+        self.process_map_or(None, process_index, |process| process.get_priority())
+    }
+}
+
+/// Ends a process's timeslice, with the kernel clock if the board set one and
+/// with the chip's `SysTick` otherwise.
+///
+/// `SysTick` only counts while the process runs. The kernel clock keeps
+/// counting while the kernel handles the process's system calls, so that time
+/// counts against the timeslice too.
+enum Timeslice<'a, S: SysTick + 'a> {
+    SysTick(&'a S),
+    Clock {
+        clock: &'a KernelTimer,
+        start: u64,
+        end: u64,
+    },
+}
+
+impl<S: SysTick> Timeslice<'a, S> {
+    /// Start a timeslice of `timeslice_us` microseconds.
+    fn start(
+        systick: &'a S,
+        clock: Option<&'a KernelTimer>,
+        timeslice_us: u32,
+    ) -> Timeslice<'a, S> {
+        match clock {
+            Some(clock) => {
+                let start = clock.now();
+                let end = start + clock.us_to_ticks(timeslice_us);
+                clock.set_deadline(Some(end));
+                Timeslice::Clock {
+                    clock: clock,
+                    start: start,
+                    end: end,
+                }
+            }
+            None => {
+                systick.reset();
+                systick.set_timer(timeslice_us);
+                systick.enable(true);
+                Timeslice::SysTick(systick)
+            }
+        }
+    }
+
+    /// Whether too little of the timeslice is left to be worth running the
+    /// process again.
+    fn expired(&self) -> bool {
+        match *self {
+            Timeslice::SysTick(systick) => {
+                systick.overflowed() || !systick.greater_than(MIN_QUANTA_THRESHOLD_US)
+            }
+            Timeslice::Clock { clock, end, .. } => {
+                clock.now() + clock.us_to_ticks(MIN_QUANTA_THRESHOLD_US) >= end
+            }
+        }
+    }
+
+    /// Tell the timer whether the process is running or the kernel is.
+    fn set_running(&self, running: bool) {
+        match *self {
+            Timeslice::SysTick(systick) => systick.enable(running),
+            Timeslice::Clock { .. } => {}
+        }
+    }
+
+    /// How many microseconds of the timeslice have been used so far.
+    fn used_us(&self, timeslice_us: u32) -> u32 {
+        match *self {
+            Timeslice::SysTick(systick) => {
+                let remaining_us = if systick.overflowed() {
+                    0
+                } else {
+                    systick.get_value()
+                };
+                timeslice_us.saturating_sub(remaining_us)
+            }
+            Timeslice::Clock { clock, start, .. } => {
+                let used_us = clock.ticks_to_us(clock.now() - start);
+                if used_us < timeslice_us as u64 {
+                    used_us as u32
+                } else {
+                    timeslice_us
+                }
+            }
+        }
+    }
+
+    /// Stop the timer. Returns how many microseconds of the timeslice were
+    /// used.
+    fn finish(self, timeslice_us: u32) -> u32 {
+        let used_us = self.used_us(timeslice_us);
+        match self {
+            Timeslice::SysTick(systick) => systick.reset(),
+            Timeslice::Clock { clock, .. } => clock.set_deadline(None),
+        }
+        used_us
+    }
+}
+
+#[cfg(test)]
+crate mod test {
+    use core::cell::Cell;
+
+    use super::ProcessSlots;
+
+    /// A process for testing schedulers, which is ready to run or not.
+    crate struct MockProcess {
+        crate ready: Cell<bool>,
+        crate priority: Option<u32>,
+    }
+
+    impl MockProcess {
+        crate fn new(ready: bool, priority: Option<u32>) -> MockProcess {
+            MockProcess {
+                ready: Cell::new(ready),
+                priority: priority,
+            }
+        }
+    }
+
+    /// Process slots for testing schedulers, where `None` is an empty slot.
+    crate struct MockProcesses<'a>(crate &'a [Option<MockProcess>]);
+
+    impl ProcessSlots for MockProcesses<'a> {
+        fn number_of_process_slots(&self) -> usize {
+            self.0.len()
+        }
+
+        fn process_ready(&self, process_index: usize) -> bool {
+            self.0[process_index]
+                .as_ref()
+                .map_or(false, |process| process.ready.get())
+        }
+
+        fn process_priority(&self, process_index: usize) -> Option<u32> {
+            self.0[process_index]
+                .as_ref()
+                .and_then(|process| process.priority)
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Fixed priority scheduler.
+//!
+//! The ready process with the highest priority always runs. Priorities come
+//! from the Priority TLV in each app's TBF header, where lower values are
+//! higher priority. Apps without a priority run only when no prioritized app
+//! is ready. Processes with equal priority are run round robin.
+
+use core::cell::Cell;
+
+use sched::KERNEL_TICK_DURATION_US;
+use sched::{ProcessSlots, Scheduler, SchedulingDecision, StoppedExecutingReason};
+
+pub struct PrioritySched {
+    /// The slot after the most recently run process. Used to rotate between
+    /// processes of the same priority.
+    next_index: Cell<usize>,
+    timeslice_us: u32,
+}
+
+impl PrioritySched {
+    /// Create a priority scheduler with the default kernel timeslice.
+    pub const fn new() -> PrioritySched {
+        PrioritySched::new_with_timeslice(KERNEL_TICK_DURATION_US)
+    }
+
+    /// Create a priority scheduler that gives a process `timeslice_us`
+    /// microseconds before re-evaluating which process should run.
+    pub const fn new_with_timeslice(timeslice_us: u32) -> PrioritySched {
+        PrioritySched {
+            next_index: Cell::new(0),
+            timeslice_us: timeslice_us,
+        }
+    }
+}
+
+impl Scheduler for PrioritySched {
+    fn next(&self, processes: &ProcessSlots) -> SchedulingDecision {
+        let num_slots = processes.number_of_process_slots();
+
+        // Find the ready process with the numerically lowest priority. Apps
+        // without a priority sort after every app that has one. Starting the
+        // search at `next_index` and only replacing on a strictly better
+        // priority rotates between processes of equal priority.
+        let mut selected: Option<(usize, Option<u32>)> = None;
+        for offset in 0..num_slots {
+            let index = (self.next_index.get() + offset) % num_slots;
+            let candidate = if processes.process_ready(index) {
+                Some(processes.process_priority(index))
+            } else {
+                None
+            };
+
+            if let Some(priority) = candidate {
+                let better = match selected {
+                    None => true,
+                    Some((_, current)) => match (priority, current) {
+                        (Some(p), Some(c)) => p < c,
+                        (Some(_), None) => true,
+                        _ => false,
+                    },
+                };
+                if better {
+                    selected = Some((index, priority));
+                }
+            }
+        }
+
+        match selected {
+            Some((index, _)) => SchedulingDecision::RunProcess {
+                process_index: index,
+                timeslice_us: self.timeslice_us,
+            },
+            None => SchedulingDecision::TrySleep,
+        }
+    }
+
+    fn result(&self, process_index: usize, _: StoppedExecutingReason, _: u32) {
+        self.next_index.set(process_index + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PrioritySched;
+    use sched::test::{MockProcess, MockProcesses};
+    use sched::{Scheduler, SchedulingDecision, StoppedExecutingReason};
+
+    fn run(process_index: usize) -> SchedulingDecision {
+        SchedulingDecision::RunProcess {
+            process_index: process_index,
+            timeslice_us: 1000,
+        }
+    }
+
+    #[test]
+    fn runs_highest_priority_first() {
+        let slots = [
+            Some(MockProcess::new(true, None)),
+            Some(MockProcess::new(true, Some(5))),
+            Some(MockProcess::new(true, Some(2))),
+            Some(MockProcess::new(false, Some(0))),
+        ];
+        let processes = MockProcesses(&slots);
+        let sched = PrioritySched::new_with_timeslice(1000);
+
+        assert_eq!(sched.next(&processes), run(2));
+        sched.result(2, StoppedExecutingReason::TimesliceExpired, 1000);
+        assert_eq!(sched.next(&processes), run(2));
+
+        // A higher priority process runs as soon as it is ready.
+        slots[3].as_ref().unwrap().ready.set(true);
+        assert_eq!(sched.next(&processes), run(3));
+
+        // Apps without a priority only run when nothing else is ready.
+        for slot in slots[1..].iter() {
+            slot.as_ref().unwrap().ready.set(false);
+        }
+        assert_eq!(sched.next(&processes), run(0));
+    }
+
+    #[test]
+    fn rotates_equal_priorities() {
+        let slots = [
+            Some(MockProcess::new(true, Some(1))),
+            Some(MockProcess::new(true, Some(3))),
+            Some(MockProcess::new(true, Some(1))),
+        ];
+        let processes = MockProcesses(&slots);
+        let sched = PrioritySched::new_with_timeslice(1000);
+
+        assert_eq!(sched.next(&processes), run(0));
+        sched.result(0, StoppedExecutingReason::NoWorkLeft, 100);
+        assert_eq!(sched.next(&processes), run(2));
+        sched.result(2, StoppedExecutingReason::KernelPreemption, 100);
+        assert_eq!(sched.next(&processes), run(0));
+    }
+
+    #[test]
+    fn sleeps_when_nothing_is_ready() {
+        let slots = [None, Some(MockProcess::new(false, Some(1)))];
+        let processes = MockProcesses(&slots);
+        assert_eq!(
+            PrioritySched::new().next(&processes),
+            SchedulingDecision::TrySleep
+        );
+    }
+}
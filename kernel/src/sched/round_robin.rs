@@ -0,0 +1,123 @@
+//! Round robin scheduler.
+//!
+//! Every ready process gets the same timeslice, and processes are run in the
+//! order they appear in the processes array. A process that is interrupted by
+//! the kernel before its timeslice expires is resumed before moving on to the
+//! next process.
+
+use core::cell::Cell;
+
+use sched::KERNEL_TICK_DURATION_US;
+use sched::{ProcessSlots, Scheduler, SchedulingDecision, StoppedExecutingReason};
+
+pub struct RoundRobinSched {
+    /// The process slot to start looking for a ready process from.
+    next_index: Cell<usize>,
+    timeslice_us: u32,
+}
+
+impl RoundRobinSched {
+    /// Create a round robin scheduler with the default kernel timeslice.
+    pub const fn new() -> RoundRobinSched {
+        RoundRobinSched::new_with_timeslice(KERNEL_TICK_DURATION_US)
+    }
+
+    /// Create a round robin scheduler that gives each process `timeslice_us`
+    /// microseconds before it is pre-empted.
+    pub const fn new_with_timeslice(timeslice_us: u32) -> RoundRobinSched {
+        RoundRobinSched {
+            next_index: Cell::new(0),
+            timeslice_us: timeslice_us,
+        }
+    }
+}
+
+impl Scheduler for RoundRobinSched {
+    fn next(&self, processes: &ProcessSlots) -> SchedulingDecision {
+        let num_slots = processes.number_of_process_slots();
+        for offset in 0..num_slots {
+            let index = (self.next_index.get() + offset) % num_slots;
+            if processes.process_ready(index) {
+                self.next_index.set(index);
+                return SchedulingDecision::RunProcess {
+                    process_index: index,
+                    timeslice_us: self.timeslice_us,
+                };
+            }
+        }
+        SchedulingDecision::TrySleep
+    }
+
+    fn result(&self, process_index: usize, result: StoppedExecutingReason, _: u32) {
+        match result {
+            // The process did not get its full turn, so let it continue.
+            StoppedExecutingReason::KernelPreemption => {}
+            _ => self.next_index.set(process_index + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RoundRobinSched;
+    use sched::test::{MockProcess, MockProcesses};
+    use sched::{Scheduler, SchedulingDecision, StoppedExecutingReason};
+
+    fn run(process_index: usize) -> SchedulingDecision {
+        SchedulingDecision::RunProcess {
+            process_index: process_index,
+            timeslice_us: 1000,
+        }
+    }
+
+    #[test]
+    fn runs_ready_processes_in_turn() {
+        let slots = [
+            Some(MockProcess::new(true, None)),
+            None,
+            Some(MockProcess::new(false, None)),
+            Some(MockProcess::new(true, None)),
+        ];
+        let processes = MockProcesses(&slots);
+        let sched = RoundRobinSched::new_with_timeslice(1000);
+
+        assert_eq!(sched.next(&processes), run(0));
+        sched.result(0, StoppedExecutingReason::TimesliceExpired, 1000);
+        assert_eq!(sched.next(&processes), run(3));
+        sched.result(3, StoppedExecutingReason::NoWorkLeft, 200);
+        assert_eq!(sched.next(&processes), run(0));
+
+        slots[2].as_ref().unwrap().ready.set(true);
+        sched.result(0, StoppedExecutingReason::Stopped, 10);
+        assert_eq!(sched.next(&processes), run(2));
+    }
+
+    #[test]
+    fn resumes_preempted_process() {
+        let slots = [
+            Some(MockProcess::new(true, None)),
+            Some(MockProcess::new(true, None)),
+        ];
+        let processes = MockProcesses(&slots);
+        let sched = RoundRobinSched::new_with_timeslice(1000);
+
+        assert_eq!(sched.next(&processes), run(0));
+        sched.result(0, StoppedExecutingReason::KernelPreemption, 300);
+        assert_eq!(sched.next(&processes), run(0));
+        sched.result(0, StoppedExecutingReason::TimesliceExpired, 700);
+        assert_eq!(sched.next(&processes), run(1));
+    }
+
+    #[test]
+    fn sleeps_when_nothing_is_ready() {
+        let slots = [None, Some(MockProcess::new(false, None))];
+        let processes = MockProcesses(&slots);
+        let sched = RoundRobinSched::new();
+
+        assert_eq!(sched.next(&processes), SchedulingDecision::TrySleep);
+        assert_eq!(
+            RoundRobinSched::new().next(&MockProcesses(&[])),
+            SchedulingDecision::TrySleep
+        );
+    }
+}
@@ -1,65 +1,38 @@
-//! Tock Binary Format Header definitions and parsing code.
+//! Tock Binary Format Header parsing code.
+//!
+//! The layout of the header is defined in the `tock-tbf` library, which host
+//! tools share.
 
 use core::{mem, slice, str};
 
-/// Takes a value and rounds it up to be aligned % 4
-macro_rules! align4 {
-    ($e:expr) => {
-        ($e) + ((4 - (($e) % 4)) % 4)
-    };
-}
-
-/// TBF fields that must be present in all v2 headers.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-crate struct TbfHeaderV2Base {
-    version: u16,
-    header_size: u16,
-    total_size: u32,
-    flags: u32,
-    checksum: u32,
-}
-
-/// Types in TLV structures for each optional block of the header.
-#[repr(u16)]
-#[derive(Clone, Copy, Debug)]
-#[allow(dead_code)]
-crate enum TbfHeaderTypes {
-    TbfHeaderMain = 1,
-    TbfHeaderWriteableFlashRegions = 2,
-    TbfHeaderPackageName = 3,
-    Unused = 5,
-}
+use process::FaultResponse;
+use returncode::ReturnCode;
+use tock_tbf::{self, TbfHeaderStruct, TbfHeaderTypes, TlvIter};
+use tock_tbf::{
+    TbfHeaderV2AppVersion, TbfHeaderV2Base, TbfHeaderV2DriverPermission, TbfHeaderV2FaultPolicy,
+    TbfHeaderV2FixedAddresses, TbfHeaderV2KernelVersion, TbfHeaderV2Main, TbfHeaderV2Priority,
+    TbfHeaderV2StorageSize, TbfHeaderV2TaskQueue, TbfHeaderV2WriteableFlashRegion,
+};
 
-/// The TLV header (T and L).
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-crate struct TbfHeaderTlv {
-    tipe: TbfHeaderTypes,
-    length: u16,
-}
-
-/// The v2 main section for apps.
-///
-/// All apps must have a main section. Without it, the header is considered as
-/// only padding.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-crate struct TbfHeaderV2Main {
-    init_fn_offset: u32,
-    protected_size: u32,
-    minimum_ram_size: u32,
-}
+/// The most Credentials TLVs the kernel will look at in one header. Any more
+/// are treated like other header contents.
+crate const MAX_CREDENTIALS: usize = 4;
 
-/// Writeable flash regions only need an offset and size.
+/// A credential for the app, such as a hash or signature.
 ///
-/// There can be multiple (or zero) flash regions defined, so this is its own
-/// struct.
-#[repr(C)]
+/// The credential covers the whole TBF image except for the header checksum
+/// and the Credentials TLVs themselves.
 #[derive(Clone, Copy, Debug)]
-crate struct TbfHeaderV2WriteableFlashRegion {
-    writeable_flash_region_offset: u32,
-    writeable_flash_region_size: u32,
+crate struct TbfHeaderV2Credentials {
+    /// Which kind of credential this is. See `CredentialsFormat`.
+    crate format: u32,
+    /// The hash or signature.
+    crate data: &'static [u8],
+    /// Offset of the TLV, including its type and length, from the start of the
+    /// header.
+    crate tlv_offset: usize,
+    /// Length of the TLV in flash, including its type, length and padding.
+    crate tlv_length: usize,
 }
 
 /// Single header that can contain all parts of a v2 header.
@@ -69,6 +42,15 @@ crate struct TbfHeaderV2 {
     main: Option<&'static TbfHeaderV2Main>,
     package_name: Option<&'static str>,
     writeable_regions: Option<&'static [TbfHeaderV2WriteableFlashRegion]>,
+    priority: Option<&'static TbfHeaderV2Priority>,
+    fault_policy: Option<&'static TbfHeaderV2FaultPolicy>,
+    credentials: [Option<TbfHeaderV2Credentials>; MAX_CREDENTIALS],
+    permissions: Option<&'static [TbfHeaderV2DriverPermission]>,
+    app_version: Option<&'static TbfHeaderV2AppVersion>,
+    kernel_version: Option<&'static TbfHeaderV2KernelVersion>,
+    fixed_addresses: Option<&'static TbfHeaderV2FixedAddresses>,
+    task_queue: Option<&'static TbfHeaderV2TaskQueue>,
+    storage_size: Option<&'static TbfHeaderV2StorageSize>,
 }
 
 /// Type that represents the fields of the Tock Binary Format header.
@@ -97,8 +79,7 @@ impl TbfHeader {
     crate fn enabled(&self) -> bool {
         match *self {
             TbfHeader::TbfHeaderV2(hd) => {
-                // Bit 1 of flags is the enable/disable bit.
-                hd.base.flags & 0x00000001 == 1
+                hd.base.flags & tock_tbf::FLAG_ENABLED != 0
             }
             TbfHeader::Padding(_) => false,
         }
@@ -176,6 +157,161 @@ impl TbfHeader {
             _ => (0, 0),
         }
     }
+
+    /// Get the scheduling priority of the app, if it specified one.
+    crate fn get_priority(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.priority.map(|p| p.priority),
+            _ => None,
+        }
+    }
+
+    /// Get how the app asked the kernel to respond to its faults, if it
+    /// specified a valid response.
+    crate fn get_fault_response(&self) -> Option<FaultResponse> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.fault_policy.and_then(|p| match p.response {
+                tock_tbf::FAULT_RESPONSE_PANIC => Some(FaultResponse::Panic),
+                tock_tbf::FAULT_RESPONSE_RESTART => Some(FaultResponse::Restart),
+                tock_tbf::FAULT_RESPONSE_STOP => Some(FaultResponse::Stop),
+                _ => None,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Get the version of the app, if it has one.
+    crate fn get_app_version(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.app_version.map(|v| v.version),
+            _ => None,
+        }
+    }
+
+    /// Get the oldest kernel version the app works with as `(major, minor)`,
+    /// if it specified one.
+    crate fn get_kernel_version(&self) -> Option<(u16, u16)> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.kernel_version.map(|v| (v.major, v.minor)),
+            _ => None,
+        }
+    }
+
+    /// Get the address the app's RAM must start at, if it is not position
+    /// independent.
+    crate fn get_fixed_address_ram(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .fixed_addresses
+                .map(|f| f.start_process_ram)
+                .filter(|&address| address != tock_tbf::FIXED_ADDRESS_NONE),
+            _ => None,
+        }
+    }
+
+    /// Get the address the app's TBF image must start at in flash, if it is
+    /// not position independent.
+    crate fn get_fixed_address_flash(&self) -> Option<u32> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .fixed_addresses
+                .map(|f| f.start_process_flash)
+                .filter(|&address| address != tock_tbf::FIXED_ADDRESS_NONE),
+            _ => None,
+        }
+    }
+
+    /// Get how many tasks the app wants queued, if it asked for a depth.
+    crate fn get_task_queue_depth(&self) -> Option<usize> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.task_queue.map(|q| q.depth as usize),
+            _ => None,
+        }
+    }
+
+    /// Get how many bytes of nonvolatile storage the app asked for, if it has
+    /// a Storage Size TLV.
+    crate fn get_storage_size(&self) -> Option<usize> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.storage_size.map(|s| s.size as usize),
+            _ => None,
+        }
+    }
+
+    /// Get whether the app wants upcalls coalesced when its task queue is
+    /// full, if it has a Task Queue TLV.
+    crate fn get_task_queue_coalesce(&self) -> Option<bool> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd
+                .task_queue
+                .map(|q| q.flags & tock_tbf::TASK_QUEUE_COALESCE != 0),
+            _ => None,
+        }
+    }
+
+    /// Get the Credentials TLVs in the header, in the order they appear.
+    crate fn get_credentials(&self) -> &[Option<TbfHeaderV2Credentials>] {
+        match *self {
+            TbfHeader::TbfHeaderV2(ref hd) => &hd.credentials,
+            _ => &[],
+        }
+    }
+
+    /// Check whether the app may use syscall driver `driver_num`, and if
+    /// `command_num` is given, whether it may call that command.
+    ///
+    /// Apps without a Permissions TLV may use every driver. Otherwise drivers
+    /// that are not listed return `ENODEVICE`, as if they did not exist, and
+    /// commands that are not allowed return `ENOSUPPORT`.
+    crate fn check_driver_permission(
+        &self,
+        driver_num: usize,
+        command_num: Option<usize>,
+    ) -> ReturnCode {
+        let permissions = match *self {
+            TbfHeader::TbfHeaderV2(hd) => match hd.permissions {
+                Some(permissions) => permissions,
+                None => return ReturnCode::SUCCESS,
+            },
+            _ => return ReturnCode::SUCCESS,
+        };
+
+        let mut driver_listed = false;
+        for permission in permissions
+            .iter()
+            .filter(|p| p.driver_number as usize == driver_num)
+        {
+            driver_listed = true;
+            match command_num {
+                None => return ReturnCode::SUCCESS,
+                Some(command_num) => {
+                    if command_num / 32 == permission.offset as usize
+                        && permission.allowed_commands & (1 << (command_num % 32)) != 0
+                    {
+                        return ReturnCode::SUCCESS;
+                    }
+                }
+            }
+        }
+
+        if driver_listed {
+            ReturnCode::ENOSUPPORT
+        } else {
+            ReturnCode::ENODEVICE
+        }
+    }
+
+    /// Get how many times the app may be restarted after faulting, if it set
+    /// a limit.
+    crate fn get_max_restarts(&self) -> Option<usize> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.fault_policy.and_then(|p| match p.max_restarts {
+                0 => None,
+                max_restarts => Some(max_restarts as usize),
+            }),
+            _ => None,
+        }
+    }
 }
 
 /// Converts a pointer to memory to a TbfHeader struct
@@ -187,55 +323,34 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
     let version = *(address as *const u16);
 
     match version {
-        2 => {
-            let tbf_header_base = &*(address as *const TbfHeaderV2Base);
+        tock_tbf::HEADER_VERSION => {
+            let tbf_header_base = TbfHeaderV2Base::from_bytes(slice::from_raw_parts(
+                address,
+                mem::size_of::<TbfHeaderV2Base>(),
+            ))?;
 
             // Some sanity checking. Make sure the header isn't longer than the
             // total app. Make sure the total app fits inside a reasonable size
             // of flash.
-            if tbf_header_base.header_size as u32 >= tbf_header_base.total_size
-                || tbf_header_base.total_size > 0x010000000
+            if (tbf_header_base.header_size as usize) < mem::size_of::<TbfHeaderV2Base>()
+                || tbf_header_base.header_size as u32 >= tbf_header_base.total_size
+                || tbf_header_base.total_size > tock_tbf::MAX_TOTAL_SIZE
             {
                 return None;
             }
 
             // Calculate checksum. The checksum is the XOR of each 4 byte word
             // in the header.
-            let mut chunks = tbf_header_base.header_size as usize / 4;
-            let leftover_bytes = tbf_header_base.header_size as usize % 4;
-            if leftover_bytes != 0 {
-                chunks += 1;
-            }
-            let mut checksum: u32 = 0;
-            let header = slice::from_raw_parts(address as *const u32, chunks);
-            for (i, chunk) in header.iter().enumerate() {
-                if i == 3 {
-                    // Skip the checksum field.
-                } else if i == chunks - 1 && leftover_bytes != 0 {
-                    // In this case, we don't want to use the entire word.
-                    checksum ^= *chunk & (0xFFFFFFFF >> (4 - leftover_bytes));
-                } else {
-                    checksum ^= *chunk;
-                }
-            }
-
-            if checksum != tbf_header_base.checksum {
+            let header = slice::from_raw_parts(address, tbf_header_base.header_size as usize);
+            if tock_tbf::checksum(header) != tbf_header_base.checksum {
                 return None;
             }
 
-            // Skip the base of the header.
-            let mut offset = mem::size_of::<TbfHeaderV2Base>() as isize;
-            let mut remaining_length = tbf_header_base.header_size as usize - offset as usize;
-
             // Check if this is a real app or just padding. Padding apps are
             // identified by not having any options.
-            if remaining_length == 0 {
+            if header.len() == mem::size_of::<TbfHeaderV2Base>() {
                 // Just padding.
-                if checksum == tbf_header_base.checksum {
-                    Some(TbfHeader::Padding(tbf_header_base))
-                } else {
-                    None
-                }
+                Some(TbfHeader::Padding(tbf_header_base))
             } else {
                 // This is an actual app.
 
@@ -246,72 +361,88 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
                     &'static [TbfHeaderV2WriteableFlashRegion],
                 > = None;
                 let mut app_name_str = "";
-
-                // Loop through the header looking for known options.
-                while remaining_length > mem::size_of::<TbfHeaderTlv>() {
-                    let tbf_tlv_header = &*(address.offset(offset) as *const TbfHeaderTlv);
-
-                    remaining_length -= mem::size_of::<TbfHeaderTlv>();
-                    offset += mem::size_of::<TbfHeaderTlv>() as isize;
-
-                    // Only parse known TLV blocks. There is no type 0.
-                    if (tbf_tlv_header.tipe as u16) < TbfHeaderTypes::Unused as u16
-                        && (tbf_tlv_header.tipe as u16) > 0
-                    {
-                        // This lets us skip unknown header types.
-
-                        match tbf_tlv_header.tipe {
-                            TbfHeaderTypes::TbfHeaderMain =>
-                            /* Main */
-                            {
-                                if remaining_length >= mem::size_of::<TbfHeaderV2Main>()
-                                    && tbf_tlv_header.length as usize
-                                        == mem::size_of::<TbfHeaderV2Main>()
-                                {
-                                    let tbf_main =
-                                        &*(address.offset(offset) as *const TbfHeaderV2Main);
-                                    main_pointer = Some(tbf_main);
-                                }
-                            }
-                            TbfHeaderTypes::TbfHeaderWriteableFlashRegions =>
-                            /* Writeable Flash Regions */
-                            {
-                                // Length must be a multiple of the size of a region definition.
-                                if tbf_tlv_header.length as usize
-                                    % mem::size_of::<TbfHeaderV2WriteableFlashRegion>()
-                                    == 0
-                                {
-                                    let number_regions = tbf_tlv_header.length as usize
-                                        / mem::size_of::<TbfHeaderV2WriteableFlashRegion>();
-                                    let region_start = &*(address.offset(offset)
-                                        as *const TbfHeaderV2WriteableFlashRegion);
-                                    let regions =
-                                        slice::from_raw_parts(region_start, number_regions);
-                                    wfr_pointer = Some(regions);
-                                }
-                            }
-                            TbfHeaderTypes::TbfHeaderPackageName =>
-                            /* Package Name */
+                let mut priority_pointer: Option<&TbfHeaderV2Priority> = None;
+                let mut fault_policy_pointer: Option<&TbfHeaderV2FaultPolicy> = None;
+                let mut credentials: [Option<TbfHeaderV2Credentials>; MAX_CREDENTIALS] =
+                    [None; MAX_CREDENTIALS];
+                let mut credentials_count = 0;
+                let mut permissions_pointer: Option<
+                    &'static [TbfHeaderV2DriverPermission],
+                > = None;
+                let mut app_version_pointer: Option<&TbfHeaderV2AppVersion> = None;
+                let mut kernel_version_pointer: Option<&TbfHeaderV2KernelVersion> = None;
+                let mut fixed_addresses_pointer: Option<&TbfHeaderV2FixedAddresses> = None;
+                let mut task_queue_pointer: Option<&TbfHeaderV2TaskQueue> = None;
+                let mut storage_size_pointer: Option<&TbfHeaderV2StorageSize> = None;
+
+                // Loop through the header looking for known options. Unknown
+                // types are skipped, as are known types with the wrong length.
+                for tlv in TlvIter::new(header) {
+                    match TbfHeaderTypes::from_u16(tlv.tipe) {
+                        Some(TbfHeaderTypes::TbfHeaderMain) => {
+                            main_pointer = TbfHeaderV2Main::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderWriteableFlashRegions) => {
+                            // Length must be a multiple of the size of a region definition.
+                            wfr_pointer =
+                                TbfHeaderV2WriteableFlashRegion::slice_from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderPackageName) => {
+                            let _ = str::from_utf8(tlv.data).map(|name_str| {
+                                app_name_str = name_str;
+                            });
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderPriority) => {
+                            priority_pointer = TbfHeaderV2Priority::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderFaultPolicy) => {
+                            fault_policy_pointer = TbfHeaderV2FaultPolicy::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderCredentials) => {
+                            if tlv.data.len() >= mem::size_of::<u32>()
+                                && credentials_count < MAX_CREDENTIALS
                             {
-                                if remaining_length >= tbf_tlv_header.length as usize {
-                                    let package_name_byte_array = slice::from_raw_parts(
-                                        address.offset(offset),
-                                        tbf_tlv_header.length as usize,
-                                    );
-                                    let _ =
-                                        str::from_utf8(package_name_byte_array).map(|name_str| {
-                                            app_name_str = name_str;
-                                        });
-                                }
+                                let (format, data) = tlv.data.split_at(mem::size_of::<u32>());
+                                credentials[credentials_count] = Some(TbfHeaderV2Credentials {
+                                    format: u32::from(format[0])
+                                        | u32::from(format[1]) << 8
+                                        | u32::from(format[2]) << 16
+                                        | u32::from(format[3]) << 24,
+                                    data: data,
+                                    tlv_offset: tlv.offset,
+                                    tlv_length: tlv.total_length,
+                                });
+                                credentials_count += 1;
                             }
-                            TbfHeaderTypes::Unused => {}
                         }
+                        Some(TbfHeaderTypes::TbfHeaderPermissions) => {
+                            // Length must be a multiple of the size of a
+                            // permission. If it is not, the app gets no
+                            // permissions at all rather than all of them.
+                            permissions_pointer = Some(
+                                TbfHeaderV2DriverPermission::slice_from_bytes(tlv.data)
+                                    .unwrap_or(&[]),
+                            );
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderAppVersion) => {
+                            app_version_pointer = TbfHeaderV2AppVersion::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderKernelVersion) => {
+                            kernel_version_pointer =
+                                TbfHeaderV2KernelVersion::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderFixedAddresses) => {
+                            fixed_addresses_pointer =
+                                TbfHeaderV2FixedAddresses::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderTaskQueue) => {
+                            task_queue_pointer = TbfHeaderV2TaskQueue::from_bytes(tlv.data);
+                        }
+                        Some(TbfHeaderTypes::TbfHeaderStorageSize) => {
+                            storage_size_pointer = TbfHeaderV2StorageSize::from_bytes(tlv.data);
+                        }
+                        None => {}
                     }
-
-                    // All TLV blocks are padded to 4 bytes, so we need to skip
-                    // more if the length is not a multiple of 4.
-                    remaining_length -= align4!(tbf_tlv_header.length) as usize;
-                    offset += align4!(tbf_tlv_header.length) as isize;
                 }
 
                 let tbf_header = TbfHeaderV2 {
@@ -319,6 +450,15 @@ crate unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfH
                     main: main_pointer,
                     package_name: Some(app_name_str),
                     writeable_regions: wfr_pointer,
+                    priority: priority_pointer,
+                    fault_policy: fault_policy_pointer,
+                    credentials: credentials,
+                    permissions: permissions_pointer,
+                    app_version: app_version_pointer,
+                    kernel_version: kernel_version_pointer,
+                    fixed_addresses: fixed_addresses_pointer,
+                    task_queue: task_queue_pointer,
+                    storage_size: storage_size_pointer,
                 };
 
                 Some(TbfHeader::TbfHeaderV2(tbf_header))
@@ -0,0 +1,50 @@
+//! Tock Binary Format (TBF) definitions.
+//!
+//! A TBF image is a header followed by the app binary. The header starts with
+//! a fixed base header, followed by type-length-value (TLV) elements. This
+//! crate defines the layout of the base header and of each TLV element, along
+//! with the checksum and a way to walk the TLV elements in a header. Both the
+//! kernel and host tools use it, so there is one definition of the format.
+//!
+//! The format is documented in `doc/TockBinaryFormat.md`.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! use tock_tbf::{TbfHeaderStruct, TbfHeaderTypes, TbfHeaderV2Priority, TlvIter};
+//!
+//! // Base header followed by a Priority TLV with priority 3.
+//! let mut header = [
+//!     2, 0, 24, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 4, 0, 4, 0, 3, 0, 0, 0,
+//! ];
+//! let checksum = tock_tbf::checksum(&header);
+//! header[12] = checksum as u8;
+//! header[13] = (checksum >> 8) as u8;
+//!
+//! for tlv in TlvIter::new(&header) {
+//!     assert_eq!(TbfHeaderTypes::from_u16(tlv.tipe), Some(TbfHeaderTypes::TbfHeaderPriority));
+//!     assert_eq!(TbfHeaderV2Priority::read(tlv.data).unwrap().priority, 3);
+//! }
+//! ```
+
+#![no_std]
+
+#[cfg(target_endian = "big")]
+compile_error!("TBF headers are little endian, and are read directly from memory.");
+
+mod parse;
+mod types;
+
+pub use parse::{align4, checksum, Tlv, TlvIter};
+pub use types::{TbfHeaderStruct, TbfHeaderTlv, TbfHeaderTypes};
+pub use types::{
+    TbfHeaderV2AppVersion, TbfHeaderV2Base, TbfHeaderV2DriverPermission, TbfHeaderV2FaultPolicy,
+    TbfHeaderV2FixedAddresses, TbfHeaderV2KernelVersion, TbfHeaderV2Main, TbfHeaderV2Priority,
+    TbfHeaderV2StorageSize, TbfHeaderV2TaskQueue, TbfHeaderV2WriteableFlashRegion,
+};
+pub use types::{
+    CREDENTIALS_ECDSA_P256, CREDENTIALS_ED25519, CREDENTIALS_SHA256, FAULT_RESPONSE_PANIC,
+    FAULT_RESPONSE_RESTART, FAULT_RESPONSE_STOP, FIXED_ADDRESS_NONE, FLAG_ENABLED, FLAG_STICKY,
+    HEADER_VERSION, MAX_TOTAL_SIZE, TASK_QUEUE_COALESCE,
+};
@@ -0,0 +1,166 @@
+//! Checksums and walking the TLV elements of a header.
+
+use core::mem;
+
+use types::{TbfHeaderStruct, TbfHeaderTlv, TbfHeaderV2Base};
+
+/// Round `length` up to a multiple of four. TLV elements are padded to this.
+pub fn align4(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+/// Compute the checksum of `header`, the bytes of a whole TBF header.
+///
+/// The checksum is the XOR of each little endian 4 byte word in the header,
+/// except for the word holding the checksum itself. If the header length is
+/// not a multiple of four, the last word only includes the bytes that are in
+/// the header.
+pub fn checksum(header: &[u8]) -> u32 {
+    let mut checksum = 0;
+    for (i, chunk) in header.chunks(4).enumerate() {
+        if i == 3 {
+            // Skip the checksum field.
+            continue;
+        }
+        let mut word = 0;
+        for (j, byte) in chunk.iter().enumerate() {
+            word |= (*byte as u32) << (8 * j);
+        }
+        checksum ^= word;
+    }
+    checksum
+}
+
+/// One TLV element of a header.
+#[derive(Clone, Copy, Debug)]
+pub struct Tlv<'a> {
+    /// The type of the element. See `TbfHeaderTypes`.
+    pub tipe: u16,
+    /// The value of the element, without padding.
+    pub data: &'a [u8],
+    /// Offset of the element, starting at its type, from the start of the
+    /// header.
+    pub offset: usize,
+    /// Length of the element in the header, including its type, length and
+    /// padding.
+    pub total_length: usize,
+}
+
+/// Iterator over the TLV elements in a header.
+///
+/// Iteration stops at the end of the header, or at an element that claims to
+/// extend past the end of the header.
+pub struct TlvIter<'a> {
+    header: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> TlvIter<'a> {
+    /// Iterate over the TLV elements in `header`, the bytes of a whole TBF
+    /// header including the base header.
+    pub fn new(header: &'a [u8]) -> TlvIter<'a> {
+        TlvIter {
+            header: header,
+            offset: mem::size_of::<TbfHeaderV2Base>(),
+        }
+    }
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Tlv<'a>;
+
+    fn next(&mut self) -> Option<Tlv<'a>> {
+        let tlv_header_size = mem::size_of::<TbfHeaderTlv>();
+        if self.offset + tlv_header_size > self.header.len() {
+            return None;
+        }
+        let tlv_header =
+            TbfHeaderTlv::read(&self.header[self.offset..self.offset + tlv_header_size])?;
+
+        let data_start = self.offset + tlv_header_size;
+        let data_end = data_start + tlv_header.length as usize;
+        if data_end > self.header.len() {
+            return None;
+        }
+
+        let tlv = Tlv {
+            tipe: tlv_header.tipe,
+            data: &self.header[data_start..data_end],
+            offset: self.offset,
+            total_length: tlv_header_size + align4(tlv_header.length as usize),
+        };
+        self.offset += tlv.total_length;
+        Some(tlv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{align4, checksum, TlvIter};
+
+    /// A base header with a header size of 32, followed by `tlvs`.
+    fn header_with(tlvs: &[u8]) -> [u8; 32] {
+        let mut header = [0; 32];
+        header[..16].copy_from_slice(&[2, 0, 32, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+        header[16..16 + tlvs.len()].copy_from_slice(tlvs);
+        header
+    }
+
+    #[test]
+    fn aligns_to_words() {
+        assert_eq!(align4(0), 0);
+        assert_eq!(align4(1), 4);
+        assert_eq!(align4(4), 4);
+        assert_eq!(align4(13), 16);
+    }
+
+    #[test]
+    fn checksum_skips_checksum_word() {
+        let mut header = [
+            2, 0, 20, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0x78, 0x56, 0x34, 0x12,
+        ];
+        let expected = 0x00140002 ^ 0x00000100 ^ 0x00000001 ^ 0x12345678;
+        assert_eq!(checksum(&header), expected);
+
+        header[12] = 0xAB;
+        header[15] = 0xCD;
+        assert_eq!(checksum(&header), expected);
+    }
+
+    #[test]
+    fn checksum_of_partial_last_word() {
+        let header = [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x11, 0x22];
+        assert_eq!(checksum(&header), 0x00000001 ^ 0x00002211);
+    }
+
+    #[test]
+    fn iterates_padded_tlvs() {
+        let header = header_with(&[3, 0, 5, 0, b'b', b'l', b'i', b'n', b'k', 0, 0, 0, 4, 0, 0, 0]);
+        let mut tlvs = TlvIter::new(&header);
+
+        let name = tlvs.next().unwrap();
+        assert_eq!(name.tipe, 3);
+        assert_eq!(name.data, b"blink");
+        assert_eq!(name.offset, 16);
+        assert_eq!(name.total_length, 12);
+
+        let empty = tlvs.next().unwrap();
+        assert_eq!(empty.tipe, 4);
+        assert_eq!(empty.data, &[]);
+        assert_eq!(empty.offset, 28);
+        assert!(tlvs.next().is_none());
+    }
+
+    #[test]
+    fn stops_at_malformed_tlv() {
+        // The second element claims more data than the header has left.
+        let header = header_with(&[4, 0, 4, 0, 3, 0, 0, 0, 3, 0, 9, 0, 1, 2, 3, 4]);
+        let mut tlvs = TlvIter::new(&header);
+        assert_eq!(tlvs.next().unwrap().tipe, 4);
+        assert!(tlvs.next().is_none());
+
+        // Too few bytes are left for a TLV header.
+        assert!(TlvIter::new(&header[..18]).next().is_none());
+        assert!(TlvIter::new(&header[..8]).next().is_none());
+    }
+}
@@ -0,0 +1,310 @@
+//! Layout of the TBF header and its TLV elements.
+
+use core::{mem, slice};
+
+/// The only header version there is.
+pub const HEADER_VERSION: u16 = 2;
+
+/// The kernel does not consider TBF images larger than this valid.
+pub const MAX_TOTAL_SIZE: u32 = 0x10000000;
+
+/// Flag for apps the kernel should start.
+pub const FLAG_ENABLED: u32 = 1 << 0;
+/// Flag for apps that tools should require confirmation to erase.
+pub const FLAG_STICKY: u32 = 1 << 1;
+
+/// `TbfHeaderV2FaultPolicy::response` for panicking the kernel.
+pub const FAULT_RESPONSE_PANIC: u32 = 0;
+/// `TbfHeaderV2FaultPolicy::response` for restarting the app.
+pub const FAULT_RESPONSE_RESTART: u32 = 1;
+/// `TbfHeaderV2FaultPolicy::response` for stopping the app.
+pub const FAULT_RESPONSE_STOP: u32 = 2;
+
+/// Credentials format of a SHA-256 hash.
+pub const CREDENTIALS_SHA256: u32 = 1;
+/// Credentials format of an ECDSA P-256 signature.
+pub const CREDENTIALS_ECDSA_P256: u32 = 2;
+/// Credentials format of an Ed25519 signature.
+pub const CREDENTIALS_ED25519: u32 = 3;
+
+/// Address in `TbfHeaderV2FixedAddresses` meaning the app can be anywhere.
+pub const FIXED_ADDRESS_NONE: u32 = 0xFFFFFFFF;
+
+/// Flag in `TbfHeaderV2TaskQueue` for replacing a queued upcall with a newer
+/// one from the same driver and subscribe number when the queue is full.
+pub const TASK_QUEUE_COALESCE: u16 = 1 << 0;
+
+/// Structs that make up a TBF header, and that can be read directly from the
+/// bytes of a header.
+///
+/// This trait is unsafe to implement because the structs are created from
+/// arbitrary bytes. Implementors must be `repr(C)`, have no padding, and be
+/// valid for every bit pattern.
+pub unsafe trait TbfHeaderStruct: Copy {
+    /// View `bytes` as this struct, if it is exactly the size of the struct
+    /// and suitably aligned.
+    fn from_bytes(bytes: &[u8]) -> Option<&Self> {
+        if bytes.len() == mem::size_of::<Self>()
+            && bytes.as_ptr() as usize % mem::align_of::<Self>() == 0
+        {
+            Some(unsafe { &*(bytes.as_ptr() as *const Self) })
+        } else {
+            None
+        }
+    }
+
+    /// View `bytes` as an array of this struct, if its length is a multiple
+    /// of the size of the struct and it is suitably aligned.
+    fn slice_from_bytes(bytes: &[u8]) -> Option<&[Self]> {
+        if bytes.len() % mem::size_of::<Self>() == 0
+            && bytes.as_ptr() as usize % mem::align_of::<Self>() == 0
+        {
+            Some(unsafe {
+                slice::from_raw_parts(
+                    bytes.as_ptr() as *const Self,
+                    bytes.len() / mem::size_of::<Self>(),
+                )
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Copy this struct out of `bytes`, if it is exactly the size of the
+    /// struct. `bytes` does not need to be aligned.
+    fn read(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() == mem::size_of::<Self>() {
+            Some(unsafe { (bytes.as_ptr() as *const Self).read_unaligned() })
+        } else {
+            None
+        }
+    }
+
+    /// The bytes of this struct as they appear in a header.
+    fn as_bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self as *const Self as *const u8, mem::size_of::<Self>()) }
+    }
+}
+
+/// TBF fields that must be present in all v2 headers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2Base {
+    pub version: u16,
+    pub header_size: u16,
+    pub total_size: u32,
+    pub flags: u32,
+    pub checksum: u32,
+}
+
+/// Types in TLV structures for each optional block of the header.
+#[repr(u16)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TbfHeaderTypes {
+    TbfHeaderMain = 1,
+    TbfHeaderWriteableFlashRegions = 2,
+    TbfHeaderPackageName = 3,
+    TbfHeaderPriority = 4,
+    TbfHeaderFaultPolicy = 5,
+    TbfHeaderCredentials = 6,
+    TbfHeaderPermissions = 7,
+    TbfHeaderAppVersion = 8,
+    TbfHeaderKernelVersion = 9,
+    TbfHeaderFixedAddresses = 10,
+    TbfHeaderTaskQueue = 11,
+    TbfHeaderStorageSize = 12,
+}
+
+impl TbfHeaderTypes {
+    /// The TLV type with number `tipe`, if it is a known type.
+    pub fn from_u16(tipe: u16) -> Option<TbfHeaderTypes> {
+        match tipe {
+            1 => Some(TbfHeaderTypes::TbfHeaderMain),
+            2 => Some(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
+            3 => Some(TbfHeaderTypes::TbfHeaderPackageName),
+            4 => Some(TbfHeaderTypes::TbfHeaderPriority),
+            5 => Some(TbfHeaderTypes::TbfHeaderFaultPolicy),
+            6 => Some(TbfHeaderTypes::TbfHeaderCredentials),
+            7 => Some(TbfHeaderTypes::TbfHeaderPermissions),
+            8 => Some(TbfHeaderTypes::TbfHeaderAppVersion),
+            9 => Some(TbfHeaderTypes::TbfHeaderKernelVersion),
+            10 => Some(TbfHeaderTypes::TbfHeaderFixedAddresses),
+            11 => Some(TbfHeaderTypes::TbfHeaderTaskQueue),
+            12 => Some(TbfHeaderTypes::TbfHeaderStorageSize),
+            _ => None,
+        }
+    }
+}
+
+/// The TLV header (T and L).
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderTlv {
+    pub tipe: u16,
+    pub length: u16,
+}
+
+/// The v2 main section for apps.
+///
+/// All apps must have a main section. Without it, the header is considered as
+/// only padding.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2Main {
+    pub init_fn_offset: u32,
+    pub protected_size: u32,
+    pub minimum_ram_size: u32,
+}
+
+/// Writeable flash regions only need an offset and size.
+///
+/// There can be multiple (or zero) flash regions defined, so this is its own
+/// struct.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2WriteableFlashRegion {
+    pub writeable_flash_region_offset: u32,
+    pub writeable_flash_region_size: u32,
+}
+
+/// Scheduling priority hint for the app.
+///
+/// Lower values are higher priority. Schedulers that do not use priorities
+/// ignore this.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2Priority {
+    pub priority: u32,
+}
+
+/// How the kernel should respond when the app faults.
+///
+/// This overrides the fault policy the board uses for apps. `response` is one
+/// of the `FAULT_RESPONSE_*` constants. When restarting, the app is stopped
+/// instead once it has been restarted `max_restarts` times, unless
+/// `max_restarts` is 0.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2FaultPolicy {
+    pub response: u32,
+    pub max_restarts: u32,
+}
+
+/// Permission for the app to use a syscall driver.
+///
+/// `allowed_commands` is a bitmask of the commands the app may call, where bit
+/// `i` allows command number `32 * offset + i`. A driver can be listed more
+/// than once with different offsets to allow higher command numbers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2DriverPermission {
+    pub driver_number: u32,
+    pub offset: u32,
+    pub allowed_commands: u32,
+}
+
+/// Version of the app, for telling apart builds of the same app. Higher is
+/// newer.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2AppVersion {
+    pub version: u32,
+}
+
+/// Oldest kernel version the app works with.
+///
+/// The app works with kernels that have the same major version and at least
+/// this minor version.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2KernelVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+/// Addresses the app must be loaded at, for apps that are not position
+/// independent. `FIXED_ADDRESS_NONE` means the app has no requirement.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2FixedAddresses {
+    pub start_process_ram: u32,
+    pub start_process_flash: u32,
+}
+
+/// How many upcalls the kernel queues for the app, and what it does when the
+/// queue is full.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2TaskQueue {
+    pub depth: u16,
+    pub flags: u16,
+}
+
+/// How many bytes of nonvolatile storage the app needs for its own data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct TbfHeaderV2StorageSize {
+    pub size: u32,
+}
+
+unsafe impl TbfHeaderStruct for TbfHeaderV2Base {}
+unsafe impl TbfHeaderStruct for TbfHeaderTlv {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2Main {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2WriteableFlashRegion {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2Priority {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2FaultPolicy {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2DriverPermission {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2AppVersion {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2KernelVersion {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2FixedAddresses {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2TaskQueue {}
+unsafe impl TbfHeaderStruct for TbfHeaderV2StorageSize {}
+
+#[cfg(test)]
+mod tests {
+    use super::{TbfHeaderStruct, TbfHeaderTypes, TbfHeaderV2Base, TbfHeaderV2Main};
+
+    #[test]
+    fn reads_structs_from_bytes() {
+        let bytes = [2, 0, 16, 0, 0, 1, 0, 0, 1, 0, 0, 0, 0x78, 0x56, 0x34, 0x12, 0];
+        let base = TbfHeaderV2Base::read(&bytes[..16]).unwrap();
+        assert_eq!(base.version, 2);
+        assert_eq!(base.header_size, 16);
+        assert_eq!(base.total_size, 256);
+        assert_eq!(base.flags, 1);
+        assert_eq!(base.checksum, 0x12345678);
+        assert_eq!(base.as_bytes(), &bytes[..16]);
+
+        // Unaligned bytes can be read, but not viewed in place.
+        let mut unaligned = [0; 17];
+        unaligned[1..].copy_from_slice(&bytes[..16]);
+        assert_eq!(TbfHeaderV2Base::read(&unaligned[1..]).unwrap().total_size, 256);
+        assert!(TbfHeaderV2Base::read(&bytes).is_none());
+        assert!(TbfHeaderV2Base::read(&bytes[..15]).is_none());
+    }
+
+    #[test]
+    fn views_structs_in_place() {
+        let words: [u32; 6] = [0x29, 0, 4096, 1, 2, 3];
+        let mains = TbfHeaderV2Main::slice_from_bytes(unsafe {
+            ::core::slice::from_raw_parts(words.as_ptr() as *const u8, 24)
+        })
+        .unwrap();
+        assert_eq!(mains.len(), 2);
+        assert_eq!(mains[0].minimum_ram_size, 4096);
+        assert_eq!(mains[1].init_fn_offset, 1);
+
+        let main = TbfHeaderV2Main::from_bytes(mains[1].as_bytes()).unwrap();
+        assert_eq!(main.minimum_ram_size, 3);
+        assert!(TbfHeaderV2Main::from_bytes(&mains[0].as_bytes()[..8]).is_none());
+    }
+
+    #[test]
+    fn converts_tlv_types() {
+        for tipe in 1..13 {
+            assert_eq!(TbfHeaderTypes::from_u16(tipe).unwrap() as u16, tipe);
+        }
+        assert_eq!(TbfHeaderTypes::from_u16(0), None);
+        assert_eq!(TbfHeaderTypes::from_u16(13), None);
+    }
+}
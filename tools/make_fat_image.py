@@ -0,0 +1,148 @@
+#!/usr/bin/env python3
+"""Make the FAT disk images the FAT capsule is tested with.
+
+The images hold a volume label, a `README.TXT` file in the root directory, and
+a `LOGS` directory with a long name entry, a deleted entry and a
+`HELLO.TXT` file that spans several clusters. The sectors of zeros at the end
+of the image are left out, so that the images stay small; tests fill them back
+in from the sector count the image was made with.
+
+    tools/make_fat_image.py 16 20000 4 63 capsules/src/test/fat16.img
+    tools/make_fat_image.py 32 66600 1 0 capsules/src/test/fat32.img
+
+The arguments are the FAT type, the number of sectors of the disk, the sectors
+per cluster, and the sector the file system starts at. If that is not 0, the
+first sector is a master boot record with one partition.
+"""
+
+import struct
+import sys
+
+SECTOR_SIZE = 512
+
+README = b'Disk image for the tests of the FAT capsule.\n'
+HELLO = b'hello from the host\n' * 120
+
+
+def entry(name, attributes, cluster, size):
+    e = bytearray(32)
+    e[0:11] = name
+    e[11] = attributes
+    struct.pack_into('<H', e, 20, cluster >> 16)
+    struct.pack_into('<HI', e, 26, cluster & 0xffff, size)
+    return e
+
+
+def make(fat32, sectors, sectors_per_cluster, start):
+    volume_sectors = sectors - start
+    entry_len = 4 if fat32 else 2
+    reserved = 32 if fat32 else 4
+    num_fats = 2
+    root_entries = 0 if fat32 else 512
+    root_sectors = root_entries * 32 // SECTOR_SIZE
+    fat_sectors = 1
+    while True:
+        clusters = (volume_sectors - reserved - num_fats * fat_sectors -
+                    root_sectors) // sectors_per_cluster
+        needed = -(-(clusters + 2) * entry_len // SECTOR_SIZE)
+        if needed <= fat_sectors:
+            break
+        fat_sectors = needed
+    if (clusters >= 65525) != fat32 or clusters < 4085:
+        sys.exit('%d clusters do not make a FAT%d file system' %
+                 (clusters, 32 if fat32 else 16))
+
+    image = bytearray(sectors * SECTOR_SIZE)
+
+    def put(sector, data):
+        image[sector * SECTOR_SIZE:sector * SECTOR_SIZE + len(data)] = data
+
+    small = volume_sectors < 0x10000 and not fat32
+    boot = bytearray(SECTOR_SIZE)
+    boot[0:11] = b'\xeb\x3c\x90TOCKTEST'
+    struct.pack_into('<HBHBHHBHHHII', boot, 11, SECTOR_SIZE,
+                     sectors_per_cluster, reserved, num_fats, root_entries,
+                     volume_sectors if small else 0, 0xf8,
+                     0 if fat32 else fat_sectors, 63, 255, start,
+                     0 if small else volume_sectors)
+    if fat32:
+        # The root directory is cluster 2, the FSInfo sector is sector 1 and
+        # the backup boot sector is sector 6.
+        struct.pack_into('<IHHIHH', boot, 36, fat_sectors, 0, 0, 2, 1, 6)
+    boot[510:512] = b'\x55\xaa'
+    put(start, boot)
+    if fat32:
+        info = bytearray(SECTOR_SIZE)
+        struct.pack_into('<I', info, 0, 0x41615252)
+        struct.pack_into('<III', info, 484, 0x61417272, 0xffffffff,
+                         0xffffffff)
+        info[510:512] = b'\x55\xaa'
+        put(start + 1, info)
+        put(start + 6, boot)
+    if start != 0:
+        mbr = bytearray(SECTOR_SIZE)
+        mbr[446 + 4] = 0x0c if fat32 else 0x06
+        struct.pack_into('<II', mbr, 446 + 8, start, volume_sectors)
+        mbr[510:512] = b'\x55\xaa'
+        put(0, mbr)
+
+    end_of_chain = 0x0fffffff if fat32 else 0xffff
+    fat = [0] * (clusters + 2)
+    fat[0] = 0x0ffffff8 if fat32 else 0xfff8
+    fat[1] = end_of_chain
+    data_start = start + reserved + num_fats * fat_sectors + root_sectors
+    cluster_bytes = sectors_per_cluster * SECTOR_SIZE
+
+    def allocate(data):
+        """Store `data` in new clusters, and return the first one."""
+        first = fat.index(0, 2)
+        count = max(1, -(-len(data) // cluster_bytes))
+        for i in range(count):
+            cluster = first + i
+            fat[cluster] = cluster + 1 if i + 1 < count else end_of_chain
+            put(data_start + (cluster - 2) * sectors_per_cluster,
+                data[i * cluster_bytes:(i + 1) * cluster_bytes])
+        return first
+
+    # The FAT32 root directory is cluster 2, filled in below.
+    root_cluster = allocate(b'') if fat32 else 0
+    readme = allocate(README)
+    hello = allocate(HELLO)
+    logs = fat.index(0, 2)
+    allocate(b''.join([
+        entry(b'.          ', 0x10, logs, 0),
+        entry(b'..         ', 0x10, 0, 0),
+        entry(b'HELLO   TXT', 0x20, hello, len(HELLO)),
+        entry(b'\xe5OLD    TXT', 0x20, 0, 0),
+    ]))
+
+    long_name = bytearray(32)
+    long_name[0] = 0x41
+    long_name[1:11] = 'Logs'.encode('utf-16-le') + b'\0\0'
+    long_name[11] = 0x0f
+    root = b''.join([
+        entry(b'TOCKTEST   ', 0x08, 0, 0),
+        entry(b'README  TXT', 0x20, readme, len(README)),
+        long_name,
+        entry(b'LOGS       ', 0x10, logs, 0),
+    ])
+    if fat32:
+        put(data_start + (root_cluster - 2) * sectors_per_cluster, root)
+    else:
+        put(data_start - root_sectors, root)
+
+    for copy in range(num_fats):
+        table = b''.join(struct.pack('<I' if fat32 else '<H', value)
+                         for value in fat)
+        put(start + reserved + copy * fat_sectors, table)
+
+    used = len(image.rstrip(b'\0'))
+    return image[:-(-used // SECTOR_SIZE) * SECTOR_SIZE]
+
+
+if __name__ == '__main__':
+    if len(sys.argv) != 6 or sys.argv[1] not in ('16', '32'):
+        sys.exit(__doc__)
+    with open(sys.argv[5], 'wb') as f:
+        f.write(make(sys.argv[1] == '32', int(sys.argv[2]), int(sys.argv[3]),
+                     int(sys.argv[4])))
@@ -0,0 +1,710 @@
+//! Create, inspect and edit Tock Binary Format (TBF) images on the host.
+//!
+//! A `TbfImage` is one app, or one block of padding, as it is laid out in
+//! flash: a TBF header followed by the app binary. Images can be parsed from
+//! and written back to bytes, and their header elements edited in between.
+//! The layout of the header comes from the `tock-tbf` library, which the
+//! kernel uses to parse the same headers.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! extern crate tbf_tool;
+//! extern crate tock_tbf;
+//!
+//! use tbf_tool::TbfImage;
+//! use tock_tbf::TbfHeaderV2Main;
+//!
+//! let main = TbfHeaderV2Main {
+//!     init_fn_offset: 1,
+//!     protected_size: 0,
+//!     minimum_ram_size: 4096,
+//! };
+//! let mut app = TbfImage::new_app(vec![0; 64], main, tock_tbf::FLAG_ENABLED);
+//! app.set_package_name("blink");
+//! app.add_writeable_flash_region(64, 0);
+//!
+//! let bytes = app.to_bytes().unwrap();
+//! let (parsed, length) = TbfImage::parse(&bytes).unwrap();
+//! assert_eq!(length, bytes.len());
+//! assert_eq!(parsed.package_name(), Some("blink"));
+//! ```
+
+extern crate tock_tbf;
+
+use std::{error, fmt, mem, str};
+
+use tock_tbf::{TbfHeaderStruct, TbfHeaderTypes, TlvIter};
+use tock_tbf::{
+    TbfHeaderV2AppVersion, TbfHeaderV2Base, TbfHeaderV2DriverPermission, TbfHeaderV2FaultPolicy,
+    TbfHeaderV2FixedAddresses, TbfHeaderV2KernelVersion, TbfHeaderV2Main, TbfHeaderV2Priority,
+    TbfHeaderV2StorageSize, TbfHeaderV2TaskQueue, TbfHeaderV2WriteableFlashRegion,
+};
+
+/// Errors from parsing or writing TBF images.
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The bytes end before the header or image does.
+    Truncated,
+    /// The header version is not one this tool knows.
+    UnsupportedVersion(u16),
+    /// The header or total size in the base header is impossible.
+    InvalidSize,
+    /// The checksum in the header does not match its contents.
+    InvalidChecksum { expected: u32, actual: u32 },
+    /// The header would be larger than a header can be.
+    HeaderTooLarge,
+    /// Padding must be a multiple of four bytes and larger than a header.
+    InvalidPadding(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Truncated => write!(f, "image is truncated"),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported TBF header version {}", version)
+            }
+            Error::InvalidSize => write!(f, "invalid header or total size"),
+            Error::InvalidChecksum { expected, actual } => write!(
+                f,
+                "invalid checksum {:#010x}, header contents give {:#010x}",
+                actual, expected
+            ),
+            Error::HeaderTooLarge => write!(f, "header is larger than 65535 bytes"),
+            Error::InvalidPadding(size) => write!(f, "cannot pad {} bytes", size),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// One TLV element of a TBF header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tlv {
+    /// The type of the element. See `tock_tbf::TbfHeaderTypes`.
+    pub tipe: u16,
+    /// The value of the element, without padding.
+    pub data: Vec<u8>,
+}
+
+impl Tlv {
+    pub fn new<T: TbfHeaderStruct>(tipe: TbfHeaderTypes, value: &T) -> Tlv {
+        Tlv {
+            tipe: tipe as u16,
+            data: value.as_bytes().to_vec(),
+        }
+    }
+
+    /// A one line description of the element and its contents.
+    pub fn describe(&self) -> String {
+        let tipe = match TbfHeaderTypes::from_u16(self.tipe) {
+            Some(tipe) => tipe,
+            None => return format!("Unknown type {} ({} bytes)", self.tipe, self.data.len()),
+        };
+        let description = match tipe {
+            TbfHeaderTypes::TbfHeaderMain => TbfHeaderV2Main::read(&self.data).map(|main| {
+                format!(
+                    "Main: init_fn_offset {:#x}, protected_size {}, minimum_ram_size {}",
+                    main.init_fn_offset, main.protected_size, main.minimum_ram_size
+                )
+            }),
+            TbfHeaderTypes::TbfHeaderWriteableFlashRegions => {
+                if self.data.len() % mem::size_of::<TbfHeaderV2WriteableFlashRegion>() == 0 {
+                    let regions: Vec<String> = self
+                        .data
+                        .chunks(mem::size_of::<TbfHeaderV2WriteableFlashRegion>())
+                        .filter_map(TbfHeaderV2WriteableFlashRegion::read)
+                        .map(|region| {
+                            format!(
+                                "{} bytes at offset {:#x}",
+                                region.writeable_flash_region_size,
+                                region.writeable_flash_region_offset
+                            )
+                        })
+                        .collect();
+                    Some(format!("Writeable flash regions: {}", regions.join(", ")))
+                } else {
+                    None
+                }
+            }
+            TbfHeaderTypes::TbfHeaderPackageName => str::from_utf8(&self.data)
+                .ok()
+                .map(|name| format!("Package name: {}", name)),
+            TbfHeaderTypes::TbfHeaderPriority => TbfHeaderV2Priority::read(&self.data)
+                .map(|priority| format!("Priority: {}", priority.priority)),
+            TbfHeaderTypes::TbfHeaderFaultPolicy => {
+                TbfHeaderV2FaultPolicy::read(&self.data).map(|policy| {
+                    let response = match policy.response {
+                        tock_tbf::FAULT_RESPONSE_PANIC => "panic",
+                        tock_tbf::FAULT_RESPONSE_RESTART => "restart",
+                        tock_tbf::FAULT_RESPONSE_STOP => "stop",
+                        _ => "unknown",
+                    };
+                    format!(
+                        "Fault policy: {}, max_restarts {}",
+                        response, policy.max_restarts
+                    )
+                })
+            }
+            TbfHeaderTypes::TbfHeaderCredentials => {
+                if self.data.len() >= 4 {
+                    let format = match read_u32(&self.data) {
+                        tock_tbf::CREDENTIALS_SHA256 => "SHA-256",
+                        tock_tbf::CREDENTIALS_ECDSA_P256 => "ECDSA P-256",
+                        tock_tbf::CREDENTIALS_ED25519 => "Ed25519",
+                        _ => "unknown format",
+                    };
+                    let hex: Vec<String> = self.data[4..]
+                        .iter()
+                        .map(|b| format!("{:02x}", b))
+                        .collect();
+                    Some(format!("Credentials: {} {}", format, hex.concat()))
+                } else {
+                    None
+                }
+            }
+            TbfHeaderTypes::TbfHeaderPermissions => {
+                if self.data.len() % mem::size_of::<TbfHeaderV2DriverPermission>() == 0 {
+                    let permissions: Vec<String> = self
+                        .data
+                        .chunks(mem::size_of::<TbfHeaderV2DriverPermission>())
+                        .filter_map(TbfHeaderV2DriverPermission::read)
+                        .map(|permission| {
+                            format!(
+                                "driver {:#x} commands {:#010x} << {}",
+                                permission.driver_number,
+                                permission.allowed_commands,
+                                32 * permission.offset
+                            )
+                        })
+                        .collect();
+                    Some(format!("Permissions: {}", permissions.join(", ")))
+                } else {
+                    None
+                }
+            }
+            TbfHeaderTypes::TbfHeaderAppVersion => TbfHeaderV2AppVersion::read(&self.data)
+                .map(|version| format!("App version: {}", version.version)),
+            TbfHeaderTypes::TbfHeaderKernelVersion => TbfHeaderV2KernelVersion::read(&self.data)
+                .map(|version| format!("Kernel version: {}.{}", version.major, version.minor)),
+            TbfHeaderTypes::TbfHeaderFixedAddresses => TbfHeaderV2FixedAddresses::read(&self.data)
+                .map(|addresses| {
+                    format!(
+                        "Fixed addresses: RAM {}, flash {}",
+                        describe_address(addresses.start_process_ram),
+                        describe_address(addresses.start_process_flash)
+                    )
+                }),
+            TbfHeaderTypes::TbfHeaderTaskQueue => {
+                TbfHeaderV2TaskQueue::read(&self.data).map(|queue| {
+                    format!(
+                        "Task queue: depth {}{}",
+                        queue.depth,
+                        if queue.flags & tock_tbf::TASK_QUEUE_COALESCE != 0 {
+                            ", coalesce"
+                        } else {
+                            ""
+                        }
+                    )
+                })
+            }
+            TbfHeaderTypes::TbfHeaderStorageSize => TbfHeaderV2StorageSize::read(&self.data)
+                .map(|storage| format!("Storage size: {} bytes", storage.size)),
+        };
+        description.unwrap_or_else(|| format!("{:?} with invalid length {}", tipe, self.data.len()))
+    }
+}
+
+fn describe_address(address: u32) -> String {
+    if address == tock_tbf::FIXED_ADDRESS_NONE {
+        String::from("any")
+    } else {
+        format!("{:#010x}", address)
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// One TBF image: an app, or padding between apps.
+#[derive(Clone, Debug)]
+pub struct TbfImage {
+    /// The `tock_tbf::FLAG_*` flags.
+    pub flags: u32,
+    /// The TLV elements of the header, in order. Padding has none.
+    pub tlvs: Vec<Tlv>,
+    /// Everything after the header, i.e. the app binary and any padding
+    /// after it.
+    pub binary: Vec<u8>,
+}
+
+impl TbfImage {
+    /// An app with `binary` as its code, padded to a multiple of four bytes.
+    pub fn new_app(mut binary: Vec<u8>, main: TbfHeaderV2Main, flags: u32) -> TbfImage {
+        let padded_length = tock_tbf::align4(binary.len());
+        binary.resize(padded_length, 0);
+        TbfImage {
+            flags: flags,
+            tlvs: vec![Tlv::new(TbfHeaderTypes::TbfHeaderMain, &main)],
+            binary: binary,
+        }
+    }
+
+    /// Padding that takes up `total_size` bytes of flash.
+    pub fn padding(total_size: usize) -> Result<TbfImage, Error> {
+        let header_size = mem::size_of::<TbfHeaderV2Base>();
+        if total_size <= header_size || total_size % 4 != 0 {
+            return Err(Error::InvalidPadding(total_size));
+        }
+        Ok(TbfImage {
+            flags: 0,
+            tlvs: Vec::new(),
+            binary: vec![0; total_size - header_size],
+        })
+    }
+
+    /// Parse the image at the start of `bytes`. Returns the image and its
+    /// total size, i.e. where the next image starts.
+    pub fn parse(bytes: &[u8]) -> Result<(TbfImage, usize), Error> {
+        let base_size = mem::size_of::<TbfHeaderV2Base>();
+        if bytes.len() < base_size {
+            return Err(Error::Truncated);
+        }
+        let base = TbfHeaderV2Base::read(&bytes[..base_size]).ok_or(Error::Truncated)?;
+        if base.version != tock_tbf::HEADER_VERSION {
+            return Err(Error::UnsupportedVersion(base.version));
+        }
+        let header_size = base.header_size as usize;
+        let total_size = base.total_size as usize;
+        if header_size < base_size
+            || header_size >= total_size
+            || base.total_size > tock_tbf::MAX_TOTAL_SIZE
+        {
+            return Err(Error::InvalidSize);
+        }
+        if bytes.len() < total_size {
+            return Err(Error::Truncated);
+        }
+
+        let header = &bytes[..header_size];
+        let checksum = tock_tbf::checksum(header);
+        if checksum != base.checksum {
+            return Err(Error::InvalidChecksum {
+                expected: checksum,
+                actual: base.checksum,
+            });
+        }
+
+        let tlvs = TlvIter::new(header)
+            .map(|tlv| Tlv {
+                tipe: tlv.tipe,
+                data: tlv.data.to_vec(),
+            })
+            .collect();
+        let image = TbfImage {
+            flags: base.flags,
+            tlvs: tlvs,
+            binary: bytes[header_size..total_size].to_vec(),
+        };
+        Ok((image, total_size))
+    }
+
+    /// Parse every image in `bytes`, such as a flash image with several apps.
+    /// Parsing stops at the end of `bytes`, or at erased flash (all `0x00` or
+    /// all `0xFF`) after the last image.
+    pub fn parse_all(mut bytes: &[u8]) -> Result<Vec<TbfImage>, Error> {
+        let mut images = Vec::new();
+        while !bytes.is_empty() {
+            let end = mem::size_of::<u16>();
+            if bytes.len() >= end && (bytes[..end] == [0, 0] || bytes[..end] == [0xFF, 0xFF]) {
+                break;
+            }
+            let (image, total_size) = TbfImage::parse(bytes)?;
+            images.push(image);
+            bytes = &bytes[total_size..];
+        }
+        Ok(images)
+    }
+
+    /// Whether this is an app rather than padding.
+    pub fn is_app(&self) -> bool {
+        !self.tlvs.is_empty()
+    }
+
+    /// Size of the header in bytes, including the base header.
+    pub fn header_size(&self) -> usize {
+        self.tlvs
+            .iter()
+            .fold(mem::size_of::<TbfHeaderV2Base>(), |size, tlv| {
+                size + mem::size_of::<tock_tbf::TbfHeaderTlv>() + tock_tbf::align4(tlv.data.len())
+            })
+    }
+
+    /// Size of the whole image in bytes.
+    pub fn total_size(&self) -> usize {
+        self.header_size() + self.binary.len()
+    }
+
+    /// Write out the image, with the sizes and checksum in the header filled
+    /// in.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        let header_size = self.header_size();
+        if header_size > u16::max_value() as usize
+            || self
+                .tlvs
+                .iter()
+                .any(|tlv| tlv.data.len() > u16::max_value() as usize)
+        {
+            return Err(Error::HeaderTooLarge);
+        }
+        if self.total_size() > tock_tbf::MAX_TOTAL_SIZE as usize {
+            return Err(Error::InvalidSize);
+        }
+
+        let base = TbfHeaderV2Base {
+            version: tock_tbf::HEADER_VERSION,
+            header_size: header_size as u16,
+            total_size: self.total_size() as u32,
+            flags: self.flags,
+            checksum: 0,
+        };
+        let mut bytes = base.as_bytes().to_vec();
+        for tlv in self.tlvs.iter() {
+            let tlv_header = tock_tbf::TbfHeaderTlv {
+                tipe: tlv.tipe,
+                length: tlv.data.len() as u16,
+            };
+            bytes.extend_from_slice(tlv_header.as_bytes());
+            bytes.extend_from_slice(&tlv.data);
+            let padded_length = tock_tbf::align4(bytes.len());
+            bytes.resize(padded_length, 0);
+        }
+
+        let checksum = tock_tbf::checksum(&bytes);
+        for i in 0..4 {
+            bytes[12 + i] = (checksum >> (8 * i)) as u8;
+        }
+
+        bytes.extend_from_slice(&self.binary);
+        Ok(bytes)
+    }
+
+    /// The TLV elements of type `tipe`.
+    pub fn find<'a>(&'a self, tipe: TbfHeaderTypes) -> impl Iterator<Item = &'a Tlv> {
+        self.tlvs.iter().filter(move |tlv| tlv.tipe == tipe as u16)
+    }
+
+    /// Remove all TLV elements of type `tipe`, and return how many there
+    /// were.
+    pub fn remove(&mut self, tipe: TbfHeaderTypes) -> usize {
+        let count = self.tlvs.len();
+        self.tlvs.retain(|tlv| tlv.tipe != tipe as u16);
+        count - self.tlvs.len()
+    }
+
+    /// The package name of the app, if it has a valid one.
+    pub fn package_name(&self) -> Option<&str> {
+        self.find(TbfHeaderTypes::TbfHeaderPackageName)
+            .next()
+            .and_then(|tlv| str::from_utf8(&tlv.data).ok())
+    }
+
+    /// Set the package name of the app, replacing any it had.
+    pub fn set_package_name(&mut self, name: &str) {
+        self.remove(TbfHeaderTypes::TbfHeaderPackageName);
+        self.tlvs.push(Tlv {
+            tipe: TbfHeaderTypes::TbfHeaderPackageName as u16,
+            data: name.as_bytes().to_vec(),
+        });
+    }
+
+    /// Add a writeable flash region of `size` bytes at `offset` from the
+    /// start of the image.
+    pub fn add_writeable_flash_region(&mut self, offset: u32, size: u32) {
+        let region = TbfHeaderV2WriteableFlashRegion {
+            writeable_flash_region_offset: offset,
+            writeable_flash_region_size: size,
+        };
+        let tipe = TbfHeaderTypes::TbfHeaderWriteableFlashRegions as u16;
+        match self.tlvs.iter().rposition(|tlv| tlv.tipe == tipe) {
+            Some(index) => self.tlvs[index].data.extend_from_slice(region.as_bytes()),
+            None => self.tlvs.push(Tlv::new(
+                TbfHeaderTypes::TbfHeaderWriteableFlashRegions,
+                &region,
+            )),
+        }
+    }
+
+    /// Edit the header with `edit`, keeping the writeable flash regions that
+    /// were already in the header pointing at the same bytes of the binary.
+    ///
+    /// Region offsets are from the start of the image, so they move when the
+    /// header changes size. Regions that started inside the header are left
+    /// alone, as are regions `edit` adds, whose offsets are taken to be in the
+    /// edited image.
+    pub fn edit_header<F, R>(&mut self, edit: F) -> R
+    where
+        F: FnOnce(&mut TbfImage) -> R,
+    {
+        let header_size = self.header_size();
+        let existing_regions = self.writeable_flash_region_offsets().count();
+        let result = edit(self);
+
+        let new_header_size = self.header_size();
+        for offset in self
+            .writeable_flash_region_offsets()
+            .take(existing_regions)
+        {
+            let value = read_u32(offset) as usize;
+            if value >= header_size {
+                let moved = (value - header_size + new_header_size) as u32;
+                for i in 0..4 {
+                    offset[i] = (moved >> (8 * i)) as u8;
+                }
+            }
+        }
+        result
+    }
+
+    /// The offset fields of the writeable flash regions, in header order.
+    fn writeable_flash_region_offsets<'a>(&'a mut self) -> impl Iterator<Item = &'a mut [u8]> {
+        let tipe = TbfHeaderTypes::TbfHeaderWriteableFlashRegions as u16;
+        let region_size = mem::size_of::<TbfHeaderV2WriteableFlashRegion>();
+        self.tlvs
+            .iter_mut()
+            .filter(move |tlv| tlv.tipe == tipe)
+            .flat_map(move |tlv| tlv.data.chunks_mut(region_size))
+            .filter(move |region| region.len() == region_size)
+            .map(|region| &mut region[..4])
+    }
+}
+
+/// Lay out `images` one after another as they would be in flash.
+///
+/// With `align`, each app starts at an offset that is a multiple of its size
+/// rounded up to a power of two, as the MPU needs on some chips. The gaps are
+/// filled with padding images so the kernel skips over them.
+pub fn concat(images: &[TbfImage], align: bool) -> Result<Vec<u8>, Error> {
+    let mut flash = Vec::new();
+    for image in images.iter() {
+        let bytes = image.to_bytes()?;
+        if align {
+            let alignment = bytes.len().next_power_of_two();
+            let mut start = (flash.len() + alignment - 1) / alignment * alignment;
+            // Padding needs room for at least a header and one word.
+            while start != flash.len() && start - flash.len() <= mem::size_of::<TbfHeaderV2Base>() {
+                start += alignment;
+            }
+            if start != flash.len() {
+                let padding = TbfImage::padding(start - flash.len())?;
+                flash.extend_from_slice(&padding.to_bytes()?);
+            }
+        }
+        flash.extend_from_slice(&bytes);
+    }
+    Ok(flash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{concat, Error, TbfImage, Tlv};
+    use tock_tbf;
+    use tock_tbf::{TbfHeaderStruct, TbfHeaderTypes, TbfHeaderV2Main, TbfHeaderV2Priority};
+    use tock_tbf::{TbfHeaderV2WriteableFlashRegion, TlvIter};
+
+    fn app(binary_size: usize) -> TbfImage {
+        let main = TbfHeaderV2Main {
+            init_fn_offset: 0x29,
+            protected_size: 0,
+            minimum_ram_size: 4096,
+        };
+        TbfImage::new_app(vec![0xA5; binary_size], main, tock_tbf::FLAG_ENABLED)
+    }
+
+    fn regions(image: &TbfImage) -> Vec<(u32, u32)> {
+        image
+            .find(TbfHeaderTypes::TbfHeaderWriteableFlashRegions)
+            .flat_map(|tlv| tlv.data.chunks(8))
+            .map(|bytes| {
+                let region = TbfHeaderV2WriteableFlashRegion::read(bytes).unwrap();
+                (
+                    region.writeable_flash_region_offset,
+                    region.writeable_flash_region_size,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut image = app(61);
+        image.set_package_name("blink");
+        image.tlvs.push(Tlv::new(
+            TbfHeaderTypes::TbfHeaderPriority,
+            &TbfHeaderV2Priority { priority: 2 },
+        ));
+        image.add_writeable_flash_region(0x400, 1024);
+
+        let bytes = image.to_bytes().unwrap();
+        // Base, main, name padded to 8 bytes, priority and region.
+        assert_eq!(image.header_size(), 16 + 16 + 12 + 8 + 12);
+        assert_eq!(bytes.len(), image.header_size() + 64);
+        assert_eq!(bytes.len(), image.total_size());
+        assert_eq!(
+            TlvIter::new(&bytes[..image.header_size()]).count(),
+            image.tlvs.len()
+        );
+
+        let (parsed, length) = TbfImage::parse(&bytes).unwrap();
+        assert_eq!(length, bytes.len());
+        assert_eq!(parsed.flags, tock_tbf::FLAG_ENABLED);
+        assert_eq!(parsed.tlvs, image.tlvs);
+        assert_eq!(parsed.binary, image.binary);
+        assert_eq!(parsed.package_name(), Some("blink"));
+        assert_eq!(parsed.to_bytes().unwrap(), bytes);
+    }
+
+    #[test]
+    fn checks_checksum() {
+        let mut bytes = app(16).to_bytes().unwrap();
+        let checksum = tock_tbf::checksum(&bytes[..32]);
+        assert_eq!(
+            bytes[12..16],
+            [
+                checksum as u8,
+                (checksum >> 8) as u8,
+                (checksum >> 16) as u8,
+                (checksum >> 24) as u8
+            ]
+        );
+
+        // The binary is not covered by the checksum.
+        bytes[40] = 0;
+        assert!(TbfImage::parse(&bytes).is_ok());
+
+        bytes[20] ^= 1;
+        assert_eq!(
+            TbfImage::parse(&bytes).unwrap_err(),
+            Error::InvalidChecksum {
+                expected: checksum ^ 1,
+                actual: checksum,
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        let bytes = app(16).to_bytes().unwrap();
+        assert_eq!(TbfImage::parse(&bytes[..12]).unwrap_err(), Error::Truncated);
+        assert_eq!(TbfImage::parse(&bytes[..40]).unwrap_err(), Error::Truncated);
+
+        let mut version = bytes.clone();
+        version[0] = 1;
+        assert_eq!(
+            TbfImage::parse(&version).unwrap_err(),
+            Error::UnsupportedVersion(1)
+        );
+
+        // A header that is larger than the image.
+        let mut size = bytes.clone();
+        size[2] = 64;
+        assert_eq!(TbfImage::parse(&size).unwrap_err(), Error::InvalidSize);
+
+        assert_eq!(
+            TbfImage::padding(16).unwrap_err(),
+            Error::InvalidPadding(16)
+        );
+        assert_eq!(
+            TbfImage::padding(30).unwrap_err(),
+            Error::InvalidPadding(30)
+        );
+    }
+
+    #[test]
+    fn keeps_unknown_and_malformed_tlvs() {
+        let mut image = app(8);
+        image.tlvs.push(Tlv {
+            tipe: 200,
+            data: vec![1, 2, 3],
+        });
+        image.tlvs.push(Tlv {
+            tipe: TbfHeaderTypes::TbfHeaderPriority as u16,
+            data: vec![1, 2],
+        });
+        let (parsed, _) = TbfImage::parse(&image.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed.tlvs, image.tlvs);
+        assert_eq!(parsed.tlvs[1].describe(), "Unknown type 200 (3 bytes)");
+        assert_eq!(
+            parsed.tlvs[2].describe(),
+            "TbfHeaderPriority with invalid length 2"
+        );
+    }
+
+    #[test]
+    fn concatenates_images() {
+        let first = app(20);
+        let second = app(100);
+        let flash = concat(&[first.clone(), second.clone()], false).unwrap();
+        assert_eq!(flash.len(), first.total_size() + second.total_size());
+
+        let images = TbfImage::parse_all(&flash).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[1].binary, second.binary);
+
+        // Erased flash after the images is not parsed.
+        let mut erased = flash.clone();
+        erased.extend_from_slice(&[0xFF; 64]);
+        assert_eq!(TbfImage::parse_all(&erased).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn aligns_concatenated_apps() {
+        // 52 bytes rounds up to 64 and 132 bytes up to 256.
+        let first = app(20);
+        let second = app(100);
+        assert_eq!(first.total_size(), 52);
+        assert_eq!(second.total_size(), 132);
+
+        let flash = concat(&[first, second], true).unwrap();
+        assert_eq!(flash.len(), 256 + 132);
+
+        let images = TbfImage::parse_all(&flash).unwrap();
+        assert_eq!(images.len(), 3);
+        assert!(images[0].is_app());
+        assert!(!images[1].is_app());
+        assert_eq!(images[1].total_size(), 256 - 52);
+        assert!(images[2].is_app());
+    }
+
+    #[test]
+    fn edits_keep_regions_on_the_binary() {
+        let mut image = app(64);
+        image.add_writeable_flash_region(0x40, 16);
+        image.add_writeable_flash_region(0x8, 4);
+        assert_eq!(image.header_size(), 52);
+
+        // A name grows the header by 12 bytes.
+        image.edit_header(|image| image.set_package_name("blink"));
+        assert_eq!(image.header_size(), 64);
+        assert_eq!(regions(&image), vec![(0x4C, 16), (0x8, 4)]);
+
+        // A new region is where it is asked to be. The region inside the
+        // header never moves.
+        image.edit_header(|image| image.add_writeable_flash_region(0x60, 8));
+        assert_eq!(regions(&image), vec![(0x54, 16), (0x8, 4), (0x60, 8)]);
+
+        image.edit_header(|image| {
+            image.remove(TbfHeaderTypes::TbfHeaderPackageName);
+        });
+        assert_eq!(image.header_size(), 60);
+        assert_eq!(regions(&image), vec![(0x48, 16), (0x8, 4), (0x54, 8)]);
+
+        image.edit_header(|image| {
+            image.remove(TbfHeaderTypes::TbfHeaderWriteableFlashRegions);
+        });
+        assert!(regions(&image).is_empty());
+    }
+}
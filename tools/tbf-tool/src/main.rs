@@ -0,0 +1,254 @@
+//! Command line tool for Tock Binary Format (TBF) images.
+//!
+//! Run `tbf-tool help` for usage.
+
+extern crate tbf_tool;
+extern crate tock_tbf;
+
+use std::fs::File;
+use std::io::{Read, Write};
+use std::{env, process};
+
+use tbf_tool::TbfImage;
+use tock_tbf::{TbfHeaderTypes, TbfHeaderV2Main};
+
+const USAGE: &'static str = "\
+Usage: tbf-tool <command> [arguments]
+
+Commands:
+  create <binary> <output> [options]
+        Create a TBF image for an app binary. Options:
+          --name <name>                Package name
+          --init-offset <offset>       Offset of the entry point in the binary
+          --protected-size <bytes>     Bytes after the header apps cannot write
+          --minimum-ram <bytes>        RAM the app needs
+          --writeable-region <offset>:<size>
+                                       Writeable flash region, may be repeated
+          --disabled                   Do not start the app at boot
+          --sticky                     Require confirmation to erase the app
+  inspect <file>
+        List the images in a TBF image or flash image, and their headers.
+  checksum <file>
+        Recompute the checksum of every image in the file.
+  set-name <file> <name>
+        Set the package name of a TBF image.
+  strip-name <file>
+        Remove the package name from a TBF image.
+  add-region <file> <offset> <size>
+        Add a writeable flash region to a TBF image.
+  strip-regions <file>
+        Remove all writeable flash regions from a TBF image.
+  concat [--align] <output> <file>...
+        Combine TBF images into one flash image. With --align, each app is
+        aligned to its size rounded up to a power of two.
+
+Numbers can be given in decimal, or in hexadecimal starting with 0x.";
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    let result = match args.split_first() {
+        Some((&"create", args)) => create(args),
+        Some((&"inspect", &[file])) => inspect(file),
+        Some((&"checksum", &[file])) => fix_checksums(file),
+        Some((&"set-name", &[file, name])) => edit(file, |image| {
+            image.set_package_name(name);
+            Ok(())
+        }),
+        Some((&"strip-name", &[file])) => edit(file, |image| {
+            image.remove(TbfHeaderTypes::TbfHeaderPackageName);
+            Ok(())
+        }),
+        Some((&"add-region", &[file, offset, size])) => edit(file, |image| {
+            image.add_writeable_flash_region(parse_number(offset)?, parse_number(size)?);
+            Ok(())
+        }),
+        Some((&"strip-regions", &[file])) => edit(file, |image| {
+            image.remove(TbfHeaderTypes::TbfHeaderWriteableFlashRegions);
+            Ok(())
+        }),
+        Some((&"concat", args)) => concat(args),
+        Some((&"help", _)) | Some((&"--help", _)) => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        _ => Err(format!("invalid arguments\n\n{}", USAGE)),
+    };
+
+    if let Err(message) = result {
+        eprintln!("tbf-tool: {}", message);
+        process::exit(1);
+    }
+}
+
+fn parse_number(number: &str) -> Result<u32, String> {
+    let result = if number.starts_with("0x") {
+        u32::from_str_radix(&number[2..], 16)
+    } else {
+        number.parse()
+    };
+    result.map_err(|_| format!("invalid number {}", number))
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .map_err(|err| format!("{}: {}", path, err))?;
+    Ok(bytes)
+}
+
+fn write_file(path: &str, bytes: &[u8]) -> Result<(), String> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(bytes))
+        .map_err(|err| format!("{}: {}", path, err))
+}
+
+fn create(args: &[&str]) -> Result<(), String> {
+    if args.len() < 2 {
+        return Err(format!("create needs a binary and an output file"));
+    }
+    let (binary_path, output_path) = (args[0], args[1]);
+
+    let mut main = TbfHeaderV2Main {
+        init_fn_offset: 0,
+        protected_size: 0,
+        minimum_ram_size: 0,
+    };
+    let mut name = None;
+    let mut regions = Vec::new();
+    let mut flags = tock_tbf::FLAG_ENABLED;
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = || {
+            options
+                .next()
+                .ok_or_else(|| format!("{} needs a value", option))
+        };
+        match *option {
+            "--name" => name = Some(value()?),
+            "--init-offset" => main.init_fn_offset = parse_number(value()?)?,
+            "--protected-size" => main.protected_size = parse_number(value()?)?,
+            "--minimum-ram" => main.minimum_ram_size = parse_number(value()?)?,
+            "--writeable-region" => {
+                let region = value()?;
+                let mut parts = region.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(offset), Some(size)) => {
+                        regions.push((parse_number(offset)?, parse_number(size)?))
+                    }
+                    _ => return Err(format!("invalid writeable region {}", region)),
+                }
+            }
+            "--disabled" => flags &= !tock_tbf::FLAG_ENABLED,
+            "--sticky" => flags |= tock_tbf::FLAG_STICKY,
+            _ => return Err(format!("unknown option {}", option)),
+        }
+    }
+
+    let mut image = TbfImage::new_app(read_file(binary_path)?, main, flags);
+    if let Some(name) = name {
+        image.set_package_name(name);
+    }
+    for (offset, size) in regions {
+        image.add_writeable_flash_region(offset, size);
+    }
+    write_file(
+        output_path,
+        &image.to_bytes().map_err(|err| err.to_string())?,
+    )
+}
+
+fn inspect(path: &str) -> Result<(), String> {
+    let bytes = read_file(path)?;
+    let images = TbfImage::parse_all(&bytes).map_err(|err| format!("{}: {}", path, err))?;
+
+    let mut offset = 0;
+    for image in images.iter() {
+        if image.is_app() {
+            println!(
+                "App at {:#x}: {} bytes, header {} bytes, flags {:#x}",
+                offset,
+                image.total_size(),
+                image.header_size(),
+                image.flags
+            );
+            for tlv in image.tlvs.iter() {
+                println!("  {}", tlv.describe());
+            }
+        } else {
+            println!("Padding at {:#x}: {} bytes", offset, image.total_size());
+        }
+        offset += image.total_size();
+    }
+    Ok(())
+}
+
+/// Apply `change` to the single TBF image in `path` and write it back. Writeable
+/// flash regions already in the image move with the binary.
+fn edit<F>(path: &str, change: F) -> Result<(), String>
+where
+    F: FnOnce(&mut TbfImage) -> Result<(), String>,
+{
+    let bytes = read_file(path)?;
+    let (mut image, length) =
+        TbfImage::parse(&bytes).map_err(|err| format!("{}: {}", path, err))?;
+    if length != bytes.len() {
+        return Err(format!("{}: expected a single TBF image", path));
+    }
+    if image
+        .find(TbfHeaderTypes::TbfHeaderCredentials)
+        .next()
+        .is_some()
+    {
+        eprintln!("tbf-tool: warning: editing the header invalidates the app's credentials");
+    }
+
+    image.edit_header(change)?;
+    write_file(path, &image.to_bytes().map_err(|err| err.to_string())?)
+}
+
+/// Recompute the checksum of every image in `path`.
+fn fix_checksums(path: &str) -> Result<(), String> {
+    let mut bytes = read_file(path)?;
+    let mut offset = 0;
+    while offset + 16 <= bytes.len() {
+        let header_size = bytes[offset + 2] as usize | (bytes[offset + 3] as usize) << 8;
+        if header_size < 16 || offset + header_size > bytes.len() {
+            break;
+        }
+        let checksum = tock_tbf::checksum(&bytes[offset..offset + header_size]);
+        for i in 0..4 {
+            bytes[offset + 12 + i] = (checksum >> (8 * i)) as u8;
+        }
+
+        // With the checksum fixed the rest of the header must be valid.
+        let (_, total_size) =
+            TbfImage::parse(&bytes[offset..]).map_err(|err| format!("{}: {}", path, err))?;
+        offset += total_size;
+    }
+    write_file(path, &bytes)
+}
+
+fn concat(args: &[&str]) -> Result<(), String> {
+    let (align, args) = match args.split_first() {
+        Some((&"--align", args)) => (true, args),
+        _ => (false, args),
+    };
+    let (output_path, paths) = match args.split_first() {
+        Some((output_path, paths)) if !paths.is_empty() => (output_path, paths),
+        _ => return Err(format!("concat needs an output file and TBF images")),
+    };
+
+    let mut images = Vec::new();
+    for path in paths.iter() {
+        let bytes = read_file(path)?;
+        let file_images =
+            TbfImage::parse_all(&bytes).map_err(|err| format!("{}: {}", path, err))?;
+        images.extend(file_images.into_iter().filter(|image| image.is_app()));
+    }
+    let flash = tbf_tool::concat(&images, align).map_err(|err| err.to_string())?;
+    write_file(output_path, &flash)
+}